@@ -0,0 +1,34 @@
+//!
+//! 構文木を作ってから評価する場合と、fast_eval()で構文解析しながら評価する場合の
+//! 時間を比較するベンチマーク。
+//!
+//! cargo bench --bench fast_eval
+//!
+use std::time::Instant;
+
+use parser::fast_eval::fast_eval;
+use parser::interpreter::Interpreter;
+use parser::parser::Ast;
+
+const LINES: usize = 100_000;
+
+fn main() {
+    let lines: Vec<String> = (0..LINES)
+        .map(|i| format!("{} + 2 * (3 - {}) / 4 ^ 2 - max({}, 7)", i, i % 10, i % 13))
+        .collect();
+
+    let start = Instant::now();
+    let mut sum = 0i64;
+    for line in &lines {
+        let ast = line.parse::<Ast>().unwrap();
+        sum = sum.wrapping_add(Interpreter::new().eval(&ast).unwrap());
+    }
+    println!("parse + eval: {:?}, sum {}", start.elapsed(), sum);
+
+    let start = Instant::now();
+    let mut sum = 0i64;
+    for line in &lines {
+        sum = sum.wrapping_add(fast_eval(line).unwrap());
+    }
+    println!("fast_eval():  {:?}, sum {}", start.elapsed(), sum);
+}
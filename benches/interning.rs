@@ -0,0 +1,90 @@
+//!
+//! 同じ形の式を大量に解析したときの、hash-consingによる
+//! メモリ使用量と等価性判定の時間を比較するベンチマーク。
+//!
+//! cargo bench --bench interning
+//!
+use std::mem::size_of;
+use std::rc::Rc;
+use std::time::Instant;
+
+use parser::interner::{same, AstInterner, Node};
+use parser::parser::{Ast, AstKind};
+
+const FORMULAS: usize = 100_000;
+
+fn count_nodes(ast: &Ast) -> usize {
+    match ast.value {
+        AstKind::Num(_) | AstKind::Var(_) => 1,
+        AstKind::Assign { ref value, .. } => 1 + count_nodes(value),
+        AstKind::Unary { ref operand, .. } => 1 + count_nodes(operand),
+        AstKind::Binary {
+            ref left,
+            ref right,
+            ..
+        } => 1 + count_nodes(left) + count_nodes(right),
+        AstKind::Call { ref args, .. } => 1 + args.iter().map(count_nodes).sum::<usize>(),
+    }
+}
+
+fn main() {
+    let sources: Vec<String> = (0..FORMULAS)
+        .map(|i| {
+            format!(
+                "(price + {}) * (1 + rate) ^ 2 - fee / {}",
+                i % 100,
+                i % 7 + 1
+            )
+        })
+        .collect();
+    let asts: Vec<Ast> = sources.iter().map(|s| s.parse().unwrap()).collect();
+
+    // メモリ使用量
+    let ast_nodes: usize = asts.iter().map(count_nodes).sum();
+    let mut interner = AstInterner::new();
+    let start = Instant::now();
+    let interned: Vec<Rc<Node>> = asts.iter().map(|ast| interner.intern(ast)).collect();
+    let intern_time = start.elapsed();
+    // Rcは参照カウント2つ分の領域を余分に使う
+    let node_size = size_of::<Node>() + 2 * size_of::<usize>();
+    println!("formulas:            {}", FORMULAS);
+    println!(
+        "ast nodes:           {} ({} bytes)",
+        ast_nodes,
+        ast_nodes * size_of::<Ast>()
+    );
+    println!(
+        "interned nodes:      {} ({} bytes)",
+        interner.len(),
+        interner.len() * node_size
+    );
+    println!("intern time:         {:?}", intern_time);
+
+    // 等価性判定（700件ごとに同じ式が現れる）
+    let start = Instant::now();
+    let mut equal = 0;
+    for (a, b) in asts.iter().zip(asts.iter().skip(700)) {
+        // 等しい木は末端まで辿らないと判定できない
+        if a == b {
+            equal += 1;
+        }
+    }
+    println!(
+        "ast eq:              {:?} ({} equal)",
+        start.elapsed(),
+        equal
+    );
+
+    let start = Instant::now();
+    let mut equal = 0;
+    for (a, b) in interned.iter().zip(interned.iter().skip(700)) {
+        if same(a, b) {
+            equal += 1;
+        }
+    }
+    println!(
+        "interned eq:         {:?} ({} equal)",
+        start.elapsed(),
+        equal
+    );
+}
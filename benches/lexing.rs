@@ -0,0 +1,79 @@
+//!
+//! 行ごとにlex()を呼ぶ場合と、lex_into()でトークンの列を使い回す場合、
+//! Lexerを使い回す場合のメモリ確保回数と時間を比較するベンチマーク。
+//!
+//! cargo bench --bench lexing
+//!
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use parser::lexer::{lex, lex_into, Lexer};
+
+/// メモリ確保の回数を数えるアロケータ
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const LINES: usize = 100_000;
+
+fn main() {
+    let lines: Vec<String> = (0..LINES)
+        .map(|i| format!("{} + 2 * (3 - {}) / 4 ^ 2", i, i % 10))
+        .collect();
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let mut count = 0;
+    for line in &lines {
+        count += lex(line).unwrap().len();
+    }
+    println!(
+        "lex():        {:?}, {} allocations, {} tokens",
+        start.elapsed(),
+        ALLOCATIONS.load(Ordering::Relaxed) - before,
+        count
+    );
+
+    let mut tokens = Vec::new();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let mut count = 0;
+    for line in &lines {
+        lex_into(line, &mut tokens).unwrap();
+        count += tokens.len();
+    }
+    println!(
+        "lex_into():   {:?}, {} allocations, {} tokens",
+        start.elapsed(),
+        ALLOCATIONS.load(Ordering::Relaxed) - before,
+        count
+    );
+
+    let mut lexer = Lexer::new();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let mut count = 0;
+    for line in &lines {
+        count += lexer.lex(line).unwrap().len();
+    }
+    println!(
+        "Lexer::lex(): {:?}, {} allocations, {} tokens",
+        start.elapsed(),
+        ALLOCATIONS.load(Ordering::Relaxed) - before,
+        count
+    );
+}
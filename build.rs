@@ -0,0 +1,28 @@
+//!
+//! ffi機能を有効にしてビルドしたとき、C・C++向けのヘッダーをOUT_DIR/calc.hへ生成する。
+//! ビルドでソースツリーを書き換えないよう、リポジトリのinclude/calc.hは更新しない。
+//! include/calc.hはsrc/ffi.rsを変えたときに次のコマンドで作り直す（古いままならffi機能のテストが失敗する）。
+//!
+//! ```text
+//! cbindgen --config cbindgen.toml --output include/calc.h src/ffi.rs
+//! ```
+//!
+fn main() {
+    #[cfg(feature = "ffi")]
+    generate_header();
+}
+
+#[cfg(feature = "ffi")]
+fn generate_header() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let crate_dir = std::path::PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).unwrap();
+    // ライブラリ全体ではなく、公開する関数を定義したファイルだけを読む
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(crate_dir.join("src/ffi.rs"))
+        .generate()
+        .expect("failed to generate calc.h")
+        .write_to_file(std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("calc.h"));
+}
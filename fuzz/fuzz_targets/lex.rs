@@ -0,0 +1,29 @@
+//!
+//! 任意のバイト列を字句解析し、パニックしないことを確かめる。
+//!
+//! cargo +nightly fuzz run lex
+//!
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use parser::lexer::{decode, lex, lex_all_errors, lex_borrowed, StreamingLexer};
+
+fuzz_target!(|data: &[u8]| {
+    let (input, _) = decode(data);
+    let tokens = lex(&input);
+    // 識別子の名前を借りても、所有しても同じトークンになる
+    let borrowed = lex_borrowed(&input).map(|tokens| {
+        tokens
+            .into_iter()
+            .map(|tok| tok.into_owned())
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(tokens, borrowed);
+    // エラーがなければ、エラーを読み飛ばす字句解析器も同じトークンを返す
+    let (recovered, errors) = lex_all_errors(&input);
+    if let Ok(ref tokens) = tokens {
+        assert_eq!(*tokens, recovered);
+        assert!(errors.is_empty());
+    }
+    StreamingLexer::new(&input).for_each(drop);
+});
@@ -0,0 +1,22 @@
+//!
+//! 任意の文字列を構文解析し、パニックしないことを確かめる。
+//! 深く入れ子になった式は、スタックを使い切らずにエラーになる。
+//!
+//! cargo +nightly fuzz run parse
+//!
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use parser::lexer::lex_all_errors;
+use parser::parser::{parse_with_recovery, Ast};
+
+fuzz_target!(|input: &str| {
+    let parsed = input.parse::<Ast>();
+    // 解析できた式は、表示して読み直しても同じ値の式になる
+    if let Ok(ref ast) = parsed {
+        let shown = ast.to_string();
+        assert!(shown.parse::<Ast>().is_ok(), "{}", shown);
+    }
+    let (tokens, _) = lex_all_errors(input);
+    let _ = parse_with_recovery(&tokens);
+});
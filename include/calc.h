@@ -0,0 +1,90 @@
+#ifndef PARSER_CALC_H
+#define PARSER_CALC_H
+
+/* This file is generated by cbindgen from src/ffi.rs. Do not edit it by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * 関数の結果
+ */
+typedef enum CalcStatus {
+  /**
+   * 処理できた
+   */
+  CALC_STATUS_OK = 0,
+  /**
+   * 入力の式に誤りがあった
+   */
+  CALC_STATUS_ERROR = 1,
+  /**
+   * 引数がNULLか、入力がUTF-8として正しくない
+   */
+  CALC_STATUS_INVALID_ARGUMENT = 2,
+  /**
+   * ライブラリの内部の誤りで処理を打ち切った
+   */
+  CALC_STATUS_INTERNAL = 3,
+} CalcStatus;
+
+/**
+ *
+ * 失敗の詳細。
+ * startとendは入力中の範囲で、入力の先頭からのバイト数で数える。
+ * messageはライブラリが確保するので、使い終わったらcalc_error_freeで解放する。
+ *
+ */
+typedef struct CalcError {
+  /**
+   * エラーの種類ごとに決まった符号（"P0004"など）。式の誤りでなければ空文字列
+   */
+  char code[8];
+  /**
+   * UTF-8のメッセージ。NULで終わる
+   */
+  char *message;
+  size_t start;
+  size_t end;
+} CalcError;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ *
+ * NULで終わるUTF-8の式を評価し、値をoutへ書き込む。
+ * 失敗したらCALC_STATUS_OK以外を返し、errがNULLでなければ最初のエラーの詳細を書き込む。
+ * 成功したときはerrに触れない。
+ *
+ * # Safety
+ *
+ * inputはNULで終わる文字列を、outは書き込めるint64_tを、errは書き込めるCalcErrorを指すか、NULLでなければならない。
+ * errへ書き込まれた詳細は、次にerrを渡す前にcalc_error_freeで解放する。
+ *
+ */
+enum CalcStatus calc_eval(const char *input,
+                          int64_t *out,
+                          struct CalcError *err);
+
+/**
+ *
+ * calc_evalがerrへ書き込んだメッセージを解放し、messageをNULLにする。
+ * 解放済みのerrやNULLを渡しても何もしない。
+ *
+ * # Safety
+ *
+ * errはcalc_evalが書き込んだCalcErrorか、messageがNULLのCalcErrorを指すか、NULLでなければならない。
+ *
+ */
+void calc_error_free(struct CalcError *err);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* PARSER_CALC_H */
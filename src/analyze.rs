@@ -0,0 +1,144 @@
+//!
+//! 抽象構文木の静的な解析。
+//! 式を実行せずに、コンパイルした結果の性質を求める。
+//!
+use super::parser::*;
+
+///
+/// 式を計算するのに必要な、値のスタックの最大の深さを返す。
+/// バイトコードのVMと同じく、被演算子を左から順に積み、関数はすべての引数を積んでから呼ぶものとする。
+/// VMがスタックを前もって確保したり、wasmやアセンブリへの変換で一時的な置き場の数を決めたりするのに使う。
+///
+/// ```
+/// use parser::analyze::max_stack_depth;
+/// use parser::parser::Ast;
+///
+/// // 1を積み、2を積み、3を積んでから掛け、足す
+/// assert_eq!(max_stack_depth(&"1 + 2 * 3".parse::<Ast>().unwrap()), 3);
+/// assert_eq!(max_stack_depth(&"1 * 2 + 3".parse::<Ast>().unwrap()), 2);
+/// ```
+///
+pub fn max_stack_depth(expr: &Ast) -> usize {
+    use super::parser::AstKind::*;
+    // 節点を、計算し始めるときにスタックに残っている値の数とともに積む。
+    // 長い演算子の連なりでもネイティブのスタックを使い切らないよう、再帰せずに辿る
+    let mut max_depth = 0;
+    let mut stack = vec![(expr, 0)];
+    while let Some((expr, below)) = stack.pop() {
+        match expr.value {
+            Num(_) | Var(_) => max_depth = max_depth.max(below + 1),
+            // 代入した値はスタックに残す
+            Assign { ref value, .. } => stack.push((value, below)),
+            // 単項演算子は値を1つ取り出して1つ積むので、深さは変わらない
+            Unary { ref operand, .. } => stack.push((operand, below)),
+            // 右辺を計算する間は、左辺の値が1つ残っている
+            Binary {
+                ref left,
+                ref right,
+                ..
+            } => stack.extend([(&**left, below), (&**right, below + 1)]),
+            // i番目の引数を計算する間は、それより前の引数の値が残っている。引数がなくても結果を1つ積む
+            Call { ref args, .. } => {
+                max_depth = max_depth.max(below + 1);
+                stack.extend(args.iter().enumerate().map(|(i, arg)| (arg, below + i)));
+            }
+        }
+    }
+    max_depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{BytecodeCompiler, Instruction, InstructionKind};
+    use crate::lexer::Location;
+    use proptest::prelude::*;
+
+    fn depth(input: &str) -> usize {
+        max_stack_depth(&input.parse().unwrap())
+    }
+
+    /// 命令列を実行したときのスタックの深さの最大値
+    fn peak(code: &[Instruction]) -> usize {
+        let mut depth = 0usize;
+        let mut peak = 0;
+        for inst in code {
+            depth -= match inst.value {
+                InstructionKind::Push(_) | InstructionKind::Load(_) => 0,
+                InstructionKind::Store(_) | InstructionKind::Neg => 1,
+                InstructionKind::Call(_, argc) => argc,
+                _ => 2,
+            };
+            depth += 1;
+            peak = peak.max(depth);
+        }
+        peak
+    }
+
+    #[test]
+    fn test_max_stack_depth() {
+        assert_eq!(depth("1"), 1);
+        assert_eq!(depth("x = -y"), 1);
+        assert_eq!(depth("(1 + 2) * (3 + 4)"), 3);
+        assert_eq!(depth("f()"), 1);
+        assert_eq!(depth("max(1, 2, 3 * 4)"), 4);
+        assert_eq!(depth("max(1 * 2 * 3, 4)"), 2);
+
+        // 左へ入れ子になった式は深さが増えない
+        let left = format!("{}1{}", "(".repeat(200), " + 1)".repeat(200));
+        assert_eq!(depth(&left), 2);
+        // 右へ入れ子になった式は入れ子の数だけ深くなる
+        let right = format!("{}1{}", "1 + (".repeat(200), ")".repeat(200));
+        assert_eq!(depth(&right), 201);
+        // べき乗は右結合
+        assert_eq!(depth(&format!("{}2", "2 ^ ".repeat(299))), 300);
+
+        // 構文解析器を通さない、さらに深い木
+        let loc = || Location(0, 0);
+        let mut ast = Ast::num(1, loc());
+        for i in 0..1000 {
+            ast = match i % 3 {
+                0 => Ast::binary(BinaryOperator::sub(loc()), Ast::var("x", loc()), ast, loc()),
+                1 => Ast::unary(UnaryOperator::minus(loc()), ast, loc()),
+                _ => Ast::call("max", vec![Ast::num(1, loc()), ast], loc()),
+            };
+        }
+        assert_eq!(max_stack_depth(&ast), 668);
+    }
+
+    /// 抽象構文木。構文解析器が作らない形（入れ子の代入など）も含める
+    fn ast() -> impl Strategy<Value = Ast> {
+        let loc = || Location(0, 0);
+        let leaf = prop_oneof![
+            (0u64..1000).prop_map(move |n| Ast::num(n, loc())),
+            prop::sample::select(vec!["x", "y"]).prop_map(move |name| Ast::var(name, loc())),
+        ];
+        leaf.prop_recursive(8, 64, 4, move |inner| {
+            prop_oneof![
+                (inner.clone(), inner.clone()).prop_map(move |(l, r)| Ast::binary(
+                    BinaryOperator::add(loc()),
+                    l,
+                    r,
+                    loc()
+                )),
+                inner
+                    .clone()
+                    .prop_map(move |e| Ast::unary(UnaryOperator::minus(loc()), e, loc())),
+                inner.clone().prop_map(move |e| Ast::assign("x", e, loc())),
+                prop::collection::vec(inner, 0..4).prop_map(move |args| Ast::call(
+                    "max",
+                    args,
+                    loc()
+                )),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_matches_bytecode(ast in ast()) {
+            let code = BytecodeCompiler::new().compile(&ast).unwrap();
+            prop_assert_eq!(max_stack_depth(&ast), peak(&code));
+        }
+    }
+}
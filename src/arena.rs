@@ -0,0 +1,303 @@
+//!
+//! 抽象構文木をまとめて格納するアリーナ。
+//! ノードを1つのVecに並べ、子ノードはボックスではなく添字で指す。
+//! 多くの式を続けて扱うとき、clearしたアリーナを使い回せば式ごとにメモリを確保せずに済む。
+//!
+use std::convert::TryFrom;
+use std::ops::Index;
+
+use smallvec::{smallvec, SmallVec};
+
+use super::lexer::{Annotation, Location};
+use super::parser::*;
+
+/// アリーナの中のノードを指す添字
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(u32);
+
+impl NodeId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// アリーナに格納するノードの種類。AstKindの子ノードを添字にしたもの
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NodeKind {
+    Num(u64),
+    /// 変数の参照
+    Var(String),
+    /// 変数への代入
+    Assign {
+        name: String,
+        value: NodeId,
+    },
+    Unary {
+        operator: UnaryOperator,
+        operand: NodeId,
+    },
+    Binary {
+        operator: BinaryOperator,
+        left: NodeId,
+        right: NodeId,
+    },
+    /// 関数の呼び出し
+    Call {
+        name: String,
+        args: Vec<NodeId>,
+    },
+}
+
+impl NodeKind {
+    /// 子ノードを左から順に返す
+    fn children(&self) -> SmallVec<[NodeId; 2]> {
+        match *self {
+            NodeKind::Num(_) | NodeKind::Var(_) => SmallVec::new(),
+            NodeKind::Assign { value, .. } => smallvec![value],
+            NodeKind::Unary { operand, .. } => smallvec![operand],
+            NodeKind::Binary { left, right, .. } => smallvec![left, right],
+            NodeKind::Call { ref args, .. } => args.iter().copied().collect(),
+        }
+    }
+}
+
+pub type Node = Annotation<NodeKind>;
+
+///
+/// 抽象構文木のノードのアリーナ。
+/// ノードはAstのコンストラクタと同じ形のメソッドで作り、子ノードは先に作っておく。
+/// そのため子ノードは必ず親より前に並び、ノードを先頭から順に見れば帰りがけ順になる。
+///
+/// ```
+/// use parser::arena::AstArena;
+/// use parser::lexer::Location;
+/// use parser::parser::{Ast, BinaryOperator};
+///
+/// let mut arena = AstArena::new();
+/// let one = arena.num(1, Location(0, 1));
+/// let x = arena.var("x", Location(4, 5));
+/// let sum = arena.binary(BinaryOperator::add(Location(2, 3)), one, x, Location(0, 5));
+/// assert_eq!(arena.to_ast(sum), "1 + x".parse::<Ast>().unwrap());
+/// ```
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AstArena {
+    nodes: Vec<Node>,
+}
+
+impl AstArena {
+    pub fn new() -> Self {
+        AstArena { nodes: Vec::new() }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        AstArena {
+            nodes: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn num(&mut self, number: u64, location: Location) -> NodeId {
+        self.push(NodeKind::Num(number), location)
+    }
+    pub fn var(&mut self, name: &str, location: Location) -> NodeId {
+        self.push(NodeKind::Var(name.to_string()), location)
+    }
+    pub fn assign(&mut self, name: &str, value: NodeId, location: Location) -> NodeId {
+        self.push(
+            NodeKind::Assign {
+                name: name.to_string(),
+                value,
+            },
+            location,
+        )
+    }
+    pub fn unary(
+        &mut self,
+        operator: UnaryOperator,
+        operand: NodeId,
+        location: Location,
+    ) -> NodeId {
+        self.push(NodeKind::Unary { operator, operand }, location)
+    }
+    pub fn binary(
+        &mut self,
+        operator: BinaryOperator,
+        left: NodeId,
+        right: NodeId,
+        location: Location,
+    ) -> NodeId {
+        self.push(
+            NodeKind::Binary {
+                operator,
+                left,
+                right,
+            },
+            location,
+        )
+    }
+    pub fn call(&mut self, name: &str, args: Vec<NodeId>, location: Location) -> NodeId {
+        self.push(
+            NodeKind::Call {
+                name: name.to_string(),
+                args,
+            },
+            location,
+        )
+    }
+
+    ///
+    /// 構文木のノードをすべてアリーナへ移し、根のノードを返す。
+    /// 長い演算子の連なりでもスタックを使い切らないよう、再帰せずに作業のスタックで辿る。
+    ///
+    pub fn alloc(&mut self, ast: &Ast) -> NodeId {
+        // 子を移し終えたノードはtrueとともに積み直し、移した子の添字から作る
+        let mut tasks = vec![(ast, false)];
+        let mut ids: Vec<NodeId> = Vec::new();
+        while let Some((ast, ready)) = tasks.pop() {
+            let children = ast.value.children();
+            if !ready {
+                tasks.push((ast, true));
+                tasks.extend(children.iter().rev().map(|&child| (child, false)));
+                continue;
+            }
+            let mut args = ids.split_off(ids.len() - children.len()).into_iter();
+            let mut next = || args.next().unwrap();
+            let kind = match ast.value {
+                AstKind::Num(n) => NodeKind::Num(n),
+                AstKind::Var(ref name) => NodeKind::Var(name.clone()),
+                AstKind::Assign { ref name, .. } => NodeKind::Assign {
+                    name: name.clone(),
+                    value: next(),
+                },
+                AstKind::Unary { ref operator, .. } => NodeKind::Unary {
+                    operator: operator.clone(),
+                    operand: next(),
+                },
+                AstKind::Binary { ref operator, .. } => NodeKind::Binary {
+                    operator: operator.clone(),
+                    left: next(),
+                    right: next(),
+                },
+                AstKind::Call { ref name, .. } => NodeKind::Call {
+                    name: name.clone(),
+                    args: args.collect(),
+                },
+            };
+            ids.push(self.push(kind, ast.location.clone()));
+        }
+        ids.pop().unwrap()
+    }
+
+    /// ノードから構文木を作る。allocと同じく、再帰せずに子から順に作る
+    pub fn to_ast(&self, id: NodeId) -> Ast {
+        let mut tasks = vec![(id, false)];
+        let mut built: Vec<Ast> = Vec::new();
+        while let Some((id, ready)) = tasks.pop() {
+            let node = &self[id];
+            let children = node.value.children();
+            if !ready {
+                tasks.push((id, true));
+                tasks.extend(children.iter().rev().map(|&child| (child, false)));
+                continue;
+            }
+            let mut args = built.split_off(built.len() - children.len()).into_iter();
+            let mut next = || args.next().unwrap();
+            let location = node.location.clone();
+            let ast = match node.value {
+                NodeKind::Num(n) => Ast::num(n, location),
+                NodeKind::Var(ref name) => Ast::var(name, location),
+                NodeKind::Assign { ref name, .. } => Ast::assign(name, next(), location),
+                NodeKind::Unary { ref operator, .. } => {
+                    Ast::unary(operator.clone(), next(), location)
+                }
+                NodeKind::Binary { ref operator, .. } => {
+                    let left = next();
+                    Ast::binary(operator.clone(), left, next(), location)
+                }
+                NodeKind::Call { ref name, .. } => Ast::call(name, args.collect(), location),
+            };
+            built.push(ast);
+        }
+        built.pop().unwrap()
+    }
+
+    /// 作ったノードをすべて捨てる。確保したメモリは次の式で使い回す
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+    }
+
+    /// 格納しているノードの数を返す
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// ノードを作った順（帰りがけ順）に返す
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Node)> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (NodeId(i as u32), node))
+    }
+
+    fn push(&mut self, kind: NodeKind, location: Location) -> NodeId {
+        let id = NodeId(u32::try_from(self.nodes.len()).expect("too many nodes in the arena"));
+        self.nodes.push(Node::new(kind, location));
+        id
+    }
+}
+
+impl Index<NodeId> for AstArena {
+    type Output = Node;
+    fn index(&self, id: NodeId) -> &Node {
+        &self.nodes[id.index()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut arena = AstArena::new();
+        for input in &[
+            "1",
+            "x = -(1 + 2) * y ^ 3",
+            "max(1, abs(x), min())",
+            "a = b = 2 | 3",
+        ] {
+            let ast = input.parse::<Ast>().unwrap();
+            let root = arena.alloc(&ast);
+            assert_eq!(arena.to_ast(root), ast, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_postorder() {
+        let mut arena = AstArena::new();
+        let root = arena.alloc(&"x = max(1, -y) + 2".parse::<Ast>().unwrap());
+        // 根は最後に作られ、子ノードは親より前に並ぶ
+        assert_eq!(root.index(), arena.len() - 1);
+        for (id, node) in arena.iter() {
+            let children = match node.value {
+                NodeKind::Num(_) | NodeKind::Var(_) => vec![],
+                NodeKind::Assign { value, .. } => vec![value],
+                NodeKind::Unary { operand, .. } => vec![operand],
+                NodeKind::Binary { left, right, .. } => vec![left, right],
+                NodeKind::Call { ref args, .. } => args.clone(),
+            };
+            assert!(children.iter().all(|&child| child < id));
+        }
+
+        // 使い回したアリーナは添字を0から振り直す
+        let capacity = arena.nodes.capacity();
+        arena.clear();
+        assert!(arena.is_empty());
+        assert_eq!(arena.num(1, Location(0, 1)).index(), 0);
+        assert_eq!(arena.nodes.capacity(), capacity);
+    }
+}
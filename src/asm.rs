@@ -0,0 +1,409 @@
+//!
+//! x86-64のアセンブリ（GNU asのIntel記法）への変換。
+//! 値はすべてスタックに積み、演算のたびにraxとrcxへ降ろして計算し、結果を積み直す。
+//! 逆ポーランド記法の出力と1対1に対応するので、実際の機械語に近い形と見比べるのに使う。
+//!
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+use super::compiler::{check_call, Backend, CompileError};
+use super::interpreter::*;
+use super::parser::*;
+
+///
+/// 組み込み関数を実装する補助関数。使われたものだけを出力する。
+/// 引数はrdiとrsiで受け取り、結果をraxで返す。
+/// 評価器がエラーにする引数（負の数の平方根、負の指数）では不正命令で止まる。
+///
+const HELPERS: [(&str, &str); 5] = [
+    (
+        "abs",
+        "\
+fn_abs:
+    mov rax, rdi
+    neg rax
+    cmovs rax, rdi
+    ret
+",
+    ),
+    (
+        "min",
+        "\
+fn_min:
+    mov rax, rdi
+    cmp rdi, rsi
+    cmovg rax, rsi
+    ret
+",
+    ),
+    (
+        "max",
+        "\
+fn_max:
+    mov rax, rdi
+    cmp rdi, rsi
+    cmovl rax, rsi
+    ret
+",
+    ),
+    (
+        "pow",
+        "\
+fn_pow:
+    mov eax, 1
+    test rsi, rsi
+    js .Lpow_negative
+    jz .Lpow_done
+    test rdi, rdi
+    cmovz rax, rdi
+    jz .Lpow_done
+.Lpow_loop:
+    test rsi, rsi
+    jz .Lpow_done
+    test sil, 1
+    jz .Lpow_square
+    imul rax, rdi
+.Lpow_square:
+    imul rdi, rdi
+    shr rsi, 1
+    jmp .Lpow_loop
+.Lpow_done:
+    ret
+.Lpow_negative:
+    ud2
+",
+    ),
+    (
+        "sqrt",
+        "\
+fn_sqrt:
+    test rdi, rdi
+    js .Lsqrt_negative
+    cvtsi2sd xmm0, rdi
+    sqrtsd xmm0, xmm0
+    cvttsd2si rax, xmm0
+.Lsqrt_down:
+    mov rcx, rax
+    imul rcx, rax
+    cmp rcx, rdi
+    jbe .Lsqrt_up
+    dec rax
+    jmp .Lsqrt_down
+.Lsqrt_up:
+    lea rcx, [rax + 1]
+    imul rcx, rcx
+    cmp rcx, rdi
+    ja .Lsqrt_done
+    inc rax
+    jmp .Lsqrt_up
+.Lsqrt_done:
+    ret
+.Lsqrt_negative:
+    ud2
+",
+    ),
+];
+
+///
+/// x86-64のアセンブリへのコンパイラ。
+/// 式は"eval"という名前の関数になり、結果をraxで返す。
+/// 変数は"var_"を付けた大域変数にするので、C言語のプログラムから値を設定して呼び出せる。
+/// 四則演算の桁あふれは検出せず、64ビットで折り返す。ゼロ除算は例外で止まる。
+///
+#[derive(Default)]
+pub struct AsmCompiler {
+    /// 使われた変数（最初に現れた順）
+    variables: Vec<String>,
+    /// 使われた補助関数（HELPERSの順）
+    helpers: [bool; HELPERS.len()],
+    /// evalの命令列
+    body: String,
+}
+
+impl AsmCompiler {
+    pub fn new() -> Self {
+        AsmCompiler::default()
+    }
+
+    /// evalに命令を1つ加える
+    fn emit(&mut self, instruction: &str) {
+        self.body.push_str("    ");
+        self.body.push_str(instruction);
+        self.body.push('\n');
+    }
+
+    fn variable(&mut self, name: &str) {
+        if !self.variables.iter().any(|n| n == name) {
+            self.variables.push(name.to_string());
+        }
+    }
+
+    /// スタックの上からargs個の値を引数として補助関数を呼び、結果を積む
+    fn call(&mut self, name: &str, args: usize) {
+        let index = HELPERS.iter().position(|&(n, _)| n == name).unwrap();
+        self.helpers[index] = true;
+        if args == 2 {
+            self.emit("pop rsi");
+        }
+        self.emit("pop rdi");
+        self.emit(&format!("call fn_{}", name));
+        self.emit("push rax");
+    }
+
+    /// 計算した2つの値をスタックから取り出し、二項演算の結果を積む
+    fn binary(&mut self, operator: &BinaryOperatorKind) {
+        if *operator == BinaryOperatorKind::Pow {
+            self.call("pow", 2);
+            return;
+        }
+        self.emit("pop rcx");
+        self.emit("pop rax");
+        match operator {
+            BinaryOperatorKind::Add => self.emit("add rax, rcx"),
+            BinaryOperatorKind::Sub => self.emit("sub rax, rcx"),
+            BinaryOperatorKind::Multi => self.emit("imul rax, rcx"),
+            // rdx:raxをrcxで割る
+            BinaryOperatorKind::Div => {
+                self.emit("cqo");
+                self.emit("idiv rcx");
+            }
+            BinaryOperatorKind::BitOr => self.emit("or rax, rcx"),
+            BinaryOperatorKind::Pow => unreachable!(),
+        }
+        self.emit("push rax");
+    }
+
+    ///
+    /// 節点を命令列へ変換する。
+    /// 長い演算子の連なりでもネイティブのスタックを使い切らないよう、
+    /// 再帰せずに作業のスタックで辿る。作業は命令を出す順の逆に積む。
+    ///
+    fn compile_inner(&mut self, expr: &Ast) -> Result<(), InterpreterError> {
+        use super::parser::AstKind::*;
+        let mut steps = vec![Step::Node(expr)];
+        while let Some(step) = steps.pop() {
+            let expr = match step {
+                Step::Node(expr) => expr,
+                // 代入した値はスタックに残したままにする
+                Step::Assign(name) => {
+                    self.variable(name);
+                    self.emit("mov rax, [rsp]");
+                    self.emit(&format!("mov qword ptr [rip + var_{}], rax", name));
+                    continue;
+                }
+                Step::Negate => {
+                    self.emit("pop rax");
+                    self.emit("neg rax");
+                    self.emit("push rax");
+                    continue;
+                }
+                Step::Binary(operator) => {
+                    self.binary(operator);
+                    continue;
+                }
+                Step::Call(name, args) => {
+                    self.call(name, args);
+                    continue;
+                }
+            };
+            match expr.value {
+                Num(n) => {
+                    let n =
+                        literal(n).map_err(|e| InterpreterError::new(e, expr.location.clone()))?;
+                    // pushで積めるのは32ビットに収まる数だけ
+                    if i32::try_from(n).is_ok() {
+                        self.emit(&format!("push {}", n));
+                    } else {
+                        self.emit(&format!("mov rax, {}", n));
+                        self.emit("push rax");
+                    }
+                }
+                Var(ref name) => {
+                    self.variable(name);
+                    self.emit(&format!("push qword ptr [rip + var_{}]", name));
+                }
+                Assign {
+                    ref name,
+                    ref value,
+                } => steps.extend([Step::Assign(name), Step::Node(value)]),
+                Unary {
+                    ref operator,
+                    ref operand,
+                } => {
+                    if operator.value == UnaryOperatorKind::Minus {
+                        steps.push(Step::Negate);
+                    }
+                    steps.push(Step::Node(operand));
+                }
+                Binary {
+                    ref operator,
+                    ref left,
+                    ref right,
+                } => steps.extend([
+                    Step::Binary(&operator.value),
+                    Step::Node(right),
+                    Step::Node(left),
+                ]),
+                Call { ref name, ref args } => {
+                    check_call(name, args.len(), &expr.location)?;
+                    // minとmaxは2つずつ比べる
+                    let pairwise = name == "min" || name == "max";
+                    if !pairwise {
+                        steps.push(Step::Call(name, args.len()));
+                    }
+                    for (i, arg) in args.iter().enumerate().rev() {
+                        if i > 0 && pairwise {
+                            steps.push(Step::Call(name, 2));
+                        }
+                        steps.push(Step::Node(arg));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 命令列へ変換するときの作業
+enum Step<'a> {
+    /// 節点を変換する
+    Node(&'a Ast),
+    /// スタックの上の値を変数に代入する
+    Assign(&'a str),
+    /// スタックの上の値の符号を反転する
+    Negate,
+    /// 二項演算をする
+    Binary(&'a BinaryOperatorKind),
+    /// 補助関数を呼ぶ
+    Call(&'a str, usize),
+}
+
+impl Backend for AsmCompiler {
+    fn name(&self) -> &str {
+        "asm"
+    }
+
+    /// 抽象構文木をアセンブリへ変換し、bufの内容を置き換える
+    fn compile_into(&mut self, expr: &Ast, buf: &mut String) -> Result<(), CompileError> {
+        buf.clear();
+        self.variables.clear();
+        self.helpers = [false; HELPERS.len()];
+        self.body.clear();
+        self.compile_inner(expr)?;
+
+        buf.push_str("    .intel_syntax noprefix\n");
+        for name in &self.variables {
+            writeln!(buf, "    .comm var_{}, 8, 8", name).unwrap();
+        }
+        buf.push_str("    .text\n    .globl eval\neval:\n");
+        buf.push_str(&self.body);
+        buf.push_str("    pop rax\n    ret\n");
+        for (&(_, helper), &used) in HELPERS.iter().zip(&self.helpers) {
+            if used {
+                buf.push_str(helper);
+            }
+        }
+        // スタックを実行可能にしなくてよいことをリンカに示す
+        buf.push_str("    .section .note.GNU-stack,\"\",@progbits\n");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Location;
+
+    fn compile(input: &str) -> Result<String, InterpreterError> {
+        AsmCompiler::new().compile(&input.parse().unwrap())
+    }
+
+    #[test]
+    fn test_asm() {
+        assert_eq!(
+            compile("x = (1 + y) * -4000000000").unwrap(),
+            "    .intel_syntax noprefix
+    .comm var_y, 8, 8
+    .comm var_x, 8, 8
+    .text
+    .globl eval
+eval:
+    push 1
+    push qword ptr [rip + var_y]
+    pop rcx
+    pop rax
+    add rax, rcx
+    push rax
+    mov rax, 4000000000
+    push rax
+    pop rax
+    neg rax
+    push rax
+    pop rcx
+    pop rax
+    imul rax, rcx
+    push rax
+    mov rax, [rsp]
+    mov qword ptr [rip + var_x], rax
+    pop rax
+    ret
+    .section .note.GNU-stack,\"\",@progbits
+"
+        );
+        assert_eq!(
+            compile("max(8 / 2, |1|, 3)").unwrap(),
+            "    .intel_syntax noprefix
+    .text
+    .globl eval
+eval:
+    push 8
+    push 2
+    pop rcx
+    pop rax
+    cqo
+    idiv rcx
+    push rax
+    push 1
+    pop rdi
+    call fn_abs
+    push rax
+    pop rsi
+    pop rdi
+    call fn_max
+    push rax
+    push 3
+    pop rsi
+    pop rdi
+    call fn_max
+    push rax
+    pop rax
+    ret
+fn_abs:
+    mov rax, rdi
+    neg rax
+    cmovs rax, rdi
+    ret
+fn_max:
+    mov rax, rdi
+    cmp rdi, rsi
+    cmovl rax, rsi
+    ret
+    .section .note.GNU-stack,\"\",@progbits
+"
+        );
+        let program = compile("sqrt(2 ^ 10)").unwrap();
+        assert!(program.contains("fn_pow:") && program.contains("fn_sqrt:"));
+
+        assert_eq!(
+            compile("pow(1)"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::WrongArgumentCount {
+                    name: "pow".to_string(),
+                    expected: Arity::Exactly(2),
+                    found: 1,
+                },
+                Location(0, 6)
+            ))
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//!
+//! 互いに独立した多数の行をまとめて処理するバッチ処理。
+//! 生成した問題ファイルの採点のように、行どうしが変数を共有しない入力に使う。
+//! parallel機能を有効にしてビルドすると、行を複数のスレッドで並列に処理する。
+//!
+use super::engine::{Engine, Outcome};
+use super::parser::ApplicationError;
+
+///
+/// 1行を処理した結果。Outcomeと同じだが、文字列を処理系から借りずに所有する。
+/// 処理系を捨てた後や、別のスレッドへ結果を渡すときに使う。
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineOutcome {
+    Value(i64),
+    Rpn(String),
+    Trace(String),
+    Error {
+        errors: Vec<ApplicationError>,
+        prefix: Option<Box<LineOutcome>>,
+    },
+}
+
+impl LineOutcome {
+    /// 処理系の返す結果と同じ形で借りる
+    pub fn as_outcome(&self) -> Outcome<'_> {
+        match self {
+            LineOutcome::Value(n) => Outcome::Value(*n),
+            LineOutcome::Rpn(text) => Outcome::Rpn(text),
+            LineOutcome::Trace(text) => Outcome::Trace(text),
+            LineOutcome::Error { errors, prefix } => Outcome::Error {
+                errors: errors.clone(),
+                prefix: prefix.as_ref().map(|prefix| Box::new(prefix.as_outcome())),
+            },
+        }
+    }
+}
+
+impl From<Outcome<'_>> for LineOutcome {
+    fn from(outcome: Outcome<'_>) -> Self {
+        match outcome {
+            Outcome::Value(n) => LineOutcome::Value(n),
+            Outcome::Rpn(text) => LineOutcome::Rpn(text.to_string()),
+            Outcome::Trace(text) => LineOutcome::Trace(text.to_string()),
+            Outcome::Error { errors, prefix } => LineOutcome::Error {
+                errors,
+                prefix: prefix.map(|prefix| Box::new(LineOutcome::from(*prefix))),
+            },
+        }
+    }
+}
+
+///
+/// 各行を独立した式として処理し、入力と同じ順に結果を返す。
+/// 処理系はnew_engineで作り、行ごとに変数を処理する前の状態へ戻すので、
+/// ある行の代入が他の行の結果に影響することはない。
+///
+/// parallel機能を有効にしていれば、jobs個のスレッドで並列に処理する（0なら論理CPUの数）。
+/// 有効にしていなければ、jobsに関わらず1つのスレッドで順に処理する。
+///
+/// ```
+/// use parser::batch::{run_batch, LineOutcome};
+/// use parser::engine::{Engine, Mode};
+///
+/// let lines = vec!["x = 2".to_string(), "1 + 2 * 3".to_string(), "x".to_string()];
+/// let outcomes = run_batch(&lines, 4, || Engine::new(Mode::Eval));
+/// assert_eq!(outcomes[0], LineOutcome::Value(2));
+/// assert_eq!(outcomes[1], LineOutcome::Value(7));
+/// // 前の行の代入は引き継がない
+/// assert!(matches!(outcomes[2], LineOutcome::Error { .. }));
+/// ```
+///
+pub fn run_batch<S, F>(lines: &[S], jobs: usize, new_engine: F) -> Vec<LineOutcome>
+where
+    S: AsRef<str> + Sync,
+    F: Fn() -> Engine + Sync + Send,
+{
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        let run = || {
+            lines
+                .par_iter()
+                .map_init(&new_engine, |engine, line| {
+                    run_isolated(engine, line.as_ref())
+                })
+                .collect()
+        };
+        // 構文木を辿る処理は再帰するので、上限の深さの式も処理できるようメインスレッドと同じ大きさのスタックを使う
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .stack_size(8 << 20)
+            .build();
+        match pool {
+            Ok(pool) => pool.install(run),
+            // スレッドを作れなければ、既定のスレッドプールで処理する
+            Err(_) => run(),
+        }
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        let _ = jobs;
+        let mut engine = new_engine();
+        lines
+            .iter()
+            .map(|line| run_isolated(&mut engine, line.as_ref()))
+            .collect()
+    }
+}
+
+/// 1行を処理し、変数をその行を処理する前の状態へ戻す
+fn run_isolated(engine: &mut Engine, line: &str) -> LineOutcome {
+    engine.checkpoint();
+    let outcome = LineOutcome::from(engine.run(line));
+    engine.rollback();
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Mode;
+    #[cfg(feature = "parallel")]
+    use crate::parser::DEFAULT_MAX_DEPTH;
+
+    #[test]
+    fn test_run_batch() {
+        let lines: Vec<String> = (0..200)
+            .map(|i| match i % 4 {
+                0 => format!("x = {}", i),
+                1 => format!("{} * 2", i),
+                2 => "x + 1".to_string(),
+                _ => format!("{} / 0", i),
+            })
+            .collect();
+        for jobs in [1, 4] {
+            let outcomes = run_batch(&lines, jobs, || Engine::new(Mode::Eval));
+            assert_eq!(outcomes.len(), lines.len());
+            for (i, outcome) in outcomes.iter().enumerate() {
+                match i % 4 {
+                    0 => assert_eq!(*outcome, LineOutcome::Value(i as i64)),
+                    1 => assert_eq!(*outcome, LineOutcome::Value(i as i64 * 2)),
+                    _ => assert!(matches!(outcome, LineOutcome::Error { .. }), "{}", i),
+                }
+            }
+        }
+
+        let outcomes = run_batch(&["1 + 2"], 2, || Engine::new(Mode::Rpn));
+        assert_eq!(outcomes, vec![LineOutcome::Rpn("1 2 +".to_string())]);
+        assert_eq!(outcomes[0].as_outcome(), Outcome::Rpn("1 2 +"));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_deep_lines() {
+        // 上限の深さの入れ子や長い和も、処理するスレッドのスタックを使い切らない
+        let depth = DEFAULT_MAX_DEPTH - 1;
+        let lines = [
+            format!("{}1{}", "(".repeat(depth - 1), ")".repeat(depth - 1)),
+            format!("1{}", " + 1".repeat(100_000)),
+        ];
+        for mode in "eval llvm-ir dot".split(' ') {
+            let outcomes = run_batch(&lines, 2, || Engine::new(mode.parse().unwrap()));
+            for outcome in &outcomes {
+                assert!(!matches!(outcome, LineOutcome::Error { .. }), "{}", mode);
+            }
+        }
+    }
+}
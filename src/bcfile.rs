@@ -0,0 +1,476 @@
+//!
+//! バイトコードのファイル形式。
+//! コンパイルした命令列をファイルに保存し、構文解析をせずにVMで実行できるようにする。
+//!
+//! 数はすべてリトルエンディアンで、次の順に並べる。
+//!
+//! ```text
+//! magic       "PBC\0"
+//! version     u16
+//! source      u32の長さとUTF-8の文字列（エラーの表示に使う元の式）
+//! constants   u32の個数と定数（u8の種類 0: i64の数、1: u32の長さとUTF-8の名前）
+//! code        u32の個数と命令（u8の命令、定数の番号などの被演算子、u32の開始位置と終了位置）
+//! ```
+//!
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fmt;
+
+use super::bytecode::{Instruction, InstructionKind};
+use super::lexer::{Annotation, Location};
+
+/// ファイルの先頭の4バイト
+pub const MAGIC: &[u8; 4] = b"PBC\0";
+
+/// この版で読み書きする形式の版
+pub const VERSION: u16 = 1;
+
+/// 定数の種類
+const CONSTANT_NUMBER: u8 = 0;
+const CONSTANT_NAME: u8 = 1;
+
+/// 命令の種類を表すバイト
+const OP_PUSH: u8 = 0x01;
+const OP_LOAD: u8 = 0x02;
+const OP_STORE: u8 = 0x03;
+const OP_ADD: u8 = 0x10;
+const OP_SUB: u8 = 0x11;
+const OP_MUL: u8 = 0x12;
+const OP_DIV: u8 = 0x13;
+const OP_POW: u8 = 0x14;
+const OP_OR: u8 = 0x15;
+const OP_NEG: u8 = 0x16;
+const OP_CALL: u8 = 0x20;
+
+/// ファイルから読んだ、実行できる命令列
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Program {
+    /// 命令列を生成した元の式
+    pub source: String,
+    pub code: Vec<Instruction>,
+}
+
+/// バイトコードのファイルが読めない理由
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LoadErrorKind {
+    /// 先頭がバイトコードのファイルを表すバイト列ではない
+    NotBytecode,
+    /// 読めない版の形式（ファイルの版）
+    UnsupportedVersion(u16),
+    /// ファイルが途中で終わっている
+    Truncated,
+    /// 定数の種類が不正
+    InvalidConstantKind(u8),
+    /// 命令の種類が不正
+    InvalidOpcode(u8),
+    /// 定数の番号が範囲外か、命令に合わない種類の定数を指している
+    InvalidConstant(u32),
+    /// UTF-8として正しくない文字列
+    InvalidUtf8,
+    /// 命令の位置が元の式の範囲外
+    InvalidSpan,
+    /// 命令に必要な数の値がスタックにない
+    StackUnderflow,
+    /// 実行を終えたときにスタックに残る値が1つではない（残る値の数）
+    UnbalancedStack(usize),
+    /// 命令列の後に余分なバイトがある
+    TrailingBytes,
+}
+
+/// 読めない理由と、ファイルの中でその原因となったバイトの範囲
+pub type LoadError = Annotation<LoadErrorKind>;
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::LoadErrorKind::*;
+        let message = match &self.value {
+            NotBytecode => return write!(f, "not a bytecode file"),
+            UnsupportedVersion(version) => {
+                format!("unsupported version {} (expected {})", version, VERSION)
+            }
+            Truncated => "unexpected end of file".to_string(),
+            InvalidConstantKind(kind) => format!("invalid constant kind {}", kind),
+            InvalidOpcode(op) => format!("invalid opcode 0x{:02x}", op),
+            InvalidConstant(index) => format!("invalid constant #{}", index),
+            InvalidUtf8 => "invalid UTF-8".to_string(),
+            InvalidSpan => "span outside the source".to_string(),
+            StackUnderflow => "not enough values on the stack".to_string(),
+            UnbalancedStack(n) => format!("{} values left on the stack", n),
+            TrailingBytes => "unexpected data after the code".to_string(),
+        };
+        write!(
+            f,
+            "corrupt bytecode at bytes {}: {}",
+            self.location, message
+        )
+    }
+}
+
+impl Error for LoadError {}
+
+/// 定数。数と名前（変数や関数）を1つの表にまとめ、同じものは1度だけ書く
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Constant {
+    Number(i64),
+    Name(String),
+}
+
+///
+/// 命令列と、それを生成した元の式をファイルの内容にする。
+///
+/// ```
+/// use parser::bcfile::{decode, encode};
+/// use parser::bytecode::BytecodeCompiler;
+///
+/// let source = "x = 1 + 2";
+/// let code = BytecodeCompiler::new()
+///     .compile(&source.parse().unwrap())
+///     .unwrap();
+/// let program = decode(&encode(&code, source)).unwrap();
+/// assert_eq!(program.code, code);
+/// assert_eq!(program.source, source);
+/// ```
+///
+pub fn encode(code: &[Instruction], source: &str) -> Vec<u8> {
+    let mut constants = Vec::new();
+    let mut body = Vec::new();
+    for inst in code {
+        use super::bytecode::InstructionKind::*;
+        let (op, constant) = match inst.value {
+            Push(n) => (OP_PUSH, Some(Constant::Number(n))),
+            Load(ref name) => (OP_LOAD, Some(Constant::Name(name.clone()))),
+            Store(ref name) => (OP_STORE, Some(Constant::Name(name.clone()))),
+            Add => (OP_ADD, None),
+            Sub => (OP_SUB, None),
+            Mul => (OP_MUL, None),
+            Div => (OP_DIV, None),
+            Pow => (OP_POW, None),
+            Or => (OP_OR, None),
+            Neg => (OP_NEG, None),
+            Call(ref name, _) => (OP_CALL, Some(Constant::Name(name.clone()))),
+        };
+        body.push(op);
+        if let Some(constant) = constant {
+            let index = match constants.iter().position(|c| *c == constant) {
+                Some(index) => index,
+                None => {
+                    constants.push(constant);
+                    constants.len() - 1
+                }
+            };
+            put_u32(&mut body, index);
+        }
+        if let Call(_, argc) = inst.value {
+            put_u32(&mut body, argc);
+        }
+        put_u32(&mut body, inst.location.0);
+        put_u32(&mut body, inst.location.1);
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    put_str(&mut bytes, source);
+    put_u32(&mut bytes, constants.len());
+    for constant in &constants {
+        match constant {
+            Constant::Number(n) => {
+                bytes.push(CONSTANT_NUMBER);
+                bytes.extend_from_slice(&n.to_le_bytes());
+            }
+            Constant::Name(name) => {
+                bytes.push(CONSTANT_NAME);
+                put_str(&mut bytes, name);
+            }
+        }
+    }
+    put_u32(&mut bytes, code.len());
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+fn put_u32(bytes: &mut Vec<u8>, n: usize) {
+    let n = u32::try_from(n).expect("too large for the bytecode format");
+    bytes.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_str(bytes: &mut Vec<u8>, s: &str) {
+    put_u32(bytes, s.len());
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+///
+/// ファイルの内容を読み、実行できる命令列にする。
+/// VMが途中で止まらないよう、各命令の前にスタックに必要な数の値があることも確かめる。
+///
+pub fn decode(bytes: &[u8]) -> Result<Program, LoadError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(LoadError::new(
+            LoadErrorKind::NotBytecode,
+            Location(0, MAGIC.len().min(bytes.len())),
+        ));
+    }
+    let mut reader = Reader {
+        bytes,
+        pos: MAGIC.len(),
+    };
+    let start = reader.pos;
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(LoadError::new(
+            LoadErrorKind::UnsupportedVersion(version),
+            Location(start, reader.pos),
+        ));
+    }
+    let source = reader.string()?;
+    let source_len = source.chars().count();
+
+    let count = reader.u32()?;
+    let mut constants = Vec::new();
+    for _ in 0..count {
+        let start = reader.pos;
+        let constant = match reader.u8()? {
+            CONSTANT_NUMBER => Constant::Number(reader.i64()?),
+            CONSTANT_NAME => Constant::Name(reader.string()?),
+            kind => {
+                return Err(LoadError::new(
+                    LoadErrorKind::InvalidConstantKind(kind),
+                    Location(start, reader.pos),
+                ))
+            }
+        };
+        constants.push(constant);
+    }
+
+    let count = reader.u32()?;
+    let mut code = Vec::new();
+    let mut depth = 0usize;
+    let code_start = reader.pos;
+    for _ in 0..count {
+        let start = reader.pos;
+        let op = reader.u8()?;
+        let error = |kind, reader: &Reader| LoadError::new(kind, Location(start, reader.pos));
+        let kind = match op {
+            OP_PUSH | OP_LOAD | OP_STORE | OP_CALL => {
+                let index = reader.u32()?;
+                match (op, constants.get(index as usize)) {
+                    (OP_PUSH, Some(&Constant::Number(n))) => InstructionKind::Push(n),
+                    (OP_LOAD, Some(Constant::Name(name))) => InstructionKind::Load(name.clone()),
+                    (OP_STORE, Some(Constant::Name(name))) => InstructionKind::Store(name.clone()),
+                    (OP_CALL, Some(Constant::Name(name))) => {
+                        InstructionKind::Call(name.clone(), reader.u32()? as usize)
+                    }
+                    _ => return Err(error(LoadErrorKind::InvalidConstant(index), &reader)),
+                }
+            }
+            OP_ADD => InstructionKind::Add,
+            OP_SUB => InstructionKind::Sub,
+            OP_MUL => InstructionKind::Mul,
+            OP_DIV => InstructionKind::Div,
+            OP_POW => InstructionKind::Pow,
+            OP_OR => InstructionKind::Or,
+            OP_NEG => InstructionKind::Neg,
+            op => return Err(error(LoadErrorKind::InvalidOpcode(op), &reader)),
+        };
+        // 命令がスタックから取り出す値の数。どの命令も結果を1つ積む
+        let pops = match kind {
+            InstructionKind::Push(_) | InstructionKind::Load(_) => 0,
+            InstructionKind::Store(_) | InstructionKind::Neg => 1,
+            InstructionKind::Call(_, argc) => argc,
+            _ => 2,
+        };
+        let location = Location(reader.u32()? as usize, reader.u32()? as usize);
+        if location.0 > location.1 || location.1 > source_len {
+            return Err(error(LoadErrorKind::InvalidSpan, &reader));
+        }
+        depth = depth
+            .checked_sub(pops)
+            .ok_or_else(|| error(LoadErrorKind::StackUnderflow, &reader))?
+            + 1;
+        code.push(Instruction::new(kind, location));
+    }
+    if depth != 1 {
+        return Err(LoadError::new(
+            LoadErrorKind::UnbalancedStack(depth),
+            Location(code_start, reader.pos),
+        ));
+    }
+    if reader.pos != bytes.len() {
+        return Err(LoadError::new(
+            LoadErrorKind::TrailingBytes,
+            Location(reader.pos, bytes.len()),
+        ));
+    }
+    Ok(Program { source, code })
+}
+
+/// ファイルの内容を先頭から順に読む
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        let bytes = self.bytes.get(self.pos..self.pos + n).ok_or_else(|| {
+            LoadError::new(
+                LoadErrorKind::Truncated,
+                Location(self.pos, self.bytes.len()),
+            )
+        })?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, LoadError> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let start = self.pos;
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| LoadError::new(LoadErrorKind::InvalidUtf8, Location(start, self.pos)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{BytecodeCompiler, Vm};
+
+    fn compile(source: &str) -> Vec<Instruction> {
+        BytecodeCompiler::new()
+            .compile(&source.parse().unwrap())
+            .unwrap()
+    }
+
+    /// 命令列の部分だけを手で書いたファイル
+    fn file(constants: &[u8], code: &[u8]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        put_str(&mut bytes, "1 + 2");
+        bytes.extend_from_slice(constants);
+        bytes.extend_from_slice(code);
+        bytes
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for source in &[
+            "x = max(2, -y) ^ 3 | 9223372036854775807",
+            "y = 2",
+            "f(x, x, x)",
+        ] {
+            let code = compile(source);
+            let program = decode(&encode(&code, source)).unwrap();
+            assert_eq!(program.code, code, "{}", source);
+            assert_eq!(program.source, *source);
+        }
+        // 同じ定数は1度だけ書く
+        let bytes = encode(&compile("x + x * 1 + 1"), "x + x * 1 + 1");
+        assert_eq!(u32::from_le_bytes(bytes[23..27].try_into().unwrap()), 2);
+
+        let mut vm = Vm::new();
+        vm.set_variable("y", 5);
+        let program = decode(&encode(&compile("max(2, -y) + 10"), "max(2, -y) + 10")).unwrap();
+        assert_eq!(vm.run(&program.code), Ok(12));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let bytes = encode(&compile("x = 1 / y"), "x = 1 / y");
+        assert_eq!(
+            decode(b"#!/bin/sh\n"),
+            Err(LoadError::new(LoadErrorKind::NotBytecode, Location(0, 4)))
+        );
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert_eq!(
+            decode(&newer),
+            Err(LoadError::new(
+                LoadErrorKind::UnsupportedVersion(2),
+                Location(4, 6)
+            ))
+        );
+        // 途中で切れたファイルや書き換えたファイルは、VMを止めずにエラーにする
+        for len in 4..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err(), "{}", len);
+        }
+        for i in 4..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[i] ^= 0xff;
+            if let Ok(program) = decode(&corrupt) {
+                let _ = Vm::new().run(&program.code);
+            }
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(
+            decode(&longer),
+            Err(LoadError::new(
+                LoadErrorKind::TrailingBytes,
+                Location(bytes.len(), bytes.len() + 1)
+            ))
+        );
+
+        // 定数: 1, "x"
+        let constants = [2, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, b'x'];
+        let push = |index: u8| vec![OP_PUSH, index, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0];
+        let add = vec![OP_ADD, 0, 0, 0, 0, 5, 0, 0, 0];
+        let code = [vec![2, 0, 0, 0], push(0), add.clone()].concat();
+        assert_eq!(
+            decode(&file(&constants, &code)),
+            Err(LoadError::new(
+                LoadErrorKind::StackUnderflow,
+                Location(51, 60)
+            ))
+        );
+        let code = [vec![2, 0, 0, 0], push(0), push(0)].concat();
+        assert_eq!(
+            decode(&file(&constants, &code)),
+            Err(LoadError::new(
+                LoadErrorKind::UnbalancedStack(2),
+                Location(38, 64)
+            ))
+        );
+        let code = [vec![1, 0, 0, 0], push(1)].concat();
+        assert_eq!(
+            decode(&file(&constants, &code)),
+            Err(LoadError::new(
+                LoadErrorKind::InvalidConstant(1),
+                Location(38, 43)
+            ))
+        );
+        let code = [vec![1, 0, 0, 0], vec![0xff]].concat();
+        assert_eq!(
+            decode(&file(&constants, &code)),
+            Err(LoadError::new(
+                LoadErrorKind::InvalidOpcode(0xff),
+                Location(38, 39)
+            ))
+        );
+        let mut code = [vec![1, 0, 0, 0], push(0)].concat();
+        code[9] = 6;
+        assert_eq!(
+            decode(&file(&constants, &code)),
+            Err(LoadError::new(LoadErrorKind::InvalidSpan, Location(38, 51)))
+        );
+        assert_eq!(
+            LoadError::new(LoadErrorKind::InvalidOpcode(0xff), Location(38, 39)).to_string(),
+            "corrupt bytecode at bytes 38-39: invalid opcode 0xff"
+        );
+    }
+}
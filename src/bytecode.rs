@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::time::Instant;
+
+use super::interpreter::*;
+use super::lexer::*;
+use super::parser::*;
+
+///
+/// スタックマシンの命令の種類
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum InstructionKind {
+    /// 数値をスタックに積む
+    Push(i64),
+    /// 変数の値をスタックに積む
+    Load(String),
+    /// スタックの先頭の値を変数へ代入する（値はスタックに残す）
+    Store(String),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    /// ビット論理和
+    Or,
+    /// 符号を反転する
+    Neg,
+    /// スタックから引数を指定した個数取り出し、組み込み関数を呼び出す
+    Call(String, usize),
+}
+
+impl fmt::Display for InstructionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InstructionKind::*;
+        match self {
+            Push(n) => write!(f, "push {}", n),
+            Load(name) => write!(f, "load {}", name),
+            Store(name) => write!(f, "store {}", name),
+            Add => write!(f, "add"),
+            Sub => write!(f, "sub"),
+            Mul => write!(f, "mul"),
+            Div => write!(f, "div"),
+            Pow => write!(f, "pow"),
+            Or => write!(f, "or"),
+            Neg => write!(f, "neg"),
+            Call(name, argc) => write!(f, "call {} {}", name, argc),
+        }
+    }
+}
+
+/// 命令の種類と、その命令を生成した式の位置情報
+pub type Instruction = Annotation<InstructionKind>;
+
+/// 抽象構文木からスタックマシンの命令列へのコンパイラ
+#[derive(Default)]
+pub struct BytecodeCompiler;
+
+impl BytecodeCompiler {
+    pub fn new() -> Self {
+        BytecodeCompiler
+    }
+
+    ///
+    /// 抽象構文木を解析し、命令列へ変換して返す。
+    /// 符号付き整数に収まらない数値リテラルがあればエラーを返す
+    ///
+    pub fn compile(&mut self, expr: &Ast) -> Result<Vec<Instruction>, InterpreterError> {
+        let mut code = Vec::new();
+        self.compile_inner(expr, &mut code)?;
+        Ok(code)
+    }
+
+    ///
+    /// 抽象構文木を命令列へ変換し、codeの内容を置き換える。
+    /// 命令は節点ごとに高々1つなので、節点の数だけ領域を前もって確保する
+    ///
+    pub fn compile_into(
+        &mut self,
+        expr: &Ast,
+        code: &mut Vec<Instruction>,
+    ) -> Result<(), InterpreterError> {
+        code.clear();
+        code.reserve(expr.node_count());
+        self.compile_inner(expr, code)
+    }
+
+    ///
+    /// 節点を命令列へ変換する。
+    /// 長い演算子の連なりでもネイティブのスタックを使い切らないよう、
+    /// 再帰せずに作業のスタックで辿る。子の後に置く命令は、節点を見たときに子より先に積む。
+    ///
+    fn compile_inner(
+        &mut self,
+        expr: &Ast,
+        code: &mut Vec<Instruction>,
+    ) -> Result<(), InterpreterError> {
+        use super::parser::AstKind::*;
+        let mut steps = vec![Step::Node(expr)];
+        while let Some(step) = steps.pop() {
+            let expr = match step {
+                Step::Node(expr) => expr,
+                Step::Emit(instruction) => {
+                    code.push(instruction);
+                    continue;
+                }
+            };
+            let loc = expr.location.clone();
+            match expr.value {
+                Num(n) => {
+                    let n = literal(n).map_err(|e| InterpreterError::new(e, loc.clone()))?;
+                    code.push(Instruction::new(InstructionKind::Push(n), loc));
+                }
+                Var(ref name) => {
+                    code.push(Instruction::new(InstructionKind::Load(name.clone()), loc))
+                }
+                Assign {
+                    ref name,
+                    ref value,
+                } => steps.extend([
+                    Step::Emit(Instruction::new(InstructionKind::Store(name.clone()), loc)),
+                    Step::Node(value),
+                ]),
+                Unary {
+                    ref operator,
+                    ref operand,
+                } => {
+                    // 単項の"+"は何もしない
+                    if operator.value == UnaryOperatorKind::Minus {
+                        steps.push(Step::Emit(Instruction::new(
+                            InstructionKind::Neg,
+                            operator.location.clone(),
+                        )));
+                    }
+                    steps.push(Step::Node(operand));
+                }
+                Binary {
+                    ref operator,
+                    ref left,
+                    ref right,
+                } => {
+                    // 演算のエラーが演算子を指すよう、演算子の位置を持たせる
+                    steps.extend([
+                        Step::Emit(Instruction::new(
+                            self.compile_binop(operator),
+                            operator.location.clone(),
+                        )),
+                        Step::Node(right),
+                        Step::Node(left),
+                    ]);
+                }
+                Call { ref name, ref args } => {
+                    steps.push(Step::Emit(Instruction::new(
+                        InstructionKind::Call(name.clone(), args.len()),
+                        loc,
+                    )));
+                    steps.extend(args.iter().rev().map(Step::Node));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 二項演算子を処理する
+    fn compile_binop(&mut self, operator: &BinaryOperator) -> InstructionKind {
+        use super::parser::BinaryOperatorKind::*;
+        match operator.value {
+            Add => InstructionKind::Add,
+            Sub => InstructionKind::Sub,
+            Multi => InstructionKind::Mul,
+            Div => InstructionKind::Div,
+            Pow => InstructionKind::Pow,
+            BitOr => InstructionKind::Or,
+        }
+    }
+}
+
+/// 命令列へ変換するときの作業
+enum Step<'a> {
+    /// 節点を変換する
+    Node(&'a Ast),
+    /// 子を変換し終えた節点の命令を加える
+    Emit(Instruction),
+}
+
+///
+/// 命令列を実行するスタックマシン。
+/// 変数の値を保持するので、REPLの行をまたいで状態が引き継がれる。
+///
+#[derive(Debug, Clone, Default)]
+pub struct Vm {
+    stack: Vec<i64>,
+    env: HashMap<String, i64>,
+    /// 実行を打ち切る時刻
+    deadline: Option<Instant>,
+    /// 1回の実行で実行する命令の数の上限
+    max_steps: Option<usize>,
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Vm {
+            stack: Vec::new(),
+            env: HashMap::new(),
+            deadline: None,
+            max_steps: None,
+        }
+    }
+
+    /// 実行を打ち切る時刻を設定する。命令を実行するたびに確かめる
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// 1回の実行で実行する命令の数の上限を設定する
+    pub fn set_max_steps(&mut self, max_steps: Option<usize>) {
+        self.max_steps = max_steps;
+    }
+
+    /// 変数の現在の値を返す
+    pub fn variable(&self, name: &str) -> Option<i64> {
+        self.env.get(name).cloned()
+    }
+
+    /// すべての変数とその値を返す
+    pub fn variables(&self) -> &HashMap<String, i64> {
+        &self.env
+    }
+
+    /// 変数をすべて置き換える
+    pub fn set_variables(&mut self, variables: HashMap<String, i64>) {
+        self.env = variables;
+    }
+
+    /// 変数に値を設定する
+    pub fn set_variable(&mut self, name: &str, value: i64) {
+        self.env.insert(name.to_string(), value);
+    }
+
+    ///
+    /// 命令列を実行し、最後にスタックに残った値を返す。
+    /// エラーの種類と位置は評価器（Interpreter）と同じになる。
+    ///
+    pub fn run(&mut self, code: &[Instruction]) -> Result<i64, InterpreterError> {
+        use self::InstructionKind::*;
+        self.stack.clear();
+        for (steps, inst) in code.iter().enumerate() {
+            if past(self.deadline) {
+                return Err(InterpreterError::new(
+                    InterpreterErrorKind::Timeout,
+                    inst.location.clone(),
+                ));
+            }
+            if self.max_steps.is_some_and(|max_steps| steps >= max_steps) {
+                return Err(InterpreterError::new(
+                    InterpreterErrorKind::TooManySteps,
+                    inst.location.clone(),
+                ));
+            }
+            let value = match inst.value {
+                Push(n) => n,
+                Load(ref name) => self.variable(name).ok_or_else(|| {
+                    InterpreterError::new(
+                        InterpreterErrorKind::UndefinedVariable(name.clone()),
+                        inst.location.clone(),
+                    )
+                })?,
+                Store(ref name) => {
+                    let value = self.pop();
+                    self.env.insert(name.clone(), value);
+                    value
+                }
+                Neg => {
+                    let operand = self.pop();
+                    apply_uniop(&UnaryOperatorKind::Minus, operand)
+                        .map_err(|e| InterpreterError::new(e, inst.location.clone()))?
+                }
+                Add | Sub | Mul | Div | Pow | Or => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    let operator = match inst.value {
+                        Add => BinaryOperatorKind::Add,
+                        Sub => BinaryOperatorKind::Sub,
+                        Mul => BinaryOperatorKind::Multi,
+                        Div => BinaryOperatorKind::Div,
+                        Pow => BinaryOperatorKind::Pow,
+                        _ => BinaryOperatorKind::BitOr,
+                    };
+                    apply_binop(&operator, left, right)
+                        .map_err(|e| InterpreterError::new(e, inst.location.clone()))?
+                }
+                Call(ref name, argc) => {
+                    let start = self.stack.len().checked_sub(argc).expect("stack underflow");
+                    let value = apply_function(name, &self.stack[start..])
+                        .map_err(|e| InterpreterError::new(e, inst.location.clone()))?;
+                    self.stack.truncate(start);
+                    value
+                }
+            };
+            self.stack.push(value);
+        }
+        Ok(self.pop())
+    }
+
+    fn pop(&mut self) -> i64 {
+        // コンパイラが生成する命令列では、スタックが空になることはない
+        self.stack.pop().expect("stack underflow")
+    }
+}
+
+///
+/// 命令列を1行に1命令ずつ、番号・命令・位置と、その命令を生成した入力の部分とともに書き、bufの内容を置き換える。
+///
+/// ```text
+///    0  push 1          4-5     1
+///    1  push 2          9-10    2
+///    2  neg             8-9     -
+///    3  add             6-7     +
+///    4  store x         0-10    x = 1 + -2
+/// ```
+///
+pub fn disassemble(code: &[Instruction], source: &str, buf: &mut String) {
+    buf.clear();
+    for (i, inst) in code.iter().enumerate() {
+        if i > 0 {
+            buf.push('\n');
+        }
+        let Location(start, end) = inst.location;
+        let excerpt: String = source
+            .chars()
+            .skip(start)
+            .take(end.saturating_sub(start))
+            .collect();
+        write!(
+            buf,
+            "{:>4}  {:<16}{:<8}{}",
+            i,
+            inst.value.to_string(),
+            inst.location.to_string(),
+            excerpt
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile() {
+        let ast = "x = 1 + -2".parse::<Ast>().unwrap();
+        let code = BytecodeCompiler::new().compile(&ast);
+        assert_eq!(
+            code,
+            Ok(vec![
+                Instruction::new(InstructionKind::Push(1), Location(4, 5)),
+                Instruction::new(InstructionKind::Push(2), Location(9, 10)),
+                Instruction::new(InstructionKind::Neg, Location(8, 9)),
+                Instruction::new(InstructionKind::Add, Location(6, 7)),
+                Instruction::new(InstructionKind::Store("x".to_string()), Location(0, 10)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let line = "x = 1 + -2";
+        let code = BytecodeCompiler::new()
+            .compile(&line.parse().unwrap())
+            .unwrap();
+        let mut buf = String::new();
+        disassemble(&code, line, &mut buf);
+        assert_eq!(
+            buf,
+            "   0  push 1          4-5     1
+   1  push 2          9-10    2
+   2  neg             8-9     -
+   3  add             6-7     +
+   4  store x         0-10    x = 1 + -2"
+        );
+        let line = "max(y, 2)";
+        let code = BytecodeCompiler::new()
+            .compile(&line.parse().unwrap())
+            .unwrap();
+        disassemble(&code, line, &mut buf);
+        assert_eq!(
+            buf,
+            "   0  load y          4-5     y
+   1  push 2          7-8     2
+   2  call max 2      0-9     max(y, 2)"
+        );
+    }
+
+    #[test]
+    fn test_vm_matches_interpreter() {
+        let mut interpreter = Interpreter::new();
+        let mut vm = Vm::new();
+        let mut compiler = BytecodeCompiler::new();
+        for line in &[
+            "1 + 2 * 3 - -10",
+            "x = 2 ^ 3 ^ 2",
+            "(x - 12) / 10",
+            "x / (5 - 5)",
+            "y + 1",
+            "2 ^ 64",
+            "9223372036854775807 + 1",
+            "9223372036854775808",
+            "z = -9223372036854775807 - 1",
+            "-z",
+            "max(1, sqrt(x), -z)",
+            "abs(z)",
+            "|x - 600| | 3",
+            "min()",
+            "0 ^ 0",
+            "pow(0, 0)",
+            "w = 0",
+            "w ^ 0",
+            "0 ^ 9223372036854775807",
+        ] {
+            let ast = line.parse::<Ast>().unwrap();
+            let result = compiler.compile(&ast).and_then(|code| vm.run(&code));
+            assert_eq!(result, interpreter.eval(&ast), "{}", line);
+        }
+    }
+}
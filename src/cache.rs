@@ -0,0 +1,319 @@
+//!
+//! 構文解析した結果をディスクに残すキャッシュ。
+//! スクリプトファイルの内容のハッシュを鍵として、各行の抽象構文木を1つのファイルに保存する。
+//! 同じ内容のファイルを再び処理するときは、保存した抽象構文木を使って構文解析を省く。
+//!
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use super::lexer::Location;
+use super::parser::*;
+
+/// キャッシュファイルの先頭行。書式や構文解析器が変われば、古いキャッシュは使わない
+const HEADER: &str = concat!("parser-ast-cache 1 ", env!("CARGO_PKG_VERSION"));
+
+///
+/// 抽象構文木のキャッシュを置くディレクトリ。
+/// ファイルの内容が変われば鍵が変わるので、古い内容のキャッシュが使われることはない。
+/// 読めないキャッシュファイルは削除し、作り直す。
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AstCache {
+    dir: PathBuf,
+}
+
+impl AstCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        AstCache { dir: dir.into() }
+    }
+
+    /// 内容がsourceのファイルについて保存した、行の文字列から抽象構文木への対応を読む
+    pub fn load(&self, source: &[u8]) -> Option<HashMap<String, Ast>> {
+        let path = self.path(source);
+        let text = fs::read_to_string(&path).ok()?;
+        let entries = decode_entries(&text);
+        if entries.is_none() {
+            // 壊れたキャッシュや古い書式のキャッシュは捨てる
+            let _ = fs::remove_file(&path);
+        }
+        entries
+    }
+
+    /// 内容がsourceのファイルについて、行の文字列から抽象構文木への対応を保存する
+    pub fn store(&self, source: &[u8], entries: &HashMap<String, Ast>) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(source);
+        // 書きかけのファイルを読まないよう、別の名前で書いてから置き換える
+        let temporary = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&temporary, encode_entries(entries))?;
+        fs::rename(&temporary, &path)
+    }
+
+    fn path(&self, source: &[u8]) -> PathBuf {
+        self.dir.join(format!("{:016x}.ast", content_hash(source)))
+    }
+}
+
+/// ファイルの内容のハッシュ（FNV-1a）。Rustのバージョンによらず同じ値になる
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// 見出しの後に、1行に1つずつ「行の文字列、タブ、抽象構文木」を並べる
+fn encode_entries(entries: &HashMap<String, Ast>) -> String {
+    let mut lines: Vec<_> = entries.iter().collect();
+    lines.sort_by(|a, b| a.0.cmp(b.0));
+    let mut buf = String::from(HEADER);
+    for (line, ast) in lines {
+        buf.push('\n');
+        escape(line, &mut buf);
+        buf.push('\t');
+        encode_ast(ast, &mut buf);
+    }
+    buf.push('\n');
+    buf
+}
+
+fn decode_entries(text: &str) -> Option<HashMap<String, Ast>> {
+    let mut lines = text.lines();
+    if lines.next()? != HEADER {
+        return None;
+    }
+    lines
+        .map(|entry| {
+            let (line, ast) = entry.split_once('\t')?;
+            let mut words = ast.split(' ');
+            let ast = decode_ast(&mut words)?;
+            match words.next() {
+                None => Some((unescape(line)?, ast)),
+                Some(_) => None,
+            }
+        })
+        .collect()
+}
+
+/// 行の中のタブと改行が区切りと紛れないよう、"\"を付けて書く
+fn escape(line: &str, buf: &mut String) {
+    for c in line.chars() {
+        match c {
+            '\\' => buf.push_str("\\\\"),
+            '\t' => buf.push_str("\\t"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            c => buf.push(c),
+        }
+    }
+}
+
+fn unescape(escaped: &str) -> Option<String> {
+    let mut line = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        line.push(match c {
+            '\\' => match chars.next()? {
+                '\\' => '\\',
+                't' => '\t',
+                'n' => '\n',
+                'r' => '\r',
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(line)
+}
+
+///
+/// 抽象構文木を前置記法の単語の列にする。各節点は種類、中身、位置、子の順に並べる。
+///
+/// ```text
+/// x = -(1 + y)
+/// = x 0 11 u - 4 5 4 11 b + 8 9 6 11 n 1 6 7 v y 10 11
+/// ```
+///
+fn encode_ast(ast: &Ast, buf: &mut String) {
+    use super::parser::AstKind::*;
+    // 長い演算子の連なりでもスタックを使い切らないよう、再帰せずに作業のスタックで辿る
+    let mut stack = vec![ast];
+    let mut first = true;
+    while let Some(ast) = stack.pop() {
+        if !first {
+            buf.push(' ');
+        }
+        first = false;
+        let loc = &ast.location;
+        match ast.value {
+            Num(n) => buf.push_str(&format!("n {} {} {}", n, loc.0, loc.1)),
+            Var(ref name) => buf.push_str(&format!("v {} {} {}", name, loc.0, loc.1)),
+            Assign { ref name, .. } => buf.push_str(&format!("= {} {} {}", name, loc.0, loc.1)),
+            Unary { ref operator, .. } => {
+                let op = &operator.location;
+                buf.push_str(&format!(
+                    "u {} {} {} {} {}",
+                    operator.value, op.0, op.1, loc.0, loc.1
+                ));
+            }
+            Binary { ref operator, .. } => {
+                let op = &operator.location;
+                buf.push_str(&format!(
+                    "b {} {} {} {} {}",
+                    operator.value, op.0, op.1, loc.0, loc.1
+                ));
+            }
+            Call { ref name, ref args } => {
+                buf.push_str(&format!("c {} {} {} {}", name, args.len(), loc.0, loc.1))
+            }
+        }
+        stack.extend(ast.value.children().into_iter().rev());
+    }
+}
+
+///
+/// 前置記法の単語の列から抽象構文木を読む。
+/// 子を読み終えていない節点は、子の代わりに仮の節点を入れて積んでおき、子が揃ったら置き換える。
+///
+fn decode_ast<'a>(words: &mut impl Iterator<Item = &'a str>) -> Option<Ast> {
+    // 子を読み終えていない節点と、その子の数、読み終えた子
+    let mut pending: Vec<(Ast, usize, Vec<Ast>)> = Vec::new();
+    loop {
+        let (mut ast, expected) = decode_node(words)?;
+        if expected > 0 {
+            pending.push((ast, expected, Vec::new()));
+            continue;
+        }
+        loop {
+            match pending.last_mut() {
+                None => return Some(ast),
+                Some((_, expected, children)) => {
+                    children.push(ast);
+                    if children.len() < *expected {
+                        break;
+                    }
+                }
+            }
+            let (parent, _, children) = pending.pop().unwrap();
+            ast = Ast::new(
+                parent.value.with_children(children),
+                parent.location.clone(),
+            );
+        }
+    }
+}
+
+/// 節点を1つ読み、子の数とともに返す。子は仮の節点にする
+fn decode_node<'a>(words: &mut impl Iterator<Item = &'a str>) -> Option<(Ast, usize)> {
+    fn location<'a>(words: &mut impl Iterator<Item = &'a str>) -> Option<Location> {
+        Some(Location(
+            words.next()?.parse().ok()?,
+            words.next()?.parse().ok()?,
+        ))
+    }
+    let placeholder = || Ast::num(0, Location(0, 0));
+    let node = match words.next()? {
+        "n" => {
+            let n = words.next()?.parse().ok()?;
+            (Ast::num(n, location(words)?), 0)
+        }
+        "v" => {
+            let name = words.next()?;
+            (Ast::var(name, location(words)?), 0)
+        }
+        "=" => {
+            let name = words.next()?;
+            (Ast::assign(name, placeholder(), location(words)?), 1)
+        }
+        "u" => {
+            let symbol = words.next()?;
+            let kind = OPERATORS.iter().find_map(|def| match def.kind {
+                OperatorKind::Prefix(ref kind) if kind.to_string() == symbol => Some(kind.clone()),
+                _ => None,
+            })?;
+            let operator = UnaryOperator::new(kind, location(words)?);
+            (Ast::unary(operator, placeholder(), location(words)?), 1)
+        }
+        "b" => {
+            let symbol = words.next()?;
+            let kind = OPERATORS.iter().find_map(|def| match def.kind {
+                OperatorKind::Infix(ref kind) if kind.to_string() == symbol => Some(kind.clone()),
+                _ => None,
+            })?;
+            let operator = BinaryOperator::new(kind, location(words)?);
+            let loc = location(words)?;
+            (Ast::binary(operator, placeholder(), placeholder(), loc), 2)
+        }
+        "c" => {
+            let name = words.next()?;
+            let len = words.next()?.parse().ok()?;
+            (Ast::call(name, Vec::new(), location(words)?), len)
+        }
+        _ => return None,
+    };
+    Some(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_ast() {
+        let ast = "x = -(1 + y)".parse::<Ast>().unwrap();
+        let mut buf = String::new();
+        encode_ast(&ast, &mut buf);
+        assert_eq!(buf, "= x 0 11 u - 4 5 4 11 b + 8 9 6 11 n 1 6 7 v y 10 11");
+        for input in [
+            "max(1, 2 ^ 3, f()) | |z / 4| * 0x10",
+            "abs()",
+            "a = b = 2 - 1",
+        ] {
+            let ast = input.parse::<Ast>().unwrap();
+            let mut buf = String::new();
+            encode_ast(&ast, &mut buf);
+            assert_eq!(decode_ast(&mut buf.split(' ')), Some(ast), "{}", input);
+        }
+        assert_eq!(
+            decode_ast(&mut "b % 0 1 0 3 n 1 0 1 n 2 2 3".split(' ')),
+            None
+        );
+        assert_eq!(decode_ast(&mut "c f 2 0 3 n 1 2 3".split(' ')), None);
+    }
+
+    #[test]
+    fn test_entries() {
+        let mut entries = HashMap::new();
+        for line in ["1 + 2", "x = 3\n\t* 4", "y # a\\b"] {
+            entries.insert(line.to_string(), line.parse::<Ast>().unwrap());
+        }
+        let encoded = encode_entries(&entries);
+        assert_eq!(encoded.lines().count(), 4);
+        assert_eq!(decode_entries(&encoded), Some(entries));
+        // 書式や版の違うキャッシュ、壊れたキャッシュは読まない
+        assert_eq!(decode_entries("parser-ast-cache 0\n"), None);
+        let truncated = &encoded[..encoded.len() - 4];
+        assert_eq!(decode_entries(truncated), None);
+        assert_eq!(unescape("a\\qb"), None);
+    }
+
+    #[test]
+    fn test_cache_dir() {
+        let dir = std::env::temp_dir().join(format!("parser-cache-test-{}", std::process::id()));
+        let cache = AstCache::new(&dir);
+        let source = b"1 + 2\nx = 3\n";
+        assert_eq!(cache.load(source), None);
+        let mut entries = HashMap::new();
+        entries.insert("1 + 2".to_string(), "1 + 2".parse::<Ast>().unwrap());
+        cache.store(source, &entries).unwrap();
+        assert_eq!(cache.load(source), Some(entries));
+        // 内容が変われば別の鍵になる
+        assert_eq!(cache.load(b"1 + 2\nx = 4\n"), None);
+        // 壊れたキャッシュは削除する
+        fs::write(cache.path(source), "garbage").unwrap();
+        assert_eq!(cache.load(source), None);
+        assert!(!cache.path(source).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
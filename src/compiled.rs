@@ -0,0 +1,131 @@
+//!
+//! 一度だけ解析・コンパイルし、何度でも評価できる式。
+//! コンパイルした式は評価しても変わらないので、Arcで包めば複数のスレッドから同時に評価できる。
+//! 変数の値や計算用のスタックは呼び出し側のVmに持たせ、スレッドごとに用意する。
+//!
+use super::bytecode::*;
+use super::interpreter::InterpreterError;
+use super::parser::*;
+
+///
+/// コンパイル済みの式。
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use parser::bytecode::Vm;
+/// use parser::compiled::CompiledExpr;
+///
+/// let expr = Arc::new(CompiledExpr::compile("x * x + 1").unwrap());
+/// let handles: Vec<_> = (0..4)
+///     .map(|x| {
+///         let expr = Arc::clone(&expr);
+///         thread::spawn(move || {
+///             let mut vm = Vm::new();
+///             vm.set_variable("x", x);
+///             expr.eval(&mut vm).unwrap()
+///         })
+///     })
+///     .collect();
+/// let results: Vec<i64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+/// assert_eq!(results, vec![1, 2, 5, 10]);
+/// ```
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledExpr {
+    source: String,
+    ast: Ast,
+    code: Vec<Instruction>,
+}
+
+impl CompiledExpr {
+    /// 1行分の式を解析し、命令列へコンパイルする
+    pub fn compile(line: &str) -> Result<Self, ApplicationError> {
+        let ast = line.parse::<Ast>()?;
+        let code = BytecodeCompiler::new().compile(&ast)?;
+        Ok(CompiledExpr {
+            source: line.to_string(),
+            ast,
+            code,
+        })
+    }
+
+    /// コンパイルした元の式
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn ast(&self) -> &Ast {
+        &self.ast
+    }
+
+    pub fn code(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    ///
+    /// vmの変数を使って式を評価する。
+    /// 式の中の代入はvmの変数を書き換えるだけで、この式は変わらない
+    ///
+    pub fn eval(&self, vm: &mut Vm) -> Result<i64, InterpreterError> {
+        vm.run(&self.code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::interpreter::Interpreter;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_thread_safety() {
+        // 処理系の状態はどれもスレッドをまたいで渡せる
+        assert_send_sync::<CompiledExpr>();
+        assert_send_sync::<Vm>();
+        assert_send_sync::<Interpreter>();
+        assert_send_sync::<Engine>();
+    }
+
+    #[test]
+    fn test_concurrent_eval() {
+        let expr = Arc::new(CompiledExpr::compile("y = x * 2").unwrap());
+        let handles: Vec<_> = (0..8)
+            .map(|x| {
+                let expr = Arc::clone(&expr);
+                thread::spawn(move || {
+                    let mut vm = Vm::new();
+                    vm.set_variable("x", x);
+                    let mut sum = 0;
+                    for _ in 0..100 {
+                        sum += expr.eval(&mut vm).unwrap();
+                    }
+                    (sum, vm.variable("y"))
+                })
+            })
+            .collect();
+        for (x, handle) in handles.into_iter().enumerate() {
+            let x = x as i64;
+            assert_eq!(handle.join().unwrap(), (x * 200, Some(x * 2)));
+        }
+        assert_eq!(expr.source(), "y = x * 2");
+    }
+
+    #[test]
+    fn test_compile_error() {
+        assert!(matches!(
+            CompiledExpr::compile("1 +"),
+            Err(ApplicationError::Parser(_))
+        ));
+        assert!(matches!(
+            CompiledExpr::compile("9223372036854775808"),
+            Err(ApplicationError::Interpreter(_))
+        ));
+        let expr = CompiledExpr::compile("x + 1").unwrap();
+        assert!(expr.eval(&mut Vm::new()).is_err());
+    }
+}
@@ -1,11 +1,189 @@
+use super::asm::AsmCompiler;
+use super::csource::CCompiler;
+use super::dc::DcCompiler;
+use super::dot::DotCompiler;
+use super::interpreter::{function_arity, Arity, InterpreterError, InterpreterErrorKind};
+use super::latex::LatexCompiler;
+use super::lexer::Location;
+use super::llvm_ir::LlvmCompiler;
 use super::parser::*;
+use super::prefix::PrefixCompiler;
+use super::sexpr::SexprCompiler;
+use super::wasm::WasmCompiler;
+
+///
+/// 変換できなかった理由。
+/// 未定義の関数や変換先で使えない演算など、評価で起きる誤りと同じ種類で表す。
+///
+pub type CompileError = InterpreterError;
+
+///
+/// 抽象構文木を別の表記やプログラムへ変換するコンパイラ。
+/// 変換先で表せない式はエラーにする。
+/// 処理系（Engine）をスレッド間で受け渡せるよう、SendとSyncを求める。
+///
+pub trait Backend: Send + Sync {
+    /// "--target"で指定する変換先の名前
+    fn name(&self) -> &str;
+
+    /// 抽象構文木を変換し、bufの内容を置き換える
+    fn compile_into(&mut self, expr: &Ast, buf: &mut String) -> Result<(), CompileError>;
+
+    /// 抽象構文木を変換して返す
+    fn compile(&mut self, expr: &Ast) -> Result<String, CompileError> {
+        let mut buf = String::new();
+        self.compile_into(expr, &mut buf)?;
+        Ok(buf)
+    }
+}
+
+///
+/// 名前で選べる変換先の一覧。
+/// 組み込みの変換先のほかに、利用者が作った変換先を登録できる。
+///
+pub struct Registry {
+    backends: Vec<Box<dyn Backend>>,
+}
+
+impl Registry {
+    /// 組み込みの変換先をすべて登録した一覧を作る
+    pub fn new() -> Self {
+        let mut registry = Registry::empty();
+        registry.register(Box::new(RpnCompiler::new()));
+        registry.register(Box::new(PrefixCompiler::new()));
+        registry.register(Box::new(SexprCompiler::new()));
+        registry.register(Box::new(LatexCompiler::new()));
+        registry.register(Box::new(DotCompiler::new()));
+        registry.register(Box::new(DcCompiler::new()));
+        registry.register(Box::new(WasmCompiler::new()));
+        registry.register(Box::new(CCompiler::new()));
+        registry.register(Box::new(AsmCompiler::new()));
+        registry.register(Box::new(LlvmCompiler::new()));
+        registry
+    }
+
+    /// 何も登録していない一覧を作る
+    pub fn empty() -> Self {
+        Registry {
+            backends: Vec::new(),
+        }
+    }
+
+    /// 変換先を登録する。同じ名前の変換先があれば置き換える
+    pub fn register(&mut self, backend: Box<dyn Backend>) {
+        match self
+            .backends
+            .iter()
+            .position(|b| b.name() == backend.name())
+        {
+            Some(i) => self.backends[i] = backend,
+            None => self.backends.push(backend),
+        }
+    }
+
+    /// 名前で変換先を探す
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut dyn Backend> {
+        self.backends
+            .iter_mut()
+            .find(|b| b.name() == name)
+            .map(|b| b.as_mut() as &mut dyn Backend)
+    }
+
+    /// 登録した順の変換先の名前
+    pub fn names(&self) -> Vec<&str> {
+        self.backends.iter().map(|b| b.name()).collect()
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 関数呼び出しを変換する前に、関数が定義されていて引数の個数が合うことを確かめる
+pub(crate) fn check_call(
+    name: &str,
+    args: usize,
+    location: &Location,
+) -> Result<(), InterpreterError> {
+    let expected = function_arity(name).ok_or_else(|| {
+        InterpreterError::new(
+            InterpreterErrorKind::UnknownFunction(name.to_string()),
+            location.clone(),
+        )
+    })?;
+    if !expected.accepts(args) {
+        return Err(InterpreterError::new(
+            InterpreterErrorKind::WrongArgumentCount {
+                name: name.to_string(),
+                expected,
+                found: args,
+            },
+            location.clone(),
+        ));
+    }
+    Ok(())
+}
+
+/// 単項の"-"を表す既定の語
+pub const DEFAULT_NEGATION: &str = "neg";
+
+///
+/// 逆ポーランド記法の出力の書式。
+/// dcやForthなど、出力を受け取る側の処理系に合わせて変更する。
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RpnOptions {
+    /// 語の区切り
+    pub separator: String,
+    /// 単項の"-"を表す語。二項の"-"や負の数と区別できるよう、独立した語として出力する
+    pub negation: String,
+    /// 除算を表す語
+    pub division: String,
+    /// 出力の末尾に改行を付けるかどうか
+    pub newline: bool,
+}
+
+impl Default for RpnOptions {
+    fn default() -> Self {
+        RpnOptions {
+            separator: " ".to_string(),
+            negation: DEFAULT_NEGATION.to_string(),
+            division: "/".to_string(),
+            newline: false,
+        }
+    }
+}
+
+impl RpnOptions {
+    /// 二項演算子を表す語を返す
+    pub fn binary_operator(&self, operator: &BinaryOperatorKind) -> String {
+        match operator {
+            BinaryOperatorKind::Div => self.division.clone(),
+            operator => operator.to_string(),
+        }
+    }
+}
 
 /// 逆ポーランド記法へのコンパイラ
-pub struct RpnCompiler;
+#[derive(Default)]
+pub struct RpnCompiler {
+    options: RpnOptions,
+}
 
 impl RpnCompiler {
     pub fn new() -> Self {
-        RpnCompiler
+        Self::with_options(RpnOptions::default())
+    }
+
+    /// 出力の書式を指定してコンパイラを作成する
+    pub fn with_options(options: RpnOptions) -> Self {
+        RpnCompiler { options }
+    }
+
+    pub fn options(&self) -> &RpnOptions {
+        &self.options
     }
 
     ///
@@ -13,52 +191,244 @@ impl RpnCompiler {
     ///
     pub fn compile(&mut self, expr: &Ast) -> String {
         let mut buf = String::new();
-        self.compile_inner(expr, &mut buf);
+        self.compile_into(expr, &mut buf);
         buf
     }
 
-    fn compile_inner(&mut self, expr: &Ast, buf: &mut String) {
+    ///
+    /// 抽象構文木を逆ポーランド記法へ変換し、bufの内容を置き換える。
+    /// bufの領域を使い回せるので、繰り返し変換する場合のメモリ確保を減らせる。
+    /// 節点ごとに少なくとも1文字と区切りを書くので、その分の領域を前もって確保する。
+    ///
+    pub fn compile_into(&mut self, expr: &Ast, buf: &mut String) {
+        buf.clear();
+        buf.reserve(expr.node_count() * 2);
+        RpnEmitter {
+            options: &self.options,
+            buf,
+        }
+        .emit(expr);
+        if self.options.newline {
+            buf.push('\n');
+        }
+    }
+}
+
+impl Backend for RpnCompiler {
+    fn name(&self) -> &str {
+        "rpn"
+    }
+
+    fn compile_into(&mut self, expr: &Ast, buf: &mut String) -> Result<(), CompileError> {
+        RpnCompiler::compile_into(self, expr, buf);
+        Ok(())
+    }
+}
+
+/// 逆ポーランド記法へ変換するときの作業
+enum Step<'a> {
+    /// 節点を変換する
+    Node(&'a Ast),
+    /// 語をそのまま書き出す
+    Word(&'a str),
+    /// 二項演算子を書き出す
+    Binary(&'a BinaryOperatorKind),
+    /// 語の区切りを書き出す
+    Separator,
+}
+
+///
+/// 構文木を辿りながら、逆ポーランド記法の語をbufへ書き出す。
+/// 深く入れ子になった式でもネイティブのスタックを使い切らないよう、
+/// 再帰せずに作業のスタックで辿る。作業は書き出す順の逆に積む。
+///
+struct RpnEmitter<'a> {
+    options: &'a RpnOptions,
+    buf: &'a mut String,
+}
+
+impl RpnEmitter<'_> {
+    fn emit(&mut self, expr: &Ast) {
         use super::parser::AstKind::*;
-        match expr.value {
-            Num(n) => buf.push_str(&n.to_string()),
-            Unary {
-                ref operator,
-                ref operand,
-            } => {
-                self.compile_uniop(operator, buf);
-                self.compile_inner(operand, buf);
-            }
-            Binary {
-                ref operator,
-                ref left,
-                ref right,
-            } => {
-                self.compile_inner(left, buf);
-                buf.push_str(" ");
-                self.compile_inner(right, buf);
-                buf.push_str(" ");
-                self.compile_binop(operator, buf);
+        let mut steps = vec![Step::Node(expr)];
+        while let Some(step) = steps.pop() {
+            let expr = match step {
+                Step::Node(expr) => expr,
+                Step::Word(word) => {
+                    self.buf.push_str(word);
+                    continue;
+                }
+                Step::Binary(operator) => {
+                    self.buf.push_str(&self.options.binary_operator(operator));
+                    continue;
+                }
+                Step::Separator => {
+                    self.buf.push_str(&self.options.separator);
+                    continue;
+                }
+            };
+            match expr.value {
+                Num(n) => self.buf.push_str(&n.to_string()),
+                Var(ref name) => self.buf.push_str(name),
+                // 代入は値を積んだ後に"=変数名"で表す
+                Assign {
+                    ref name,
+                    ref value,
+                } => steps.extend([
+                    Step::Word(name),
+                    Step::Word("="),
+                    Step::Separator,
+                    Step::Node(value),
+                ]),
+                Unary {
+                    ref operator,
+                    ref operand,
+                } => {
+                    match operator.value {
+                        // 値を変えないので何も出力しない。"+x"のように被演算子へ付けると、
+                        // 二項の"+"と区別できない語になる
+                        UnaryOperatorKind::Plus => {}
+                        // 被演算子の後に置く
+                        UnaryOperatorKind::Minus => {
+                            steps.extend([Step::Word(&self.options.negation), Step::Separator])
+                        }
+                    }
+                    steps.push(Step::Node(operand));
+                }
+                Binary {
+                    ref operator,
+                    ref left,
+                    ref right,
+                } => steps.extend([
+                    Step::Binary(&operator.value),
+                    Step::Separator,
+                    Step::Node(right),
+                    Step::Separator,
+                    Step::Node(left),
+                ]),
+                // 関数呼び出しは引数を積んだ後に関数名を置く。
+                // 可変個の引数を取る関数は、2引数の呼び出しを繰り返す形（"a b min c min"）にする
+                Call { ref name, ref args } => match (function_arity(name), args.split_first()) {
+                    (Some(Arity::AtLeast(_)), Some((first, rest))) => {
+                        for arg in rest.iter().rev() {
+                            steps.extend([
+                                Step::Word(name),
+                                Step::Separator,
+                                Step::Node(arg),
+                                Step::Separator,
+                            ]);
+                        }
+                        steps.push(Step::Node(first));
+                    }
+                    _ => {
+                        steps.push(Step::Word(name));
+                        for arg in args.iter().rev() {
+                            steps.extend([Step::Separator, Step::Node(arg)]);
+                        }
+                    }
+                },
             }
         }
     }
+}
 
-    /// 単項演算子を処理する
-    fn compile_uniop(&mut self, operator: &UnaryOperator, buf: &mut String) {
-        use super::parser::UnaryOperatorKind::*;
-        match operator.value {
-            Plus => buf.push_str("+"),
-            Minus => buf.push_str("-"),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unary_minus() {
+        let ast = "1 - -10".parse::<Ast>().unwrap();
+        assert_eq!(RpnCompiler::new().compile(&ast), "1 10 neg -");
+        let ast = "-(2 ^ 2)".parse::<Ast>().unwrap();
+        let options = RpnOptions {
+            negation: "_".to_string(),
+            ..RpnOptions::default()
+        };
+        assert_eq!(RpnCompiler::with_options(options).compile(&ast), "2 2 ^ _");
+    }
+
+    #[test]
+    fn test_radix_literals() {
+        // 16進数・8進数・2進数で書いた数も10進数で出力する
+        let ast = "0x1F * 0o17 - 0b1010".parse::<Ast>().unwrap();
+        assert_eq!(RpnCompiler::new().compile(&ast), "31 15 * 10 -");
+    }
+
+    #[test]
+    fn test_double_negation() {
+        let ast = "--5".parse::<Ast>().unwrap();
+        assert_eq!(RpnCompiler::new().compile(&ast), "5 neg neg");
+        let ast = "- -(-3)".parse::<Ast>().unwrap();
+        assert_eq!(RpnCompiler::new().compile(&ast), "3 neg neg neg");
+        let ast = "2 * -+-x".parse::<Ast>().unwrap();
+        assert_eq!(RpnCompiler::new().compile(&ast), "2 x neg neg *");
+    }
+
+    #[test]
+    fn test_unary_plus() {
+        let ast = "1 - +2".parse::<Ast>().unwrap();
+        assert_eq!(RpnCompiler::new().compile(&ast), "1 2 -");
+        let ast = "+(1 + 2) * - +x".parse::<Ast>().unwrap();
+        assert_eq!(RpnCompiler::new().compile(&ast), "1 2 + x neg *");
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = Registry::new();
+        assert_eq!(
+            registry.names(),
+            vec!["rpn", "prefix", "sexpr", "latex", "dot", "dc", "wat", "c", "asm", "llvm-ir"]
+        );
+        let ast = "1 + 2".parse::<Ast>().unwrap();
+        let rpn = registry.get_mut("rpn").unwrap();
+        assert_eq!(rpn.compile(&ast), Ok("1 2 +".to_string()));
+        assert!(registry.get_mut("forth").is_none());
+
+        // 同じ名前で登録すると置き換わる
+        registry.register(Box::new(RpnCompiler::with_options(RpnOptions {
+            separator: ",".to_string(),
+            ..RpnOptions::default()
+        })));
+        assert_eq!(registry.names().len(), 10);
+        let rpn = registry.get_mut("rpn").unwrap();
+        assert_eq!(rpn.compile(&ast), Ok("1,2,+".to_string()));
+        assert_eq!(Registry::empty().names(), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_options() {
+        let options = RpnOptions {
+            separator: ",".to_string(),
+            negation: "chs".to_string(),
+            division: "div".to_string(),
+            newline: true,
+        };
+        let ast = "x = -max(1, 2, 3) / 4".parse::<Ast>().unwrap();
+        assert_eq!(
+            RpnCompiler::with_options(options).compile(&ast),
+            "1,2,max,3,max,chs,4,div,=x\n"
+        );
     }
 
-    /// 二項演算子を処理する
-    fn compile_binop(&mut self, operator: &BinaryOperator, buf: &mut String) {
-        use super::parser::BinaryOperatorKind::*;
-        match operator.value {
-            Add => buf.push_str("+"),
-            Sub => buf.push_str("-"),
-            Multi => buf.push_str("*"),
-            Div => buf.push_str("/"),
+    #[test]
+    fn test_deep_nesting() {
+        // 構文解析器の入れ子の上限を超える深さの木も、スタックを使い切らずに変換できる
+        let loc = || Location(0, 0);
+        let n = 100_000;
+        let mut ast = Ast::num(1, loc());
+        for _ in 0..n {
+            let negated = Ast::unary(UnaryOperator::minus(loc()), ast, loc());
+            ast = Ast::binary(
+                BinaryOperator::sub(loc()),
+                Ast::var("x", loc()),
+                negated,
+                loc(),
+            );
         }
+        assert_eq!(
+            RpnCompiler::new().compile(&ast),
+            format!("{}1{}", "x ".repeat(n), " neg -".repeat(n))
+        );
     }
 }
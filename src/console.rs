@@ -0,0 +1,311 @@
+//!
+//! 端末への入出力の差異を吸収する。
+//! Windowsでは入力の行末に"\r"が残ることがあり、ANSIエスケープシーケンスも
+//! 明示的に有効化しなければ解釈されない。
+//!
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+///
+/// 入力から読み取った1行の行末の改行文字（"\r\n"、"\n"、"\r"）を取り除く
+///
+pub fn normalize_line(line: &str) -> &str {
+    line.trim_end_matches(['\n', '\r'])
+}
+
+///
+/// 標準エラー出力でANSIエスケープシーケンス（色付け）を使えるようにする。
+/// 使える場合はtrue、色を付けずに出力すべき場合はfalseを返す。
+///
+pub fn enable_ansi() -> bool {
+    // https://no-color.org/
+    if std::env::var_os("NO_COLOR").is_some() || !std::io::stderr().is_terminal() {
+        return false;
+    }
+    enable_virtual_terminal()
+}
+
+#[cfg(windows)]
+fn enable_virtual_terminal() -> bool {
+    use windows_sys::Win32::System::Console::{
+        GetConsoleMode, GetStdHandle, SetConsoleMode, ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+        STD_ERROR_HANDLE,
+    };
+
+    // 古いコンソールでは有効化に失敗するので、その場合は色を付けない
+    unsafe {
+        let handle = GetStdHandle(STD_ERROR_HANDLE);
+        let mut mode = 0;
+        if GetConsoleMode(handle, &mut mode) == 0 {
+            return false;
+        }
+        SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) != 0
+    }
+}
+
+#[cfg(not(windows))]
+fn enable_virtual_terminal() -> bool {
+    std::env::var("TERM").map_or(true, |term| term != "dumb")
+}
+
+///
+/// 出力の色付けを行う。無効な場合は文字列をそのまま返す。
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Style {
+    pub ansi: bool,
+}
+
+impl Style {
+    pub fn new(ansi: bool) -> Self {
+        Style { ansi }
+    }
+
+    /// エラーを表す赤色の文字列にする
+    pub fn error(&self, s: &str) -> String {
+        self.paint("31", s)
+    }
+
+    /// 補足情報を表す暗い色の文字列にする
+    pub fn note(&self, s: &str) -> String {
+        self.paint("2", s)
+    }
+
+    fn paint(&self, code: &str, s: &str) -> String {
+        if self.ansi {
+            format!("\x1b[{}m{}\x1b[0m", code, s)
+        } else {
+            s.to_string()
+        }
+    }
+}
+
+/// 長い出力をページャで表示するかどうか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Paging {
+    /// 標準出力が端末で、出力が端末の高さを超える場合だけ使う
+    #[default]
+    Auto,
+    /// 常に使う
+    Always,
+    /// 使わない
+    Never,
+}
+
+impl FromStr for Paging {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Paging::Auto),
+            "always" => Ok(Paging::Always),
+            "never" => Ok(Paging::Never),
+            _ => Err(format!(
+                "unknown paging '{}' (expected auto, always or never)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Paging {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Paging::Auto => write!(f, "auto"),
+            Paging::Always => write!(f, "always"),
+            Paging::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl Paging {
+    ///
+    /// heightが返す高さの端末で、textをページャで表示すべきかどうかを返す。
+    /// 端末の高さを調べるのは手間がかかるので、heightはAutoで複数行を表示するときだけ呼ぶ。
+    ///
+    pub fn should_page(self, text: &str, height: impl FnOnce() -> Option<usize>) -> bool {
+        match self {
+            Paging::Always => true,
+            Paging::Never => false,
+            Paging::Auto => {
+                let lines = text.lines().count();
+                // プロンプトの分の1行を残す
+                lines > 1 && height().is_some_and(|height| lines >= height)
+            }
+        }
+    }
+}
+
+///
+/// 設定に従ってtextをページャ（環境変数PAGER、なければless）で表示する。
+/// ページャを使わなかった場合や起動できなかった場合はfalseを返すので、呼び出し側で表示する。
+///
+pub fn page(text: &str, paging: Paging) -> bool {
+    let height = || {
+        if io::stdout().is_terminal() {
+            cached_terminal_height()
+        } else {
+            None
+        }
+    };
+    if !paging.should_page(text, height) {
+        return false;
+    }
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| default_pager().to_string());
+    let mut words = pager.split_whitespace();
+    let program = match words.next() {
+        Some(program) => program,
+        None => return false,
+    };
+    let mut child = match Command::new(program)
+        .args(words)
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(_) => return false,
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // 途中でページャを終了すると書き込みに失敗するが、それは問題ではない
+        let _ = writeln!(stdin, "{}", text);
+    }
+    let _ = child.wait();
+    true
+}
+
+/// 端末の行数を最初に必要になったときに一度だけ調べ、以後はその値を使う
+fn cached_terminal_height() -> Option<usize> {
+    static HEIGHT: OnceLock<Option<usize>> = OnceLock::new();
+    *HEIGHT.get_or_init(terminal_height)
+}
+
+#[cfg(windows)]
+fn default_pager() -> &'static str {
+    "more"
+}
+
+#[cfg(not(windows))]
+fn default_pager() -> &'static str {
+    "less"
+}
+
+///
+/// 端末の行数を返す。取得できなければNoneを返す
+///
+#[cfg(windows)]
+pub fn terminal_height() -> Option<usize> {
+    use windows_sys::Win32::System::Console::{
+        GetConsoleScreenBufferInfo, GetStdHandle, CONSOLE_SCREEN_BUFFER_INFO, STD_OUTPUT_HANDLE,
+    };
+
+    unsafe {
+        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+        let mut info: CONSOLE_SCREEN_BUFFER_INFO = std::mem::zeroed();
+        if GetConsoleScreenBufferInfo(handle, &mut info) == 0 {
+            return None;
+        }
+        Some((info.srWindow.Bottom - info.srWindow.Top + 1) as usize)
+    }
+}
+
+///
+/// 端末の行数を返す。取得できなければNoneを返す
+///
+#[cfg(not(windows))]
+pub fn terminal_height() -> Option<usize> {
+    // シェルが設定するLINESを優先し、なければsttyに問い合わせる
+    if let Some(lines) = std::env::var("LINES").ok().and_then(|s| s.parse().ok()) {
+        return Some(lines).filter(|&lines| lines > 0);
+    }
+    let tty = std::fs::File::open("/dev/tty").ok()?;
+    let output = Command::new("stty")
+        .arg("size")
+        .stdin(tty)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    // "行数 桁数"の形で出力される。大きさを設定していない疑似端末では0になる
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+        .filter(|&lines| lines > 0)
+}
+
+///
+/// 文字列をシステムのクリップボードへ送る。
+/// clipboard機能を有効にしてビルドしていなければ、常にエラーを返す
+///
+#[cfg(feature = "clipboard")]
+pub fn copy_to_clipboard(text: &str) -> Result<(), String> {
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_text(text))
+        .map_err(|e| format!("cannot copy to the clipboard: {}", e))
+}
+
+///
+/// 文字列をシステムのクリップボードへ送る。
+/// clipboard機能を有効にしてビルドしていなければ、常にエラーを返す
+///
+#[cfg(not(feature = "clipboard"))]
+pub fn copy_to_clipboard(_text: &str) -> Result<(), String> {
+    Err("clipboard support is not enabled (rebuild with --features clipboard)".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_line() {
+        assert_eq!(normalize_line("1 + 2\r\n"), "1 + 2");
+        assert_eq!(normalize_line("1 + 2\r"), "1 + 2");
+        assert_eq!(normalize_line("exit\n"), "exit");
+        assert_eq!(normalize_line("1\r+ 2"), "1\r+ 2");
+    }
+
+    #[test]
+    fn test_style() {
+        assert_eq!(Style::new(false).error("parse error"), "parse error");
+        assert_eq!(
+            Style::new(true).error("parse error"),
+            "\x1b[31mparse error\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_should_page() {
+        let text = "1\n2\n3";
+        assert!(Paging::Auto.should_page(text, || Some(3)));
+        assert!(!Paging::Auto.should_page(text, || Some(24)));
+        // 端末でなければ使わない
+        assert!(!Paging::Auto.should_page(text, || None));
+        assert!(Paging::Always.should_page("", || None));
+        assert!(!Paging::Never.should_page(text, || Some(1)));
+        // 1行の出力や、設定で決まる場合は端末の高さを調べない
+        let unused = || -> Option<usize> { panic!("terminal height is not needed") };
+        assert!(!Paging::Auto.should_page("3", unused));
+        assert!(!Paging::Auto.should_page("", unused));
+        assert!(Paging::Always.should_page(text, unused));
+        assert!(!Paging::Never.should_page(text, unused));
+        assert_eq!("never".parse(), Ok(Paging::Never));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_enable_ansi_on_windows() {
+        // コンソールに接続されていない場合でも失敗せずに結果を返す
+        let _ = enable_ansi();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_crlf_input_on_windows() {
+        use crate::engine::{Engine, Mode, Outcome};
+        let mut engine = Engine::new(Mode::Eval);
+        assert_eq!(engine.run(normalize_line("1 + 2\r\n")), Outcome::Value(3));
+    }
+}
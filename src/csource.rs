@@ -0,0 +1,460 @@
+//!
+//! C言語のソースファイルへの変換。
+//! 式を計算する`int64_t eval(void)`と、その結果を表示するmainを含むmain.cを出力する。
+//! 評価器と同じく、桁あふれやゼロ除算は実行時に検出してエラーにする。
+//!
+use std::fmt::Write;
+
+use super::compiler::{check_call, Backend, CompileError};
+use super::interpreter::*;
+use super::parser::*;
+
+/// エラーを表示して終了する関数。補助関数より前に置く
+const FAIL: &str = "\
+static void fail(const char *message)
+{
+    fprintf(stderr, \"error: %s\\n\", message);
+    exit(1);
+}
+";
+
+///
+/// 評価器がエラーにする場合を確かめてから計算する補助関数。使われたものだけを出力する。
+/// 補助関数から呼ぶものは、呼ぶものより前に並べる。
+///
+const HELPERS: [(&str, &str); 10] = [
+    (
+        "add",
+        "\
+static int64_t checked_add(int64_t a, int64_t b)
+{
+    if ((b > 0 && a > INT64_MAX - b) || (b < 0 && a < INT64_MIN - b))
+        fail(\"overflow\");
+    return a + b;
+}
+",
+    ),
+    (
+        "sub",
+        "\
+static int64_t checked_sub(int64_t a, int64_t b)
+{
+    if ((b < 0 && a > INT64_MAX + b) || (b > 0 && a < INT64_MIN + b))
+        fail(\"overflow\");
+    return a - b;
+}
+",
+    ),
+    (
+        "mul",
+        "\
+static int64_t checked_mul(int64_t a, int64_t b)
+{
+    if (a != 0 && b != 0
+        && (a > 0 ? (b > 0 ? a > INT64_MAX / b : b < INT64_MIN / a)
+                  : (b > 0 ? a < INT64_MIN / b : a < INT64_MAX / b)))
+        fail(\"overflow\");
+    return a * b;
+}
+",
+    ),
+    (
+        "div",
+        "\
+static int64_t checked_div(int64_t a, int64_t b)
+{
+    if (b == 0)
+        fail(\"division by zero\");
+    if (a == INT64_MIN && b == -1)
+        fail(\"overflow\");
+    return a / b;
+}
+",
+    ),
+    (
+        "neg",
+        "\
+static int64_t checked_neg(int64_t a)
+{
+    if (a == INT64_MIN)
+        fail(\"overflow\");
+    return -a;
+}
+",
+    ),
+    (
+        "pow",
+        "\
+static int64_t checked_pow(int64_t a, int64_t b)
+{
+    int64_t result = 1;
+    if (b < 0)
+        fail(\"negative exponent\");
+    if (b == 0)
+        return 1;
+    if (a == 0 || a == 1)
+        return a;
+    if (a == -1)
+        return b % 2 == 0 ? 1 : -1;
+    for (; b > 0; b--)
+        result = checked_mul(result, a);
+    return result;
+}
+",
+    ),
+    (
+        "sqrt",
+        "\
+static int64_t checked_sqrt(int64_t a)
+{
+    int64_t low = 0, high = 3037000499;
+    if (a < 0)
+        fail(\"invalid argument to sqrt\");
+    while (low < high) {
+        int64_t mid = low + (high - low + 1) / 2;
+        if (mid <= a / mid)
+            low = mid;
+        else
+            high = mid - 1;
+    }
+    return low;
+}
+",
+    ),
+    (
+        "abs",
+        "\
+static int64_t checked_abs(int64_t a)
+{
+    return a < 0 ? checked_neg(a) : a;
+}
+",
+    ),
+    (
+        "min",
+        "\
+static int64_t min(int64_t a, int64_t b)
+{
+    return a < b ? a : b;
+}
+",
+    ),
+    (
+        "max",
+        "\
+static int64_t max(int64_t a, int64_t b)
+{
+    return a > b ? a : b;
+}
+",
+    ),
+];
+
+///
+/// C言語のソースファイルへのコンパイラ。
+/// 変数は"var_"を付けた大域変数にする。代入より前に読む変数は、mainがコマンドライン引数から値を設定する。
+///
+#[derive(Default)]
+pub struct CCompiler {
+    /// コマンドライン引数から値を設定する変数（最初に読む順）
+    inputs: Vec<String>,
+    /// 代入してから読む変数
+    assigned: Vec<String>,
+    /// 使われた補助関数（HELPERSの順）
+    helpers: [bool; HELPERS.len()],
+    /// evalが返す式
+    body: String,
+}
+
+impl CCompiler {
+    pub fn new() -> Self {
+        CCompiler::default()
+    }
+
+    /// 変数を初めて見たとき、読むなら入力に、代入するなら代入される変数にする
+    fn declare(&mut self, name: &str, assigned: bool) {
+        if self.inputs.iter().chain(&self.assigned).any(|n| n == name) {
+            return;
+        }
+        if assigned {
+            self.assigned.push(name.to_string());
+        } else {
+            self.inputs.push(name.to_string());
+        }
+    }
+
+    /// 補助関数と、その補助関数が呼ぶ補助関数を出力するよう記録する
+    fn use_helper(&mut self, helper: &str) {
+        let index = HELPERS.iter().position(|&(n, _)| n == helper).unwrap();
+        self.helpers[index] = true;
+        match helper {
+            "abs" => self.use_helper("neg"),
+            "pow" => self.use_helper("mul"),
+            _ => {}
+        }
+    }
+
+    /// 補助関数の呼び出しを書き始める
+    fn call(&mut self, helper: &str) {
+        self.use_helper(helper);
+        if helper == "min" || helper == "max" {
+            self.body.push_str(helper);
+        } else {
+            self.body.push_str("checked_");
+            self.body.push_str(helper);
+        }
+        self.body.push('(');
+    }
+
+    ///
+    /// 節点をCの式へ変換する。
+    /// 長い演算子の連なりでもネイティブのスタックを使い切らないよう、
+    /// 再帰せずに作業のスタックで辿る。作業は書き出す順の逆に積む。
+    ///
+    fn compile_inner(&mut self, expr: &Ast) -> Result<(), InterpreterError> {
+        use super::parser::AstKind::*;
+        let mut steps = vec![Step::Node(expr)];
+        while let Some(step) = steps.pop() {
+            let expr = match step {
+                Step::Node(expr) => expr,
+                Step::Text(text) => {
+                    self.body.push_str(text);
+                    continue;
+                }
+                Step::Assigned(name) => {
+                    self.declare(name, true);
+                    continue;
+                }
+            };
+            match expr.value {
+                Num(n) => {
+                    let n =
+                        literal(n).map_err(|e| InterpreterError::new(e, expr.location.clone()))?;
+                    write!(self.body, "{}", n).unwrap();
+                }
+                Var(ref name) => {
+                    self.declare(name, false);
+                    write!(self.body, "var_{}", name).unwrap();
+                }
+                // 代入は文の先頭にしか書けないので、かっこで囲まなくてよい
+                Assign {
+                    ref name,
+                    ref value,
+                } => {
+                    write!(self.body, "var_{} = ", name).unwrap();
+                    steps.extend([Step::Assigned(name), Step::Node(value)]);
+                }
+                Unary {
+                    ref operator,
+                    ref operand,
+                } => match operator.value {
+                    UnaryOperatorKind::Plus => steps.push(Step::Node(operand)),
+                    UnaryOperatorKind::Minus => {
+                        self.call("neg");
+                        steps.extend([Step::Text(")"), Step::Node(operand)]);
+                    }
+                },
+                Binary {
+                    ref operator,
+                    ref left,
+                    ref right,
+                } => {
+                    let helper = match operator.value {
+                        BinaryOperatorKind::Add => "add",
+                        BinaryOperatorKind::Sub => "sub",
+                        BinaryOperatorKind::Multi => "mul",
+                        BinaryOperatorKind::Div => "div",
+                        BinaryOperatorKind::Pow => "pow",
+                        // 桁あふれしないので、そのまま書く
+                        BinaryOperatorKind::BitOr => {
+                            self.body.push('(');
+                            steps.extend([
+                                Step::Text(")"),
+                                Step::Node(right),
+                                Step::Text(" | "),
+                                Step::Node(left),
+                            ]);
+                            continue;
+                        }
+                    };
+                    self.call(helper);
+                    steps.extend([
+                        Step::Text(")"),
+                        Step::Node(right),
+                        Step::Text(", "),
+                        Step::Node(left),
+                    ]);
+                }
+                Call { ref name, ref args } => {
+                    check_call(name, args.len(), &expr.location)?;
+                    // minとmaxは2つずつ比べる: max(max(a, b), c)
+                    let variadic = name == "min" || name == "max";
+                    let calls = if variadic { args.len() - 1 } else { 1 };
+                    for _ in 0..calls {
+                        self.call(name);
+                    }
+                    if !variadic {
+                        steps.push(Step::Text(")"));
+                    }
+                    for (i, arg) in args.iter().enumerate().rev() {
+                        if variadic && i > 0 {
+                            steps.push(Step::Text(")"));
+                        }
+                        steps.push(Step::Node(arg));
+                        if i > 0 {
+                            steps.push(Step::Text(", "));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Cの式へ変換するときの作業
+enum Step<'a> {
+    /// 節点を変換する
+    Node(&'a Ast),
+    /// 文字列をそのまま書き出す
+    Text(&'static str),
+    /// 右辺を書き終えた代入の変数を記録する
+    Assigned(&'a str),
+}
+
+impl Backend for CCompiler {
+    fn name(&self) -> &str {
+        "c"
+    }
+
+    /// 抽象構文木をC言語のソースファイルへ変換し、bufの内容を置き換える
+    fn compile_into(&mut self, expr: &Ast, buf: &mut String) -> Result<(), CompileError> {
+        buf.clear();
+        self.inputs.clear();
+        self.assigned.clear();
+        self.helpers = [false; HELPERS.len()];
+        self.body.clear();
+        self.compile_inner(expr)?;
+
+        buf.push_str("#include <inttypes.h>\n#include <stdio.h>\n#include <stdlib.h>\n\n");
+        for name in self.inputs.iter().chain(&self.assigned) {
+            writeln!(buf, "int64_t var_{};", name).unwrap();
+        }
+        if !self.inputs.is_empty() || !self.assigned.is_empty() {
+            buf.push('\n');
+        }
+        if self.helpers.contains(&true) {
+            buf.push_str(FAIL);
+            buf.push('\n');
+        }
+        for (&(_, helper), &used) in HELPERS.iter().zip(&self.helpers) {
+            if used {
+                buf.push_str(helper);
+                buf.push('\n');
+            }
+        }
+        writeln!(
+            buf,
+            "int64_t eval(void)\n{{\n    return {};\n}}\n",
+            self.body
+        )
+        .unwrap();
+        buf.push_str("int main(int argc, char **argv)\n{\n");
+        if self.inputs.is_empty() {
+            buf.push_str("    (void)argc;\n    (void)argv;\n");
+        } else {
+            writeln!(
+                buf,
+                "    if (argc != {}) {{\n        fprintf(stderr, \"usage: %s {}\\n\", argv[0]);\n        return 2;\n    }}",
+                self.inputs.len() + 1,
+                self.inputs.join(" ")
+            )
+            .unwrap();
+            for (i, name) in self.inputs.iter().enumerate() {
+                writeln!(buf, "    var_{} = strtoll(argv[{}], NULL, 0);", name, i + 1).unwrap();
+            }
+        }
+        buf.push_str("    printf(\"%\" PRId64 \"\\n\", eval());\n    return 0;\n}\n");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Location;
+
+    fn compile(input: &str) -> Result<String, InterpreterError> {
+        CCompiler::new().compile(&input.parse().unwrap())
+    }
+
+    /// evalの本体
+    fn eval_body(input: &str) -> String {
+        let program = compile(input).unwrap();
+        let start = program.find("int64_t eval(void)\n{\n    return ").unwrap();
+        let body = &program[start + "int64_t eval(void)\n{\n    return ".len()..];
+        body[..body.find(";\n").unwrap()].to_string()
+    }
+
+    #[test]
+    fn test_c() {
+        assert_eq!(
+            compile("1 | 2"),
+            Ok("#include <inttypes.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+int64_t eval(void)
+{
+    return (1 | 2);
+}
+
+int main(int argc, char **argv)
+{
+    (void)argc;
+    (void)argv;
+    printf(\"%\" PRId64 \"\\n\", eval());
+    return 0;
+}
+"
+            .to_string())
+        );
+        assert_eq!(
+            eval_body("1 + 2 * -3"),
+            "checked_add(1, checked_mul(2, checked_neg(3)))"
+        );
+        assert_eq!(eval_body("max(1, 2, 3)"), "max(max(1, 2), 3)");
+        assert_eq!(eval_body("min(x)"), "var_x");
+        assert_eq!(
+            eval_body("y = x = 2 ^ sqrt(abs(y))"),
+            "var_y = var_x = checked_pow(2, checked_sqrt(checked_abs(var_y)))"
+        );
+
+        // 代入より前に読む変数はコマンドライン引数から設定する
+        let program = compile("y = x / y").unwrap();
+        assert!(program.contains("int64_t var_x;\nint64_t var_y;\n"));
+        assert!(program.contains("fprintf(stderr, \"usage: %s x y\\n\", argv[0]);"));
+        assert!(program.contains("var_y = strtoll(argv[2], NULL, 0);"));
+        // 使う補助関数と、その補助関数が呼ぶものだけを出力する
+        assert!(program.contains("static void fail("));
+        assert!(program.contains("static int64_t checked_div("));
+        assert!(!program.contains("checked_add"));
+        let program = compile("abs(2)").unwrap();
+        assert!(program.find("checked_neg(").unwrap() < program.find("checked_abs(").unwrap());
+
+        assert_eq!(
+            compile("9223372036854775808"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::Overflow,
+                Location(0, 19)
+            ))
+        );
+        assert_eq!(
+            compile("f(1)"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::UnknownFunction("f".to_string()),
+                Location(0, 4)
+            ))
+        );
+    }
+}
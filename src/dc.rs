@@ -0,0 +1,221 @@
+//!
+//! Unixのdcコマンドでそのまま実行できるプログラムへの変換。
+//! 多倍長整数で計算する信頼できる逆ポーランド記法の処理系と結果を比べ、
+//! コンパイラの出力を確かめるのに使う。
+//!
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+use super::compiler::{check_call, Backend, CompileError};
+use super::interpreter::*;
+use super::parser::*;
+
+///
+/// dcのプログラムへのコンパイラ。
+/// 変数はdcのレジスタに対応させるので、1文字の名前だけを扱える。
+/// 最後に計算結果を表示する"p"を付ける。
+///
+#[derive(Default)]
+pub struct DcCompiler;
+
+impl DcCompiler {
+    pub fn new() -> Self {
+        DcCompiler
+    }
+
+    ///
+    /// 節点をdcのプログラムへ変換する。
+    /// 長い演算子の連なりでもネイティブのスタックを使い切らないよう、
+    /// 再帰せずに作業のスタックで辿る。作業は書き出す順の逆に積む。
+    ///
+    fn compile_inner(&mut self, expr: &Ast, buf: &mut String) -> Result<(), InterpreterError> {
+        use super::parser::AstKind::*;
+        let mut steps = vec![Step::Node(expr)];
+        while let Some(step) = steps.pop() {
+            let expr = match step {
+                Step::Node(expr) => expr,
+                Step::Text(text) => {
+                    buf.push_str(text);
+                    continue;
+                }
+                Step::Binary(operator) => {
+                    buf.push_str(&operator.to_string());
+                    continue;
+                }
+            };
+            let unsupported = |what: &str| {
+                InterpreterError::new(
+                    InterpreterErrorKind::Unsupported(what.to_string()),
+                    expr.location.clone(),
+                )
+            };
+            match expr.value {
+                Num(n) => buf.push_str(&n.to_string()),
+                Var(ref name) => {
+                    if !is_register(name) {
+                        return Err(unsupported(name));
+                    }
+                    buf.push('l');
+                    buf.push_str(name);
+                }
+                // "s"はスタックから値を取り出すので、複製してから格納する
+                Assign {
+                    ref name,
+                    ref value,
+                } => {
+                    if !is_register(name) {
+                        return Err(unsupported(name));
+                    }
+                    steps.extend([Step::Text(name), Step::Text(" d s"), Step::Node(value)]);
+                }
+                Unary {
+                    ref operator,
+                    ref operand,
+                } => {
+                    // dcでは"_"で負の数を書く
+                    if operator.value == UnaryOperatorKind::Minus {
+                        steps.push(Step::Text(" _1 *"));
+                    }
+                    steps.push(Step::Node(operand));
+                }
+                Binary {
+                    ref operator,
+                    ref left,
+                    ref right,
+                } => {
+                    if operator.value == BinaryOperatorKind::BitOr {
+                        return Err(InterpreterError::new(
+                            InterpreterErrorKind::Unsupported(operator.value.to_string()),
+                            operator.location.clone(),
+                        ));
+                    }
+                    steps.extend([
+                        Step::Binary(&operator.value),
+                        Step::Text(" "),
+                        Step::Node(right),
+                        Step::Text(" "),
+                        Step::Node(left),
+                    ]);
+                }
+                Call { ref name, ref args } => {
+                    check_call(name, args.len(), &expr.location)?;
+                    let word = match name.as_str() {
+                        "sqrt" => "v",
+                        // 2乗の平方根で絶対値を求める
+                        "abs" => "d * v",
+                        "pow" => "^",
+                        _ => return Err(unsupported(name)),
+                    };
+                    steps.push(Step::Text(word));
+                    for arg in args.iter().rev() {
+                        steps.extend([Step::Text(" "), Step::Node(arg)]);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// dcのプログラムへ変換するときの作業
+enum Step<'a> {
+    /// 節点を変換する
+    Node(&'a Ast),
+    /// 文字列をそのまま書き出す
+    Text(&'a str),
+    /// 二項演算子を書き出す
+    Binary(&'a BinaryOperatorKind),
+}
+
+impl Backend for DcCompiler {
+    fn name(&self) -> &str {
+        "dc"
+    }
+
+    /// 抽象構文木をdcのプログラムへ変換し、bufの内容を置き換える
+    fn compile_into(&mut self, expr: &Ast, buf: &mut String) -> Result<(), CompileError> {
+        buf.clear();
+        self.compile_inner(expr, buf)?;
+        buf.push_str(" p");
+        Ok(())
+    }
+}
+
+/// dcのレジスタとして使える名前（英字1文字）かどうかを返す
+fn is_register(name: &str) -> bool {
+    name.len() == 1 && name.as_bytes()[0].is_ascii_alphabetic()
+}
+
+///
+/// dcコマンドにプログラムを渡して実行し、表示された結果を返す。
+/// dcがエラーを報告した場合は、その内容をエラーとして返す。
+///
+pub fn run_dc(program: &str) -> io::Result<String> {
+    let mut child = Command::new("dc")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    {
+        let stdin = child.stdin.as_mut().unwrap();
+        stdin.write_all(program.as_bytes())?;
+        stdin.write_all(b"\n")?;
+    }
+    let output = child.wait_with_output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || !stderr.trim().is_empty() {
+        return Err(io::Error::other(stderr.trim().to_string()));
+    }
+    // dcは長い数を"\"と改行で折り返して表示する
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .replace("\\\n", "")
+        .trim()
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Location;
+
+    fn compile(input: &str) -> Result<String, InterpreterError> {
+        DcCompiler::new().compile(&input.parse().unwrap())
+    }
+
+    #[test]
+    fn test_dc() {
+        assert_eq!(compile("1 + 2 * 3"), Ok("1 2 3 * + p".to_string()));
+        assert_eq!(
+            compile("x = -2 ^ abs(y)"),
+            Ok("2 ly d * v ^ _1 * d sx p".to_string())
+        );
+        assert_eq!(
+            compile("sqrt(16) / pow(2, 2)"),
+            Ok("16 v 2 2 ^ / p".to_string())
+        );
+        assert_eq!(
+            compile("count + 1"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::Unsupported("count".to_string()),
+                Location(0, 5)
+            ))
+        );
+        assert_eq!(
+            compile("1 | 2"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::Unsupported("|".to_string()),
+                Location(2, 3)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_run_dc() {
+        // dcがインストールされていない環境では確かめない
+        match run_dc("1 2 3 * + p") {
+            Ok(answer) => assert_eq!(answer, "7"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//!
+//! 字句解析・構文解析・評価のエラーを共通の形で表す診断情報。
+//! 複数の位置に説明を付けられるので、閉じられていないかっこと入力の終わりのように、
+//! 関係する場所をまとめて示せる。
+//!
+use std::fmt;
+use std::io::{self, Write};
+
+use super::interpreter::*;
+use super::lexer::*;
+use super::parser::*;
+
+/// 診断の重大さ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+///
+/// 診断情報。
+/// labelsは入力中の位置とその説明で、notesは位置を持たない補足（直し方の提案など）。
+/// serdeで直列化すると、fingerprintの値も"fingerprint"として書き出す。
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// エラーの種類ごとに決まった符号（"P0004"など）
+    pub code: String,
+    pub message: String,
+    pub labels: Vec<(Location, String)>,
+    pub notes: Vec<String>,
+    /// 入力の出どころ（ファイル名など）
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub source: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &str, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code: code.to_string(),
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+            source: None,
+        }
+    }
+
+    /// 入力の出どころを設定する
+    pub fn with_source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// 位置とその説明を加える
+    pub fn with_label(mut self, location: Location, message: impl Into<String>) -> Self {
+        self.labels.push((location, message.into()));
+        self
+    }
+
+    /// 補足を加える
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    ///
+    /// 診断を見分けるための識別子（"P0004-1b2c3d4e"など）。
+    /// 符号、位置、入力の出どころから作るので、実行をまたいでも同じ診断には同じ値になる。
+    /// ログの中で繰り返し起きているエラーをまとめるのに使う
+    ///
+    pub fn fingerprint(&self) -> String {
+        // 位置は説明を加えた順によらないよう、並べ替えてから使う
+        let mut spans: Vec<_> = self.labels.iter().map(|(location, _)| location).collect();
+        spans.sort_by_key(|location| (location.0, location.1));
+        let mut key = self.code.clone();
+        for span in spans {
+            key.push_str(&format!("\0{}", span));
+        }
+        key.push_str("\0\0");
+        key.push_str(self.source.as_deref().unwrap_or(""));
+        // 標準ライブラリのハッシュ関数はRustの版によって値が変わりうるので、FNV-1aを使う
+        let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+        format!("{}-{:08x}", self.code, (hash >> 32) as u32 ^ hash as u32)
+    }
+
+    /// 入力に注釈を付けた表示を文字列として返す
+    pub fn render(&self, input: &str) -> String {
+        let mut buf = Vec::new();
+        self.write(input, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    ///
+    /// 入力に注釈を付けてwへ書き出す。
+    /// 同じ行の位置は1つの行に"^"を並べ、右端の説明はその後ろに、
+    /// 残りの説明はその下に右から順に書く。最後に識別子を書く。
+    ///
+    /// ```text
+    /// error[P0004]: '(' is not closed
+    /// 1:5 | 2 * (1 + 3
+    ///     |     ^     ^ expected ')'
+    ///     |     unclosed '('
+    ///     = id: P0004-5f0e1d7a
+    /// ```
+    ///
+    pub fn write(&self, input: &str, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "{}[{}]: {}", self.severity, self.code, self.message)?;
+        let map = SourceMap::new(input);
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        labels.sort_by_key(|(location, _)| location.0);
+        let mut indent = String::new();
+        let mut rest = &labels[..];
+        while let Some((first, _)) = rest.first() {
+            let line = map.line_index(first.0);
+            let count = rest
+                .iter()
+                .take_while(|(location, _)| map.line_index(location.0) == line)
+                .count();
+            let (group, next) = rest.split_at(count);
+            rest = next;
+
+            let (line_no, col) = map.line_col(first.0);
+            let prefix = format!("{}:{} | ", line_no, col);
+            indent = " ".repeat(prefix.len() - 2);
+            writeln!(w, "{}{}", prefix, display_line(map.line(line)))?;
+            write_labels(&map, line, group, &indent, w)?;
+        }
+        for note in &self.notes {
+            writeln!(w, "{}= {}", indent, note)?;
+        }
+        writeln!(w, "{}= id: {}", indent, self.fingerprint())
+    }
+}
+
+/// 識別子も"fingerprint"として書き出す
+#[cfg(feature = "serde")]
+impl serde::Serialize for Diagnostic {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("Diagnostic", 7)?;
+        state.serialize_field("severity", &self.severity)?;
+        state.serialize_field("code", &self.code)?;
+        state.serialize_field("message", &self.message)?;
+        state.serialize_field("labels", &self.labels)?;
+        state.serialize_field("notes", &self.notes)?;
+        state.serialize_field("source", &self.source)?;
+        state.serialize_field("fingerprint", &self.fingerprint())?;
+        state.end()
+    }
+}
+
+/// 1行の中の位置について、"^"の行と説明の行を書く
+fn write_labels(
+    map: &SourceMap,
+    line: usize,
+    labels: &[&(Location, String)],
+    indent: &str,
+    w: &mut impl Write,
+) -> io::Result<()> {
+    let text = map.line(line);
+    let start = map.line_start(line);
+    // 各位置の"^"の行を重ね合わせる。複数行にまたがる位置は行末までを指す
+    let mut carets: Vec<char> = Vec::new();
+    let mut columns = Vec::new();
+    for (location, _) in labels {
+        let end = std::cmp::min(location.1, start + text.chars().count()).max(location.0 + 1);
+        let relative = Location(location.0 - start, end - start);
+        let underline = caret_line(text, &relative);
+        columns.push(underline.len() - underline.trim_start().len());
+        if carets.len() < underline.len() {
+            carets.resize(underline.len(), ' ');
+        }
+        for (i, c) in underline.chars().enumerate() {
+            if c == '^' {
+                carets[i] = '^';
+            }
+        }
+    }
+    let carets: String = carets.into_iter().collect();
+    let (last, others) = labels.split_last().unwrap();
+    if last.1.is_empty() {
+        writeln!(w, "{}| {}", indent, carets)?;
+    } else {
+        writeln!(w, "{}| {} {}", indent, carets, last.1)?;
+    }
+    for (label, column) in others.iter().zip(&columns).rev() {
+        if !label.1.is_empty() {
+            writeln!(w, "{}| {}{}", indent, " ".repeat(*column), label.1)?;
+        }
+    }
+    Ok(())
+}
+
+impl LexError {
+    /// 診断情報へ変換する
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        use self::LexErrorKind::*;
+        let (code, label) = match self.value {
+            InvalidChar(_) => ("L0001", "not part of any token"),
+            NumberTooLarge => ("L0002", "does not fit in a 64-bit integer"),
+            Eof => ("L0003", ""),
+            IdentAfterNumber => ("L0004", "insert an operator or a space"),
+            MissingDigits => ("L0005", "expected digits after the prefix"),
+            InvalidDigit(_) => ("L0006", "not a number in this base"),
+            NotAnInteger => ("L0008", "only integers are supported"),
+            InvalidUtf8 { .. } => ("L0009", "not valid UTF-8"),
+            InputTooLong => ("L0010", "beyond the length limit"),
+            TooManyTokens => ("L0011", "beyond the token limit"),
+        };
+        let diagnostic =
+            Diagnostic::error(code, self.message()).with_label(self.location.clone(), label);
+        match self.value {
+            IdentAfterNumber => diagnostic
+                .with_note("help: write '2 * x' to multiply, or start names with a letter"),
+            _ => diagnostic,
+        }
+    }
+}
+
+impl ParseError {
+    /// 診断情報へ変換する。入力の終わりを指すために、解析した入力を渡す
+    pub fn to_diagnostic(&self, input: &str) -> Diagnostic {
+        use self::ParseError::*;
+        let len = input.chars().count();
+        let end = Location(len, len + 1);
+        let diagnostic = match self {
+            UnexpectedToken(tok) => {
+                Diagnostic::error("P0001", self.message()).with_label(tok.location.clone(), "")
+            }
+            NotExpression(tok) => Diagnostic::error("P0002", self.message())
+                .with_label(tok.location.clone(), "expected an expression"),
+            NotOperator(tok) => Diagnostic::error("P0003", self.message())
+                .with_label(tok.location.clone(), "expected an operator"),
+            UnclosedOpenParen(tok) => Diagnostic::error("P0004", self.message())
+                .with_label(tok.location.clone(), "unclosed '('")
+                .with_label(end, "expected ')'"),
+            // 冗長なトークンがある場合、それ以降のすべてが冗長である
+            RedundantExpression(tok) => Diagnostic::error("P0005", self.message())
+                .with_label(Location(tok.location.0, len), ""),
+            UnmatchedRParen(rparen, closed) => {
+                let diagnostic = Diagnostic::error("P0010", self.message())
+                    .with_label(rparen.location.clone(), "no matching '('");
+                match closed {
+                    Some(open) => {
+                        diagnostic.with_label(open.location.clone(), "this '(' is already closed")
+                    }
+                    None => diagnostic,
+                }
+            }
+            MissingOperand(tok) => {
+                Diagnostic::error("P0006", self.message()).with_label(tok.location.clone(), "")
+            }
+            EmptyParens(open, close) => Diagnostic::error("P0007", self.message())
+                .with_label(open.location.merge(&close.location), ""),
+            InvalidAssignment(tok) => {
+                Diagnostic::error("P0008", self.message()).with_label(tok.location.clone(), "")
+            }
+            TooDeep(tok) => Diagnostic::error("P0011", self.message())
+                .with_label(tok.location.clone(), "nesting limit reached here"),
+            Eof => Diagnostic::error("P0009", self.message()).with_label(end, ""),
+        };
+        match self.suggestion() {
+            Some(suggestion) => diagnostic.with_note(format!("help: {}", suggestion)),
+            None => diagnostic,
+        }
+    }
+}
+
+impl InterpreterError {
+    /// 診断情報へ変換する
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        use self::InterpreterErrorKind::*;
+        let code = match self.value {
+            DivisionByZero => "E0001",
+            UndefinedVariable(_) => "E0002",
+            Overflow => "E0003",
+            NegativeExponent => "E0004",
+            UnknownFunction(_) => "E0005",
+            WrongArgumentCount { .. } => "E0006",
+            InvalidArgument(_) => "E0007",
+            Unsupported(_) => "E0008",
+            Timeout => "E0009",
+            TooManySteps => "E0010",
+        };
+        Diagnostic::error(code, self.to_string()).with_label(self.location.clone(), "")
+    }
+}
+
+impl ApplicationError {
+    /// 診断情報へ変換する。入力の終わりを指すために、処理した入力を渡す
+    pub fn to_diagnostic(&self, input: &str) -> Diagnostic {
+        match self {
+            ApplicationError::Lexer(e) => e.to_diagnostic(),
+            ApplicationError::Parser(e) => e.to_diagnostic(input),
+            ApplicationError::Interpreter(e) => e.to_diagnostic(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(input: &str) -> Diagnostic {
+        let tokens = lex(input).unwrap();
+        parse(&tokens).unwrap_err().to_diagnostic(input)
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            parse_error("2 * (1 + 3").render("2 * (1 + 3"),
+            "error[P0004]: '(' is not closed\n\
+             1:5 | 2 * (1 + 3\n    \
+             |     ^     ^ expected ')'\n    \
+             |     unclosed '('\n    \
+             = id: P0004-3d9f25ab\n"
+        );
+        assert_eq!(
+            parse_error("1 +").render("1 +"),
+            "error[P0006]: expression expected after operator '+'\n\
+             1:3 | 1 +\n    \
+             |   ^\n    \
+             = help: add an expression after '+', e.g. '+ 1'\n    \
+             = id: P0006-11bb0953\n"
+        );
+        // 別の行の位置は、行ごとに分けて示す
+        let diagnostic = Diagnostic::error("X0000", "two lines")
+            .with_label(Location(4, 5), "second")
+            .with_label(Location(0, 1), "first");
+        assert_eq!(
+            diagnostic.render("1 +\n2"),
+            "error[X0000]: two lines\n\
+             1:1 | 1 +\n    \
+             | ^ first\n\
+             2:1 | 2\n    \
+             | ^ second\n    \
+             = id: X0000-5a2b2853\n"
+        );
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        // 同じ入力の同じエラーは、何度解析しても同じ識別子になる
+        let id = parse_error("2 * (1 + 3").fingerprint();
+        assert_eq!(id, "P0004-3d9f25ab");
+        assert_eq!(parse_error("2 * (1 + 3").fingerprint(), id);
+        // 位置や入力の出どころが違えば区別する
+        assert_ne!(parse_error("2 * (1 + 33").fingerprint(), id);
+        let sourced = parse_error("2 * (1 + 3").with_source("a.txt");
+        assert_ne!(sourced.fingerprint(), id);
+        assert_eq!(
+            sourced.fingerprint(),
+            parse_error("2 * (1 + 3").with_source("a.txt").fingerprint()
+        );
+        // 説明を加えた順や説明の文言にはよらない
+        let a = Diagnostic::error("X0000", "a")
+            .with_label(Location(0, 1), "first")
+            .with_label(Location(2, 3), "second");
+        let b = Diagnostic::error("X0000", "b")
+            .with_label(Location(2, 3), "")
+            .with_label(Location(0, 1), "");
+        assert_eq!(a.fingerprint(), b.fingerprint());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize_fingerprint() {
+        let diagnostic = parse_error("2 * (1 + 3");
+        let json = serde_json::to_value(&diagnostic).unwrap();
+        assert_eq!(json["fingerprint"], "P0004-3d9f25ab");
+        let back: Diagnostic = serde_json::from_value(json).unwrap();
+        assert_eq!(back, diagnostic);
+    }
+
+    #[test]
+    fn test_to_diagnostic() {
+        let (_, errors) = lex_all_errors("1 $");
+        let diagnostic = errors[0].to_diagnostic();
+        assert_eq!(diagnostic.code, "L0001");
+        assert_eq!(diagnostic.labels[0].0, Location(2, 3));
+
+        let diagnostic = parse_error("x + 1 2");
+        assert_eq!(diagnostic.code, "P0005");
+        assert_eq!(diagnostic.labels, vec![(Location(6, 7), String::new())]);
+
+        // 余計な閉じかっこは、直前に閉じたかっこの開きかっこもあわせて示す
+        assert_eq!(
+            parse_error("(1 + 2) * 3)").render("(1 + 2) * 3)"),
+            "error[P0010]: ')' has no matching '('\n\
+             1:1 | (1 + 2) * 3)\n    \
+             | ^          ^ no matching '('\n    \
+             | this '(' is already closed\n    \
+             = help: remove the ')', or add a '(' where the group should start\n    \
+             = id: P0010-efab9d05\n"
+        );
+    }
+}
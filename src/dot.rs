@@ -0,0 +1,133 @@
+use std::fmt::Write;
+
+use super::compiler::{Backend, CompileError};
+use super::parser::*;
+use super::tree::node;
+
+///
+/// 抽象構文木をGraphvizのDOT形式へ変換する。
+/// 各節点には演算子や値と、入力中の位置（"開始..終了"）を表示する。
+///
+/// ```text
+/// digraph ast {
+///     n0 [label="+\n0..5"];
+///     n1 [label="1\n0..1"];
+///     n0 -> n1;
+///     ...
+/// }
+/// ```
+///
+#[derive(Default)]
+pub struct DotCompiler {
+    /// 次に割り当てる節点の番号
+    next_id: usize,
+}
+
+impl DotCompiler {
+    pub fn new() -> Self {
+        DotCompiler { next_id: 0 }
+    }
+
+    /// 抽象構文木をDOT形式の文字列へ変換して返す
+    pub fn compile(&mut self, expr: &Ast) -> String {
+        let mut buf = String::new();
+        self.compile_into(expr, &mut buf);
+        buf
+    }
+
+    /// 抽象構文木をDOT形式へ変換し、bufの内容を置き換える
+    pub fn compile_into(&mut self, expr: &Ast, buf: &mut String) {
+        buf.clear();
+        self.next_id = 0;
+        buf.push_str("digraph ast {\n    node [shape=box];\n");
+        self.compile_node(expr, buf);
+        buf.push('}');
+    }
+
+    ///
+    /// 節点とその子孫を出力する。
+    /// 長い演算子の連なりでもネイティブのスタックを使い切らないよう、再帰せずに作業のスタックで辿る。
+    ///
+    fn compile_node(&mut self, expr: &Ast, buf: &mut String) {
+        let mut steps = vec![Step::Node(expr, None)];
+        while let Some(step) = steps.pop() {
+            let (expr, parent) = match step {
+                Step::Node(expr, parent) => (expr, parent),
+                Step::Edge(parent, child) => {
+                    writeln!(buf, "    n{} -> n{};", parent, child).unwrap();
+                    continue;
+                }
+            };
+            let id = self.next_id;
+            self.next_id += 1;
+            let (label, children) = node(expr);
+            writeln!(
+                buf,
+                "    n{} [label=\"{}\\n{}..{}\"];",
+                id, label, expr.location.0, expr.location.1
+            )
+            .unwrap();
+            // 親からの辺は子孫をすべて出力した後に置く
+            if let Some(parent) = parent {
+                steps.push(Step::Edge(parent, id));
+            }
+            // 子は左から順に出力し、辺も同じ順に並べる
+            steps.extend(
+                children
+                    .into_iter()
+                    .rev()
+                    .map(|child| Step::Node(child, Some(id))),
+            );
+        }
+    }
+}
+
+/// DOT形式へ変換するときの作業
+enum Step<'a> {
+    /// 節点とその子孫を出力する。親があれば親の番号を持つ
+    Node(&'a Ast, Option<usize>),
+    /// 親から子への辺を出力する
+    Edge(usize, usize),
+}
+
+impl Backend for DotCompiler {
+    fn name(&self) -> &str {
+        "dot"
+    }
+
+    fn compile_into(&mut self, expr: &Ast, buf: &mut String) -> Result<(), CompileError> {
+        DotCompiler::compile_into(self, expr, buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dot() {
+        let ast = "1 + 2 * 3".parse::<Ast>().unwrap();
+        assert_eq!(
+            DotCompiler::new().compile(&ast),
+            r#"digraph ast {
+    node [shape=box];
+    n0 [label="+\n0..9"];
+    n1 [label="1\n0..1"];
+    n0 -> n1;
+    n2 [label="*\n4..9"];
+    n3 [label="2\n4..5"];
+    n2 -> n3;
+    n4 [label="3\n8..9"];
+    n2 -> n4;
+    n0 -> n2;
+}"#
+        );
+
+        let ast = "x = max(-y)".parse::<Ast>().unwrap();
+        let dot = DotCompiler::new().compile(&ast);
+        assert!(dot.contains(r#"n0 [label="x =\n0..11"];"#), "{}", dot);
+        assert!(dot.contains(r#"n1 [label="max()\n4..11"];"#), "{}", dot);
+        assert!(dot.contains(r#"n2 [label="- (unary)\n8..10"];"#), "{}", dot);
+    }
+}
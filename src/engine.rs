@@ -0,0 +1,1345 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use super::bytecode::*;
+use super::compiler::{Backend, Registry, RpnCompiler, RpnOptions};
+use super::events::{notify_reductions, Event, Observer};
+use super::interpreter::Interpreter;
+use super::lexer::{LexError, Lexer, LiteralReader, Token};
+use super::limits::Limits;
+use super::minify::minify_into;
+use super::optimizer::{ConstantFolder, FoldedFrom};
+use super::parser::*;
+use super::peephole;
+use super::provenance::{explain, format_explanation};
+use super::rpn::{RpnError, RpnEvaluator};
+use super::shunting_yard::{format_steps, ShuntingYard};
+use super::trace::PrecedenceTracer;
+use super::tree::{format_json, format_tree};
+
+/// 入力された式をどのように処理するか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Mode {
+    /// 評価器で値を求める
+    Eval,
+    /// 逆ポーランド記法へ変換する
+    #[default]
+    Rpn,
+    /// 前置記法へ変換する
+    Prefix,
+    /// S式へ変換する
+    Sexpr,
+    /// LaTeXの数式へ変換する
+    Latex,
+    /// バイトコードへコンパイルし、VMで実行する
+    Vm,
+    /// バイトコードへコンパイルし、命令列を入力の位置とともに表示する
+    Disasm,
+    /// バイトコードをのぞき穴最適化の前後で比べて表示する
+    OptDisasm,
+    /// 演算子の結合の様子を図示する
+    PrecedenceTrace,
+    /// 逆ポーランド記法への変換の途中経過を表示する
+    Steps,
+    /// 抽象構文木をGraphvizのDOT形式で出力する
+    Dot,
+    /// Unixのdcコマンドで実行できるプログラムを出力する
+    Dc,
+    /// WebAssemblyのテキスト形式のモジュールを出力する
+    Wat,
+    /// C言語のソースファイルを出力する
+    C,
+    /// x86-64のアセンブリを出力する
+    Asm,
+    /// LLVM IRのテキスト形式のモジュールを出力する
+    LlvmIr,
+    /// 抽象構文木を字下げした木の形で表示する
+    Ast,
+    /// 抽象構文木を位置情報とともにJSONで出力する
+    AstJson,
+    /// 同じ意味の最も短い中置記法の式を出力する
+    Minify,
+    /// 式を評価し、入力の各部分が結果にどれだけ影響したかを示す
+    Why,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "eval" => Ok(Mode::Eval),
+            "rpn" => Ok(Mode::Rpn),
+            "prefix" => Ok(Mode::Prefix),
+            "sexpr" => Ok(Mode::Sexpr),
+            "latex" => Ok(Mode::Latex),
+            "vm" => Ok(Mode::Vm),
+            "disasm" => Ok(Mode::Disasm),
+            "opt-disasm" => Ok(Mode::OptDisasm),
+            "precedence-trace" => Ok(Mode::PrecedenceTrace),
+            "steps" => Ok(Mode::Steps),
+            "dot" => Ok(Mode::Dot),
+            "dc" => Ok(Mode::Dc),
+            "wat" => Ok(Mode::Wat),
+            "c" => Ok(Mode::C),
+            "asm" => Ok(Mode::Asm),
+            "llvm-ir" => Ok(Mode::LlvmIr),
+            "ast" => Ok(Mode::Ast),
+            "ast-json" => Ok(Mode::AstJson),
+            "minify" => Ok(Mode::Minify),
+            "why" => Ok(Mode::Why),
+            _ => Err(format!(
+                "unknown mode '{}' (expected eval, rpn, prefix, sexpr, latex, vm, disasm, opt-disasm, precedence-trace, steps, dot, dc, wat, c, asm, llvm-ir, ast, ast-json, minify or why)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mode::Eval => write!(f, "eval"),
+            Mode::Rpn => write!(f, "rpn"),
+            Mode::Prefix => write!(f, "prefix"),
+            Mode::Sexpr => write!(f, "sexpr"),
+            Mode::Latex => write!(f, "latex"),
+            Mode::Vm => write!(f, "vm"),
+            Mode::Disasm => write!(f, "disasm"),
+            Mode::OptDisasm => write!(f, "opt-disasm"),
+            Mode::PrecedenceTrace => write!(f, "precedence-trace"),
+            Mode::Steps => write!(f, "steps"),
+            Mode::Dot => write!(f, "dot"),
+            Mode::Dc => write!(f, "dc"),
+            Mode::Wat => write!(f, "wat"),
+            Mode::C => write!(f, "c"),
+            Mode::Asm => write!(f, "asm"),
+            Mode::LlvmIr => write!(f, "llvm-ir"),
+            Mode::Ast => write!(f, "ast"),
+            Mode::AstJson => write!(f, "ast-json"),
+            Mode::Minify => write!(f, "minify"),
+            Mode::Why => write!(f, "why"),
+        }
+    }
+}
+
+impl Mode {
+    ///
+    /// 変換先の一覧（Registry）の変換器で処理するモードなら、その変換先の名前を返す。
+    /// 逆ポーランド記法は書式の設定を持つので、処理系が別に持つ変換器で処理する。
+    ///
+    pub fn target(self) -> Option<&'static str> {
+        match self {
+            Mode::Prefix => Some("prefix"),
+            Mode::Sexpr => Some("sexpr"),
+            Mode::Latex => Some("latex"),
+            Mode::Dot => Some("dot"),
+            Mode::Dc => Some("dc"),
+            Mode::Wat => Some("wat"),
+            Mode::C => Some("c"),
+            Mode::Asm => Some("asm"),
+            Mode::LlvmIr => Some("llvm-ir"),
+            _ => None,
+        }
+    }
+}
+
+/// 逆ポーランド記法へ変換する方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Pipeline {
+    /// 構文木を作ってから変換する
+    #[default]
+    Ast,
+    /// 構文木を作らず、トークン列から操車場アルゴリズムで直接変換する
+    ShuntingYard,
+}
+
+impl FromStr for Pipeline {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ast" => Ok(Pipeline::Ast),
+            "shunting-yard" => Ok(Pipeline::ShuntingYard),
+            _ => Err(format!(
+                "unknown pipeline '{}' (expected ast or shunting-yard)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pipeline::Ast => write!(f, "ast"),
+            Pipeline::ShuntingYard => write!(f, "shunting-yard"),
+        }
+    }
+}
+
+/// 1行を処理した結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome<'a> {
+    /// 式を評価した値
+    Value(i64),
+    /// 逆ポーランド記法へ変換した文字列
+    Rpn(&'a str),
+    /// 演算子の結合の図や、変換の途中経過
+    Trace(&'a str),
+    /// 処理に失敗した
+    Error {
+        /// 見つかったエラー。字句解析と構文解析では1行中のエラーをすべて報告する
+        errors: Vec<ApplicationError>,
+        /// 先頭から解析できた部分があれば、その処理結果
+        prefix: Option<Box<Outcome<'a>>>,
+    },
+}
+
+/// 1行の処理の各段階にかかった時間
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Timings {
+    pub lex: Duration,
+    pub parse: Duration,
+    /// 評価や変換など、構文解析より後の処理
+    pub run: Duration,
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "lex {:?}, parse {:?}, run {:?}",
+            self.lex, self.parse, self.run
+        )
+    }
+}
+
+///
+/// 1行を処理した結果と、その途中で作ったものすべて。
+/// 表示する側はこれを受け取るだけでよいので、REPL以外の画面やテストからも
+/// 同じ処理の流れを使い、途中の段階を調べられる。
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report<'a> {
+    /// 字句解析したトークン。無効な文字は読み飛ばしている
+    pub tokens: Vec<Token>,
+    /// 構文解析した抽象構文木。最適化した場合は畳み込んだ後のもの
+    pub ast: Option<Ast>,
+    pub outcome: Outcome<'a>,
+    pub timings: Timings,
+}
+
+impl Report<'_> {
+    /// 式を評価した値
+    pub fn value(&self) -> Option<i64> {
+        match self.outcome {
+            Outcome::Value(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// 逆ポーランド記法やdcのプログラムへ変換した文字列
+    pub fn rpn(&self) -> Option<&str> {
+        match self.outcome {
+            Outcome::Rpn(rpn) => Some(rpn),
+            _ => None,
+        }
+    }
+
+    /// 見つかったエラー
+    pub fn diagnostics(&self) -> &[ApplicationError] {
+        match self.outcome {
+            Outcome::Error { ref errors, .. } => errors,
+            _ => &[],
+        }
+    }
+}
+
+/// reportで記録する途中の段階と、出来事を通知する相手
+#[derive(Default)]
+struct Stages<'o> {
+    tokens: Vec<Token>,
+    ast: Option<Ast>,
+    timings: Timings,
+    observer: Option<&'o mut dyn Observer>,
+}
+
+///
+/// 字句解析から評価・変換までを行う処理系。
+/// トークンや出力文字列、命令列の領域を使い回すので、
+/// 大量の行を処理する場合でも行ごとのメモリ確保が少なくなる。
+///
+#[derive(Default)]
+pub struct Engine {
+    mode: Mode,
+    pipeline: Pipeline,
+    lexer: Lexer,
+    interpreter: Interpreter,
+    compiler: RpnCompiler,
+    tracer: PrecedenceTracer,
+    /// 前置記法やDOT形式、dcなどのプログラムへの変換器
+    backends: Registry,
+    folder: ConstantFolder,
+    shunting_yard: ShuntingYard,
+    rpn: RpnEvaluator,
+    bytecode_compiler: BytecodeCompiler,
+    vm: Vm,
+    code: Vec<Instruction>,
+    output: String,
+    /// 1行の処理にかけられる時間
+    timeout: Option<Duration>,
+    /// 1行の大きさと処理の量の上限
+    limits: Limits,
+    /// checkpointで記録した変数の値。最後の要素が最新
+    checkpoints: Vec<Checkpoint>,
+    /// 行の文字列から、その行を構文解析した抽象構文木への対応（有効な場合だけ）
+    ast_cache: Option<HashMap<String, Ast>>,
+}
+
+/// ある時点の変数の値。評価器・VM・逆ポーランド記法の評価器はそれぞれ変数を持つ
+#[derive(Debug, Clone)]
+struct Checkpoint {
+    interpreter: HashMap<String, i64>,
+    vm: HashMap<String, i64>,
+    rpn: HashMap<String, i64>,
+}
+
+impl Engine {
+    pub fn new(mode: Mode) -> Self {
+        Engine {
+            mode,
+            ..Engine::default()
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    pub fn rpn_options(&self) -> &RpnOptions {
+        self.compiler.options()
+    }
+
+    /// 逆ポーランド記法の出力の書式を変更する。:rpn-evalもこの書式で読む
+    pub fn set_rpn_options(&mut self, options: RpnOptions) {
+        self.compiler = RpnCompiler::with_options(options.clone());
+        self.shunting_yard = ShuntingYard::with_options(options.clone());
+        self.rpn.set_options(options);
+    }
+
+    pub fn pipeline(&self) -> Pipeline {
+        self.pipeline
+    }
+
+    pub fn set_pipeline(&mut self, pipeline: Pipeline) {
+        self.pipeline = pipeline;
+    }
+
+    ///
+    /// 変換器を登録する。同じ名前の組み込みの変換器（"c"など）は置き換わり、
+    /// 対応するモードで使われる。
+    ///
+    pub fn register_backend(&mut self, backend: Box<dyn Backend>) {
+        self.backends.register(backend);
+    }
+
+    /// 独自のリテラルを読む関数を字句解析器に登録する
+    pub fn register_literal(&mut self, reader: LiteralReader) {
+        self.lexer.register_literal(reader);
+    }
+
+    ///
+    /// 構文解析の結果を行の文字列ごとに覚えるようにする。entriesは以前に覚えた分である。
+    /// 覚えている行は字句解析と構文解析を省いて処理する
+    ///
+    pub fn enable_ast_cache(&mut self, entries: HashMap<String, Ast>) {
+        self.ast_cache = Some(entries);
+    }
+
+    /// 覚えた構文解析の結果を取り出し、覚えるのをやめる
+    pub fn take_ast_cache(&mut self) -> Option<HashMap<String, Ast>> {
+        self.ast_cache.take()
+    }
+
+    ///
+    /// キャッシュから行の抽象構文木を探す。
+    /// トークンの列を使う処理や、トークンを通知する場合はキャッシュを使わない
+    ///
+    fn cached_ast(
+        &self,
+        mode: Mode,
+        optimize: bool,
+        line: &str,
+        stages: Option<&Stages>,
+    ) -> Option<Ast> {
+        let uses_tokens = mode == Mode::Steps
+            || (mode == Mode::Rpn && self.pipeline == Pipeline::ShuntingYard && !optimize)
+            || stages.is_some_and(|stages| stages.observer.is_some());
+        if uses_tokens {
+            return None;
+        }
+        self.ast_cache.as_ref()?.get(line).cloned()
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    ///
+    /// 1行の処理にかけられる時間を設定する。
+    /// 行の処理を始めてからこの時間が過ぎると、評価を打ち切ってTimeoutのエラーにする
+    ///
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    ///
+    /// 1行の入力の大きさと処理の量の上限を設定する。
+    /// 上限を超えた行は、字句解析、構文解析、評価のそれぞれのエラーにする
+    ///
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// 現在の変数の値を記録し、記録の数を返す
+    pub fn checkpoint(&mut self) -> usize {
+        self.checkpoints.push(Checkpoint {
+            interpreter: self.interpreter.variables().clone(),
+            vm: self.vm.variables().clone(),
+            rpn: self.rpn.variables().clone(),
+        });
+        self.checkpoints.len()
+    }
+
+    ///
+    /// 最後に記録した時点まで変数の値を戻し、その記録を捨てる。
+    /// 戻した記録の番号（1から数える）を返し、記録がなければNoneを返す
+    ///
+    pub fn rollback(&mut self) -> Option<usize> {
+        let checkpoint = self.checkpoints.pop()?;
+        self.interpreter.set_variables(checkpoint.interpreter);
+        self.vm.set_variables(checkpoint.vm);
+        self.rpn.set_variables(checkpoint.rpn);
+        Some(self.checkpoints.len() + 1)
+    }
+
+    /// 1行分の式を処理する
+    pub fn run(&mut self, line: &str) -> Outcome<'_> {
+        self.run_as(self.mode, false, line)
+    }
+
+    ///
+    /// 1行分の式をrunと同じように処理し、途中で作ったトークンや構文木、
+    /// 各段階にかかった時間もあわせて返す
+    ///
+    pub fn report(&mut self, line: &str) -> Report<'_> {
+        self.report_with(line, None)
+    }
+
+    ///
+    /// reportと同じように処理し、その途中の出来事をobserverへ通知する。
+    /// エラーは処理を終えてから、見つけた順に通知する
+    ///
+    pub fn observe(&mut self, line: &str, observer: &mut dyn Observer) -> Report<'_> {
+        self.report_with(line, Some(observer))
+    }
+
+    fn report_with(&mut self, line: &str, observer: Option<&mut dyn Observer>) -> Report<'_> {
+        let start = Instant::now();
+        let mut stages = Stages {
+            observer,
+            ..Stages::default()
+        };
+        let outcome = self.run_staged(self.mode, false, line, Some(&mut stages));
+        let Stages {
+            tokens,
+            ast,
+            mut timings,
+            observer,
+        } = stages;
+        if let (Outcome::Error { errors, .. }, Some(observer)) = (&outcome, observer) {
+            for error in errors {
+                observer.on_event(Event::DiagnosticEmitted(error));
+            }
+        }
+        timings.run = start.elapsed().saturating_sub(timings.lex + timings.parse);
+        Report {
+            tokens,
+            ast,
+            outcome,
+            timings,
+        }
+    }
+
+    /// 現在のモードに関わらず、指定したモードで式を処理する
+    pub fn run_in(&mut self, mode: Mode, line: &str) -> Outcome<'_> {
+        self.run_as(mode, false, line)
+    }
+
+    /// 現在のモードに関わらず、式の抽象構文木をDOT形式で出力する
+    pub fn dot(&mut self, line: &str) -> Outcome<'_> {
+        self.run_in(Mode::Dot, line)
+    }
+
+    /// 現在のモードに関わらず、式をバイトコードへコンパイルして命令列を表示する
+    pub fn disasm(&mut self, line: &str) -> Outcome<'_> {
+        self.run_in(Mode::Disasm, line)
+    }
+
+    /// 現在のモードに関わらず、のぞき穴最適化の前後の命令列を表示する
+    pub fn opt_disasm(&mut self, line: &str) -> Outcome<'_> {
+        self.run_in(Mode::OptDisasm, line)
+    }
+
+    /// 直前にバイトコードへコンパイルした命令列
+    pub fn code(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    /// 字句解析したトークンを、1行に1つずつ位置とともに表示する
+    pub fn tokens(&mut self, line: &str) -> Outcome<'_> {
+        let (tokens, errors) = self.lexer.lex_all_errors(line);
+        if !errors.is_empty() {
+            return Outcome::Error {
+                errors: errors.into_iter().map(Into::into).collect(),
+                prefix: None,
+            };
+        }
+        self.output.clear();
+        for tok in tokens {
+            if !self.output.is_empty() {
+                self.output.push('\n');
+            }
+            write!(
+                self.output,
+                "{:<8}{:?}",
+                tok.location.to_string(),
+                tok.value
+            )
+            .unwrap();
+        }
+        Outcome::Trace(&self.output)
+    }
+
+    /// 定数の部分木を畳み込んでから、現在のモードで式を処理する
+    pub fn optimize(&mut self, line: &str) -> Outcome<'_> {
+        self.run_as(self.mode, true, line)
+    }
+
+    /// 直前にoptimizeで処理した式について、畳み込んだリテラルとその元の部分木を返す
+    pub fn folded(&self) -> &[FoldedFrom] {
+        self.folder.folded()
+    }
+
+    fn run_as(&mut self, mode: Mode, optimize: bool, line: &str) -> Outcome<'_> {
+        self.run_staged(mode, optimize, line, None)
+    }
+
+    /// 式を処理する。stagesがあれば、途中で作ったものとかかった時間を記録する
+    fn run_staged(
+        &mut self,
+        mode: Mode,
+        optimize: bool,
+        line: &str,
+        mut stages: Option<&mut Stages>,
+    ) -> Outcome<'_> {
+        // wasm32-unknown-unknownではInstant::nowが使えないので、時間を計るときだけ時刻を読む
+        let start = (self.timeout.is_some() || stages.is_some()).then(Instant::now);
+        let deadline = start
+            .zip(self.timeout)
+            .map(|(start, timeout)| start + timeout);
+        self.interpreter.set_deadline(deadline);
+        self.vm.set_deadline(deadline);
+        self.interpreter
+            .set_max_steps(Some(self.limits.max_eval_steps));
+        self.vm.set_max_steps(Some(self.limits.max_eval_steps));
+        if let Err(e) = self.limits.check_input(line) {
+            return Outcome::Error {
+                errors: vec![e.into()],
+                prefix: None,
+            };
+        }
+        // キャッシュに構文木があれば、字句解析と構文解析を省く
+        let cached = self.cached_ast(mode, optimize, line, stages.as_deref());
+        let ast = match cached {
+            Some(ast) => ast,
+            None => {
+                // 字句解析（無効な文字を読み飛ばし、構文のエラーもあわせて報告する）
+                let (tokens, lex_errors) = self.lexer.lex_all_errors(line);
+                if let Err(e) = self.limits.check_tokens(tokens) {
+                    return Outcome::Error {
+                        errors: vec![e.into()],
+                        prefix: None,
+                    };
+                }
+                if let Some(stages) = stages.as_mut() {
+                    stages.timings.lex = start.map(|start| start.elapsed()).unwrap_or_default();
+                    stages.tokens = tokens.to_vec();
+                    if let Some(observer) = stages.observer.as_mut() {
+                        for token in tokens {
+                            observer.on_event(Event::TokenProduced(token));
+                        }
+                    }
+                }
+                if mode == Mode::Rpn && self.pipeline == Pipeline::ShuntingYard && !optimize {
+                    let mut errors: Vec<ApplicationError> =
+                        lex_errors.into_iter().map(Into::into).collect();
+                    if errors.is_empty() {
+                        match self.shunting_yard.compile_into(tokens, &mut self.output) {
+                            Ok(()) => return Outcome::Rpn(&self.output),
+                            Err(e) => errors.push(e.into()),
+                        }
+                    }
+                    return Outcome::Error {
+                        errors,
+                        prefix: None,
+                    };
+                }
+                let start = stages.is_some().then(Instant::now);
+                let parsed = parse_with_recovery_and_max_depth(tokens, self.limits.max_ast_depth)
+                    .map_err(|mut partial| {
+                        // 字句解析のエラーから続いて起きた構文エラーは報告しない
+                        if !lex_errors.is_empty() {
+                            let len = line.chars().count();
+                            partial
+                                .errors
+                                .retain(|e| !is_follow_on(e, tokens, &lex_errors, len));
+                        }
+                        partial
+                    });
+                if let Some(stages) = stages.as_mut() {
+                    stages.timings.parse = start.map(|start| start.elapsed()).unwrap_or_default();
+                }
+                let mut errors: Vec<ApplicationError> =
+                    lex_errors.into_iter().map(Into::into).collect();
+
+                // 構文解析
+                let ast = match parsed {
+                    Ok(ast) if errors.is_empty() => ast,
+                    Ok(_) => {
+                        return Outcome::Error {
+                            errors,
+                            prefix: None,
+                        }
+                    }
+                    Err(partial) => {
+                        let PartialParse {
+                            ast,
+                            errors: parse_errors,
+                        } = *partial;
+                        // 字句解析に失敗した行では、途中までの式も信頼できない
+                        let prefix = match ast {
+                            Some(ast) if errors.is_empty() => self.run_prefix(mode, &ast),
+                            _ => None,
+                        };
+                        errors.extend(parse_errors.into_iter().map(Into::into));
+                        return Outcome::Error {
+                            errors,
+                            prefix: prefix.map(Box::new),
+                        };
+                    }
+                };
+                if let Some(cache) = self.ast_cache.as_mut() {
+                    cache.insert(line.to_string(), ast.clone());
+                }
+                ast
+            }
+        };
+
+        let ast = if optimize {
+            self.folder.fold_constants(&ast)
+        } else {
+            ast
+        };
+        if let Some(stages) = stages.as_mut() {
+            stages.ast = Some(ast.clone());
+            if let Some(observer) = stages.observer.as_mut() {
+                notify_reductions(&ast, *observer);
+            }
+        }
+        let mut observer = stages.and_then(|stages| stages.observer.as_mut());
+        let result = match mode {
+            Mode::Eval => match observer {
+                Some(ref mut observer) => self.interpreter.eval_with(&ast, &mut |node, value| {
+                    observer.on_event(Event::ValueComputed { node, value })
+                }),
+                None => self.interpreter.eval(&ast),
+            }
+            .map_err(Into::into),
+            Mode::Vm => self
+                .bytecode_compiler
+                .compile_into(&ast, &mut self.code)
+                .and_then(|_| {
+                    peephole::optimize(&mut self.code);
+                    self.vm.run(&self.code)
+                })
+                .map_err(Into::into),
+            Mode::Disasm => match self.bytecode_compiler.compile_into(&ast, &mut self.code) {
+                Ok(()) => {
+                    disassemble(&self.code, line, &mut self.output);
+                    return Outcome::Trace(&self.output);
+                }
+                Err(e) => Err(e.into()),
+            },
+            Mode::OptDisasm => match self.bytecode_compiler.compile_into(&ast, &mut self.code) {
+                Ok(()) => {
+                    peephole::format_optimized(&self.code, line, &mut self.output);
+                    return Outcome::Trace(&self.output);
+                }
+                Err(e) => Err(e.into()),
+            },
+            Mode::Rpn => {
+                self.compiler.compile_into(&ast, &mut self.output);
+                return Outcome::Rpn(&self.output);
+            }
+            Mode::PrecedenceTrace => {
+                self.tracer.trace_into(line, &ast, &mut self.output);
+                return Outcome::Trace(&self.output);
+            }
+            Mode::Ast => {
+                format_tree(&ast, &mut self.output);
+                return Outcome::Trace(&self.output);
+            }
+            Mode::AstJson => {
+                format_json(&ast, &mut self.output);
+                return Outcome::Trace(&self.output);
+            }
+            Mode::Minify => {
+                minify_into(&ast, &mut self.output);
+                return Outcome::Rpn(&self.output);
+            }
+            Mode::Why => match explain(&mut self.interpreter, &ast) {
+                Ok(explanation) => {
+                    format_explanation(line, &explanation, &mut self.output);
+                    return Outcome::Trace(&self.output);
+                }
+                Err(e) => Err(e.into()),
+            },
+            Mode::Prefix
+            | Mode::Sexpr
+            | Mode::Latex
+            | Mode::Dot
+            | Mode::Dc
+            | Mode::Wat
+            | Mode::C
+            | Mode::Asm
+            | Mode::LlvmIr => {
+                // 組み込みの変換器は置き換えられても取り除かれないので、必ず見つかる
+                let backend = self.backends.get_mut(mode.target().unwrap()).unwrap();
+                match backend.compile_into(&ast, &mut self.output) {
+                    // 1行で表す記法は、逆ポーランド記法と同じ形で返す
+                    Ok(())
+                        if matches!(mode, Mode::Prefix | Mode::Sexpr | Mode::Latex | Mode::Dc) =>
+                    {
+                        return Outcome::Rpn(&self.output)
+                    }
+                    Ok(()) => return Outcome::Trace(&self.output),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Mode::Steps => match self.shunting_yard.convert(self.lexer.tokens()) {
+                Ok(steps) => {
+                    format_steps(&steps, &mut self.output);
+                    return Outcome::Trace(&self.output);
+                }
+                Err(e) => Err(e.into()),
+            },
+        };
+        match result {
+            Ok(n) => {
+                // 評価器以外では、式全体の値だけを通知する
+                if let (Some(observer), false) = (observer, mode == Mode::Eval) {
+                    observer.on_event(Event::ValueComputed {
+                        node: &ast,
+                        value: n,
+                    });
+                }
+                Outcome::Value(n)
+            }
+            Err(e) => Outcome::Error {
+                errors: vec![e],
+                prefix: None,
+            },
+        }
+    }
+
+    /// 逆ポーランド記法の文字列を実行する。変数は式の評価とは別に保持する
+    pub fn rpn_eval(&mut self, rpn: &str) -> Result<i64, RpnError> {
+        self.rpn.eval(rpn)
+    }
+
+    /// 途中まで解析できた式を、変数を書き換えないよう複製した状態で処理する
+    fn run_prefix(&mut self, mode: Mode, ast: &Ast) -> Option<Outcome<'_>> {
+        match mode {
+            Mode::Eval => self.interpreter.clone().eval(ast).ok().map(Outcome::Value),
+            Mode::Vm => {
+                self.bytecode_compiler
+                    .compile_into(ast, &mut self.code)
+                    .ok()?;
+                peephole::optimize(&mut self.code);
+                self.vm.clone().run(&self.code).ok().map(Outcome::Value)
+            }
+            Mode::Rpn => {
+                self.compiler.compile_into(ast, &mut self.output);
+                Some(Outcome::Rpn(&self.output))
+            }
+            Mode::Minify => {
+                minify_into(ast, &mut self.output);
+                Some(Outcome::Rpn(&self.output))
+            }
+            Mode::Prefix | Mode::Sexpr | Mode::Latex => {
+                self.backends
+                    .get_mut(mode.target()?)?
+                    .compile_into(ast, &mut self.output)
+                    .ok()?;
+                Some(Outcome::Rpn(&self.output))
+            }
+            // 図は入力全体に対して描くので、途中までの式では描かない
+            Mode::PrecedenceTrace
+            | Mode::Steps
+            | Mode::Dot
+            | Mode::Dc
+            | Mode::Wat
+            | Mode::C
+            | Mode::Asm
+            | Mode::LlvmIr
+            | Mode::Disasm
+            | Mode::OptDisasm
+            | Mode::Ast
+            | Mode::AstJson
+            | Mode::Why => None,
+        }
+    }
+}
+
+///
+/// 字句解析で読み飛ばした入力のせいで起きた構文エラーかどうかを返す。
+/// エラーのトークンとその直前のトークンの間（入力の終わりに達したなら、最後のトークンの後ろ）に
+/// 字句解析のエラーがあれば、読み飛ばした部分が式や演算子のつもりだったとみなす。
+///
+fn is_follow_on(error: &ParseError, tokens: &[Token], lex_errors: &[LexError], len: usize) -> bool {
+    use self::ParseError::*;
+    let end_of = |tok: &Token| tok.location.1;
+    let (start, end) = match error {
+        Eof => (tokens.last().map_or(0, end_of), len),
+        MissingOperand(op) => (end_of(op), len),
+        EmptyParens(open, close) => (end_of(open), close.location.0),
+        UnexpectedToken(tok)
+        | NotExpression(tok)
+        | NotOperator(tok)
+        | RedundantExpression(tok)
+        | UnmatchedRParen(tok, _) => {
+            let before = tokens
+                .iter()
+                .take_while(|t| t.location.0 < tok.location.0)
+                .last();
+            (before.map_or(0, end_of), tok.location.0)
+        }
+        // かっこの閉じ忘れなどは、読み飛ばした入力がなくても起きる
+        UnclosedOpenParen(_) | InvalidAssignment(_) | TooDeep(_) => return false,
+    };
+    lex_errors
+        .iter()
+        .any(|e| start <= e.location.0 && e.location.0 < end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompileError;
+    use crate::interpreter::InterpreterErrorKind;
+    use crate::lexer::{Annotation, LexErrorKind, Location};
+
+    #[test]
+    fn test_engine_modes() {
+        let mut engine = Engine::new(Mode::Rpn);
+        assert_eq!(engine.run("1 + 2 * 3"), Outcome::Rpn("1 2 3 * +"));
+        assert_eq!(engine.run("pow(2, 10)"), Outcome::Rpn("2 10 pow"));
+        assert_eq!(engine.run("max(1, 2, 3)"), Outcome::Rpn("1 2 max 3 max"));
+        assert_eq!(engine.optimize("x * (1 + 2 * 3)"), Outcome::Rpn("x 7 *"));
+        assert_eq!(engine.folded()[0].location, Location(5, 14));
+        engine.set_rpn_options(RpnOptions {
+            separator: ",".to_string(),
+            ..RpnOptions::default()
+        });
+        assert_eq!(engine.run("-1 / 2"), Outcome::Rpn("1,neg,2,/"));
+        assert_eq!(engine.rpn_eval("1,neg,2,/"), Ok(0));
+        engine.set_rpn_options(RpnOptions::default());
+        engine.set_pipeline("shunting-yard".parse().unwrap());
+        assert_eq!(engine.run("max(1, 2, 3)"), Outcome::Rpn("1 2 max 3 max"));
+        assert!(matches!(
+            engine.run("1 +"),
+            Outcome::Error { prefix: None, .. }
+        ));
+        engine.set_pipeline(Pipeline::Ast);
+        assert_eq!(
+            engine.tokens("x = 12"),
+            Outcome::Trace("0-1     Ident(\"x\")\n2-3     Equal\n4-6     Number(12)")
+        );
+        assert_eq!(
+            engine.run_in(Mode::Ast, "-1"),
+            Outcome::Trace("- (unary) 0-2\n└── 1 1-2")
+        );
+        assert_eq!(
+            engine.disasm("-x"),
+            Outcome::Trace("   0  load x          1-2     x\n   1  neg             0-1     -")
+        );
+        assert_eq!(
+            engine.opt_disasm("-2"),
+            Outcome::Trace(
+                "before:\n   0  push 2          1-2     2\n   1  neg             0-1     -\nafter:\n   0  push -2         0-2     -2"
+            )
+        );
+        assert_eq!(
+            engine.run_in(Mode::AstJson, "x"),
+            Outcome::Trace(r#"{"kind":"var","span":[0,1],"name":"x"}"#)
+        );
+        engine.set_mode(Mode::Dc);
+        assert_eq!(engine.run("-x"), Outcome::Rpn("lx _1 * p"));
+        engine.set_mode("c".parse().unwrap());
+        assert!(matches!(
+            engine.run("x / 0"),
+            Outcome::Trace(program) if program.contains("return checked_div(var_x, 0);")
+        ));
+        engine.set_mode("asm".parse().unwrap());
+        assert!(matches!(
+            engine.run("2 * 3"),
+            Outcome::Trace(program) if program.contains("    imul rax, rcx\n    push rax\n    pop rax\n    ret\n")
+        ));
+        engine.set_mode("prefix".parse().unwrap());
+        assert_eq!(engine.run("-(1 + 2) * x"), Outcome::Rpn("* neg + 1 2 x"));
+        engine.set_mode("sexpr".parse().unwrap());
+        assert_eq!(
+            engine.run("x = 1 / 2"),
+            Outcome::Rpn("(assign x (div 1 2))")
+        );
+        engine.set_mode("latex".parse().unwrap());
+        assert_eq!(engine.run("x = 1 / 2"), Outcome::Rpn(r"x = \frac{1}{2}"));
+        engine.set_mode("llvm-ir".parse().unwrap());
+        assert_eq!(
+            engine.run("1 | 2"),
+            Outcome::Trace("define i64 @expr() {\nentry:\n  %t1 = or i64 1, 2\n  ret i64 %t1\n}\n")
+        );
+        engine.set_mode("wat".parse().unwrap());
+        assert_eq!(
+            engine.run("x | 1"),
+            Outcome::Trace(
+                "(module\n  (func $main (export \"main\") (param $x i64) (result i64)\n    local.get $x\n    i64.const 1\n    i64.or\n  )\n)"
+            )
+        );
+        engine.set_mode("minify".parse().unwrap());
+        assert_eq!(engine.run("( x * 1000 ) - -y"), Outcome::Rpn("x*1e3--y"));
+        engine.set_mode("why".parse().unwrap());
+        assert_eq!(
+            engine.run("max(2, 3)"),
+            Outcome::Trace(
+                "max(2, 3) = 3\nspan  text  value  effect of +1\n4-5   2     2      not used\n7-8   3     3      +1"
+            )
+        );
+        engine.set_mode("precedence-trace".parse().unwrap());
+        assert_eq!(engine.run("1*2"), Outcome::Trace("1*2\n[-] * (depth 1)"));
+        engine.set_mode(Mode::Steps);
+        assert_eq!(
+            engine.run("-1"),
+            Outcome::Trace(
+                "token  output  stack\n-              neg\n1      1       neg\n(end)  1 neg"
+            )
+        );
+        assert_eq!(
+            engine.dot("1"),
+            Outcome::Trace("digraph ast {\n    node [shape=box];\n    n0 [label=\"1\\n0..1\"];\n}")
+        );
+        assert_eq!(engine.mode(), Mode::Steps);
+        engine.set_mode(Mode::Eval);
+        assert_eq!(engine.run("x = 1 + 2 * 3"), Outcome::Value(7));
+        assert_eq!(engine.run("x * 2"), Outcome::Value(14));
+        match engine.run("x + 1 )") {
+            Outcome::Error {
+                errors,
+                prefix: Some(prefix),
+            } => {
+                assert!(matches!(errors[..], [ApplicationError::Parser(_)]));
+                assert_eq!(*prefix, Outcome::Value(8))
+            }
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let mut engine = Engine::new(Mode::Eval);
+        let report = engine.report("x = 2 * 3");
+        assert_eq!(report.tokens.len(), 5);
+        assert_eq!(report.ast, Some("x = 2 * 3".parse().unwrap()));
+        assert_eq!(report.value(), Some(6));
+        assert_eq!(report.rpn(), None);
+        assert!(report.diagnostics().is_empty());
+
+        engine.set_mode(Mode::Rpn);
+        let report = engine.report("x + 1 )");
+        assert_eq!(report.tokens.len(), 4);
+        assert_eq!(report.ast, None);
+        assert!(matches!(
+            report.diagnostics(),
+            [ApplicationError::Parser(_)]
+        ));
+        assert!(matches!(
+            report.outcome,
+            Outcome::Error {
+                prefix: Some(_),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_observe() {
+        let mut events = Vec::new();
+        let mut engine = Engine::new(Mode::Eval);
+        let report = engine.observe("-2 * 3", &mut |event: Event| {
+            events.push(match event {
+                Event::TokenProduced(token) => format!("token {:?}", token.value),
+                Event::NodeReduced(node) => format!("node {}", node.location),
+                Event::ValueComputed { node, value } => {
+                    format!("value {} {}", node.location, value)
+                }
+                Event::DiagnosticEmitted(e) => format!("error {}", e),
+            })
+        });
+        assert_eq!(report.outcome, Outcome::Value(-6));
+        assert_eq!(
+            events,
+            vec![
+                "token Minus",
+                "token Number(2)",
+                "token Asterisk",
+                "token Number(3)",
+                "node 1-2",
+                "node 0-2",
+                "node 5-6",
+                "node 0-6",
+                "value 1-2 2",
+                "value 0-2 -2",
+                "value 5-6 3",
+                "value 0-6 -6",
+            ]
+        );
+
+        let mut errors = 0;
+        engine.set_mode(Mode::Vm);
+        engine.observe("1 / 0", &mut |event: Event| {
+            if let Event::DiagnosticEmitted(_) = event {
+                errors += 1
+            }
+        });
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let mut engine = Engine::new(Mode::Eval);
+        assert_eq!(engine.rollback(), None);
+        engine.run("x = 1");
+        assert_eq!(engine.checkpoint(), 1);
+        engine.run("x = 2");
+        engine.run_in(Mode::Vm, "y = 3");
+        engine.rpn_eval("4 =z").unwrap();
+        assert_eq!(engine.checkpoint(), 2);
+        engine.run("x = 5");
+        assert_eq!(engine.rollback(), Some(2));
+        assert_eq!(engine.run("x"), Outcome::Value(2));
+        assert_eq!(engine.rollback(), Some(1));
+        assert_eq!(engine.run("x"), Outcome::Value(1));
+        assert!(matches!(
+            engine.run_in(Mode::Vm, "y"),
+            Outcome::Error { .. }
+        ));
+        assert!(engine.rpn_eval("z").is_err());
+        assert_eq!(engine.rollback(), None);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut engine = Engine::new(Mode::Eval);
+        engine.set_timeout(Some(Duration::ZERO));
+        for mode in [Mode::Eval, Mode::Vm] {
+            match engine.run_in(mode, "x = 1 + 2") {
+                Outcome::Error { errors, .. } => assert!(
+                    matches!(
+                        errors[..],
+                        [ApplicationError::Interpreter(Annotation {
+                            value: InterpreterErrorKind::Timeout,
+                            ..
+                        })]
+                    ),
+                    "{:?}",
+                    errors
+                ),
+                outcome => panic!("unexpected outcome: {:?}", outcome),
+            }
+        }
+        // 打ち切った式の代入は行われない
+        engine.set_timeout(None);
+        assert!(matches!(engine.run("x"), Outcome::Error { .. }));
+        // 評価を伴わないモードには影響しない
+        engine.set_timeout(Some(Duration::ZERO));
+        assert_eq!(engine.run_in(Mode::Rpn, "1 + 2"), Outcome::Rpn("1 2 +"));
+        engine.set_timeout(Some(Duration::from_secs(60)));
+        assert_eq!(engine.run("1 + 2"), Outcome::Value(3));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut engine = Engine::new(Mode::Eval);
+        engine.set_limits(Limits {
+            max_input_len: 12,
+            max_tokens: 7,
+            max_ast_depth: 4,
+            max_eval_steps: 5,
+        });
+        let mut errors = |mode, line| match engine.run_in(mode, line) {
+            Outcome::Error { errors, .. } => errors,
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        };
+        assert!(matches!(
+            errors(Mode::Rpn, "1234567890123")[..],
+            [ApplicationError::Lexer(Annotation {
+                value: LexErrorKind::InputTooLong,
+                ..
+            })]
+        ));
+        assert!(matches!(
+            errors(Mode::Rpn, "1+1+1+1+1")[..],
+            [ApplicationError::Lexer(Annotation {
+                value: LexErrorKind::TooManyTokens,
+                ..
+            })]
+        ));
+        assert!(matches!(
+            errors(Mode::Rpn, "----1")[..],
+            [ApplicationError::Parser(ParseError::TooDeep(_))]
+        ));
+        // 変数の代入は2歩で、上限に収まる
+        assert_eq!(engine.run("x = 1"), Outcome::Value(1));
+        assert_eq!(engine.run_in(Mode::Vm, "x = 1"), Outcome::Value(1));
+        let mut errors = |mode, line| match engine.run_in(mode, line) {
+            Outcome::Error { errors, .. } => errors,
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        };
+        for mode in [Mode::Eval, Mode::Vm] {
+            assert!(matches!(
+                errors(mode, "x+x+x+x")[..],
+                [ApplicationError::Interpreter(Annotation {
+                    value: InterpreterErrorKind::TooManySteps,
+                    ..
+                })]
+            ));
+        }
+
+        assert_eq!(
+            engine.run_in(Mode::Rpn, "---1"),
+            Outcome::Rpn("1 neg neg neg")
+        );
+        assert_eq!(engine.run_in(Mode::Vm, "x + x"), Outcome::Value(2));
+    }
+
+    #[test]
+    fn test_long_sum() {
+        // デバッグビルドでも、メインスレッドと同じ大きさのスタックで確かめる
+        std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(|| {
+                let modes =
+                    "eval rpn prefix sexpr latex vm dot dc wat c asm llvm-ir ast-json minify";
+                // 出力や処理が項の数の2乗で増えるモードは短い和で確かめる
+                let quadratic = "disasm opt-disasm precedence-trace steps ast why";
+                let long = format!("1{}", " + 1".repeat(100_000));
+                let short = format!("1{}", " + 1".repeat(1_000));
+                let cases = modes
+                    .split(' ')
+                    .map(|mode| (mode, &long))
+                    .chain(quadratic.split(' ').map(|mode| (mode, &short)));
+                for (mode, input) in cases {
+                    let mut engine = Engine::new(mode.parse().unwrap());
+                    let report = engine.report(input);
+                    assert!(report.diagnostics().is_empty(), "{}", mode);
+                    assert_eq!(&report.ast.unwrap().to_string(), input, "{}", mode);
+                    assert!(
+                        !matches!(engine.run(input), Outcome::Error { .. }),
+                        "{}",
+                        mode
+                    );
+                }
+                let mut engine = Engine::new(Mode::Eval);
+                assert_eq!(engine.run(&long), Outcome::Value(100_001));
+                engine.set_pipeline(Pipeline::ShuntingYard);
+                assert!(matches!(engine.run_in(Mode::Rpn, &long), Outcome::Rpn(_)));
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_engine_reports_all_errors() {
+        let mut engine = Engine::new(Mode::Eval);
+        match engine.run("1 $ + ) * 2 @ 3") {
+            Outcome::Error {
+                errors,
+                prefix: None,
+            } => assert!(matches!(
+                errors[..],
+                [
+                    ApplicationError::Lexer(_),
+                    ApplicationError::Lexer(_),
+                    ApplicationError::Parser(ParseError::NotExpression(_)),
+                ]
+            )),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_follow_on_errors() {
+        let mut engine = Engine::new(Mode::Eval);
+        let mut codes = |line| match engine.run(line) {
+            Outcome::Error { errors, .. } => errors
+                .iter()
+                .map(|e| e.to_diagnostic(line).code)
+                .collect::<Vec<_>>(),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        };
+        // 読み飛ばした入力の後で入力が終わったり、式が続いたりしても、構文エラーにしない
+        assert_eq!(codes("@"), ["L0001"]);
+        assert_eq!(codes("1 @ 2"), ["L0001"]);
+        assert_eq!(codes("2x"), ["L0004"]);
+        assert_eq!(codes("0x"), ["L0005"]);
+        assert_eq!(codes("1e"), ["L0004"]);
+        assert_eq!(codes("99999999999999999999"), ["L0002"]);
+        assert_eq!(codes("1 + 99999999999999999999"), ["L0002"]);
+        assert_eq!(codes("1 + $)"), ["L0001"]);
+        assert_eq!(codes("($)"), ["L0001"]);
+        // 読み飛ばした入力と離れた構文エラーは報告する
+        assert_eq!(codes("(1 $"), ["L0001", "P0004"]);
+        assert_eq!(codes("1 + ) $"), ["L0001", "P0002"]);
+        assert_eq!(codes("$ 1 +"), ["L0001", "P0006"]);
+    }
+
+    #[test]
+    fn test_engine_backends() {
+        // 変換先の名前はすべてモードとして選べる
+        for name in Registry::new().names() {
+            let mode: Mode = name.parse().unwrap();
+            assert!(mode.target() == Some(name) || mode == Mode::Rpn, "{}", name);
+        }
+
+        /// 入力をそのまま返す変換器
+        struct Echo;
+        impl Backend for Echo {
+            fn name(&self) -> &str {
+                "c"
+            }
+            fn compile_into(&mut self, expr: &Ast, buf: &mut String) -> Result<(), CompileError> {
+                buf.clear();
+                buf.push_str(&expr.to_string());
+                Ok(())
+            }
+        }
+        let mut engine = Engine::new(Mode::C);
+        engine.register_backend(Box::new(Echo));
+        assert_eq!(engine.run("(1+2)*3"), Outcome::Trace("(1 + 2) * 3"));
+    }
+
+    #[test]
+    fn test_engine_literals() {
+        use crate::lexer::{Literal, TokenKind};
+
+        // "v1.2.3"のようなバージョン番号。数としては扱えない
+        fn version(input: &[char]) -> Option<(usize, Literal)> {
+            if input.first() != Some(&'v') || !input.get(1)?.is_ascii_digit() {
+                return None;
+            }
+            let len = input
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || **c == '.')
+                .count();
+            Some((
+                len,
+                Literal {
+                    kind: 1,
+                    value: None,
+                },
+            ))
+        }
+        // "50%"のような百分率を千分率の値として読む
+        fn percent(input: &[char]) -> Option<(usize, Literal)> {
+            let digits = input.iter().take_while(|c| c.is_ascii_digit()).count();
+            if digits == 0 || input.get(digits) != Some(&'%') {
+                return None;
+            }
+            let n: u64 = input[..digits].iter().collect::<String>().parse().ok()?;
+            Some((
+                digits + 1,
+                Literal {
+                    kind: 2,
+                    value: Some(n * 10),
+                },
+            ))
+        }
+
+        let mut engine = Engine::new(Mode::Eval);
+        engine.register_literal(version);
+        engine.register_literal(percent);
+        assert_eq!(engine.run("50% + 5"), Outcome::Value(505));
+        // 登録していない形は組み込みの字句解析に任せる
+        assert_eq!(engine.run("v = 2"), Outcome::Value(2));
+        assert_eq!(engine.run("v * 10%"), Outcome::Value(200));
+        let literal = Token::new(
+            TokenKind::Literal(Literal {
+                kind: 1,
+                value: None,
+            }),
+            Location(4, 10),
+        );
+        assert_eq!(
+            engine.run("1 + v1.2.3"),
+            Outcome::Error {
+                errors: vec![ParseError::NotExpression(literal).into()],
+                prefix: Some(Box::new(Outcome::Value(1))),
+            }
+        );
+        engine.set_mode(Mode::Rpn);
+        engine.set_pipeline(Pipeline::ShuntingYard);
+        assert_eq!(engine.run("2 * 7%"), Outcome::Rpn("2 70 *"));
+        assert!(matches!(engine.run("7% v1"), Outcome::Error { .. }));
+    }
+
+    #[test]
+    fn test_ast_cache() {
+        let mut engine = Engine::new(Mode::Eval);
+        engine.enable_ast_cache(HashMap::new());
+        assert_eq!(engine.run("1 + 2"), Outcome::Value(3));
+        assert!(matches!(engine.run("1 +"), Outcome::Error { .. }));
+        let mut entries = engine.take_ast_cache().unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), vec!["1 + 2"]);
+        // キャッシュにある行は構文解析せずに、覚えた構文木を使う
+        entries.insert("1 + 2".to_string(), "5".parse().unwrap());
+        engine.enable_ast_cache(entries);
+        assert_eq!(engine.run("1 + 2"), Outcome::Value(5));
+        assert_eq!(engine.run_in(Mode::Rpn, "1 + 2"), Outcome::Rpn("5"));
+        // トークンの列を使う処理では使わない
+        engine.set_pipeline(Pipeline::ShuntingYard);
+        assert_eq!(engine.run_in(Mode::Rpn, "1 + 2"), Outcome::Rpn("1 2 +"));
+        assert!(engine.take_ast_cache().is_some());
+        assert_eq!(engine.run("1 + 2"), Outcome::Value(3));
+    }
+}
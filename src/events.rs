@@ -0,0 +1,169 @@
+//!
+//! 処理の流れを出来事の列として外へ伝える仕組み。
+//! 可視化する画面などは、処理系に手を入れずに字句解析・構文解析・評価の進み方を追える。
+//!
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::lexer::Token;
+use super::parser::*;
+use super::tree::{json_string, node};
+
+/// 処理の途中で起きた出来事
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// 字句解析でトークンを1つ作った
+    TokenProduced(&'a Token),
+    /// 構文解析で節点を1つ組み立てた。子は親より先に通知する
+    NodeReduced(&'a Ast),
+    /// 節点の値を求めた。評価器では部分式ごとに、それ以外では式全体について通知する
+    ValueComputed { node: &'a Ast, value: i64 },
+    /// エラーを見つけた
+    DiagnosticEmitted(&'a ApplicationError),
+}
+
+///
+/// 処理の出来事を受け取る。
+/// 出来事は起きた順に通知する。
+///
+pub trait Observer {
+    fn on_event(&mut self, event: Event<'_>);
+}
+
+/// 関数で出来事を受け取る
+impl<F: FnMut(Event<'_>)> Observer for F {
+    fn on_event(&mut self, event: Event<'_>) {
+        self(event)
+    }
+}
+
+///
+/// 構文木の節点を、再帰下降の構文解析で組み立てた順（帰りがけ順）に通知する。
+/// 長い演算子の連なりでもスタックを使い切らないよう、再帰せずに作業のスタックで辿る。
+///
+pub(crate) fn notify_reductions(expr: &Ast, observer: &mut dyn Observer) {
+    // 子を通知し終えた節点はtrueとともに積み直す
+    let mut tasks = vec![(expr, false)];
+    while let Some((expr, ready)) = tasks.pop() {
+        if ready {
+            observer.on_event(Event::NodeReduced(expr));
+            continue;
+        }
+        tasks.push((expr, true));
+        let children = expr.value.children();
+        tasks.extend(children.into_iter().rev().map(|child| (child, false)));
+    }
+}
+
+///
+/// 評価の各段階を、1行に1つのJSONオブジェクトとして書き出す（JSON Lines）。
+/// 節点の表示、範囲、子の値、求めた値、時刻（UNIX時間の秒）を記録する。
+///
+/// ```text
+/// {"node":"+","span":[0,5],"inputs":[1,4],"output":5,"timestamp":1760000000.123456}
+/// ```
+///
+pub struct TraceRecorder<W: Write> {
+    writer: W,
+    /// 求めた値のうち、まだ親に渡していないもの
+    values: Vec<i64>,
+    /// 書き出しに失敗した場合の最初のエラー
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceRecorder<W> {
+    pub fn new(writer: W) -> Self {
+        TraceRecorder {
+            writer,
+            values: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// 書き出した内容を確定させる。それまでに書き出しに失敗していれば、そのエラーを返す
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn record(&mut self, expr: &Ast, value: i64) -> io::Result<()> {
+        let (label, children) = node(expr);
+        // 子の値は親より先に求めているので、その分を取り出す
+        let inputs = self
+            .values
+            .split_off(self.values.len().saturating_sub(children.len()));
+        self.values.push(value);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        writeln!(
+            self.writer,
+            "{{\"node\":{},\"span\":[{},{}],\"inputs\":[{}],\"output\":{},\"timestamp\":{}.{:06}}}",
+            json_string(&label),
+            expr.location.0,
+            expr.location.1,
+            inputs
+                .iter()
+                .map(i64::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+            value,
+            timestamp.as_secs(),
+            timestamp.subsec_micros()
+        )
+    }
+}
+
+impl<W: Write> Observer for TraceRecorder<W> {
+    fn on_event(&mut self, event: Event<'_>) {
+        match event {
+            // 新しい行の処理が始まった
+            Event::TokenProduced(_) => self.values.clear(),
+            Event::ValueComputed { node, value } => {
+                if let Err(e) = self.record(node, value) {
+                    self.error.get_or_insert(e);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Engine, Mode};
+
+    #[test]
+    fn test_trace_recorder() {
+        let mut engine = Engine::new(Mode::Eval);
+        let mut recorder = TraceRecorder::new(Vec::new());
+        engine.observe("x = 1 + 4", &mut recorder);
+        engine.observe("-x", &mut recorder);
+        recorder.flush().unwrap();
+        let trace = String::from_utf8(recorder.into_inner()).unwrap();
+        // 時刻は実行ごとに変わるので除いて比べる
+        let lines: Vec<_> = trace
+            .lines()
+            .map(|line| &line[..line.find(",\"timestamp\"").unwrap()])
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"node":"1","span":[4,5],"inputs":[],"output":1"#,
+                r#"{"node":"4","span":[8,9],"inputs":[],"output":4"#,
+                r#"{"node":"+","span":[4,9],"inputs":[1,4],"output":5"#,
+                r#"{"node":"x =","span":[0,9],"inputs":[5],"output":5"#,
+                r#"{"node":"x","span":[1,2],"inputs":[],"output":5"#,
+                r#"{"node":"- (unary)","span":[0,2],"inputs":[5],"output":-5"#,
+            ]
+        );
+        assert_eq!(json_string("a\"b\\\n"), r#""a\"b\\\u000a""#);
+    }
+}
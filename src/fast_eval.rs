@@ -0,0 +1,345 @@
+//!
+//! 抽象構文木を作らずに、構文解析しながら式を評価する電卓向けの評価器。
+//! 演算子の優先順位に従ってトークンを1つずつ読み、値のスタックと演算子のスタックだけで計算する。
+//!
+//! 数、演算子、かっこ、組み込み関数の呼び出しからなる式だけをこの方法で評価する。
+//! 変数や代入、絶対値の"|"を含む式と、字句解析・構文解析・評価のいずれかで失敗する式は、
+//! 構文木を作る通常の方法で評価し直す。そのため結果とエラーは、構文解析して評価した場合と常に同じになる。
+//!
+use smallvec::SmallVec;
+
+use super::interpreter::{apply_binop, apply_function, apply_uniop, literal, Interpreter};
+use super::lexer::*;
+use super::parser::*;
+
+///
+/// 入力の式を評価する。
+/// 変数の定義されていない新しい評価器で、構文解析して評価した場合と同じ結果を返す。
+///
+/// ```
+/// use parser::fast_eval::fast_eval;
+///
+/// assert_eq!(fast_eval("1 + 2 * 3").unwrap(), 7);
+/// assert_eq!(fast_eval("-2 ^ 2 + max(1, 5, 3)").unwrap(), 1);
+/// // 構文木を作って評価した場合と同じエラーになる
+/// assert!(fast_eval("1 / 0").is_err());
+/// assert!(fast_eval("x = 2").is_ok());
+/// ```
+///
+pub fn fast_eval(input: &str) -> Result<i64, ApplicationError> {
+    if let Some(value) = eval_tokens(StreamingLexer::new(input)) {
+        return Ok(value);
+    }
+    let ast = input.parse::<Ast>()?;
+    Ok(Interpreter::new().eval(&ast)?)
+}
+
+/// 演算子のスタックに積むもの
+enum Entry {
+    Prefix(&'static UnaryOperatorKind, u8),
+    Binary(&'static BinaryOperatorKind, &'static OperatorDef),
+    /// 開きかっこ
+    Open,
+    /// 関数と、これまでに区切った引数の数
+    Call(String, usize),
+}
+
+impl Entry {
+    /// 二項演算子defを積む前に、この演算子を先に計算するかどうか
+    fn binds_before(&self, def: &OperatorDef) -> bool {
+        match *self {
+            // 単項演算子は右結合なので、同じ優先順位では後の演算子を先に計算する
+            Entry::Prefix(_, precedence) => precedence > def.precedence,
+            Entry::Binary(_, top) => {
+                top.precedence > def.precedence
+                    || (top.precedence == def.precedence
+                        && def.associativity == Associativity::Left)
+            }
+            Entry::Open | Entry::Call(..) => false,
+        }
+    }
+}
+
+/// 値と演算子のスタック。ほとんどの式はヒープ確保なしで収まる
+struct Stacks {
+    values: SmallVec<[i64; 16]>,
+    entries: SmallVec<[Entry; 16]>,
+}
+
+impl Stacks {
+    /// 積まれた演算子の最上段を計算する。計算できなければNoneを返す
+    fn reduce(&mut self) -> Option<()> {
+        let value = match self.entries.pop()? {
+            Entry::Prefix(kind, _) => {
+                let operand = self.values.pop()?;
+                apply_uniop(kind, operand).ok()?
+            }
+            Entry::Binary(kind, _) => {
+                let right = self.values.pop()?;
+                let left = self.values.pop()?;
+                apply_binop(kind, left, right).ok()?
+            }
+            Entry::Open | Entry::Call(..) => return None,
+        };
+        self.values.push(value);
+        Some(())
+    }
+
+    /// かっこや関数の呼び出しの中で、まだ計算していない演算子をすべて計算する
+    fn reduce_group(&mut self) -> Option<()> {
+        while !matches!(self.entries.last()?, Entry::Open | Entry::Call(..)) {
+            self.reduce()?;
+        }
+        Some(())
+    }
+
+    /// 関数を呼び出し、引数を値のスタックから取り除いて結果を積む
+    fn call(&mut self, name: &str, argc: usize) -> Option<()> {
+        let start = self.values.len().checked_sub(argc)?;
+        let value = apply_function(name, &self.values[start..]).ok()?;
+        self.values.truncate(start);
+        self.values.push(value);
+        Some(())
+    }
+}
+
+///
+/// トークンを読みながら式を評価する。
+/// この方法で評価できない式や、どこかで失敗する式ならNoneを返す。
+///
+fn eval_tokens<I>(tokens: I) -> Option<i64>
+where
+    I: Iterator<Item = Result<Token, LexError>>,
+{
+    let mut tokens = tokens.peekable();
+    let mut stacks = Stacks {
+        values: SmallVec::new(),
+        entries: SmallVec::new(),
+    };
+    // 次に被演算子を読むか、演算子を読むか
+    let mut operand = true;
+    while let Some(tok) = tokens.next() {
+        let tok = tok.ok()?;
+        // 構文解析器と同じく深さの上限でTooDeepを報告するよう、深い式は通常の方法に任せる
+        if stacks.entries.len() >= DEFAULT_MAX_DEPTH / 2 {
+            return None;
+        }
+        if operand {
+            match tok.value {
+                TokenKind::Number(n) => {
+                    stacks.values.push(literal(n).ok()?);
+                    operand = false;
+                }
+                TokenKind::LParen => stacks.entries.push(Entry::Open),
+                TokenKind::Ident(name) => {
+                    let is_lparen = |tok: &Result<Token, LexError>| matches!(tok, Ok(tok) if tok.value == TokenKind::LParen);
+                    // 変数は通常の方法で評価する
+                    tokens.next_if(is_lparen)?.ok()?;
+                    let is_rparen = |tok: &Result<Token, LexError>| matches!(tok, Ok(tok) if tok.value == TokenKind::RParen);
+                    if tokens.next_if(is_rparen).is_some() {
+                        stacks.call(&name, 0)?;
+                        operand = false;
+                    } else {
+                        stacks.entries.push(Entry::Call(name, 0));
+                    }
+                }
+                _ => {
+                    let (kind, def) = prefix_operator(&tok)?;
+                    stacks.entries.push(Entry::Prefix(kind, def.precedence));
+                }
+            }
+            continue;
+        }
+        match tok.value {
+            TokenKind::RParen => {
+                stacks.reduce_group()?;
+                if let Entry::Call(name, argc) = stacks.entries.pop()? {
+                    stacks.call(&name, argc + 1)?;
+                }
+            }
+            TokenKind::Comma => {
+                stacks.reduce_group()?;
+                match stacks.entries.last_mut()? {
+                    Entry::Call(_, argc) => *argc += 1,
+                    _ => return None,
+                }
+                operand = true;
+            }
+            _ => {
+                let (kind, def) = binary_operator(&tok)?;
+                while stacks
+                    .entries
+                    .last()
+                    .is_some_and(|top| top.binds_before(def))
+                {
+                    stacks.reduce()?;
+                }
+                stacks.entries.push(Entry::Binary(kind, def));
+                operand = true;
+            }
+        }
+    }
+    if operand {
+        return None;
+    }
+    while !stacks.entries.is_empty() {
+        stacks.reduce()?;
+    }
+    match stacks.values[..] {
+        [value] => Some(value),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// 構文木を作って評価した結果
+    fn slow_eval(input: &str) -> Result<i64, ApplicationError> {
+        let ast = input.parse::<Ast>()?;
+        Ok(Interpreter::new().eval(&ast)?)
+    }
+
+    fn fast_path(input: &str) -> Option<i64> {
+        eval_tokens(StreamingLexer::new(input))
+    }
+
+    #[test]
+    fn test_fast_path() {
+        assert_eq!(fast_path("1 + 2 * 3 - 4"), Some(3));
+        assert_eq!(fast_path("2 ^ 3 ^ 2"), Some(512));
+        assert_eq!(fast_path("8 / 2 / 2"), Some(2));
+        assert_eq!(fast_path("-2 ^ 2"), Some(-4));
+        assert_eq!(fast_path("-2 * 3 | 1"), Some(-5));
+        assert_eq!(fast_path("(1 + 2) * -(3)"), Some(-9));
+        assert_eq!(
+            fast_path("max(1, 2 * 3, min(4, 5)) + sqrt(16) + pow(2, 3)"),
+            Some(18)
+        );
+        assert_eq!(fast_path("0x10 + 1e3 # comment"), Some(1016));
+        // 通常の方法に任せる式
+        for input in &[
+            "x",
+            "x = 1",
+            "|1 - 2|",
+            "max()",
+            "f(1)",
+            "1 / 0",
+            "2 ^ -1 ^ 2 * 0",
+            "(1, 2)",
+            "1 +",
+            "()",
+            "(1",
+            "1)",
+            "1 2",
+            "1 $ 2",
+            "9223372036854775808",
+        ] {
+            assert_eq!(fast_path(input), None, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_same_as_slow_eval() {
+        for input in &[
+            "1 + 2 * 3",
+            "-2 ^ 2",
+            "--2 ^ 2",
+            "2 ^ -1",
+            "0 ^ 0",
+            "9223372036854775807 + 1",
+            "-9223372036854775807 - 1",
+            "max(1, -(2), 3 | 4)",
+            "min(5)",
+            "pow(2, 10) / sqrt(-1)",
+            "x = 2 * 3",
+            "y + 1",
+            "|-3| * 2",
+            "1 + + 2",
+            "1 + (2 * 3",
+            "abs(1, 2)",
+            "",
+        ] {
+            assert_eq!(fast_eval(input), slow_eval(input), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_too_deep() {
+        // 深さの上限に達するまで解析するので、大きなスタックで実行する
+        std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(|| {
+                for input in &[
+                    format!("{}1{}", "(".repeat(1000), ")".repeat(1000)),
+                    format!("{}1", "-".repeat(1000)),
+                    format!("{}1{}", "max(".repeat(1000), ")".repeat(1000)),
+                    format!("{}1{}", "(1 + ".repeat(1000), ")".repeat(1000)),
+                ] {
+                    assert!(matches!(
+                        fast_eval(input),
+                        Err(ApplicationError::Parser(ParseError::TooDeep(_)))
+                    ));
+                }
+                // 左結合の連なりは入れ子にならないので、長くても評価できる
+                let input = format!("1{}", " + 1".repeat(100_000));
+                assert_eq!(fast_eval(&input), Ok(100_001));
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    /// 数と演算子とかっこを並べた文字列。構文として正しくないものも含む
+    fn tokens() -> impl Strategy<Value = String> {
+        let token = prop_oneof![
+            4 => (0u64..20).prop_map(|n| n.to_string()),
+            1 => Just("9223372036854775807".to_string()),
+            4 => prop::sample::select(vec!["+", "-", "*", "/", "^", "|"]).prop_map(String::from),
+            2 => prop::sample::select(vec!["(", ")", ","]).prop_map(String::from),
+            1 => prop::sample::select(vec!["max(", "pow(", "abs(", "x"]).prop_map(String::from),
+        ];
+        prop::collection::vec(token, 1..16).prop_map(|tokens| tokens.join(" "))
+    }
+
+    /// 数と演算子とかっこと関数の呼び出しからなる、構文として正しい式
+    fn expression() -> impl Strategy<Value = String> {
+        let leaf = (0u64..20).prop_map(|n| n.to_string());
+        leaf.prop_recursive(4, 32, 3, |inner| {
+            prop_oneof![
+                (
+                    inner.clone(),
+                    prop::sample::select(vec!["+", "-", "*", "/", "^", "|"]),
+                    inner.clone()
+                )
+                    .prop_map(|(l, op, r)| format!("{} {} {}", l, op, r)),
+                (prop::sample::select(vec!["-", "+"]), inner.clone())
+                    .prop_map(|(op, e)| format!("{}{}", op, e)),
+                inner.clone().prop_map(|e| format!("({})", e)),
+                (
+                    prop::sample::select(vec!["max", "min", "pow", "sqrt"]),
+                    prop::collection::vec(inner, 0..4)
+                )
+                    .prop_map(|(name, args)| format!(
+                        "{}({})",
+                        name,
+                        args.join(", ")
+                    )),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_same_as_slow_eval(input in tokens()) {
+            prop_assert_eq!(fast_eval(&input), slow_eval(&input), "{}", input);
+        }
+
+        #[test]
+        fn prop_expression_same_as_slow_eval(input in expression()) {
+            prop_assert_eq!(fast_eval(&input), slow_eval(&input), "{}", input);
+        }
+    }
+}
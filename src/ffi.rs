@@ -0,0 +1,280 @@
+//!
+//! C・C++のプログラムへ電卓を組み込むための関数。
+//! ffi機能を有効にして、共有ライブラリ（cdylib）としてビルドする。
+//! 関数の宣言はリポジトリのinclude/calc.hにある。
+//!
+//! ```text
+//! cargo rustc --lib --release --features ffi --crate-type cdylib
+//! cc main.c -Iinclude -Ltarget/release -lparser
+//! ```
+//!
+//! ```c
+//! #include "calc.h"
+//!
+//! int64_t value;
+//! CalcError err;
+//! if (calc_eval("1 + 2 * 3", &value, &err) == CALC_STATUS_OK) {
+//!     printf("%lld\n", (long long)value);
+//! } else {
+//!     fprintf(stderr, "%s: %s at %zu..%zu\n", err.code, err.message, err.start, err.end);
+//!     calc_error_free(&err);
+//! }
+//! ```
+//!
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic;
+use std::ptr;
+
+use super::batch::LineOutcome;
+use super::engine::{Engine, Mode};
+use super::parser::ApplicationError;
+
+/// 関数の結果
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalcStatus {
+    /// 処理できた
+    Ok = 0,
+    /// 入力の式に誤りがあった
+    Error = 1,
+    /// 引数がNULLか、入力がUTF-8として正しくない
+    InvalidArgument = 2,
+    /// ライブラリの内部の誤りで処理を打ち切った
+    Internal = 3,
+}
+
+///
+/// 失敗の詳細。
+/// startとendは入力中の範囲で、入力の先頭からのバイト数で数える。
+/// messageはライブラリが確保するので、使い終わったらcalc_error_freeで解放する。
+///
+#[repr(C)]
+#[derive(Debug)]
+pub struct CalcError {
+    /// エラーの種類ごとに決まった符号（"P0004"など）。式の誤りでなければ空文字列
+    pub code: [c_char; 8],
+    /// UTF-8のメッセージ。NULで終わる
+    pub message: *mut c_char,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl CalcError {
+    fn new(code: &str, message: &str, start: usize, end: usize) -> Self {
+        let mut buf = [0; 8];
+        for (dst, src) in buf.iter_mut().zip(code.bytes().take(7)) {
+            *dst = src as c_char;
+        }
+        // 入力はNULを含まないので、メッセージにもNULは入らない
+        let message = CString::new(message).unwrap_or_default();
+        CalcError {
+            code: buf,
+            message: message.into_raw(),
+            start,
+            end,
+        }
+    }
+
+    /// 入力の最初のエラーから作る
+    fn from_error(input: &str, error: &ApplicationError) -> Self {
+        let diagnostic = error.to_diagnostic(input);
+        let (start, end) = match diagnostic.labels.first() {
+            Some((location, _)) => (location.0, location.1),
+            None => {
+                let len = input.chars().count();
+                (len, len)
+            }
+        };
+        CalcError::new(
+            &diagnostic.code,
+            &diagnostic.message,
+            byte_offset(input, start),
+            byte_offset(input, end),
+        )
+    }
+
+    fn free(&mut self) {
+        if !self.message.is_null() {
+            // messageはCString::into_rawで作ったもの
+            drop(unsafe { CString::from_raw(self.message) });
+            self.message = ptr::null_mut();
+        }
+    }
+}
+
+/// 文字単位の位置を、入力の先頭からのバイト数へ変換する
+fn byte_offset(input: &str, chars: usize) -> usize {
+    input
+        .char_indices()
+        .nth(chars)
+        .map_or(input.len(), |(i, _)| i)
+}
+
+/// 失敗をerrへ書き込んでstatusを返す。errがNULLなら詳細は捨てる
+unsafe fn fail(err: *mut CalcError, status: CalcStatus, mut error: CalcError) -> CalcStatus {
+    match err.as_mut() {
+        Some(err) => *err = error,
+        None => error.free(),
+    }
+    status
+}
+
+///
+/// NULで終わるUTF-8の式を評価し、値をoutへ書き込む。
+/// 失敗したらCALC_STATUS_OK以外を返し、errがNULLでなければ最初のエラーの詳細を書き込む。
+/// 成功したときはerrに触れない。
+///
+/// # Safety
+///
+/// inputはNULで終わる文字列を、outは書き込めるint64_tを、errは書き込めるCalcErrorを指すか、NULLでなければならない。
+/// errへ書き込まれた詳細は、次にerrを渡す前にcalc_error_freeで解放する。
+///
+#[no_mangle]
+pub unsafe extern "C" fn calc_eval(
+    input: *const c_char,
+    out: *mut i64,
+    err: *mut CalcError,
+) -> CalcStatus {
+    if input.is_null() || out.is_null() {
+        let error = CalcError::new("", "input and out must not be NULL", 0, 0);
+        return fail(err, CalcStatus::InvalidArgument, error);
+    }
+    let input = match CStr::from_ptr(input).to_str() {
+        Ok(input) => input,
+        Err(e) => {
+            let start = e.valid_up_to();
+            let end = start + e.error_len().unwrap_or(0);
+            let error = CalcError::new("", "input is not valid UTF-8", start, end);
+            return fail(err, CalcStatus::InvalidArgument, error);
+        }
+    };
+    // 呼び出し元のCのコードへパニックを伝えないよう、ここで止める
+    let outcome = panic::catch_unwind(|| LineOutcome::from(Engine::new(Mode::Eval).run(input)));
+    match outcome {
+        Ok(LineOutcome::Value(value)) => {
+            *out = value;
+            CalcStatus::Ok
+        }
+        Ok(LineOutcome::Error { errors, .. }) if !errors.is_empty() => {
+            let error = CalcError::from_error(input, &errors[0]);
+            fail(err, CalcStatus::Error, error)
+        }
+        _ => {
+            let error = CalcError::new("", "internal error", 0, 0);
+            fail(err, CalcStatus::Internal, error)
+        }
+    }
+}
+
+///
+/// calc_evalがerrへ書き込んだメッセージを解放し、messageをNULLにする。
+/// 解放済みのerrやNULLを渡しても何もしない。
+///
+/// # Safety
+///
+/// errはcalc_evalが書き込んだCalcErrorか、messageがNULLのCalcErrorを指すか、NULLでなければならない。
+///
+#[no_mangle]
+pub unsafe extern "C" fn calc_error_free(err: *mut CalcError) {
+    if let Some(err) = err.as_mut() {
+        err.free();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zeroed() -> CalcError {
+        CalcError {
+            code: [0; 8],
+            message: ptr::null_mut(),
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// 符号、メッセージ、範囲の始まりと終わり
+    type Detail = (String, String, usize, usize);
+
+    fn eval(input: &[u8]) -> (CalcStatus, i64, Option<Detail>) {
+        let input = CString::new(input).unwrap();
+        let mut value = 0;
+        let mut err = zeroed();
+        let status = unsafe { calc_eval(input.as_ptr(), &mut value, &mut err) };
+        let detail = if err.message.is_null() {
+            None
+        } else {
+            let code = unsafe { CStr::from_ptr(err.code.as_ptr()) };
+            let message = unsafe { CStr::from_ptr(err.message) };
+            Some((
+                code.to_str().unwrap().to_string(),
+                message.to_str().unwrap().to_string(),
+                err.start,
+                err.end,
+            ))
+        };
+        unsafe { calc_error_free(&mut err) };
+        assert!(err.message.is_null());
+        (status, value, detail)
+    }
+
+    #[test]
+    fn test_calc_eval() {
+        assert_eq!(eval(b"1 + 2 * 3"), (CalcStatus::Ok, 7, None));
+
+        let (status, _, detail) = eval(b"1 / 0");
+        let (code, message, start, end) = detail.unwrap();
+        assert_eq!(status, CalcStatus::Error);
+        assert_eq!((code.as_str(), start, end), ("E0001", 2, 3));
+        assert!(!message.is_empty());
+
+        // 範囲はバイトで数える
+        let (status, _, detail) = eval("1 +\n(あ) $".as_bytes());
+        let (code, _, start, end) = detail.unwrap();
+        assert_eq!(status, CalcStatus::Error);
+        assert_eq!((code.as_str(), start, end), ("L0001", 5, 8));
+
+        let (status, _, detail) = eval(b"1 + \xff");
+        assert_eq!(status, CalcStatus::InvalidArgument);
+        let (code, _, start, end) = detail.unwrap();
+        assert_eq!((code.as_str(), start, end), ("", 4, 5));
+    }
+
+    #[test]
+    fn test_header_is_up_to_date() {
+        // build.rsが生成したヘッダーと、リポジトリに置いたヘッダーが一致する
+        assert_eq!(
+            include_str!(concat!(env!("OUT_DIR"), "/calc.h")),
+            include_str!("../include/calc.h"),
+            "include/calc.h is out of date; regenerate it as described in build.rs"
+        );
+    }
+
+    #[test]
+    fn test_null_arguments() {
+        let mut value = 0;
+        let mut err = zeroed();
+        unsafe {
+            assert_eq!(
+                calc_eval(ptr::null(), &mut value, &mut err),
+                CalcStatus::InvalidArgument
+            );
+            assert!(!err.message.is_null());
+            calc_error_free(&mut err);
+            calc_error_free(&mut err);
+            calc_error_free(ptr::null_mut());
+
+            let input = CString::new("1 / 0").unwrap();
+            assert_eq!(
+                calc_eval(input.as_ptr(), &mut value, ptr::null_mut()),
+                CalcStatus::Error
+            );
+            assert_eq!(
+                calc_eval(input.as_ptr(), ptr::null_mut(), ptr::null_mut()),
+                CalcStatus::InvalidArgument
+            );
+        }
+    }
+}
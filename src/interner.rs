@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::parser::*;
+
+///
+/// 位置情報を持たない、共有可能な構文木のノード。
+/// AstInternerを通して作成したノードは、構造が同じであれば同じメモリを指す。
+///
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum Node {
+    Num(u64),
+    Var(String),
+    Assign {
+        name: String,
+        value: Rc<Node>,
+    },
+    Unary {
+        operator: UnaryOperatorKind,
+        operand: Rc<Node>,
+    },
+    Binary {
+        operator: BinaryOperatorKind,
+        left: Rc<Node>,
+        right: Rc<Node>,
+    },
+    Call {
+        name: String,
+        args: Vec<Rc<Node>>,
+    },
+}
+
+/// 子ノードをアドレスで表したハッシュテーブルのキー
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Num(u64),
+    Var(String),
+    Assign(String, *const Node),
+    Unary(UnaryOperatorKind, *const Node),
+    Binary(BinaryOperatorKind, *const Node, *const Node),
+    Call(String, Vec<*const Node>),
+}
+
+///
+/// 同じ部分木を1つのノードにまとめる（hash-consing）インターナ。
+/// 子ノードは既にまとめられているので、キーの比較はアドレスの比較で済む。
+///
+#[derive(Debug, Default)]
+pub struct AstInterner {
+    table: HashMap<Key, Rc<Node>>,
+}
+
+impl AstInterner {
+    pub fn new() -> Self {
+        AstInterner {
+            table: HashMap::new(),
+        }
+    }
+
+    /// 構文木をまとめられたノードへ変換する
+    pub fn intern(&mut self, ast: &Ast) -> Rc<Node> {
+        use super::parser::AstKind::*;
+        match ast.value {
+            Num(n) => self.insert(Key::Num(n), || Node::Num(n)),
+            Var(ref name) => self.insert(Key::Var(name.clone()), || Node::Var(name.clone())),
+            Assign {
+                ref name,
+                ref value,
+            } => {
+                let value = self.intern(value);
+                self.insert(Key::Assign(name.clone(), Rc::as_ptr(&value)), || {
+                    Node::Assign {
+                        name: name.clone(),
+                        value,
+                    }
+                })
+            }
+            Unary {
+                ref operator,
+                ref operand,
+            } => {
+                let operand = self.intern(operand);
+                let key = Key::Unary(operator.value.clone(), Rc::as_ptr(&operand));
+                self.insert(key, || Node::Unary {
+                    operator: operator.value.clone(),
+                    operand,
+                })
+            }
+            Binary {
+                ref operator,
+                ref left,
+                ref right,
+            } => {
+                let left = self.intern(left);
+                let right = self.intern(right);
+                let key = Key::Binary(
+                    operator.value.clone(),
+                    Rc::as_ptr(&left),
+                    Rc::as_ptr(&right),
+                );
+                self.insert(key, || Node::Binary {
+                    operator: operator.value.clone(),
+                    left,
+                    right,
+                })
+            }
+            Call { ref name, ref args } => {
+                let args: Vec<_> = args.iter().map(|arg| self.intern(arg)).collect();
+                let key = Key::Call(name.clone(), args.iter().map(Rc::as_ptr).collect());
+                self.insert(key, || Node::Call {
+                    name: name.clone(),
+                    args,
+                })
+            }
+        }
+    }
+
+    /// まとめられたノードの数を返す
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    fn insert<F>(&mut self, key: Key, node: F) -> Rc<Node>
+    where
+        F: FnOnce() -> Node,
+    {
+        self.table
+            .entry(key)
+            .or_insert_with(|| Rc::new(node()))
+            .clone()
+    }
+}
+
+///
+/// まとめられたノード同士が同じ構造かどうかを返す。
+/// 同じインターナで作成したノードであれば、アドレスの比較だけで判定できる。
+///
+pub fn same(a: &Rc<Node>, b: &Rc<Node>) -> bool {
+    Rc::ptr_eq(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern_shares_subtrees() {
+        let mut interner = AstInterner::new();
+        // 位置情報が異なっても同じ構造であればまとめられる
+        let a = interner.intern(&"(1 + 2) * (1 + 2)".parse::<Ast>().unwrap());
+        let b = interner.intern(&"(1+2)*(1+2)".parse::<Ast>().unwrap());
+        assert!(same(&a, &b));
+        match *a {
+            Node::Binary {
+                ref left,
+                ref right,
+                ..
+            } => assert!(same(left, right)),
+            _ => panic!("unexpected node: {:?}", a),
+        }
+        // 1, 2, 1 + 2, (1 + 2) * (1 + 2)
+        assert_eq!(interner.len(), 4);
+
+        let c = interner.intern(&"(1 + 2) * (2 + 1)".parse::<Ast>().unwrap());
+        assert!(!same(&a, &c));
+    }
+}
@@ -1,12 +1,66 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
+use std::io::{self, Write};
+use std::time::Instant;
 
 use super::lexer::*;
 use super::parser::*;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum InterpreterErrorKind {
     DivisionByZero,
+    /// 未定義の変数を参照した
+    UndefinedVariable(String),
+    /// 計算結果が整数の範囲を超えた
+    Overflow,
+    /// 負の指数でべき乗しようとした
+    NegativeExponent,
+    /// 未定義の関数を呼び出した
+    UnknownFunction(String),
+    /// 関数の引数の個数が合わない
+    WrongArgumentCount {
+        name: String,
+        expected: Arity,
+        found: usize,
+    },
+    /// 関数に渡せない値を渡した
+    InvalidArgument(String),
+    /// 変換先の処理系では表せない演算子・関数・変数
+    Unsupported(String),
+    /// 制限時間までに評価が終わらなかった
+    Timeout,
+    /// 評価の歩数が上限を超えた
+    TooManySteps,
+}
+
+/// 組み込み関数が受け取る引数の個数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    /// n個の引数を受け取れるかどうかを返す
+    pub fn accepts(self, n: usize) -> bool {
+        match self {
+            Arity::Exactly(m) => n == m,
+            Arity::AtLeast(m) => n >= m,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arity::Exactly(n) => write!(f, "{}個", n),
+            Arity::AtLeast(n) => write!(f, "{}個以上", n),
+        }
+    }
 }
 
 pub type InterpreterError = Annotation<InterpreterErrorKind>;
@@ -14,8 +68,25 @@ pub type InterpreterError = Annotation<InterpreterErrorKind>;
 impl fmt::Display for InterpreterError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::InterpreterErrorKind::*;
-        match self.value {
+        match &self.value {
             DivisionByZero => write!(f, "ゼロで除算できません"),
+            UndefinedVariable(name) => write!(f, "変数'{}'は定義されていません", name),
+            Overflow => write!(f, "計算結果が大きすぎます"),
+            NegativeExponent => write!(f, "負の数でべき乗できません"),
+            UnknownFunction(name) => write!(f, "関数'{}'は定義されていません", name),
+            WrongArgumentCount {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "関数'{}'の引数は{}ですが、{}個渡されました",
+                name, expected, found
+            ),
+            InvalidArgument(name) => write!(f, "関数'{}'に渡せない値です", name),
+            Unsupported(what) => write!(f, "'{}'は変換先で使えません", what),
+            Timeout => write!(f, "制限時間内に評価が終わりませんでした"),
+            TooManySteps => write!(f, "評価の歩数が上限を超えました"),
         }
     }
 }
@@ -25,57 +96,193 @@ impl Error for InterpreterError {
         use self::InterpreterErrorKind::*;
         match self.value {
             DivisionByZero => "the right hand expression of the division evaluates to zero",
+            UndefinedVariable(_) => "the variable is referenced before assignment",
+            Overflow => "the result does not fit in a 64-bit signed integer",
+            NegativeExponent => "the exponent evaluates to a negative number",
+            UnknownFunction(_) => "the function is not a built-in function",
+            WrongArgumentCount { .. } => "the number of arguments does not match the function",
+            InvalidArgument(_) => "the argument is out of the domain of the function",
+            Unsupported(_) => "the target of the compilation has no equivalent",
+            Timeout => "the evaluation did not finish within the time limit",
+            TooManySteps => "the evaluation took more steps than allowed",
         }
     }
 }
 
 impl InterpreterError {
+    /// エラーの詳細を標準エラー出力へ表示する
     pub fn show_diagnostic(&self, input: &str) {
-        // エラー情報を簡単に表示し
-        eprintln!("{}", self);
-        // エラー位置を指示する
-        print_annote(input, self.location.clone());
+        let _ = self.write_diagnostic(input, &mut io::stderr().lock());
+    }
+
+    /// エラーの詳細をwへ書き出す
+    pub fn write_diagnostic(&self, input: &str, w: &mut impl Write) -> io::Result<()> {
+        self.to_diagnostic().write(input, w)
     }
 }
 
 /// 評価器を表すデータ型
-pub struct Interpreter;
+///
+/// 変数の値を環境として保持するので、REPLの行をまたいで状態が引き継がれる。
+#[derive(Debug, Clone, Default)]
+pub struct Interpreter {
+    env: HashMap<String, i64>,
+    /// 評価を打ち切る時刻
+    deadline: Option<Instant>,
+    /// 1回の評価で訪れる節点の数の上限
+    max_steps: Option<usize>,
+}
 
 impl Interpreter {
     pub fn new() -> Self {
-        Interpreter
+        Interpreter {
+            env: HashMap::new(),
+            deadline: None,
+            max_steps: None,
+        }
+    }
+
+    ///
+    /// 評価を打ち切る時刻を設定する。
+    /// 節点を評価するたびに時刻を確かめ、過ぎていればTimeoutのエラーにする
+    ///
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    ///
+    /// 1回の評価で訪れる節点の数の上限を設定する。
+    /// 上限を超えて節点を訪れようとしたら、その節点を指すTooManyStepsのエラーにする
+    ///
+    pub fn set_max_steps(&mut self, max_steps: Option<usize>) {
+        self.max_steps = max_steps;
+    }
+
+    /// 変数の現在の値を返す
+    pub fn variable(&self, name: &str) -> Option<i64> {
+        self.env.get(name).cloned()
+    }
+
+    /// すべての変数とその値を返す
+    pub fn variables(&self) -> &HashMap<String, i64> {
+        &self.env
+    }
+
+    /// 変数をすべて置き換える
+    pub fn set_variables(&mut self, variables: HashMap<String, i64>) {
+        self.env = variables;
     }
 
     pub fn eval(&mut self, expr: &Ast) -> Result<i64, InterpreterError> {
+        self.eval_with(expr, &mut |_, _| {})
+    }
+
+    ///
+    /// 式を評価し、部分式の値が求まるたびにその節点と値をon_valueへ渡す。
+    /// 深く入れ子になった式でもネイティブのスタックを使い切らないよう、
+    /// 再帰せずに作業のスタックと値のスタックで構文木を辿る。
+    ///
+    pub fn eval_with<F>(&mut self, expr: &Ast, on_value: &mut F) -> Result<i64, InterpreterError>
+    where
+        F: FnMut(&Ast, i64),
+    {
         use self::AstKind::*;
-        match expr.value {
-            Num(n) => Ok(n as i64),
-            Unary {
-                ref operator, // match式は値を可能な限り所有しようとする。それでは都合が悪い場合、"ref" で参照する。
-                ref operand,
-            } => {
-                let operand = self.eval(operand)?;
-                Ok(self.eval_uniop(operator, operand))
-            }
-            Binary {
-                ref operator,
-                ref left,
-                ref right,
-            } => {
-                let left = self.eval(left)?;
-                let right = self.eval(right)?;
-                self.eval_binop(operator, left, right)
-                    .map_err(|e| InterpreterError::new(e, expr.location.clone()))
-            }
+        let mut tasks = vec![Task::Visit(expr)];
+        let mut values: Vec<i64> = Vec::new();
+        let mut steps = 0;
+        while let Some(task) = tasks.pop() {
+            let expr = match task {
+                // 子を左から順に評価するよう、逆順に積む
+                Task::Visit(expr) => {
+                    if past(self.deadline) {
+                        return Err(InterpreterError::new(
+                            InterpreterErrorKind::Timeout,
+                            expr.location.clone(),
+                        ));
+                    }
+                    steps += 1;
+                    if self.max_steps.is_some_and(|max_steps| steps > max_steps) {
+                        return Err(InterpreterError::new(
+                            InterpreterErrorKind::TooManySteps,
+                            expr.location.clone(),
+                        ));
+                    }
+                    match expr.value {
+                        Num(_) | Var(_) => {}
+                        Assign { ref value, .. } => {
+                            tasks.push(Task::Apply(expr));
+                            tasks.push(Task::Visit(value));
+                            continue;
+                        }
+                        Unary { ref operand, .. } => {
+                            tasks.push(Task::Apply(expr));
+                            tasks.push(Task::Visit(operand));
+                            continue;
+                        }
+                        Binary {
+                            ref left,
+                            ref right,
+                            ..
+                        } => {
+                            tasks.push(Task::Apply(expr));
+                            tasks.push(Task::Visit(right));
+                            tasks.push(Task::Visit(left));
+                            continue;
+                        }
+                        Call { ref args, .. } => {
+                            tasks.push(Task::Apply(expr));
+                            tasks.extend(args.iter().rev().map(Task::Visit));
+                            continue;
+                        }
+                    }
+                    expr
+                }
+                Task::Apply(expr) => expr,
+            };
+            // 子の値はすべて値のスタックに積まれている
+            let value = match expr.value {
+                Num(n) => literal(n).map_err(|e| InterpreterError::new(e, expr.location.clone())),
+                Var(ref name) => self.variable(name).ok_or_else(|| {
+                    InterpreterError::new(
+                        InterpreterErrorKind::UndefinedVariable(name.clone()),
+                        expr.location.clone(),
+                    )
+                }),
+                Assign { ref name, .. } => {
+                    let value = values.pop().unwrap();
+                    self.env.insert(name.clone(), value);
+                    Ok(value)
+                }
+                Unary { ref operator, .. } => {
+                    let operand = values.pop().unwrap();
+                    self.eval_uniop(operator, operand)
+                        .map_err(|e| InterpreterError::new(e, operator.location.clone()))
+                }
+                Binary { ref operator, .. } => {
+                    let right = values.pop().unwrap();
+                    let left = values.pop().unwrap();
+                    // 演算のエラーは演算子の位置を指す
+                    self.eval_binop(operator, left, right)
+                        .map_err(|e| InterpreterError::new(e, operator.location.clone()))
+                }
+                Call { ref name, ref args } => {
+                    let args = values.split_off(values.len() - args.len());
+                    apply_function(name, &args)
+                        .map_err(|e| InterpreterError::new(e, expr.location.clone()))
+                }
+            }?;
+            on_value(expr, value);
+            values.push(value);
         }
+        Ok(values.pop().unwrap())
     }
 
-    fn eval_uniop(&mut self, operator: &UnaryOperator, operand: i64) -> i64 {
-        use super::parser::UnaryOperatorKind::*;
-        match operator.value {
-            Plus => operand,
-            Minus => -operand,
-        }
+    fn eval_uniop(
+        &mut self,
+        operator: &UnaryOperator,
+        operand: i64,
+    ) -> Result<i64, InterpreterErrorKind> {
+        apply_uniop(&operator.value, operand)
     }
 
     fn eval_binop(
@@ -84,18 +291,284 @@ impl Interpreter {
         left: i64,
         right: i64,
     ) -> Result<i64, InterpreterErrorKind> {
-        use super::parser::BinaryOperatorKind::*;
-        match operator.value {
-            Add => Ok(left + right),
-            Sub => Ok(left - right),
-            Multi => Ok(left * right),
-            Div => {
-                if right == 0 {
-                    Err(InterpreterErrorKind::DivisionByZero)
-                } else {
-                    Ok(left / right)
+        apply_binop(&operator.value, left, right)
+    }
+}
+
+/// 評価器が構文木を辿るときの作業
+enum Task<'a> {
+    /// 節点を評価し始める
+    Visit(&'a Ast),
+    /// 子の値から節点の値を求める
+    Apply(&'a Ast),
+}
+
+/// 打ち切る時刻を過ぎたかどうか
+pub(crate) fn past(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// 数値リテラルを符号付き整数へ変換する
+pub(crate) fn literal(n: u64) -> Result<i64, InterpreterErrorKind> {
+    i64::try_from(n).map_err(|_| InterpreterErrorKind::Overflow)
+}
+
+///
+/// 単項演算を計算する
+///
+pub(crate) fn apply_uniop(
+    operator: &UnaryOperatorKind,
+    operand: i64,
+) -> Result<i64, InterpreterErrorKind> {
+    use super::parser::UnaryOperatorKind::*;
+    match operator {
+        Plus => Ok(operand),
+        Minus => operand.checked_neg().ok_or(InterpreterErrorKind::Overflow),
+    }
+}
+
+///
+/// 二項演算を計算する。
+/// 評価器とバイトコードVMで同じ結果になるよう、計算はここにまとめる。
+///
+pub(crate) fn apply_binop(
+    operator: &BinaryOperatorKind,
+    left: i64,
+    right: i64,
+) -> Result<i64, InterpreterErrorKind> {
+    use super::parser::BinaryOperatorKind::*;
+    match operator {
+        Add => left
+            .checked_add(right)
+            .ok_or(InterpreterErrorKind::Overflow),
+        Sub => left
+            .checked_sub(right)
+            .ok_or(InterpreterErrorKind::Overflow),
+        Multi => left
+            .checked_mul(right)
+            .ok_or(InterpreterErrorKind::Overflow),
+        Div => {
+            if right == 0 {
+                Err(InterpreterErrorKind::DivisionByZero)
+            } else {
+                // i64::MIN / -1 はオーバーフローする
+                left.checked_div(right)
+                    .ok_or(InterpreterErrorKind::Overflow)
+            }
+        }
+        Pow => {
+            if right < 0 {
+                return Err(InterpreterErrorKind::NegativeExponent);
+            }
+            // 0 ^ 0も含め、0乗は1とする（i64::checked_powと同じ）
+            if right == 0 {
+                return Ok(1);
+            }
+            // u32に収まらない指数は、底が0, 1, -1でない限り必ずオーバーフローする
+            match left {
+                0 | 1 => Ok(left),
+                -1 => Ok(if right % 2 == 0 { 1 } else { -1 }),
+                _ => {
+                    let exp = u32::try_from(right).map_err(|_| InterpreterErrorKind::Overflow)?;
+                    left.checked_pow(exp).ok_or(InterpreterErrorKind::Overflow)
                 }
             }
         }
+        BitOr => Ok(left | right),
+    }
+}
+
+/// 組み込み関数の引数の個数を返す。未定義の関数ならNoneを返す
+pub fn function_arity(name: &str) -> Option<Arity> {
+    match name {
+        "sqrt" | "abs" => Some(Arity::Exactly(1)),
+        "pow" => Some(Arity::Exactly(2)),
+        "min" | "max" => Some(Arity::AtLeast(1)),
+        _ => None,
+    }
+}
+
+///
+/// 組み込み関数を計算する。
+/// 評価器とバイトコードVMで同じ結果になるよう、計算はここにまとめる。
+///
+pub(crate) fn apply_function(name: &str, args: &[i64]) -> Result<i64, InterpreterErrorKind> {
+    let expected = function_arity(name)
+        .ok_or_else(|| InterpreterErrorKind::UnknownFunction(name.to_string()))?;
+    if !expected.accepts(args.len()) {
+        return Err(InterpreterErrorKind::WrongArgumentCount {
+            name: name.to_string(),
+            expected,
+            found: args.len(),
+        });
+    }
+    match name {
+        // 整数の平方根は小数点以下を切り捨てる
+        "sqrt" if args[0] < 0 => Err(InterpreterErrorKind::InvalidArgument(name.to_string())),
+        "sqrt" => Ok(args[0].isqrt()),
+        "abs" => args[0].checked_abs().ok_or(InterpreterErrorKind::Overflow),
+        "pow" => apply_binop(&BinaryOperatorKind::Pow, args[0], args[1]),
+        "min" => Ok(args.iter().cloned().min().unwrap()),
+        "max" => Ok(args.iter().cloned().max().unwrap()),
+        _ => unreachable!("function_arity and apply_function disagree on '{}'", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pow() {
+        let mut interpreter = Interpreter::new();
+        let mut eval = |s: &str| interpreter.eval(&s.parse::<Ast>().unwrap());
+        assert_eq!(eval("2 ^ 3 ^ 2"), Ok(512));
+        assert_eq!(eval("-2 ^ 2"), Ok(-4));
+        assert_eq!(eval("(-2) ^ 3"), Ok(-8));
+        assert_eq!(eval("2 * 3 ^ 2"), Ok(18));
+        // 0乗は底によらず1
+        assert_eq!(eval("0 ^ 0"), Ok(1));
+        assert_eq!(eval("pow(0, 0)"), Ok(1));
+        assert_eq!(eval("x = 0"), Ok(0));
+        assert_eq!(eval("x ^ 0"), Ok(1));
+        assert_eq!(eval("0 ^ 3"), Ok(0));
+        assert_eq!(eval("1 ^ 0"), Ok(1));
+        assert_eq!(eval("(-1) ^ 0"), Ok(1));
+        assert_eq!(
+            eval("2 ^ 64"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::Overflow,
+                Location(2, 3)
+            ))
+        );
+        assert_eq!(
+            eval("2 ^ -1"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::NegativeExponent,
+                Location(2, 3)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_radix_literals() {
+        let mut interpreter = Interpreter::new();
+        let mut eval = |s: &str| interpreter.eval(&s.parse::<Ast>().unwrap());
+        assert_eq!(eval("0x1F + 0o17 + 0b1010"), Ok(56));
+        assert_eq!(eval("0x7fffffffffffffff"), Ok(i64::MAX));
+    }
+
+    #[test]
+    fn test_nested_unary() {
+        let mut interpreter = Interpreter::new();
+        let mut eval = |s: &str| interpreter.eval(&s.parse::<Ast>().unwrap());
+        assert_eq!(eval("--5"), Ok(5));
+        assert_eq!(eval("- -(-3)"), Ok(-3));
+        assert_eq!(eval("-+-2"), Ok(2));
+        assert_eq!(eval("1 - --1"), Ok(0));
+        assert_eq!(eval("--2 ^ 2"), Ok(4));
+        // 内側の符号反転で桁あふれすれば、その演算子を指す
+        assert_eq!(eval("x = -9223372036854775807 - 1"), Ok(i64::MIN));
+        assert_eq!(
+            eval("--x"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::Overflow,
+                Location(1, 2)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut interpreter = Interpreter::new();
+        let mut eval = |s: &str| interpreter.eval(&s.parse::<Ast>().unwrap());
+        let overflow = |loc| Err(InterpreterError::new(InterpreterErrorKind::Overflow, loc));
+        assert_eq!(eval("9223372036854775807 + 1"), overflow(Location(20, 21)));
+        assert_eq!(eval("-9223372036854775807 - 2"), overflow(Location(21, 22)));
+        assert_eq!(eval("4294967296 * 4294967296"), overflow(Location(11, 12)));
+        assert_eq!(eval("9223372036854775808"), overflow(Location(0, 19)));
+        assert_eq!(eval("x = -9223372036854775807 - 1"), Ok(i64::MIN));
+        assert_eq!(eval("-x"), overflow(Location(0, 1)));
+        assert_eq!(eval("x / -1"), overflow(Location(2, 3)));
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut interpreter = Interpreter::new();
+        let assign = "x = 1 + 2".parse::<Ast>().unwrap();
+        assert_eq!(interpreter.eval(&assign), Ok(3));
+        // 代入した値は次の評価でも参照できる
+        let use_var = "x * x".parse::<Ast>().unwrap();
+        assert_eq!(interpreter.eval(&use_var), Ok(9));
+
+        let undefined = "1 + y".parse::<Ast>().unwrap();
+        assert_eq!(
+            interpreter.eval(&undefined),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::UndefinedVariable("y".to_string()),
+                Location(4, 5)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut interpreter = Interpreter::new();
+        let mut eval = |s: &str| interpreter.eval(&s.parse::<Ast>().unwrap());
+        assert_eq!(eval("sqrt(16)"), Ok(4));
+        assert_eq!(eval("sqrt(17)"), Ok(4));
+        assert_eq!(eval("abs(-3)"), Ok(3));
+        assert_eq!(eval("min(2, 5)"), Ok(2));
+        assert_eq!(eval("max(2, 5 * 2, -1)"), Ok(10));
+        assert_eq!(eval("pow(2, 10) + 1"), Ok(1025));
+        assert_eq!(eval("||-3| - 5|"), Ok(2));
+        assert_eq!(eval("|2 | 4| | 1"), Ok(7));
+        assert_eq!(
+            eval("1 + sqrt(-1)"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::InvalidArgument("sqrt".to_string()),
+                Location(4, 12)
+            ))
+        );
+        assert_eq!(
+            eval("pow(2)"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::WrongArgumentCount {
+                    name: "pow".to_string(),
+                    expected: Arity::Exactly(2),
+                    found: 1,
+                },
+                Location(0, 6)
+            ))
+        );
+        assert_eq!(
+            eval("f(1)"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::UnknownFunction("f".to_string()),
+                Location(0, 4)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        // 構文解析器の入れ子の上限を超える深さの木も、スタックを使い切らずに評価できる
+        let loc = || Location(0, 0);
+        let n = 100_000;
+        let mut ast = Ast::var("x", loc());
+        for i in 0..n {
+            ast = match i % 3 {
+                0 => Ast::binary(BinaryOperator::add(loc()), Ast::num(1, loc()), ast, loc()),
+                1 => Ast::unary(UnaryOperator::minus(loc()), ast, loc()),
+                _ => Ast::call("abs", vec![ast], loc()),
+            };
+        }
+        let mut interpreter = Interpreter::new();
+        interpreter.eval(&"x = 5".parse().unwrap()).unwrap();
+        let mut count = 0;
+        let value = interpreter.eval_with(&ast, &mut |_, _| count += 1);
+        assert_eq!(value, Ok(5 + (n as i64 + 2) / 3));
+        // 節点ごとに1度ずつ値を知らせる
+        assert_eq!(count, n + (n + 2) / 3 + 1);
     }
 }
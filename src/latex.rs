@@ -0,0 +1,270 @@
+//!
+//! LaTeXの数式への変換。
+//! 除算は分数（\frac）、べき乗は上付き文字で書き、かっこは必要なところだけに\left(\right)で補う。
+//! REPLで書いた式を文書へ貼り付けるのに使う。
+//!
+use super::compiler::{Backend, CompileError};
+use super::minify::infix_definition;
+use super::parser::*;
+
+/// 単項演算子の優先順位。演算子の表と同じく、乗除より強くべき乗より弱い
+const UNARY_PRECEDENCE: u8 = 4;
+
+///
+/// LaTeXの数式へのコンパイラ。
+/// 出力は数式環境の中身だけで、"$"や"\["は付けない。
+///
+/// ```
+/// use parser::compiler::Backend;
+/// use parser::latex::LatexCompiler;
+/// use parser::parser::Ast;
+///
+/// let ast = "(1 + 2) / 3 * -x ^ 2".parse::<Ast>().unwrap();
+/// let latex = LatexCompiler::new().compile(&ast).unwrap();
+/// assert_eq!(latex, r"\frac{1 + 2}{3} \cdot \left(-x^{2}\right)");
+/// ```
+///
+#[derive(Default)]
+pub struct LatexCompiler;
+
+impl LatexCompiler {
+    pub fn new() -> Self {
+        LatexCompiler
+    }
+}
+
+impl Backend for LatexCompiler {
+    fn name(&self) -> &str {
+        "latex"
+    }
+
+    fn compile_into(&mut self, expr: &Ast, buf: &mut String) -> Result<(), CompileError> {
+        buf.clear();
+        write_statement(expr, buf);
+        Ok(())
+    }
+}
+
+///
+/// 数式での優先順位。
+/// 分数は分子と分母が線で区切られるので、数や変数と同じくどこに置いてもかっこが要らない。
+///
+fn precedence(expr: &Ast) -> u8 {
+    match expr.value {
+        AstKind::Assign { .. } => 0,
+        AstKind::Unary { .. } => UNARY_PRECEDENCE,
+        AstKind::Binary { ref operator, .. } if operator.value == BinaryOperatorKind::Div => {
+            u8::MAX
+        }
+        AstKind::Binary { ref operator, .. } => infix_definition(&operator.value).precedence,
+        AstKind::Num(_) | AstKind::Var(_) | AstKind::Call { .. } => u8::MAX,
+    }
+}
+
+/// LaTeXへ変換するときの作業
+enum Step<'a> {
+    /// 代入は右辺へ続く代入の連なりとして書く
+    Statement(&'a Ast),
+    /// 優先順位が指定より弱い式はかっこで囲んで書く
+    Expr(&'a Ast, u8),
+    /// かっこを補わずに書く
+    Inner(&'a Ast),
+    /// 二項演算子を前後の空白とともに書く
+    Operator(&'a BinaryOperatorKind),
+    /// 文字列をそのまま書く
+    Text(&'static str),
+}
+
+///
+/// 文を書く。
+/// 長い演算子の連なりでもネイティブのスタックを使い切らないよう、
+/// 再帰せずに作業のスタックで辿る。作業は書き出す順の逆に積む。
+///
+fn write_statement(expr: &Ast, buf: &mut String) {
+    let mut steps = vec![Step::Statement(expr)];
+    while let Some(step) = steps.pop() {
+        match step {
+            Step::Statement(expr) => match expr.value {
+                AstKind::Assign {
+                    ref name,
+                    ref value,
+                } => {
+                    write_name(name, buf);
+                    buf.push_str(" = ");
+                    steps.push(Step::Statement(value));
+                }
+                _ => steps.push(Step::Inner(expr)),
+            },
+            Step::Expr(expr, min_precedence) if precedence(expr) < min_precedence => {
+                buf.push_str(r"\left(");
+                steps.extend([Step::Text(r"\right)"), Step::Statement(expr)]);
+            }
+            Step::Expr(expr, _) | Step::Inner(expr) => write_inner(expr, buf, &mut steps),
+            Step::Operator(kind) => match kind {
+                BinaryOperatorKind::Multi => buf.push_str(r" \cdot "),
+                BinaryOperatorKind::BitOr => buf.push_str(r" \mathbin{|} "),
+                kind => {
+                    buf.push(' ');
+                    buf.push_str(&kind.to_string());
+                    buf.push(' ');
+                }
+            },
+            Step::Text(text) => buf.push_str(text),
+        }
+    }
+}
+
+/// 節点の先頭をbufへ書き、残りを作業として積む
+fn write_inner<'a>(expr: &'a Ast, buf: &mut String, steps: &mut Vec<Step<'a>>) {
+    match expr.value {
+        AstKind::Num(n) => buf.push_str(&n.to_string()),
+        AstKind::Var(ref name) => write_name(name, buf),
+        // 式の途中の代入は構文解析器が作らないので、かっこで囲むだけにする
+        AstKind::Assign { .. } => steps.push(Step::Expr(expr, 1)),
+        // "--x"は読みにくいので、被演算子が単項演算子ならかっこで囲む
+        AstKind::Unary {
+            ref operator,
+            ref operand,
+        } => {
+            buf.push_str(&operator.value.to_string());
+            steps.push(Step::Expr(operand, UNARY_PRECEDENCE + 1));
+        }
+        AstKind::Binary {
+            ref operator,
+            ref left,
+            ref right,
+        } => match operator.value {
+            // 分子と分母は波かっこの中なので、かっこは要らない
+            BinaryOperatorKind::Div => {
+                buf.push_str(r"\frac{");
+                steps.extend([
+                    Step::Text("}"),
+                    Step::Statement(right),
+                    Step::Text("}{"),
+                    Step::Statement(left),
+                ]);
+            }
+            // 底は数、変数、関数呼び出しのほかはかっこで囲む。"x^{2}^{3}"はLaTeXの誤りになる
+            BinaryOperatorKind::Pow => {
+                steps.extend([Step::Text("}"), Step::Statement(right), Step::Text("^{")]);
+                match left.value {
+                    AstKind::Num(_) | AstKind::Var(_) | AstKind::Call { .. } => {
+                        steps.push(Step::Inner(left))
+                    }
+                    _ => steps.extend([
+                        Step::Text(r"\right)"),
+                        Step::Statement(left),
+                        Step::Text(r"\left("),
+                    ]),
+                }
+            }
+            ref kind => {
+                let def = infix_definition(kind);
+                // 左結合なら、右辺に同じ優先順位の演算子があればかっこが要る
+                let (left_min, right_min) = match def.associativity {
+                    Associativity::Left => (def.precedence, def.precedence + 1),
+                    Associativity::Right => (def.precedence + 1, def.precedence),
+                };
+                // 右辺の単項演算子は演算子が並んで読みにくいので、かっこで囲む
+                match right.value {
+                    AstKind::Unary { .. } => steps.push(Step::Expr(right, u8::MAX)),
+                    _ => steps.push(Step::Expr(right, right_min)),
+                }
+                steps.extend([Step::Operator(kind), Step::Expr(left, left_min)]);
+            }
+        },
+        AstKind::Call { ref name, ref args } => match (name.as_str(), args.as_slice()) {
+            ("abs", [arg]) => {
+                buf.push_str(r"\left|");
+                steps.extend([Step::Text(r"\right|"), Step::Statement(arg)]);
+            }
+            ("sqrt", [arg]) => {
+                buf.push_str(r"\sqrt{");
+                steps.extend([Step::Text("}"), Step::Statement(arg)]);
+            }
+            _ => {
+                match name.as_str() {
+                    "min" | "max" => {
+                        buf.push('\\');
+                        buf.push_str(name);
+                    }
+                    _ => {
+                        buf.push_str(r"\operatorname{");
+                        write_escaped(name, buf);
+                        buf.push('}');
+                    }
+                }
+                buf.push_str(r"\left(");
+                steps.push(Step::Text(r"\right)"));
+                for (i, arg) in args.iter().enumerate().rev() {
+                    steps.push(Step::Statement(arg));
+                    if i > 0 {
+                        steps.push(Step::Text(", "));
+                    }
+                }
+            }
+        },
+    }
+}
+
+/// 1文字の変数名はそのまま、2文字以上は1つの語として斜体で書く
+fn write_name(name: &str, buf: &mut String) {
+    if name.len() == 1 {
+        write_escaped(name, buf);
+    } else {
+        buf.push_str(r"\mathit{");
+        write_escaped(name, buf);
+        buf.push('}');
+    }
+}
+
+/// 名前の"_"は下付き文字にならないよう"\_"にする
+fn write_escaped(name: &str, buf: &mut String) {
+    for c in name.chars() {
+        if c == '_' {
+            buf.push('\\');
+        }
+        buf.push(c);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(input: &str) -> String {
+        LatexCompiler::new()
+            .compile(&input.parse().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_latex() {
+        assert_eq!(compile("(1 + 2) / 3"), r"\frac{1 + 2}{3}");
+        assert_eq!(compile("1 + 2 * 3"), r"1 + 2 \cdot 3");
+        assert_eq!(compile("(1 + 2) * 3"), r"\left(1 + 2\right) \cdot 3");
+        assert_eq!(compile("8 - (4 - 2) - 1"), r"8 - \left(4 - 2\right) - 1");
+        // 分数は分子と分母の中にも外にもかっこが要らない
+        assert_eq!(compile("1 / (2 / x) * 4"), r"\frac{1}{\frac{2}{x}} \cdot 4");
+        // べき乗は右結合で、指数は波かっこの中に書く
+        assert_eq!(compile("2 ^ 3 ^ 2"), r"2^{3^{2}}");
+        assert_eq!(compile("(2 ^ 3) ^ (1 + 1)"), r"\left(2^{3}\right)^{1 + 1}");
+        assert_eq!(compile("(1 / 2) ^ 2"), r"\left(\frac{1}{2}\right)^{2}");
+        // 単項演算子はべき乗より弱い
+        assert_eq!(compile("-2 ^ 2"), r"-2^{2}");
+        assert_eq!(compile("(-2) ^ 2"), r"\left(-2\right)^{2}");
+        assert_eq!(compile("-(2 * 3)"), r"-\left(2 \cdot 3\right)");
+        assert_eq!(compile("-2 * 3 - -x"), r"-2 \cdot 3 - \left(-x\right)");
+        assert_eq!(compile("- - x"), r"-\left(-x\right)");
+        assert_eq!(compile("1 | 2 + 3"), r"1 \mathbin{|} 2 + 3");
+        // 組み込み関数は数式の記号で書く
+        assert_eq!(
+            compile("x = |y| + sqrt(2) - max(1, 2)"),
+            r"x = \left|y\right| + \sqrt{2} - \max\left(1, 2\right)"
+        );
+        assert_eq!(
+            compile("rate_1 = my_f(sqrt(1, 2))"),
+            r"\mathit{rate\_1} = \operatorname{my\_f}\left(\operatorname{sqrt}\left(1, 2\right)\right)"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//!
+//! 字句解析の様子を1文字ずつ示すダンプ。
+//! タブや全角文字、制御文字を含む入力で字句解析器がどう振る舞ったかを報告しやすくする。
+//!
+use std::fmt::Write;
+
+use super::lexer::*;
+
+///
+/// 入力の各文字について、位置（文字数）、UTF-8のバイト列、文字、分類、トークンの境界を並べる。
+/// トークンの境界は、始まりを"["、終わりを"]"、途中を"-"で示し、始まりの行にトークンを書く。
+/// 字句解析のエラーは、その始まりの行に"!"を付けて書く。
+///
+/// ```text
+/// offset  bytes        char      class     token
+///      0  31           '1'       digit     [  1
+///      1  30           '0'       digit     ]
+///      2  09           '\t'      space
+///      3  2b           '+'       operator  [] +
+/// ```
+///
+pub fn format_lex_debug(input: &str) -> String {
+    let (tokens, errors) = lex_all_errors(input);
+    let mut buf = String::new();
+    writeln!(
+        buf,
+        "{:>6}  {:<11}  {:<8}  {:<8}  token",
+        "offset", "bytes", "char", "class"
+    )
+    .unwrap();
+    let mut in_comment = false;
+    for (n, (i, c)) in input.char_indices().enumerate() {
+        let bytes: Vec<_> = input.as_bytes()[i..i + c.len_utf8()]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let token = tokens
+            .iter()
+            .find(|token| token.location.0 <= n && n < token.location.1);
+        let error = errors
+            .iter()
+            .find(|error| error.location.0 <= n && n < error.location.1);
+        let class = match (token, error) {
+            (Some(_), _) => classify(c),
+            (None, Some(_)) => "invalid",
+            // コメントは行末まで続く
+            (None, None) if in_comment && c != '\n' => "comment",
+            (None, None) if c.is_ascii_whitespace() => {
+                in_comment = false;
+                "space"
+            }
+            // 字句解析器がエラーにせずに読み飛ばした空白以外の文字は、コメントの始まりである
+            (None, None) => {
+                in_comment = true;
+                "comment"
+            }
+        };
+        let mark = match token {
+            Some(token) => {
+                let starts = token.location.0 == n;
+                let ends = token.location.1 == n + 1;
+                match (starts, ends) {
+                    (true, true) => format!("[] {}", token.value),
+                    (true, false) => format!("[  {}", token.value),
+                    (false, true) => "]".to_string(),
+                    (false, false) => "-".to_string(),
+                }
+            }
+            None => match error {
+                Some(error) if error.location.0 == n => format!("!  {}", error.message()),
+                _ => String::new(),
+            },
+        };
+        let line = format!(
+            "{:>6}  {:<11}  {:<8}  {:<8}  {}",
+            n,
+            bytes.join(" "),
+            format!("'{}'", c.escape_debug()),
+            class,
+            mark
+        );
+        writeln!(buf, "{}", line.trim_end()).unwrap();
+    }
+    buf
+}
+
+/// トークンの中の文字を分類する
+fn classify(c: char) -> &'static str {
+    match c {
+        '0'..='9' => "digit",
+        'a'..='z' | 'A'..='Z' | '_' => "letter",
+        '(' | ')' | ',' => "punct",
+        '.' => "point",
+        _ => "operator",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_lex_debug() {
+        assert_eq!(
+            format_lex_debug("10\t+ é # c"),
+            "offset  bytes        char      class     token\n     \
+                  0  31           '1'       digit     [  10\n     \
+                  1  30           '0'       digit     ]\n     \
+                  2  09           '\\t'      space\n     \
+                  3  2b           '+'       operator  [] +\n     \
+                  4  20           ' '       space\n     \
+                  5  c3 a9        'é'       invalid   !  invalid character 'é'\n     \
+                  6  20           ' '       space\n     \
+                  7  23           '#'       comment\n     \
+                  8  20           ' '       comment\n     \
+                  9  63           'c'       comment\n"
+        );
+    }
+}
@@ -1,14 +1,58 @@
+use smallvec::SmallVec;
+
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
 
 ///
 /// 入力文字の何文字目から何文字目までかを表す構造体。ただし、数値は0始まり。
 /// 例えばLocation(5, 8)は6文字目から9文字目までを表す。
+/// バイト数ではなく文字数で数えるので、マルチバイト文字も1文字として数える。
 ///
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Location(pub usize, pub usize);
 
 impl Location {
+    /// start文字目からend文字目の手前までを表す位置情報を作る
+    pub fn new(start: usize, end: usize) -> Self {
+        Location(start, end)
+    }
+
+    /// 始まりの位置
+    pub fn start(&self) -> usize {
+        self.0
+    }
+
+    /// 終わりの位置（この文字は含まない）
+    pub fn end(&self) -> usize {
+        self.1
+    }
+
+    /// 範囲の文字数
+    pub fn len(&self) -> usize {
+        self.1.saturating_sub(self.0)
+    }
+
+    /// 入力の終わりを指すエラーなどの、幅のない位置かどうか
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    ///
+    /// 範囲として返す。文字数で数えた範囲なので、文字列ではなく文字の配列を切り出すのに使う
+    ///
+    /// ```
+    /// use parser::lexer::Location;
+    ///
+    /// let chars: Vec<char> = "α + β".chars().collect();
+    /// assert_eq!(chars[Location::new(4, 5).as_range()], ['β']);
+    /// ```
+    ///
+    pub fn as_range(&self) -> std::ops::Range<usize> {
+        self.0..self.1
+    }
+
     ///
     ///　位置情報をマージする
     ///
@@ -24,15 +68,170 @@ impl fmt::Display for Location {
     }
 }
 
+///
+/// 入力中の位置（文字数）を行と列へ変換する対応表
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMap<'a> {
+    input: &'a str,
+    /// 各行の先頭の位置
+    line_starts: Vec<usize>,
+    /// 各行の先頭のバイト数。行の内容を切り出すのに使う
+    byte_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(input: &'a str) -> Self {
+        let mut line_starts = vec![0];
+        let mut byte_starts = vec![0];
+        for (n, (i, c)) in input.char_indices().enumerate() {
+            if c == '\n' {
+                line_starts.push(n + 1);
+                byte_starts.push(i + 1);
+            }
+        }
+        SourceMap {
+            input,
+            line_starts,
+            byte_starts,
+        }
+    }
+
+    /// 位置を含む行の番号を返す（0始まり）
+    pub fn line_index(&self, offset: usize) -> usize {
+        match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        }
+    }
+
+    /// 行の先頭の位置を返す
+    pub fn line_start(&self, line: usize) -> usize {
+        self.line_starts[line]
+    }
+
+    /// 行の内容を改行文字を除いて返す
+    pub fn line(&self, line: usize) -> &'a str {
+        let start = self.byte_starts[line];
+        let end = self
+            .byte_starts
+            .get(line + 1)
+            .map_or(self.input.len(), |next| next - 1);
+        self.input[start..end].trim_end_matches('\r')
+    }
+
+    ///
+    /// 位置を行番号と列番号へ変換する。どちらも1始まりで、列は文字数で数える。
+    ///
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = self.line_index(offset);
+        (line + 1, offset - self.line_starts[line] + 1)
+    }
+}
+
+/// 位置情報が指す行と"^"の注釈を標準エラー出力へ表示する
 pub fn print_annote(input: &str, loc: Location) {
-    eprintln!("{}", input);
-    eprintln!("{}{}", " ".repeat(loc.0), "^".repeat(loc.1 - loc.0));
+    eprintln!("{}", annotate(input, &loc));
+}
+
+///
+/// 位置情報が指す行を"行:列"と共に表示し、その下に"^"を並べた文字列を作る。
+///
+/// ```text
+/// 2:3 | 2 * )
+///     |     ^
+/// ```
+///
+pub fn annotate(input: &str, loc: &Location) -> String {
+    let map = SourceMap::new(input);
+    let line = map.line_index(loc.0);
+    let (line_no, col) = map.line_col(loc.0);
+    let text = map.line(line);
+    let start = map.line_start(line);
+    let line_end = start + text.chars().count();
+    // 複数行にまたがる場合は、最初の行の末尾までを指す
+    let end = if loc.1 > line_end && line_end < input.chars().count() {
+        line_end
+    } else {
+        loc.1
+    };
+    let prefix = format!("{}:{} | ", line_no, col);
+    let relative = Location(loc.0 - start, std::cmp::max(end, loc.0) - start);
+    format!(
+        "{}{}\n{}| {}",
+        prefix,
+        display_line(text),
+        " ".repeat(prefix.len() - 2),
+        caret_line(text, &relative)
+    )
+}
+
+///
+/// 位置情報が指す範囲の下に"^"を並べた行を作る。
+/// 全角文字などの幅やタブの位置を考慮するので、display_lineで表示した行と位置が揃う。
+/// 入力の末尾より後ろの位置は、1文字を幅1として扱う。
+///
+pub fn caret_line(input: &str, loc: &Location) -> String {
+    let mut padding = 0;
+    let mut carets = 0;
+    let mut len = 0;
+    for (i, c) in input.chars().enumerate() {
+        len += 1;
+        let width = display_width(c, padding + carets);
+        if i < loc.0 {
+            padding += width;
+        } else if i < loc.1 {
+            carets += width;
+        }
+    }
+    padding += loc.0.saturating_sub(len);
+    carets += loc.1.saturating_sub(std::cmp::max(loc.0, len));
+    format!(
+        "{}{}",
+        " ".repeat(padding),
+        "^".repeat(std::cmp::max(carets, 1))
+    )
+}
+
+/// タブを展開するときの間隔
+pub const TAB_WIDTH: usize = 4;
+
+///
+/// 入力の行を端末へ表示できる形にする。
+/// 制御文字はそのまま出力すると表示が乱れるので、タブは次のタブ位置までの空白に、
+/// それ以外はU+FFFDに置き換える。caret_lineも同じ幅で数えるので、"^"と位置が揃う。
+///
+pub fn display_line(line: &str) -> String {
+    let mut buf = String::with_capacity(line.len());
+    let mut column = 0;
+    for c in line.chars() {
+        let width = display_width(c, column);
+        match c {
+            '\t' => buf.push_str(&" ".repeat(width)),
+            c if c.is_control() => buf.push(char::REPLACEMENT_CHARACTER),
+            c => buf.push(c),
+        }
+        column += width;
+    }
+    buf
+}
+
+/// display_lineで表示したときの文字の幅。タブの幅は表示を始める列で決まる
+fn display_width(c: char, column: usize) -> usize {
+    use unicode_width::UnicodeWidthChar;
+
+    match c {
+        '\t' => TAB_WIDTH - column % TAB_WIDTH,
+        c if c.is_control() => 1,
+        c => c.width().unwrap_or(0),
+    }
 }
 
 ///
 /// トークンの種類などの値と位置情報を持つアノテーション。
 ///
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Annotation<T> {
     pub value: T,
     pub location: Location,
@@ -45,15 +244,32 @@ impl<T> Annotation<T> {
     pub fn new(value: T, location: Location) -> Self {
         Self { value, location }
     }
+
+    /// トークンの種類などの値
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    /// 値の位置情報
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
 }
 
 ///
-/// トークンの種類
+/// トークンの種類。
+/// 識別子の名前の持ち方をSで選ぶ。通常は所有したStringで持ち、
+/// BorrowedTokenでは入力の一部を指す&strで持つ。
 ///
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum TokenKind {
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TokenKind<S = String> {
     /// [0-9][0-9]*
     Number(u64),
+    /// [a-zA-Z_][a-zA-Z0-9_]*
+    Ident(S),
+    /// =
+    Equal,
     /// +
     Plus,
     /// -
@@ -62,23 +278,66 @@ pub enum TokenKind {
     Asterisk,
     /// /
     Slash,
+    /// ^
+    Caret,
     /// (
     LParen,
     /// )
     RParen,
+    /// ,
+    Comma,
+    /// |
+    Pipe,
+    /// 組み込み側が登録したLiteralReaderで読んだリテラル
+    Literal(Literal),
 }
 
-impl fmt::Display for TokenKind {
+///
+/// 組み込み側が定義したリテラル（GUIDやバージョン番号、金額など）の中身。
+/// 字句解析器は種類と値を運ぶだけで、その意味には立ち入らない。
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Literal {
+    /// 組み込み側が決めたリテラルの種類
+    pub kind: u32,
+    /// 数として扱えるリテラルの値。構文解析器は値のあるリテラルを数として読む
+    pub value: Option<u64>,
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            Some(n) => n.fmt(f),
+            None => write!(f, "<literal {}>", self.kind),
+        }
+    }
+}
+
+///
+/// 独自のリテラルを読む関数。
+/// 入力の残りを文字の配列で受け取り、先頭にリテラルがあればその長さ（文字数）と中身を返す。
+/// 位置情報は字句解析器が長さから付ける。
+///
+pub type LiteralReader = fn(&[char]) -> Option<(usize, Literal)>;
+
+impl<S: fmt::Display> fmt::Display for TokenKind<S> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::TokenKind::*;
         match self {
             Number(n) => n.fmt(f),
+            Ident(name) => name.fmt(f),
+            Equal => write!(f, "="),
             Plus => write!(f, "+"),
             Minus => write!(f, "-"),
             Asterisk => write!(f, "*"),
             Slash => write!(f, "/"),
+            Caret => write!(f, "^"),
             LParen => write!(f, "("),
             RParen => write!(f, ")"),
+            Comma => write!(f, ","),
+            Pipe => write!(f, "|"),
+            Literal(literal) => literal.fmt(f),
         }
     }
 }
@@ -86,11 +345,24 @@ impl fmt::Display for TokenKind {
 /// TokenKindを持つアノテーションをTokenとして定義する
 pub type Token = Annotation<TokenKind>;
 
+/// 入力での書き方と位置を書く（"'+' at 2-3"など）
+impl<S: fmt::Display> fmt::Display for Annotation<TokenKind<S>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'{}' at {}", self.value, self.location)
+    }
+}
+
 /// ファクトリメソッドをトークン種類ごとに用意する
 impl Token {
     pub fn number(n: u64, location: Location) -> Self {
         Self::new(TokenKind::Number(n), location)
     }
+    pub fn ident(name: &str, location: Location) -> Self {
+        Self::new(TokenKind::Ident(name.to_string()), location)
+    }
+    pub fn equal(location: Location) -> Self {
+        Self::new(TokenKind::Equal, location)
+    }
     pub fn plus(location: Location) -> Self {
         Self::new(TokenKind::Plus, location)
     }
@@ -103,21 +375,83 @@ impl Token {
     pub fn slash(location: Location) -> Self {
         Self::new(TokenKind::Slash, location)
     }
+    pub fn caret(location: Location) -> Self {
+        Self::new(TokenKind::Caret, location)
+    }
     pub fn lparen(location: Location) -> Self {
         Self::new(TokenKind::LParen, location)
     }
     pub fn rparen(location: Location) -> Self {
         Self::new(TokenKind::RParen, location)
     }
+    pub fn comma(location: Location) -> Self {
+        Self::new(TokenKind::Comma, location)
+    }
+    pub fn pipe(location: Location) -> Self {
+        Self::new(TokenKind::Pipe, location)
+    }
+}
+
+impl<S> TokenKind<S> {
+    /// 識別子の名前の持ち方を変える。識別子以外のトークンはそのまま移す
+    pub fn map_ident<T>(self, f: impl FnOnce(S) -> T) -> TokenKind<T> {
+        use self::TokenKind::*;
+        match self {
+            Number(n) => Number(n),
+            Ident(name) => Ident(f(name)),
+            Equal => Equal,
+            Plus => Plus,
+            Minus => Minus,
+            Asterisk => Asterisk,
+            Slash => Slash,
+            Caret => Caret,
+            LParen => LParen,
+            RParen => RParen,
+            Comma => Comma,
+            Pipe => Pipe,
+            Literal(literal) => Literal(literal),
+        }
+    }
+}
+
+///
+/// 識別子の名前を入力の文字列から借りるトークン。
+/// 数は値としてトークンに収めるので、トークンごとのメモリ確保が一切ない。
+/// 大きなファイルをまとめて字句解析する場合に使う。
+///
+pub type BorrowedToken<'src> = Annotation<TokenKind<&'src str>>;
+
+impl BorrowedToken<'_> {
+    /// 名前を所有するトークンにする
+    pub fn into_owned(self) -> Token {
+        Token::new(self.value.map_ident(str::to_string), self.location)
+    }
 }
 
 ///
 /// 字句解析エラーの種類
 ///
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum LexErrorKind {
     /// 無効な文字
     InvalidChar(char),
+    /// 数値が大きすぎて表せない
+    NumberTooLarge,
+    /// 数値の直後に識別子が続いている（"2x"など）
+    IdentAfterNumber,
+    /// "0x"などの接頭辞の後に数字がない
+    MissingDigits,
+    /// 接頭辞で決まる基数では使えない数字（"0b12"の"2"など）
+    InvalidDigit(char),
+    /// 小数点や負の指数を使った数値が整数にならない（"2.5"、"1e-3"など）
+    NotAnInteger,
+    /// UTF-8として正しくないバイト列。位置は元のバイト列の中の範囲
+    InvalidUtf8 { start: usize, end: usize },
+    /// 入力の文字数が上限を超えた。位置は上限を超えた部分
+    InputTooLong,
+    /// トークンの数が上限を超えた。位置は上限を超えた最初のトークン
+    TooManyTokens,
     /// 文字列の終わり
     Eof,
 }
@@ -130,136 +464,744 @@ impl LexError {
     fn invalid_char(c: char, location: Location) -> Self {
         Self::new(LexErrorKind::InvalidChar(c), location)
     }
+    fn number_too_large(location: Location) -> Self {
+        Self::new(LexErrorKind::NumberTooLarge, location)
+    }
+    fn ident_after_number(location: Location) -> Self {
+        Self::new(LexErrorKind::IdentAfterNumber, location)
+    }
+    fn missing_digits(location: Location) -> Self {
+        Self::new(LexErrorKind::MissingDigits, location)
+    }
+    fn invalid_digit(c: char, location: Location) -> Self {
+        Self::new(LexErrorKind::InvalidDigit(c), location)
+    }
     fn eof(location: Location) -> Self {
         Self::new(LexErrorKind::Eof, location)
     }
 }
 
+impl LexError {
+    /// 位置を除いたエラーの説明を返す
+    pub fn message(&self) -> String {
+        use self::LexErrorKind::*;
+        match self.value {
+            // 制御文字などはそのまま書くと端末の表示が乱れるので、エスケープして書く
+            InvalidChar(c) => format!("invalid character '{}'", c.escape_debug()),
+            NumberTooLarge => "number literal is too large".to_string(),
+            IdentAfterNumber => "number literal is directly followed by an identifier".to_string(),
+            MissingDigits => "number literal has no digits after its prefix".to_string(),
+            InvalidDigit(c) => format!("invalid digit '{}' in number literal", c),
+            NotAnInteger => "number literal is not an integer".to_string(),
+            InvalidUtf8 { start, end } => {
+                format!("invalid UTF-8 sequence at bytes {}-{}", start, end)
+            }
+            InputTooLong => "input is too long".to_string(),
+            TooManyTokens => "input has too many tokens".to_string(),
+            Eof => "End of file".to_string(),
+        }
+    }
+}
+
 impl fmt::Display for LexError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use self::LexErrorKind::*;
         match self.value {
-            InvalidChar(c) => write!(f, "{}: invalid character '{}'", self.location, c),
-            Eof => write!(f, "End of file"),
+            LexErrorKind::Eof => write!(f, "{}", self.message()),
+            _ => write!(f, "{}: {}", self.location, self.message()),
         }
     }
 }
 
 impl Error for LexError {}
 
+///
+/// トークンの列。
+/// ほとんどの式はトークンが16個未満なので、その範囲ではヒープ確保を行わない。
+///
+pub type Tokens = SmallVec<[Token; 16]>;
+
+///
+/// トークンの領域を使い回す字句解析器。
+/// REPLのように何度も字句解析を行う場合、行ごとのメモリ確保を減らせる。
+///
+#[derive(Debug, Clone, Default)]
+pub struct Lexer {
+    tokens: Tokens,
+    /// 入力を文字ごとに分けたもの
+    chars: Vec<char>,
+    /// 組み込みのトークンより先に試す、独自のリテラルを読む関数
+    literals: Vec<LiteralReader>,
+}
+
+impl Lexer {
+    pub fn new() -> Self {
+        Lexer {
+            tokens: Tokens::new(),
+            chars: Vec::new(),
+            literals: Vec::new(),
+        }
+    }
+
+    ///
+    /// 独自のリテラルを読む関数を登録する。
+    /// 登録した順に、組み込みのトークンより先に試す。
+    ///
+    /// ```
+    /// use parser::lexer::{Lexer, Literal, TokenKind};
+    ///
+    /// // "$1,200"のような金額を数として読む
+    /// fn dollars(input: &[char]) -> Option<(usize, Literal)> {
+    ///     if input.first() != Some(&'$') {
+    ///         return None;
+    ///     }
+    ///     let len = 1 + input[1..]
+    ///         .iter()
+    ///         .take_while(|c| c.is_ascii_digit() || **c == ',')
+    ///         .count();
+    ///     let digits: String = input[1..len].iter().filter(|c| **c != ',').collect();
+    ///     let value = digits.parse().ok();
+    ///     Some((len, Literal { kind: 1, value }))
+    /// }
+    ///
+    /// let mut lexer = Lexer::new();
+    /// lexer.register_literal(dollars);
+    /// let tokens = lexer.lex("$1,200 * 2").unwrap();
+    /// let literal = Literal { kind: 1, value: Some(1200) };
+    /// assert_eq!(tokens[0].value, TokenKind::Literal(literal));
+    /// assert_eq!(tokens[0].location.1, 6);
+    /// ```
+    ///
+    pub fn register_literal(&mut self, reader: LiteralReader) {
+        self.literals.push(reader);
+    }
+
+    /// 前回の解析結果を捨てる。確保済みの領域はそのまま残す。
+    pub fn reset(&mut self) {
+        self.tokens.clear();
+        self.chars.clear();
+    }
+
+    /// 入力を字句解析し、トークンの列を返す
+    pub fn lex(&mut self, input: &str) -> Result<&[Token], LexError> {
+        self.reset();
+        self.chars.extend(input.chars());
+        lex_tokens(&self.chars, &self.literals, &mut self.tokens)?;
+        Ok(&self.tokens)
+    }
+
+    /// 前回の字句解析で得たトークンの列を返す
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    /// エラーを読み飛ばしながら字句解析し、トークンの列と見つかったエラーを返す
+    pub fn lex_all_errors(&mut self, input: &str) -> (&[Token], Vec<LexError>) {
+        self.reset();
+        self.chars.extend(input.chars());
+        let errors = lex_tokens_recovering(&self.chars, &self.literals, &mut self.tokens);
+        (&self.tokens, errors)
+    }
+}
+
+///
+/// 入力を1行ずつ読みながら、トークンを1つずつ返す字句解析器。
+/// トークンは行をまたがないので、入力全体を文字の配列にしたり、トークンの列を作ったりしない。
+/// エラーを返した後は、lex_all_errorsと同じくエラーの位置の直後から解析を続ける。
+///
+/// ```
+/// use parser::lexer::{StreamingLexer, TokenKind};
+///
+/// let mut lexer = StreamingLexer::new("1 +\n2");
+/// assert_eq!(lexer.next().unwrap().unwrap().value, TokenKind::Number(1));
+/// assert_eq!(lexer.next().unwrap().unwrap().value, TokenKind::Plus);
+/// assert_eq!(lexer.next().unwrap().unwrap().location.0, 4);
+/// assert!(lexer.next().is_none());
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct StreamingLexer<'a> {
+    /// まだ読んでいない行
+    lines: std::str::SplitInclusive<'a, char>,
+    /// 解析中の行を文字ごとに分けたもの
+    line: Vec<char>,
+    /// 解析中の行の中の位置
+    index: usize,
+    /// 解析中の行の先頭の位置
+    offset: usize,
+    /// 解析したトークンの一時的な置き場
+    tokens: Tokens,
+}
+
+impl<'a> StreamingLexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        StreamingLexer {
+            lines: input.split_inclusive('\n'),
+            line: Vec::new(),
+            index: 0,
+            offset: 0,
+            tokens: Tokens::new(),
+        }
+    }
+
+    /// 行の中の位置を入力全体の中の位置へ直す
+    fn shift(&self, location: &Location) -> Location {
+        Location(location.0 + self.offset, location.1 + self.offset)
+    }
+}
+
+impl Iterator for StreamingLexer<'_> {
+    type Item = Result<Token, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match lex_token(&self.line, &[], &mut self.index, &mut self.tokens) {
+                Ok(true) => {
+                    let mut token = self.tokens.pop().unwrap();
+                    token.location = self.shift(&token.location);
+                    return Some(Ok(token));
+                }
+                // 行の終わりに達したので、次の行へ進む
+                Ok(false) => {
+                    let line = self.lines.next()?;
+                    self.offset += self.line.len();
+                    self.line.clear();
+                    self.line.extend(line.chars());
+                    self.index = 0;
+                }
+                Err(mut e) => {
+                    self.index = e.location.1;
+                    e.location = self.shift(&e.location);
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
 ///
 /// 字句解析器
 ///
 pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Tokens::new();
+    lex_tokens(&chars, &[], &mut tokens)?;
+    Ok(tokens.into_vec())
+}
+
+///
+/// 入力を字句解析し、tokensの内容をトークンの列で置き換える。
+/// tokensの領域を使い回すので、繰り返し字句解析する場合のメモリ確保を減らせる。
+/// 入力を文字ごとに分けた配列は呼び出しごとに作る。それも使い回すにはLexerを使う。
+///
+/// ```
+/// use parser::lexer::{lex_into, TokenKind};
+///
+/// let mut tokens = Vec::new();
+/// lex_into("1 + 2", &mut tokens).unwrap();
+/// assert_eq!(tokens.len(), 3);
+/// lex_into("x", &mut tokens).unwrap();
+/// assert_eq!(tokens[0].value, TokenKind::Ident("x".to_string()));
+/// assert_eq!(tokens.len(), 1);
+/// ```
+///
+pub fn lex_into(input: &str, tokens: &mut Vec<Token>) -> Result<(), LexError> {
+    // 文字数はバイト数を超えないので、1度の確保で済む
+    let mut chars = Vec::with_capacity(input.len());
+    chars.extend(input.chars());
+    tokens.clear();
+    lex_tokens(&chars, &[], tokens)
+}
+
+///
+/// エラーがあっても最後まで字句解析を続ける字句解析器。
+/// 無効な文字や大きすぎる数値は読み飛ばし、見つかったエラーをすべて返す。
+///
+pub fn lex_all_errors(input: &str) -> (Vec<Token>, Vec<LexError>) {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Tokens::new();
+    let errors = lex_tokens_recovering(&chars, &[], &mut tokens);
+    (tokens.into_vec(), errors)
+}
+
+///
+/// 識別子の名前を入力から借りる字句解析器。
+/// 得られるトークンはlexと同じだが、名前ごとにStringを確保しない。
+///
+/// ```
+/// use parser::lexer::{lex_borrowed, TokenKind};
+///
+/// let input = "# αβ\nfoo * 2";
+/// let tokens = lex_borrowed(input).unwrap();
+/// assert_eq!(tokens[0].value, TokenKind::Ident("foo"));
+/// assert_eq!(tokens[2].value, TokenKind::Number(2));
+/// ```
+///
+pub fn lex_borrowed(input: &str) -> Result<Vec<BorrowedToken<'_>>, LexError> {
+    let chars: Vec<char> = input.chars().collect();
     let mut tokens = Vec::new();
+    lex_tokens(&chars, &[], &mut Borrowing::new(input, &mut tokens))?;
+    Ok(tokens)
+}
+
+///
+/// バイト列を文字列へ変換する。
+/// UTF-8として正しくない部分はU+FFFDに置き換え、変換後の文字列の中でその文字を指すエラーを返す。
+/// エラーには元のバイト列の中の位置も記録する。
+///
+pub fn decode(input: &[u8]) -> (String, Vec<LexError>) {
+    let mut decoded = String::with_capacity(input.len());
+    let mut errors = Vec::new();
+    let mut offset = 0;
+    // 変換後の文字列の文字数
+    let mut chars = 0;
+    for chunk in input.utf8_chunks() {
+        decoded.push_str(chunk.valid());
+        offset += chunk.valid().len();
+        chars += chunk.valid().chars().count();
+        let invalid = chunk.invalid();
+        if !invalid.is_empty() {
+            decoded.push(char::REPLACEMENT_CHARACTER);
+            errors.push(LexError::new(
+                LexErrorKind::InvalidUtf8 {
+                    start: offset,
+                    end: offset + invalid.len(),
+                },
+                Location(chars, chars + 1),
+            ));
+            offset += invalid.len();
+            chars += 1;
+        }
+    }
+    (decoded, errors)
+}
+
+///
+/// 入力が空白とコメントだけからなるかどうか。
+/// 式の書かれていない行を読み飛ばすのに使う。
+///
+pub fn is_blank(input: &str) -> bool {
+    matches!(lex(input), Ok(tokens) if tokens.is_empty())
+}
+
+///
+/// 解析したトークンの受け取り先。
+/// 字句解析器は識別子の範囲だけを知らせ、名前を所有するか入力から借りるかは受け取り先が決める。
+///
+trait TokenSink {
+    /// inputのlocationの範囲にあるトークンを追加する
+    fn push_token(&mut self, kind: TokenKind<()>, location: Location, input: &[char]);
+}
+
+impl TokenSink for Tokens {
+    fn push_token(&mut self, kind: TokenKind<()>, location: Location, input: &[char]) {
+        let kind = kind.map_ident(|()| collect(&input[location.0..location.1]));
+        self.push(Token::new(kind, location));
+    }
+}
+
+impl TokenSink for Vec<Token> {
+    fn push_token(&mut self, kind: TokenKind<()>, location: Location, input: &[char]) {
+        let kind = kind.map_ident(|()| collect(&input[location.0..location.1]));
+        self.push(Token::new(kind, location));
+    }
+}
+
+/// 識別子の名前を元の文字列から借りてトークンを追加する受け取り先
+struct Borrowing<'a, 'src> {
+    source: &'src str,
+    tokens: &'a mut Vec<BorrowedToken<'src>>,
+    /// 最後にバイト位置へ直した文字の位置と、そのバイト位置
+    cursor: (usize, usize),
+}
+
+impl<'a, 'src> Borrowing<'a, 'src> {
+    fn new(source: &'src str, tokens: &'a mut Vec<BorrowedToken<'src>>) -> Self {
+        Borrowing {
+            source,
+            tokens,
+            cursor: (0, 0),
+        }
+    }
+
+    /// 文字の位置をバイト位置へ直す。トークンは前から順に届くので、前回の位置から数え進める
+    fn byte_offset(&mut self, index: usize) -> usize {
+        let (chars, bytes) = self.cursor;
+        let bytes = bytes
+            + self.source[bytes..]
+                .chars()
+                .take(index - chars)
+                .map(char::len_utf8)
+                .sum::<usize>();
+        self.cursor = (index, bytes);
+        bytes
+    }
+}
+
+impl TokenSink for Borrowing<'_, '_> {
+    fn push_token(&mut self, kind: TokenKind<()>, location: Location, _: &[char]) {
+        let source = self.source;
+        let kind = kind.map_ident(|()| {
+            let start = self.byte_offset(location.0);
+            let end = self.byte_offset(location.1);
+            &source[start..end]
+        });
+        self.tokens.push(BorrowedToken::new(kind, location));
+    }
+}
 
-    // バイト配列のスライスへ入力を変換
-    let input_bytes = input.as_bytes();
-    // バイトスライスの位置
+/// 入力を字句解析し、トークンを追加していく
+fn lex_tokens(
+    input: &[char],
+    literals: &[LiteralReader],
+    tokens: &mut impl TokenSink,
+) -> Result<(), LexError> {
+    lex_from(input, literals, &mut 0, tokens)
+}
+
+/// エラーの箇所を読み飛ばしながら字句解析し、見つかったエラーを返す
+fn lex_tokens_recovering(
+    input: &[char],
+    literals: &[LiteralReader],
+    tokens: &mut impl TokenSink,
+) -> Vec<LexError> {
+    let mut errors = Vec::new();
     let mut index = 0;
+    while let Err(e) = lex_from(input, literals, &mut index, tokens) {
+        // エラーの位置の直後から解析を再開する
+        index = e.location.1;
+        errors.push(e);
+    }
+    errors
+}
 
-    while index < input_bytes.len() {
-        match input_bytes[index] {
-            // 四則演算
-            b'+' => lex_one_byte(input_bytes, &mut index, b'+', &mut tokens)?,
-            b'-' => lex_one_byte(input_bytes, &mut index, b'-', &mut tokens)?,
-            b'*' => lex_one_byte(input_bytes, &mut index, b'*', &mut tokens)?,
-            b'/' => lex_one_byte(input_bytes, &mut index, b'/', &mut tokens)?,
-            // かっこ
-            b'(' => lex_one_byte(input_bytes, &mut index, b'(', &mut tokens)?,
-            b')' => lex_one_byte(input_bytes, &mut index, b')', &mut tokens)?,
-            // 上記以外の文字の場合
-            b => {
-                if is_number(b) {
-                    // 数値
-                    lex_number(input_bytes, &mut index, &mut tokens);
-                } else if is_space(b) {
-                    // 空白文字
-                    skip_spaces(input_bytes, &mut index);
-                } else {
-                    return Err(LexError::invalid_char(
-                        b as char,
-                        Location(index, index + 1),
-                    ));
-                }
-            }
+///
+/// 入力のindexの位置から字句解析し、トークンを追加していく。
+/// 入力は文字の配列で、indexや位置情報は何文字目かを表す
+///
+fn lex_from(
+    input: &[char],
+    literals: &[LiteralReader],
+    index_address: &mut usize,
+    tokens: &mut impl TokenSink,
+) -> Result<(), LexError> {
+    while lex_token(input, literals, index_address, tokens)? {}
+    Ok(())
+}
+
+///
+/// 空白とコメントを読み飛ばし、次のトークンを1つだけ解析して追加する。
+/// 入力の終わりに達してトークンがなければfalseを返す
+///
+fn lex_token(
+    input: &[char],
+    literals: &[LiteralReader],
+    index_address: &mut usize,
+    tokens: &mut impl TokenSink,
+) -> Result<bool, LexError> {
+    // 文字配列の位置
+    let index = index_address;
+
+    loop {
+        match input.get(*index) {
+            // "//"から行末まではコメント
+            Some('/') if input.get(*index + 1) == Some(&'/') => skip_comment(input, index),
+            // "#"から行末まではコメント
+            Some('#') => skip_comment(input, index),
+            // 空白文字
+            Some(&c) if is_space(c) => skip_spaces(input, index),
+            Some(_) => break,
+            None => return Ok(false),
         }
     }
-    Ok(tokens)
+    // 独自のリテラルは組み込みのトークンより先に試す。長さが0や入力の外に及ぶものは読めなかったとみなす
+    let rest = &input[*index..];
+    let literal = literals
+        .iter()
+        .filter_map(|read| read(rest))
+        .find(|&(len, _)| 0 < len && len <= rest.len());
+    if let Some((len, literal)) = literal {
+        let location = Location(*index, *index + len);
+        *index += len;
+        tokens.push_token(TokenKind::Literal(literal), location, input);
+        return Ok(true);
+    }
+    match input[*index] {
+        // 四則演算
+        '+' => lex_one_char(input, index, '+', tokens)?,
+        '-' => lex_one_char(input, index, '-', tokens)?,
+        '*' => lex_one_char(input, index, '*', tokens)?,
+        '/' => lex_one_char(input, index, '/', tokens)?,
+        // べき乗
+        '^' => lex_one_char(input, index, '^', tokens)?,
+        // かっこ
+        '(' => lex_one_char(input, index, '(', tokens)?,
+        ')' => lex_one_char(input, index, ')', tokens)?,
+        // 代入
+        '=' => lex_one_char(input, index, '=', tokens)?,
+        // 関数の引数の区切り
+        ',' => lex_one_char(input, index, ',', tokens)?,
+        // 絶対値またはビット論理和
+        '|' => lex_one_char(input, index, '|', tokens)?,
+        // 数値
+        c if is_number(c) => lex_number(input, index, tokens)?,
+        // 識別子
+        c if is_ident_start(c) => lex_ident(input, index, tokens),
+        // 上記以外の文字の場合
+        c => return Err(LexError::invalid_char(c, Location(*index, *index + 1))),
+    }
+    Ok(true)
 }
 
-/// 数値を解析する
-fn lex_number(input: &[u8], index_address: &mut usize, tokens: &mut Vec<Token>) {
-    use std::str::from_utf8;
+///
+/// 数値を解析する。
+/// 指数表記（"1e6"、"2.5e3"）や小数点も読めるが、値は整数でなければならない
+///
+fn lex_number(
+    input: &[char],
+    index_address: &mut usize,
+    tokens: &mut impl TokenSink,
+) -> Result<(), LexError> {
+    let start = *index_address;
+    if let Some(radix) = radix_prefix(&input[start..]) {
+        return lex_radix_number(input, index_address, radix, tokens);
+    }
+    let integer = skip_digits(input, index_address);
+    // 小数部は小数点の後に数字が続く場合だけ読む
+    let mut fraction = *index_address..*index_address;
+    if input.get(*index_address) == Some(&'.')
+        && input.get(*index_address + 1).is_some_and(|&c| is_number(c))
+    {
+        *index_address += 1;
+        fraction = skip_digits(input, index_address);
+    }
+    // 指数は"e"の後に数字か、符号と数字が続く場合だけ読む。
+    // それ以外の"e"（"2else"、"2e"など）は、数値の直後の識別子として扱う
+    let mut exponent = 0;
+    let sign = match input.get(*index_address + 1) {
+        Some('+') | Some('-') => 1,
+        _ => 0,
+    };
+    if matches!(input.get(*index_address), Some('e') | Some('E'))
+        && input
+            .get(*index_address + 1 + sign)
+            .is_some_and(|&c| is_number(c))
+    {
+        let negative = input.get(*index_address + 1) == Some(&'-');
+        *index_address += 1 + sign;
+        let digits = skip_digits(input, index_address);
+        // 桁数の多すぎる指数は、どのみち表せないので上限で止める
+        let magnitude = accumulate(digit_values(&input[digits], 10), 10)
+            .and_then(|m| i64::try_from(m).ok())
+            .unwrap_or(i64::MAX / 2);
+        exponent = if negative { -magnitude } else { magnitude };
+    }
+    // "2x"は掛け算とも1つの名前とも読めるので、どちらにも決めずにエラーとする
+    if *index_address < input.len() && is_ident_start(input[*index_address]) {
+        while *index_address < input.len() && is_ident_continue(input[*index_address]) {
+            *index_address += 1;
+        }
+        return Err(LexError::ident_after_number(Location(
+            start,
+            *index_address,
+        )));
+    }
+
+    // 数値の文字列を実際の数値へ変換する
+    let location = Location(start, *index_address);
+    let number = decimal_value(&input[integer], &input[fraction], exponent)
+        .map_err(|kind| LexError::new(kind, location.clone()))?;
 
+    tokens.push_token(TokenKind::Number(number), location, input);
+    Ok(())
+}
+
+/// 数字の並びを読み飛ばし、その範囲を返す
+fn skip_digits(input: &[char], index_address: &mut usize) -> std::ops::Range<usize> {
     let start = *index_address;
     while *index_address < input.len() && is_number(input[*index_address]) {
         *index_address += 1;
     }
+    start..*index_address
+}
 
-    // 数値の文字列を実際の数値へ変換する
-    let numbber: u64 = from_utf8(&input[start..*index_address])
-        // バイト配列から文字列への変換はここでは失敗することはないので無条件にunwrapする
-        .unwrap()
-        .parse()
-        // 文字列から数値への変換もここでは失敗することはないので無条件にunwrapする
-        .unwrap();
+///
+/// 整数部・小数部・指数から値を求める（"2.5e3"なら"2"、"5"、3）。
+/// 整数にならない場合や、u64に収まらない場合はエラーを返す
+///
+fn decimal_value(integer: &[char], fraction: &[char], exponent: i64) -> Result<u64, LexErrorKind> {
+    // 先頭の0を除いた数字の並び
+    let leading = integer
+        .iter()
+        .chain(fraction)
+        .take_while(|&&c| c == '0')
+        .count();
+    let len = integer.len() + fraction.len() - leading;
+    let digits = || {
+        digit_values(integer, 10)
+            .chain(digit_values(fraction, 10))
+            .skip(leading)
+    };
+    if len == 0 {
+        return Ok(0);
+    }
+    // 数字の並びを整数とみたときに、10を何乗すれば値になるか
+    let shift = exponent.saturating_sub(fraction.len() as i64);
+    if shift >= 0 {
+        // 20桁を超える数はu64に収まらない
+        if len as i64 + shift > 20 {
+            return Err(LexErrorKind::NumberTooLarge);
+        }
+        let zeros = std::iter::repeat_n(0, shift as usize);
+        return accumulate(digits().chain(zeros), 10).ok_or(LexErrorKind::NumberTooLarge);
+    }
+    // 小数点以下に0でない数字が残れば整数ではない。
+    // 先頭の数字は0でないので、すべてが小数点以下になる場合も整数ではない
+    let cut = len.saturating_sub(shift.unsigned_abs() as usize);
+    if digits().skip(cut).any(|d| d != 0) {
+        return Err(LexErrorKind::NotAnInteger);
+    }
+    accumulate(digits().take(cut), 10).ok_or(LexErrorKind::NumberTooLarge)
+}
 
-    tokens.push(Token::number(numbber, Location(start, *index_address)));
+/// 数字の並びの各桁の値。数字は基数で使えるものだけでなければならない
+fn digit_values(digits: &[char], radix: u32) -> impl Iterator<Item = u64> + '_ {
+    digits
+        .iter()
+        .map(move |c| u64::from(c.to_digit(radix).unwrap()))
 }
 
-fn is_number(byte: u8) -> bool {
-    b'0' <= byte && byte <= b'9'
+/// 各桁の値から数を組み立てる。u64に収まらなければNoneを返す
+fn accumulate(mut digits: impl Iterator<Item = u64>, radix: u64) -> Option<u64> {
+    digits.try_fold(0u64, |n, d| n.checked_mul(radix)?.checked_add(d))
 }
 
-/// 空白文字（半角スペース、改行、タブ）を無視する
-fn skip_spaces(input: &[u8], index_address: &mut usize) {
+/// "0x"・"0o"・"0b"で始まっていれば、その基数を返す
+fn radix_prefix(input: &[char]) -> Option<u32> {
+    match input {
+        ['0', 'x', ..] | ['0', 'X', ..] => Some(16),
+        ['0', 'o', ..] | ['0', 'O', ..] => Some(8),
+        ['0', 'b', ..] | ['0', 'B', ..] => Some(2),
+        _ => None,
+    }
+}
+
+/// 接頭辞の付いた16進数・8進数・2進数の数値を解析する
+fn lex_radix_number(
+    input: &[char],
+    index_address: &mut usize,
+    radix: u32,
+    tokens: &mut impl TokenSink,
+) -> Result<(), LexError> {
+    let start = *index_address;
+    *index_address += 2;
+    // 基数で使えない文字も含めて、名前に使える文字の並びを1つの数値として読む
+    let digits_start = *index_address;
+    while *index_address < input.len() && is_ident_continue(input[*index_address]) {
+        *index_address += 1;
+    }
+
+    let location = Location(start, *index_address);
+    let digits = &input[digits_start..*index_address];
+    if digits.is_empty() {
+        return Err(LexError::missing_digits(location));
+    }
+    if let Some(&c) = digits.iter().find(|c| !c.is_digit(radix)) {
+        return Err(LexError::invalid_digit(c, location));
+    }
+    let number = accumulate(digit_values(digits, radix), u64::from(radix))
+        // 数字は検査済みなので、失敗するのは数値が大きすぎる場合だけである
+        .ok_or_else(|| LexError::number_too_large(location.clone()))?;
+
+    tokens.push_token(TokenKind::Number(number), location, input);
+    Ok(())
+}
+
+fn is_number(c: char) -> bool {
+    c.is_ascii_digit()
+}
+
+/// 文字の並びを文字列にする
+fn collect(chars: &[char]) -> String {
+    chars.iter().collect()
+}
+
+/// 識別子を解析する
+fn lex_ident(input: &[char], index_address: &mut usize, tokens: &mut impl TokenSink) {
+    let start = *index_address;
+    while *index_address < input.len() && is_ident_continue(input[*index_address]) {
+        *index_address += 1;
+    }
+
+    tokens.push_token(TokenKind::Ident(()), Location(start, *index_address), input);
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_ident_continue(c: char) -> bool {
+    is_ident_start(c) || c.is_ascii_digit()
+}
+
+/// 空白文字（半角スペース、改行、復帰、タブ）を無視する
+fn skip_spaces(input: &[char], index_address: &mut usize) {
     while *index_address < input.len() && is_space(input[*index_address]) {
         *index_address += 1;
     }
 }
 
-fn is_space(byte: u8) -> bool {
-    byte == b' ' || byte == b'\t' || byte == b'\n'
+/// 行末までのコメントを無視する。改行は空白として残す
+fn skip_comment(input: &[char], index_address: &mut usize) {
+    while *index_address < input.len() && input[*index_address] != '\n' {
+        *index_address += 1;
+    }
+}
+
+fn is_space(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\n' || c == '\r'
 }
 
 /// 1文字のトークンを解析する
-fn lex_one_byte(
-    input: &[u8],
+fn lex_one_char(
+    input: &[char],
     index_address: &mut usize,
-    byte: u8,
-    tokens: &mut Vec<Token>,
+    c: char,
+    tokens: &mut impl TokenSink,
 ) -> Result<(), LexError> {
     let start = *index_address;
-    consume_byte(input, index_address, byte)?;
-    tokens.push(create_one_byte_token(byte, start, *index_address));
+    consume_char(input, index_address, c)?;
+    tokens.push_token(one_char_kind(c), Location(start, *index_address), input);
     Ok(())
 }
 
-fn create_one_byte_token(byte: u8, start_index: usize, end_index: usize) -> Token {
-    match byte {
-        b'+' => Token::plus(Location(start_index, end_index)),
-        b'-' => Token::minus(Location(start_index, end_index)),
-        b'*' => Token::asterisk(Location(start_index, end_index)),
-        b'/' => Token::slash(Location(start_index, end_index)),
-        b'(' => Token::lparen(Location(start_index, end_index)),
-        b')' => Token::rparen(Location(start_index, end_index)),
-        b => panic!("unexpected byte : {}", b),
+fn one_char_kind(c: char) -> TokenKind<()> {
+    match c {
+        '+' => TokenKind::Plus,
+        '-' => TokenKind::Minus,
+        '*' => TokenKind::Asterisk,
+        '/' => TokenKind::Slash,
+        '^' => TokenKind::Caret,
+        '(' => TokenKind::LParen,
+        ')' => TokenKind::RParen,
+        '=' => TokenKind::Equal,
+        ',' => TokenKind::Comma,
+        '|' => TokenKind::Pipe,
+        c => panic!("unexpected char : {}", c),
     }
 }
 
 ///
-/// 引数に渡されたバイトスライスのposの位置が期待するバイト外の場合、エラーを返す。
+/// 引数に渡された文字配列のposの位置が期待する文字以外の場合、その文字を指すエラーを返す。
 /// それ以外の場合、インデックスを1進める。
 ///
-fn consume_byte(input: &[u8], index_address: &mut usize, expected: u8) -> Result<(), LexError> {
+fn consume_char(input: &[char], index_address: &mut usize, expected: char) -> Result<(), LexError> {
     if input.len() <= *index_address {
         return Err(LexError::eof(Location(*index_address, *index_address)));
     }
 
     if input[*index_address] != expected {
         return Err(LexError::invalid_char(
-            input[*index_address] as char,
+            input[*index_address],
             Location(*index_address, *index_address + 1),
         ));
     }
@@ -287,4 +1229,479 @@ mod test {
             ])
         )
     }
+
+    #[test]
+    fn test_caret_line() {
+        assert_eq!(caret_line("1 + 23", &Location(4, 6)), "    ^^");
+        // 全角文字は幅2として数える
+        assert_eq!(caret_line("あい + (", &Location(5, 6)), "       ^");
+        assert_eq!(caret_line("1 ＋ 2", &Location(2, 3)), "  ^^");
+        // 入力の末尾より後ろ
+        assert_eq!(caret_line("１+", &Location(2, 3)), "   ^");
+        // タブは次のタブ位置まで進める
+        assert_eq!(display_line("\t1\tあ\t+"), "    1   あ  +");
+        assert_eq!(caret_line("\t1\tあ\t+", &Location(5, 6)), "            ^");
+        assert_eq!(caret_line("1\t+", &Location(0, 2)), "^^^^");
+        assert_eq!(
+            annotate("x\t= 1 +\t)", &Location(8, 9)),
+            "1:9 | x   = 1 +   )\n    |             ^"
+        );
+    }
+
+    #[test]
+    fn test_source_map() {
+        let map = SourceMap::new("1 +\nあ * 2\n");
+        assert_eq!(map.line_col(0), (1, 1));
+        assert_eq!(map.line_col(2), (1, 3));
+        assert_eq!(map.line_col(4), (2, 1));
+        // 全角文字は1文字として数える
+        assert_eq!(map.line_col(6), (2, 3));
+        assert_eq!(map.line(1), "あ * 2");
+        assert_eq!(map.line_col(10), (3, 1));
+    }
+
+    #[test]
+    fn test_annotate() {
+        assert_eq!(
+            annotate("1 + )", &Location(4, 5)),
+            "1:5 | 1 + )\n    |     ^"
+        );
+        assert_eq!(
+            annotate("1 +\n2 * )", &Location(8, 9)),
+            "2:5 | 2 * )\n    |     ^"
+        );
+        // 入力の末尾
+        assert_eq!(annotate("1 +", &Location(3, 4)), "1:4 | 1 +\n    |    ^");
+    }
+
+    #[test]
+    fn test_lexer_reuse() {
+        let mut lexer = Lexer::new();
+        assert_eq!(
+            lexer.lex("1 + 2"),
+            Ok(&[
+                Token::number(1, Location(0, 1)),
+                Token::plus(Location(2, 3)),
+                Token::number(2, Location(4, 5)),
+            ][..])
+        );
+        // 前回のトークンは残らない
+        assert_eq!(lexer.lex("3"), Ok(&[Token::number(3, Location(0, 1))][..]));
+    }
+
+    #[test]
+    fn test_lex_into() {
+        let mut tokens = Vec::new();
+        for input in &["1 + 2", "x = max(1, 2)", "", "# comment"] {
+            lex_into(input, &mut tokens).unwrap();
+            assert_eq!(tokens, lex(input).unwrap(), "{}", input);
+        }
+        // エラーのときも前回のトークンは残らない
+        lex_into("1 + 2", &mut tokens).unwrap();
+        assert_eq!(
+            lex_into("1 $", &mut tokens),
+            Err(LexError::invalid_char('$', Location(2, 3)))
+        );
+        assert_eq!(tokens, vec![Token::number(1, Location(0, 1))]);
+    }
+
+    #[test]
+    fn test_lexer_number_too_large() {
+        assert_eq!(
+            lex("1 + 99999999999999999999999"),
+            Err(LexError::number_too_large(Location(4, 27)))
+        );
+        assert_eq!(
+            lex("18446744073709551615"),
+            Ok(vec![Token::number(u64::MAX, Location(0, 20))])
+        );
+    }
+
+    #[test]
+    fn test_lexer_radix_number() {
+        assert_eq!(
+            lex("0x1F + 0o17*0b1010"),
+            Ok(vec![
+                Token::number(31, Location(0, 4)),
+                Token::plus(Location(5, 6)),
+                Token::number(15, Location(7, 11)),
+                Token::asterisk(Location(11, 12)),
+                Token::number(10, Location(12, 18)),
+            ])
+        );
+        assert_eq!(lex("0XfF"), Ok(vec![Token::number(255, Location(0, 4))]));
+        assert_eq!(
+            lex("0xffffffffffffffff"),
+            Ok(vec![Token::number(u64::MAX, Location(0, 18))])
+        );
+        assert_eq!(
+            lex("0x1_0000_0000_0000_0000"),
+            Err(LexError::invalid_digit('_', Location(0, 23)))
+        );
+        assert_eq!(
+            lex("0x10000000000000000"),
+            Err(LexError::number_too_large(Location(0, 19)))
+        );
+        assert_eq!(lex("1 + 0x"), Err(LexError::missing_digits(Location(4, 6))));
+        assert_eq!(lex("0b)"), Err(LexError::missing_digits(Location(0, 2))));
+        assert_eq!(
+            lex("0b102"),
+            Err(LexError::invalid_digit('2', Location(0, 5)))
+        );
+        assert_eq!(
+            lex("0o8"),
+            Err(LexError::invalid_digit('8', Location(0, 3)))
+        );
+        // 接頭辞のない"0"はこれまでどおり10進数である
+        assert_eq!(lex("007"), Ok(vec![Token::number(7, Location(0, 3))]));
+    }
+
+    #[test]
+    fn test_lexer_scientific() {
+        let number = |input: &str| lex(input).map(|tokens| tokens[0].clone());
+        assert_eq!(number("1e6"), Ok(Token::number(1_000_000, Location(0, 3))));
+        assert_eq!(number("2.5e3"), Ok(Token::number(2500, Location(0, 5))));
+        assert_eq!(number("2.5E+3"), Ok(Token::number(2500, Location(0, 6))));
+        assert_eq!(number("1200e-2"), Ok(Token::number(12, Location(0, 7))));
+        assert_eq!(number("4.0"), Ok(Token::number(4, Location(0, 3))));
+        assert_eq!(
+            number("0.0e99999999999999999999"),
+            Ok(Token::number(0, Location(0, 24)))
+        );
+        assert_eq!(
+            number("1.8446744073709551615e19"),
+            Ok(Token::number(u64::MAX, Location(0, 24)))
+        );
+        // "e"の後に数字がなければ指数ではなく、数値の直後の識別子になる
+        assert_eq!(
+            lex("2 * 1e+"),
+            Err(LexError::ident_after_number(Location(4, 6)))
+        );
+        assert_eq!(lex("1e"), Err(LexError::ident_after_number(Location(0, 2))));
+        assert_eq!(
+            lex("2else"),
+            Err(LexError::ident_after_number(Location(0, 5)))
+        );
+        assert_eq!(
+            lex("2ex"),
+            Err(LexError::ident_after_number(Location(0, 3)))
+        );
+        assert_eq!(
+            lex("2.5e-3"),
+            Err(LexError::new(LexErrorKind::NotAnInteger, Location(0, 6)))
+        );
+        assert_eq!(
+            lex("2.5"),
+            Err(LexError::new(LexErrorKind::NotAnInteger, Location(0, 3)))
+        );
+        assert_eq!(lex("1e20"), Err(LexError::number_too_large(Location(0, 4))));
+        assert_eq!(
+            lex("1e5x"),
+            Err(LexError::ident_after_number(Location(0, 4)))
+        );
+        // 小数点の後に数字がなければ、小数点は数値の一部ではない
+        assert_eq!(lex("1."), Err(LexError::invalid_char('.', Location(1, 2))));
+    }
+
+    #[test]
+    fn test_decode() {
+        let (decoded, errors) = decode(b"1 + \xff\xfe 2");
+        assert_eq!(decoded, "1 + \u{fffd}\u{fffd} 2");
+        assert_eq!(
+            errors,
+            vec![
+                LexError::new(
+                    LexErrorKind::InvalidUtf8 { start: 4, end: 5 },
+                    Location(4, 5)
+                ),
+                LexError::new(
+                    LexErrorKind::InvalidUtf8 { start: 5, end: 6 },
+                    Location(5, 6)
+                ),
+            ]
+        );
+        assert_eq!(errors[0].message(), "invalid UTF-8 sequence at bytes 4-5");
+        // 途中で切れた複数バイトの文字は、まとめて1つのエラーにする
+        let (decoded, errors) = decode(b"\xc3x");
+        assert_eq!(decoded, "\u{fffd}x");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(decode("あ+1".as_bytes()), ("あ+1".to_string(), vec![]));
+    }
+
+    #[test]
+    fn test_control_chars() {
+        let e = lex("1 +\x1b[2J").unwrap_err();
+        assert_eq!(e.message(), "invalid character '\\u{1b}'");
+        assert_eq!(display_line("\x1b\t1"), "\u{fffd}   1");
+        assert_eq!(
+            annotate("\x1b\t1 $", &Location(4, 5)),
+            "1:5 | \u{fffd}   1 $\n    |       ^"
+        );
+    }
+
+    #[test]
+    fn test_lexer_crlf() {
+        assert_eq!(
+            lex("1 +\r\n2\r"),
+            Ok(vec![
+                Token::number(1, Location(0, 1)),
+                Token::plus(Location(2, 3)),
+                Token::number(2, Location(5, 6)),
+            ])
+        )
+    }
+
+    #[test]
+    fn test_lexer_ident() {
+        assert_eq!(
+            lex("x1 = _y"),
+            Ok(vec![
+                Token::ident("x1", Location(0, 2)),
+                Token::equal(Location(3, 4)),
+                Token::ident("_y", Location(5, 7)),
+            ])
+        );
+        assert_eq!(
+            lex("min(1,2)"),
+            Ok(vec![
+                Token::ident("min", Location(0, 3)),
+                Token::lparen(Location(3, 4)),
+                Token::number(1, Location(4, 5)),
+                Token::comma(Location(5, 6)),
+                Token::number(2, Location(6, 7)),
+                Token::rparen(Location(7, 8)),
+            ])
+        )
+    }
+
+    #[test]
+    fn test_lexer_ident_after_number() {
+        // 数値と識別子の間に空白があれば、2つのトークンになる
+        assert_eq!(
+            lex("2 x"),
+            Ok(vec![
+                Token::number(2, Location(0, 1)),
+                Token::ident("x", Location(2, 3)),
+            ])
+        );
+        // 識別子の中の数字は識別子の一部である
+        assert_eq!(lex("x2"), Ok(vec![Token::ident("x2", Location(0, 2))]));
+        // 空白なしに続く場合は、数値から識別子の終わりまでを指すエラーにする
+        assert_eq!(
+            lex("1 + 2x_1"),
+            Err(LexError::ident_after_number(Location(4, 8)))
+        );
+        let (tokens, errors) = lex_all_errors("2x + 3");
+        assert_eq!(errors, vec![LexError::ident_after_number(Location(0, 2))]);
+        assert_eq!(
+            tokens,
+            vec![
+                Token::plus(Location(3, 4)),
+                Token::number(3, Location(5, 6))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lexer_comment() {
+        assert_eq!(
+            lex("1 + 2 # three\n* 4 // four"),
+            Ok(vec![
+                Token::number(1, Location(0, 1)),
+                Token::plus(Location(2, 3)),
+                Token::number(2, Location(4, 5)),
+                Token::asterisk(Location(14, 15)),
+                Token::number(4, Location(16, 17)),
+            ])
+        );
+        // "/"が1つだけなら割り算である
+        assert_eq!(
+            lex("6/2"),
+            Ok(vec![
+                Token::number(6, Location(0, 1)),
+                Token::slash(Location(1, 2)),
+                Token::number(2, Location(2, 3)),
+            ])
+        );
+        // コメントの中の文字はエラーにしない
+        assert_eq!(lex("# é $"), Ok(vec![]));
+        assert!(is_blank("  // note"));
+        assert!(is_blank(""));
+        assert!(!is_blank("1 # one"));
+        assert!(!is_blank("$"));
+    }
+
+    #[test]
+    fn test_lex_all_errors() {
+        let (tokens, errors) = lex_all_errors("1 $ 2 é 99999999999999999999 + 3");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::number(1, Location(0, 1)),
+                Token::number(2, Location(4, 5)),
+                Token::plus(Location(29, 30)),
+                Token::number(3, Location(31, 32)),
+            ]
+        );
+        assert_eq!(
+            errors,
+            vec![
+                LexError::invalid_char('$', Location(2, 3)),
+                LexError::invalid_char('é', Location(6, 7)),
+                LexError::number_too_large(Location(8, 28)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_streaming_lexer() {
+        // 一度に字句解析した結果と同じトークンとエラーを、同じ順に返す
+        for input in [
+            "1 + 2 * 3 - -10",
+            "x = 1 # one\n// two\n  + 0x1f\r\n* (y)",
+            "1 $ 2 é\n99999999999999999999 + 3",
+            "2x + 1e-3\n\n\n| あ |",
+            "",
+            "\n",
+        ] {
+            let (tokens, errors) = lex_all_errors(input);
+            let (streamed, streamed_errors): (Vec<_>, Vec<_>) =
+                StreamingLexer::new(input).partition(Result::is_ok);
+            let streamed: Vec<_> = streamed.into_iter().map(Result::unwrap).collect();
+            let streamed_errors: Vec<_> = streamed_errors
+                .into_iter()
+                .map(Result::unwrap_err)
+                .collect();
+            assert_eq!(streamed, tokens, "{:?}", input);
+            assert_eq!(streamed_errors, errors, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_lex_borrowed() {
+        let input = "sum = x_1 * 0x1F // ∑é\n  + |y| # ü\n- 2.5e3";
+        let tokens = lex_borrowed(input).unwrap();
+        let owned: Vec<_> = tokens
+            .iter()
+            .cloned()
+            .map(BorrowedToken::into_owned)
+            .collect();
+        let (expected, errors) = lex_all_errors(input);
+        assert_eq!(owned, expected);
+        assert!(errors.is_empty());
+        // 名前は入力の一部をそのまま指す
+        let names: Vec<_> = tokens
+            .iter()
+            .filter_map(|token| match token.value {
+                TokenKind::Ident(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, vec!["sum", "x_1", "y"]);
+        // 名前の前に複数バイトの文字があっても、正しいバイト位置から借りる
+        let y = input.find("|y").unwrap() + 1;
+        assert!(std::ptr::eq(names[2], &input[y..y + 1]));
+        assert_eq!(
+            lex_borrowed("a + 99999999999999999999"),
+            Err(LexError::number_too_large(Location(4, 24)))
+        );
+    }
+
+    #[test]
+    fn test_literal_reader() {
+        // "c"で始まり16進数の数字だけが続く色の指定。コメントの中では試さない
+        fn color(input: &[char]) -> Option<(usize, Literal)> {
+            let len = input.iter().take_while(|c| c.is_ascii_hexdigit()).count();
+            (input.first() == Some(&'c') && len > 1).then_some((
+                len,
+                Literal {
+                    kind: 7,
+                    value: None,
+                },
+            ))
+        }
+        // 長さが0や入力の外に及ぶものは読めなかったとみなす
+        fn broken(input: &[char]) -> Option<(usize, Literal)> {
+            let len = if input.first() == Some(&'0') {
+                0
+            } else {
+                input.len() + 1
+            };
+            Some((
+                len,
+                Literal {
+                    kind: 0,
+                    value: None,
+                },
+            ))
+        }
+        let mut lexer = Lexer::new();
+        lexer.register_literal(broken);
+        lexer.register_literal(color);
+        let color = |location| {
+            Token::new(
+                TokenKind::Literal(Literal {
+                    kind: 7,
+                    value: None,
+                }),
+                location,
+            )
+        };
+        assert_eq!(
+            lexer.lex("cafe + 0 # c0ffee"),
+            Ok(&[
+                color(Location(0, 4)),
+                Token::plus(Location(5, 6)),
+                Token::number(0, Location(7, 8)),
+            ][..])
+        );
+        let (tokens, errors) = lexer.lex_all_errors("$ cab");
+        assert_eq!(tokens, &[color(Location(2, 5))]);
+        assert_eq!(errors, vec![LexError::invalid_char('$', Location(0, 1))]);
+        assert_eq!(color(Location(0, 1)).value.to_string(), "<literal 7>");
+        // 登録しなければ組み込みの字句解析だけを行う
+        assert_eq!(lex("cafe"), Ok(vec![Token::ident("cafe", Location(0, 4))]));
+    }
+
+    #[test]
+    fn test_location_accessors() {
+        let location = Location::new(2, 5);
+        assert_eq!((location.start(), location.end()), (2, 5));
+        assert_eq!(location.len(), 3);
+        assert_eq!(location.as_range(), 2..5);
+        assert!(Location::new(4, 4).is_empty());
+        let token = Token::number(12, location.clone());
+        assert_eq!(token.value(), &TokenKind::Number(12));
+        assert_eq!(token.location(), &location);
+    }
+
+    #[test]
+    fn test_token_display() {
+        let tokens = lex("x1 = 0x10 |").unwrap();
+        let shown: Vec<_> = tokens.iter().map(Token::to_string).collect();
+        assert_eq!(
+            shown,
+            vec!["'x1' at 0-2", "'=' at 3-4", "'16' at 5-9", "'|' at 10-11"]
+        );
+        let borrowed = lex_borrowed("max").unwrap();
+        assert_eq!(borrowed[0].to_string(), "'max' at 0-3");
+    }
+
+    #[test]
+    fn test_char_locations() {
+        // 位置は文字数で数えるので、マルチバイト文字の後ろでもずれない
+        let (tokens, errors) = lex_all_errors("あ + 1 ＄");
+        assert_eq!(tokens[0], Token::plus(Location(2, 3)));
+        assert_eq!(
+            errors,
+            vec![
+                LexError::invalid_char('あ', Location(0, 1)),
+                LexError::invalid_char('＄', Location(6, 7)),
+            ]
+        );
+        assert_eq!(
+            annotate("あ + 1 ＄", &errors[1].location),
+            "1:7 | あ + 1 ＄\n    |        ^^"
+        );
+    }
 }
@@ -0,0 +1,47 @@
+pub mod analyze;
+pub mod arena;
+pub mod asm;
+pub mod batch;
+pub mod bcfile;
+pub mod bytecode;
+pub mod cache;
+pub mod compiled;
+pub mod compiler;
+pub mod console;
+pub mod csource;
+pub mod dc;
+pub mod diagnostic;
+pub mod dot;
+pub mod engine;
+pub mod events;
+pub mod fast_eval;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod interner;
+pub mod interpreter;
+pub mod latex;
+pub mod lexdump;
+pub mod lexer;
+pub mod limits;
+pub mod llvm_ir;
+pub mod minify;
+pub mod optimizer;
+pub mod parser;
+pub mod peephole;
+pub mod postprocess;
+pub mod prefix;
+pub mod provenance;
+pub mod quiz;
+pub mod rewrite;
+pub mod rpn;
+pub mod sexpr;
+pub mod shunting_yard;
+pub mod stats;
+#[cfg(test)]
+mod strategy;
+pub mod trace;
+pub mod tree;
+pub mod visitor;
+pub mod wasm;
+#[cfg(feature = "wasm")]
+pub mod web;
@@ -0,0 +1,163 @@
+//!
+//! 入力の大きさと処理の量の上限。
+//! サーバーなどに組み込んで信頼できない入力を処理するとき、1つの入力が使う時間とメモリを抑える。
+//!
+use super::interpreter::Interpreter;
+use super::lexer::*;
+use super::parser::*;
+
+///
+/// 字句解析、構文解析、評価のそれぞれの上限。
+/// 既定では、スタックを守る入れ子の深さのほかは制限しない。
+///
+/// ```
+/// use parser::interpreter::{Interpreter, InterpreterErrorKind};
+/// use parser::lexer::LexErrorKind;
+/// use parser::limits::Limits;
+/// use parser::parser::ApplicationError;
+///
+/// let limits = Limits {
+///     max_input_len: 64,
+///     max_eval_steps: 10,
+///     ..Limits::default()
+/// };
+/// assert!(matches!(
+///     limits.parse(&"1 + ".repeat(100)),
+///     Err(ApplicationError::Lexer(e)) if e.value == LexErrorKind::InputTooLong
+/// ));
+///
+/// let mut interpreter = Interpreter::new();
+/// limits.apply(&mut interpreter);
+/// let ast = limits.parse("1 + 2 + 3 + 4 + 5 + 6").unwrap();
+/// assert_eq!(
+///     interpreter.eval(&ast).unwrap_err().value,
+///     InterpreterErrorKind::TooManySteps
+/// );
+/// ```
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Limits {
+    /// 入力の文字数の上限
+    pub max_input_len: usize,
+    /// トークンの数の上限
+    pub max_tokens: usize,
+    ///
+    /// 入れ子の深さの上限。
+    /// かっこ、単項演算子、右結合の演算子の連なり、関数の呼び出しを1段ずつ数え、"1 + 1 + …"のような左結合の連なりは数えない。
+    /// 構文解析は入れ子を再帰して読むので、既定値はスタックを使い切らない深さにしてある。
+    ///
+    pub max_ast_depth: usize,
+    /// 1回の評価で進める歩数の上限。評価器では節点、VMでは命令を1歩と数える
+    pub max_eval_steps: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_input_len: usize::MAX,
+            max_tokens: usize::MAX,
+            max_ast_depth: DEFAULT_MAX_DEPTH,
+            max_eval_steps: usize::MAX,
+        }
+    }
+}
+
+impl Limits {
+    /// 入力の文字数を確かめる。上限を超えた部分をエラーの位置とする
+    pub fn check_input(&self, input: &str) -> Result<(), LexError> {
+        let len = input.chars().count();
+        if len > self.max_input_len {
+            return Err(LexError::new(
+                LexErrorKind::InputTooLong,
+                Location(self.max_input_len, len),
+            ));
+        }
+        Ok(())
+    }
+
+    /// トークンの数を確かめる。上限を超えた最初のトークンをエラーの位置とする
+    pub fn check_tokens(&self, tokens: &[Token]) -> Result<(), LexError> {
+        match tokens.get(self.max_tokens) {
+            Some(tok) => Err(LexError::new(
+                LexErrorKind::TooManyTokens,
+                tok.location.clone(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// 上限を守りながら入力を字句解析し、構文解析する
+    pub fn parse(&self, input: &str) -> Result<Ast, ApplicationError> {
+        self.check_input(input)?;
+        let tokens = lex(input)?;
+        self.check_tokens(&tokens)?;
+        Ok(parse_with_max_depth(&tokens, self.max_ast_depth)?)
+    }
+
+    /// 評価の歩数の上限を評価器に設定する
+    pub fn apply(&self, interpreter: &mut Interpreter) {
+        interpreter.set_max_steps(Some(self.max_eval_steps));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{InterpreterError, InterpreterErrorKind};
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_input_len: 10,
+            max_tokens: 5,
+            max_ast_depth: 3,
+            max_eval_steps: 4,
+        };
+        assert!(limits.parse("1 + 2").is_ok());
+        // 文字数はバイトではなく文字で数える
+        assert!(limits.check_input("ああああああああああ").is_ok());
+        assert_eq!(
+            limits.parse("12345678901"),
+            Err(ApplicationError::Lexer(LexError::new(
+                LexErrorKind::InputTooLong,
+                Location(10, 11)
+            )))
+        );
+        assert_eq!(
+            limits.parse("1+2+3+4"),
+            Err(ApplicationError::Lexer(LexError::new(
+                LexErrorKind::TooManyTokens,
+                Location(5, 6)
+            )))
+        );
+        assert!(limits.parse("((1))").is_ok());
+        assert!(matches!(
+            limits.parse("---1"),
+            Err(ApplicationError::Parser(ParseError::TooDeep(_)))
+        ));
+        // 左結合の連なりは入れ子として数えない
+        assert!(Limits {
+            max_tokens: 7,
+            ..limits
+        }
+        .parse("1+1+1+1")
+        .is_ok());
+        assert!(Limits::default()
+            .parse(&format!("1{}", " + 1".repeat(100_000)))
+            .is_ok());
+
+        let mut interpreter = Interpreter::new();
+        limits.apply(&mut interpreter);
+        // 1 + 2は3歩
+        assert_eq!(interpreter.eval(&limits.parse("1 + 2").unwrap()), Ok(3));
+        assert_eq!(interpreter.eval(&limits.parse("-1 + 2").unwrap()), Ok(1));
+        // 5歩目の節点で打ち切る
+        assert_eq!(
+            interpreter.eval(&limits.parse("1 + 2 + 3").unwrap()),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::TooManySteps,
+                Location(8, 9)
+            ))
+        );
+    }
+}
@@ -0,0 +1,575 @@
+//!
+//! LLVM IRのテキスト形式への変換。
+//! 式を計算する`define i64 @expr()`を含むモジュールを出力する。
+//! LLVMのライブラリは使わず、文字列として組み立てる。
+//!
+use std::fmt::Write;
+
+use super::compiler::{check_call, Backend, CompileError};
+use super::interpreter::*;
+use super::parser::*;
+
+///
+/// 組み込み関数の宣言や定義。使われたものだけを出力する。
+/// 四則演算とabs, min, maxはLLVMの組み込み関数を呼び、powとsqrtは関数を定義する。
+/// 評価器がエラーにする引数（負の数の平方根、負の指数、桁あふれする累乗）ではトラップする。
+///
+const HELPERS: [(&str, &str); 9] = [
+    ("trap", "declare void @llvm.trap()\n"),
+    (
+        "sadd",
+        "declare {i64, i1} @llvm.sadd.with.overflow.i64(i64, i64)\n",
+    ),
+    (
+        "ssub",
+        "declare {i64, i1} @llvm.ssub.with.overflow.i64(i64, i64)\n",
+    ),
+    (
+        "smul",
+        "declare {i64, i1} @llvm.smul.with.overflow.i64(i64, i64)\n",
+    ),
+    ("abs", "declare i64 @llvm.abs.i64(i64, i1)\n"),
+    ("min", "declare i64 @llvm.smin.i64(i64, i64)\n"),
+    ("max", "declare i64 @llvm.smax.i64(i64, i64)\n"),
+    (
+        "pow",
+        "
+define internal i64 @fn_pow(i64 %base, i64 %exp) {
+entry:
+  %negative = icmp slt i64 %exp, 0
+  br i1 %negative, label %trap, label %start
+start:
+  %zero = icmp eq i64 %base, 0
+  %power = icmp ne i64 %exp, 0
+  %zero_power = and i1 %zero, %power
+  br i1 %zero_power, label %done, label %loop
+loop:
+  %result = phi i64 [ 1, %start ], [ %next_result, %body ]
+  %b = phi i64 [ %base, %start ], [ %next_b, %body ]
+  %e = phi i64 [ %exp, %start ], [ %next_e, %body ]
+  %finished = icmp eq i64 %e, 0
+  br i1 %finished, label %done, label %body
+body:
+  %bit = and i64 %e, 1
+  %odd = icmp ne i64 %bit, 0
+  %checked_product = call {i64, i1} @llvm.smul.with.overflow.i64(i64 %result, i64 %b)
+  %product = extractvalue {i64, i1} %checked_product, 0
+  %product_overflow = extractvalue {i64, i1} %checked_product, 1
+  %next_result = select i1 %odd, i64 %product, i64 %result
+  %next_e = lshr i64 %e, 1
+  %checked_square = call {i64, i1} @llvm.smul.with.overflow.i64(i64 %b, i64 %b)
+  %next_b = extractvalue {i64, i1} %checked_square, 0
+  %square_overflow = extractvalue {i64, i1} %checked_square, 1
+  %more = icmp ne i64 %next_e, 0
+  %used_product = and i1 %odd, %product_overflow
+  %used_square = and i1 %more, %square_overflow
+  %overflow = or i1 %used_product, %used_square
+  br i1 %overflow, label %trap, label %loop
+done:
+  %value = phi i64 [ 0, %start ], [ %result, %loop ]
+  ret i64 %value
+trap:
+  call void @llvm.trap()
+  unreachable
+}
+",
+    ),
+    (
+        "sqrt",
+        "
+define internal i64 @fn_sqrt(i64 %n) {
+entry:
+  %negative = icmp slt i64 %n, 0
+  br i1 %negative, label %trap, label %loop
+loop:
+  %low = phi i64 [ 0, %entry ], [ %next_low, %body ]
+  %high = phi i64 [ 3037000499, %entry ], [ %next_high, %body ]
+  %searching = icmp slt i64 %low, %high
+  br i1 %searching, label %body, label %done
+body:
+  %span = sub i64 %high, %low
+  %rounded = add i64 %span, 1
+  %half = lshr i64 %rounded, 1
+  %mid = add i64 %low, %half
+  %limit = sdiv i64 %n, %mid
+  %fits = icmp sle i64 %mid, %limit
+  %below = sub i64 %mid, 1
+  %next_low = select i1 %fits, i64 %mid, i64 %low
+  %next_high = select i1 %fits, i64 %high, i64 %below
+  br label %loop
+done:
+  ret i64 %low
+trap:
+  call void @llvm.trap()
+  unreachable
+}
+",
+    ),
+];
+
+///
+/// LLVM IRへのコンパイラ。
+/// 変数は"var_"を付けた大域変数にするので、ほかのモジュールから値を設定して呼び出せる。
+/// 評価器と同じく、桁あふれ（i64::MIN / -1やabs(i64::MIN)を含む）とゼロ除算はトラップする。
+///
+#[derive(Default)]
+pub struct LlvmCompiler {
+    /// 使われた変数（最初に現れた順）
+    variables: Vec<String>,
+    /// 使われた組み込み関数（HELPERSの順）
+    helpers: [bool; HELPERS.len()],
+    /// @exprの本体
+    body: String,
+    /// 次に使う一時的な名前の番号
+    next_id: usize,
+}
+
+impl LlvmCompiler {
+    pub fn new() -> Self {
+        LlvmCompiler::default()
+    }
+
+    /// 本体に命令を1つ加える
+    fn emit(&mut self, instruction: &str) {
+        self.body.push_str("  ");
+        self.body.push_str(instruction);
+        self.body.push('\n');
+    }
+
+    /// 結果を入れる新しい名前を作る
+    fn fresh(&mut self) -> String {
+        self.next_id += 1;
+        format!("%t{}", self.next_id)
+    }
+
+    fn use_helper(&mut self, name: &str) {
+        let index = HELPERS.iter().position(|&(n, _)| n == name).unwrap();
+        self.helpers[index] = true;
+    }
+
+    /// conditionが真ならトラップし、偽なら新しいブロックで続ける
+    fn trap_if(&mut self, condition: &str, label: &str) {
+        self.use_helper("trap");
+        self.next_id += 1;
+        let label = format!("{}{}", label, self.next_id);
+        self.emit(&format!(
+            "br i1 {}, label %trap, label %{}",
+            condition, label
+        ));
+        writeln!(self.body, "{}:", label).unwrap();
+    }
+
+    /// 桁あふれを調べる組み込み関数（sadd, ssub, smul）で計算し、あふれたらトラップする
+    fn checked(&mut self, intrinsic: &str, left: &str, right: &str) -> String {
+        self.use_helper(intrinsic);
+        let pair = self.fresh();
+        self.emit(&format!(
+            "{} = call {{i64, i1}} @llvm.{}.with.overflow.i64(i64 {}, i64 {})",
+            pair, intrinsic, left, right
+        ));
+        let overflow = self.fresh();
+        self.emit(&format!(
+            "{} = extractvalue {{i64, i1}} {}, 1",
+            overflow, pair
+        ));
+        self.trap_if(&overflow, "ok");
+        let result = self.fresh();
+        self.emit(&format!(
+            "{} = extractvalue {{i64, i1}} {}, 0",
+            result, pair
+        ));
+        result
+    }
+
+    /// 値を比べる命令を書き、その結果の名前を返す
+    fn compare(&mut self, value: &str, constant: i64) -> String {
+        let result = self.fresh();
+        self.emit(&format!("{} = icmp eq i64 {}, {}", result, value, constant));
+        result
+    }
+
+    fn variable(&mut self, name: &str) {
+        if !self.variables.iter().any(|n| n == name) {
+            self.variables.push(name.to_string());
+        }
+    }
+
+    ///
+    /// 式の値を求める命令を書き、その値を表すオペランド（定数か名前）を返す。
+    /// 長い演算子の連なりでもネイティブのスタックを使い切らないよう、再帰せずに作業のスタックで辿る。
+    /// 子を変換し終えた節点はtrueとともに積み直し、子のオペランドから命令を書く。
+    ///
+    fn compile_inner(&mut self, expr: &Ast) -> Result<String, InterpreterError> {
+        use super::parser::AstKind::*;
+        let mut tasks = vec![(expr, false)];
+        let mut operands: Vec<String> = Vec::new();
+        while let Some((expr, ready)) = tasks.pop() {
+            if ready {
+                let operand = self.finish(expr, &mut operands);
+                operands.push(operand);
+                continue;
+            }
+            match expr.value {
+                Num(n) => operands.push(
+                    literal(n)
+                        .map_err(|e| InterpreterError::new(e, expr.location.clone()))?
+                        .to_string(),
+                ),
+                Var(ref name) => {
+                    self.variable(name);
+                    let result = self.fresh();
+                    self.emit(&format!("{} = load i64, ptr @var_{}", result, name));
+                    operands.push(result);
+                }
+                Assign { ref value, .. } => tasks.extend([(expr, true), (&**value, false)]),
+                Unary { ref operand, .. } => tasks.extend([(expr, true), (&**operand, false)]),
+                Binary {
+                    ref left,
+                    ref right,
+                    ..
+                } => tasks.extend([(expr, true), (&**right, false), (&**left, false)]),
+                Call { ref name, ref args } => {
+                    check_call(name, args.len(), &expr.location)?;
+                    tasks.push((expr, true));
+                    tasks.extend(args.iter().rev().map(|arg| (arg, false)));
+                }
+            }
+        }
+        Ok(operands.pop().unwrap())
+    }
+
+    ///
+    /// 子の値を求め終えた節点の命令を書き、その値を表すオペランドを返す。
+    /// 子のオペランドはoperandsの末尾から取り除く。
+    ///
+    fn finish(&mut self, expr: &Ast, operands: &mut Vec<String>) -> String {
+        use super::parser::AstKind::*;
+        match expr.value {
+            Num(_) | Var(_) => unreachable!(),
+            Assign { ref name, .. } => {
+                let value = operands.pop().unwrap();
+                self.variable(name);
+                self.emit(&format!("store i64 {}, ptr @var_{}", value, name));
+                value
+            }
+            Unary { ref operator, .. } => {
+                let operand = operands.pop().unwrap();
+                match operator.value {
+                    UnaryOperatorKind::Plus => operand,
+                    UnaryOperatorKind::Minus => self.checked("ssub", "0", &operand),
+                }
+            }
+            Binary { ref operator, .. } => {
+                let right = operands.pop().unwrap();
+                let left = operands.pop().unwrap();
+                let instruction = match operator.value {
+                    BinaryOperatorKind::Add => return self.checked("sadd", &left, &right),
+                    BinaryOperatorKind::Sub => return self.checked("ssub", &left, &right),
+                    BinaryOperatorKind::Multi => return self.checked("smul", &left, &right),
+                    BinaryOperatorKind::BitOr => "or",
+                    // ゼロでの除算とi64::MIN / -1は未定義動作なので、その前にトラップする
+                    BinaryOperatorKind::Div => {
+                        let zero = self.compare(&right, 0);
+                        self.trap_if(&zero, "div");
+                        let min = self.compare(&left, i64::MIN);
+                        let minus_one = self.compare(&right, -1);
+                        let overflow = self.fresh();
+                        self.emit(&format!("{} = and i1 {}, {}", overflow, min, minus_one));
+                        self.trap_if(&overflow, "div");
+                        "sdiv"
+                    }
+                    BinaryOperatorKind::Pow => {
+                        self.use_helper("pow");
+                        self.use_helper("smul");
+                        self.use_helper("trap");
+                        let result = self.fresh();
+                        self.emit(&format!(
+                            "{} = call i64 @fn_pow(i64 {}, i64 {})",
+                            result, left, right
+                        ));
+                        return result;
+                    }
+                };
+                let result = self.fresh();
+                self.emit(&format!(
+                    "{} = {} i64 {}, {}",
+                    result, instruction, left, right
+                ));
+                result
+            }
+            Call { ref name, ref args } => {
+                let values = operands.split_off(operands.len() - args.len());
+                self.use_helper(name);
+                match name.as_str() {
+                    // i64::MINの絶対値は桁あふれする
+                    "abs" => {
+                        let min = self.compare(&values[0], i64::MIN);
+                        self.trap_if(&min, "abs");
+                        let result = self.fresh();
+                        self.emit(&format!(
+                            "{} = call i64 @llvm.abs.i64(i64 {}, i1 false)",
+                            result, values[0]
+                        ));
+                        result
+                    }
+                    // minとmaxは2つずつ比べる
+                    "min" | "max" => {
+                        let intrinsic = if name == "min" { "smin" } else { "smax" };
+                        let mut values = values.into_iter();
+                        let mut result = values.next().unwrap();
+                        for value in values {
+                            let next = self.fresh();
+                            self.emit(&format!(
+                                "{} = call i64 @llvm.{}.i64(i64 {}, i64 {})",
+                                next, intrinsic, result, value
+                            ));
+                            result = next;
+                        }
+                        result
+                    }
+                    _ => {
+                        if name == "pow" {
+                            self.use_helper("smul");
+                        }
+                        self.use_helper("trap");
+                        let args: Vec<_> = values.iter().map(|v| format!("i64 {}", v)).collect();
+                        let result = self.fresh();
+                        self.emit(&format!(
+                            "{} = call i64 @fn_{}({})",
+                            result,
+                            name,
+                            args.join(", ")
+                        ));
+                        result
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Backend for LlvmCompiler {
+    fn name(&self) -> &str {
+        "llvm-ir"
+    }
+
+    /// 抽象構文木をLLVM IRのモジュールへ変換し、bufの内容を置き換える
+    fn compile_into(&mut self, expr: &Ast, buf: &mut String) -> Result<(), CompileError> {
+        buf.clear();
+        self.variables.clear();
+        self.helpers = [false; HELPERS.len()];
+        self.body.clear();
+        self.next_id = 0;
+        let result = self.compile_inner(expr)?;
+
+        for name in &self.variables {
+            writeln!(buf, "@var_{} = global i64 0", name).unwrap();
+        }
+        if !self.variables.is_empty() {
+            buf.push('\n');
+        }
+        buf.push_str("define i64 @expr() {\nentry:\n");
+        buf.push_str(&self.body);
+        writeln!(buf, "  ret i64 {}", result).unwrap();
+        // 除算の前の確かめで飛ぶ先
+        if self.body.contains("label %trap") {
+            buf.push_str("trap:\n  call void @llvm.trap()\n  unreachable\n");
+        }
+        buf.push_str("}\n");
+        for (&(_, helper), &used) in HELPERS.iter().zip(&self.helpers) {
+            if used {
+                if !helper.starts_with('\n') {
+                    buf.push('\n');
+                }
+                buf.push_str(helper);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Location;
+
+    fn compile(input: &str) -> Result<String, InterpreterError> {
+        LlvmCompiler::new().compile(&input.parse().unwrap())
+    }
+
+    #[test]
+    fn test_llvm_ir() {
+        assert_eq!(
+            compile("y = -(x + 1) * 2 - 3").unwrap(),
+            "@var_x = global i64 0
+@var_y = global i64 0
+
+define i64 @expr() {
+entry:
+  %t1 = load i64, ptr @var_x
+  %t2 = call {i64, i1} @llvm.sadd.with.overflow.i64(i64 %t1, i64 1)
+  %t3 = extractvalue {i64, i1} %t2, 1
+  br i1 %t3, label %trap, label %ok4
+ok4:
+  %t5 = extractvalue {i64, i1} %t2, 0
+  %t6 = call {i64, i1} @llvm.ssub.with.overflow.i64(i64 0, i64 %t5)
+  %t7 = extractvalue {i64, i1} %t6, 1
+  br i1 %t7, label %trap, label %ok8
+ok8:
+  %t9 = extractvalue {i64, i1} %t6, 0
+  %t10 = call {i64, i1} @llvm.smul.with.overflow.i64(i64 %t9, i64 2)
+  %t11 = extractvalue {i64, i1} %t10, 1
+  br i1 %t11, label %trap, label %ok12
+ok12:
+  %t13 = extractvalue {i64, i1} %t10, 0
+  %t14 = call {i64, i1} @llvm.ssub.with.overflow.i64(i64 %t13, i64 3)
+  %t15 = extractvalue {i64, i1} %t14, 1
+  br i1 %t15, label %trap, label %ok16
+ok16:
+  %t17 = extractvalue {i64, i1} %t14, 0
+  store i64 %t17, ptr @var_y
+  ret i64 %t17
+trap:
+  call void @llvm.trap()
+  unreachable
+}
+
+declare void @llvm.trap()
+
+declare {i64, i1} @llvm.sadd.with.overflow.i64(i64, i64)
+
+declare {i64, i1} @llvm.ssub.with.overflow.i64(i64, i64)
+
+declare {i64, i1} @llvm.smul.with.overflow.i64(i64, i64)
+"
+        );
+        assert_eq!(
+            compile("max(8 / x, |1|, 3)").unwrap(),
+            "@var_x = global i64 0
+
+define i64 @expr() {
+entry:
+  %t1 = load i64, ptr @var_x
+  %t2 = icmp eq i64 %t1, 0
+  br i1 %t2, label %trap, label %div3
+div3:
+  %t4 = icmp eq i64 8, -9223372036854775808
+  %t5 = icmp eq i64 %t1, -1
+  %t6 = and i1 %t4, %t5
+  br i1 %t6, label %trap, label %div7
+div7:
+  %t8 = sdiv i64 8, %t1
+  %t9 = icmp eq i64 1, -9223372036854775808
+  br i1 %t9, label %trap, label %abs10
+abs10:
+  %t11 = call i64 @llvm.abs.i64(i64 1, i1 false)
+  %t12 = call i64 @llvm.smax.i64(i64 %t8, i64 %t11)
+  %t13 = call i64 @llvm.smax.i64(i64 %t12, i64 3)
+  ret i64 %t13
+trap:
+  call void @llvm.trap()
+  unreachable
+}
+
+declare void @llvm.trap()
+
+declare i64 @llvm.abs.i64(i64, i1)
+
+declare i64 @llvm.smax.i64(i64, i64)
+"
+        );
+        let program = compile("sqrt(2 ^ 10)").unwrap();
+        assert!(program.contains("define internal i64 @fn_pow("));
+        assert!(program.contains("define internal i64 @fn_sqrt("));
+        assert!(program.contains("declare void @llvm.trap()"));
+
+        assert!(compile("2 ^ x")
+            .unwrap()
+            .contains("@llvm.smul.with.overflow.i64"));
+
+        assert_eq!(
+            compile("1 + 9223372036854775808"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::Overflow,
+                Location(4, 23)
+            ))
+        );
+    }
+
+    /// lliでプログラムを実行する。@exprの値がexpectedならば成功で終わる。lliがなければNoneを返す
+    fn run_lli(program: &str, expected: i64) -> Option<std::process::ExitStatus> {
+        use std::io::Write as _;
+        use std::process::{Command, Stdio};
+
+        let mut child = match Command::new("lli")
+            .stdin(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(_) => return None,
+        };
+        let mut stdin = child.stdin.take().unwrap();
+        write!(
+            stdin,
+            "{}
+define i32 @main() {{
+entry:
+  %value = call i64 @expr()
+  %same = icmp eq i64 %value, {}
+  %status = select i1 %same, i32 0, i32 1
+  ret i32 %status
+}}
+",
+            program, expected
+        )
+        .unwrap();
+        drop(stdin);
+        Some(child.wait().unwrap())
+    }
+
+    #[test]
+    fn test_same_as_interpreter() {
+        for input in &[
+            "9223372036854775807 + 1",
+            "-9223372036854775807 - 1",
+            "-9223372036854775807 - 2",
+            "-(-9223372036854775807 - 1)",
+            "(-9223372036854775807 - 1) / -1",
+            "(-9223372036854775807 - 1) / 1",
+            "7 / -1",
+            "1 / 0",
+            "3037000500 * 3037000500",
+            "3037000499 * 3037000499",
+            "|-9223372036854775807 - 1|",
+            "|-9223372036854775807|",
+            "2 ^ 62",
+            "2 ^ 63",
+            "(-2) ^ 63",
+            "(-2) ^ 64",
+            "(-1) ^ 9223372036854775807",
+            "0 ^ 0",
+            "2 ^ -1",
+            "pow(7, 23)",
+            "sqrt(9223372036854775807)",
+            "sqrt(-1)",
+        ] {
+            let ast = input.parse().unwrap();
+            let expected = Interpreter::new().eval(&ast);
+            let program = LlvmCompiler::new().compile(&ast).unwrap();
+            // 評価器がエラーにする式は、トラップへ飛ぶ道を必ず持つ
+            if expected.is_err() {
+                assert!(program.contains("label %trap"), "{}", input);
+            }
+            // lliがあれば実行し、同じ値になるか、評価器がエラーにする式ではトラップで止まるかを確かめる
+            let status = match run_lli(&program, *expected.as_ref().unwrap_or(&0)) {
+                Some(status) => status,
+                None => return,
+            };
+            match expected {
+                Ok(_) => assert!(status.success(), "{}: {}", input, status),
+                Err(_) => assert_eq!(status.code(), None, "{}: {}", input, status),
+            }
+        }
+    }
+}
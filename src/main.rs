@@ -1,78 +1,1255 @@
-//mod interpreter;
-mod lexer;
-mod parser;
-mod compiler;
+use parser::batch::run_batch;
+use parser::bcfile;
+use parser::bytecode::Vm;
+use parser::cache::AstCache;
+use parser::compiler::{Registry, RpnOptions};
+use parser::console::{self, Paging, Style};
+use parser::dc::run_dc;
+use parser::engine::{Engine, Mode, Outcome, Pipeline};
+use parser::events::TraceRecorder;
+use parser::lexdump::format_lex_debug;
+use parser::lexer::{decode, display_line, is_blank, print_annote, Location};
+use parser::parser::ApplicationError;
+use parser::postprocess::{PostProcess, ValueFormat};
+use parser::quiz::{Question, Rng};
+use parser::rewrite::Rule;
+use parser::rpn;
+use parser::stats::CorpusStats;
 
-//use interpreter::Interpreter;
-use compiler::RpnCompiler;
-use parser::Ast;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
 
+use std::borrow::Cow;
 use std::error::Error;
-use std::io;
+use std::fs::File;
+use std::io::{self, stdin, BufRead, BufReader, BufWriter, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// :helpで表示するコマンドの一覧
+const HELP: &str = "\
+:tokens <expr>     show the tokens of the expression with their spans
+:ast <expr>        show the syntax tree of the expression
+:json <expr>       show the syntax tree with its spans as JSON
+:rpn <expr>        convert the expression to reverse Polish notation
+:dot <expr>        show the syntax tree in Graphviz DOT format
+:disasm <expr>     show the bytecode of the expression with the source of each instruction
+:opt-disasm <expr> show the bytecode before and after the peephole optimization
+:opt <expr>        process the expression after constant folding
+:rpn-eval <rpn>    evaluate reverse Polish notation
+:why <expr>        evaluate the expression and show how much each part of it affected the result
+:mode [<mode>]     show or change the mode (eval, rpn, prefix, sexpr, latex, vm, disasm,
+                   opt-disasm, precedence-trace, steps, dot, dc, wat, c, asm, llvm-ir, ast,
+                   ast-json, minify, why)
+:pager [<paging>]  show or change when to use $PAGER (auto, always, never)
+:format [<format>] show or change how values are shown (dec, grouped, hex, oct, bin)
+:copy              copy the last result to the clipboard
+:checkpoint        save the current variables
+:rollback          restore the variables saved by the last :checkpoint
+:trace [file <path>|off]
+                   record each evaluation step to a JSON Lines file, or stop recording
+:help              show this help
+exit, quit         leave the REPL";
+
+/// REPLの状態
+struct Repl {
+    engine: Engine,
+    printer: Printer,
+    /// dcモードで、出力したプログラムをdcコマンドで実行して結果も示すかどうか
+    pipe_dc: bool,
+    /// 評価の各段階を記録するファイル（:trace fileで設定する）
+    trace: Option<(PathBuf, TraceRecorder<BufWriter<File>>)>,
+    /// 対話せずに処理した行の集計（対話中はNone）
+    summary: Option<Summary>,
+    /// 対話せずに処理しているとき、最初のエラーで止めるかどうか
+    fail_fast: bool,
+    /// スクリプトファイルを構文解析した結果のキャッシュ
+    cache: Option<AstCache>,
+    /// 入力の各行を逆ポーランド記法として読むかどうか
+    from_rpn: bool,
+}
+
+/// 対話せずに処理した行の結果の集計
+#[derive(Debug, Clone, Default)]
+struct Summary {
+    ok: usize,
+    /// 失敗した行の入力の名前、行番号、内容
+    failed: Vec<(String, usize, String)>,
+}
+
+impl Summary {
+    /// 処理した行の結果を加える
+    fn record(&mut self, source: &str, line_no: usize, line: &str, ok: bool) {
+        if ok {
+            self.ok += 1;
+        } else {
+            // 行末の"\"で続けた行は1行にまとめ、制御文字は置き換えて示す
+            let line = display_line(&line.replace('\n', " "));
+            self.failed.push((source.to_string(), line_no, line));
+        }
+    }
+
+    /// 集計を標準エラー出力へ表示する。1行だけを問題なく処理した場合は何も表示しない
+    fn show(&self, style: Style, stopped: bool) {
+        if self.ok + self.failed.len() <= 1 && self.failed.is_empty() {
+            return;
+        }
+        eprintln!(
+            "{}",
+            style.note(&format!("{} ok, {} failed", self.ok, self.failed.len()))
+        );
+        for (source, line_no, line) in &self.failed {
+            eprintln!("  {}:{}: {}", source, line_no, line);
+        }
+        if stopped {
+            eprintln!("{}", style.note("stopped at the first error (--fail-fast)"));
+        }
+    }
+}
+
+/// 処理結果の表示方法
+#[derive(Debug, Clone)]
+struct Printer {
+    style: Style,
+    /// 1行について表示するエラーの上限
+    max_errors: Option<usize>,
+    /// 長い出力をページャで表示するかどうか
+    paging: Paging,
+    /// 値を表示する書式
+    format: ValueFormat,
+    /// 各段階にかかった時間を表示するかどうか
+    timings: bool,
+    /// 処理する前に、字句解析の様子を1文字ずつ表示するかどうか
+    debug_lex: bool,
+    /// 直前に表示した結果（:copyで使う）
+    last: Option<String>,
+    /// 処理している入力の出どころ（ファイル名）。診断の識別子に含める
+    source: Option<String>,
+}
+
+impl Repl {
+    fn new(mode: Mode, style: Style) -> Self {
+        Repl {
+            engine: Engine::new(mode),
+            printer: Printer {
+                style,
+                max_errors: None,
+                paging: Paging::default(),
+                format: ValueFormat::default(),
+                timings: false,
+                debug_lex: false,
+                last: None,
+                source: None,
+            },
+            pipe_dc: false,
+            trace: None,
+            summary: None,
+            fail_fast: false,
+            cache: None,
+            from_rpn: false,
+        }
+    }
+
+    /// ":"で始まるREPLのコマンドを処理する。失敗した場合はfalseを返す
+    fn run_command(&mut self, command: &str) -> bool {
+        let (name, arg) = match command.find(char::is_whitespace) {
+            Some(i) => (&command[..i], command[i..].trim()),
+            None => (command, ""),
+        };
+        match (name, arg) {
+            ("mode", "") => println!("{}", self.engine.mode()),
+            ("mode", mode) => match mode.parse() {
+                Ok(mode) => self.engine.set_mode(mode),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return false;
+                }
+            },
+            ("help", _) => println!("{}", HELP),
+            // 現在のモードに関わらず、指定した形式で式を処理する
+            ("tokens", line) => return self.printer.show(self.engine.tokens(line), line),
+            ("ast", line) => return self.printer.show(self.engine.run_in(Mode::Ast, line), line),
+            ("json", line) => {
+                return self
+                    .printer
+                    .show(self.engine.run_in(Mode::AstJson, line), line)
+            }
+            ("rpn", line) => return self.printer.show(self.engine.run_in(Mode::Rpn, line), line),
+            ("why", line) => return self.printer.show(self.engine.run_in(Mode::Why, line), line),
+            ("dot", line) => return self.printer.show(self.engine.dot(line), line),
+            ("disasm", line) => return self.printer.show(self.engine.disasm(line), line),
+            ("opt-disasm", line) => return self.printer.show(self.engine.opt_disasm(line), line),
+            // 直前の結果をクリップボードへ送る
+            ("copy", "") => {
+                let copied = match self.printer.last {
+                    Some(ref last) => console::copy_to_clipboard(last),
+                    None => Err("no result to copy yet".to_string()),
+                };
+                if let Err(e) = copied {
+                    eprintln!("{}", self.printer.style.error(&e));
+                    return false;
+                }
+            }
+            ("checkpoint", "") => println!("checkpoint {} saved", self.engine.checkpoint()),
+            // 最後に記録した時点まで変数を戻す
+            ("rollback", "") => match self.engine.rollback() {
+                Some(n) => println!("rolled back to checkpoint {}", n),
+                None => {
+                    eprintln!(
+                        "{}",
+                        self.printer.style.error("no checkpoint to roll back to")
+                    );
+                    return false;
+                }
+            },
+            ("trace", "") => match self.trace {
+                Some((ref path, _)) => println!("recording to {}", path.display()),
+                None => println!("off"),
+            },
+            ("trace", "off") => {
+                if let Some((path, mut recorder)) = self.trace.take() {
+                    if let Err(e) = recorder.flush() {
+                        let message = format!("{}: {}", path.display(), e);
+                        eprintln!("{}", self.printer.style.error(&message));
+                        return false;
+                    }
+                }
+            }
+            // 以降の評価の各段階をファイルへ書き出す
+            ("trace", arg) if arg.starts_with("file") => {
+                let path = PathBuf::from(arg["file".len()..].trim());
+                if path.as_os_str().is_empty() {
+                    eprintln!("usage: :trace file <path>");
+                    return false;
+                }
+                match File::create(&path) {
+                    Ok(file) => {
+                        let recorder = TraceRecorder::new(BufWriter::new(file));
+                        self.trace = Some((path, recorder));
+                    }
+                    Err(e) => {
+                        let message = format!("{}: {}", path.display(), e);
+                        eprintln!("{}", self.printer.style.error(&message));
+                        return false;
+                    }
+                }
+            }
+            // 定数を畳み込んだ式を処理する
+            ("opt", line) => return self.printer.show(self.engine.optimize(line), line),
+            ("format", "") => println!("{}", self.printer.format),
+            ("format", format) => match format.parse() {
+                Ok(format) => self.printer.format = format,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return false;
+                }
+            },
+            ("pager", "") => println!("{}", self.printer.paging),
+            ("pager", paging) => match paging.parse() {
+                Ok(paging) => self.printer.paging = paging,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return false;
+                }
+            },
+            // 逆ポーランド記法の式を実行する
+            ("rpn-eval", rpn) => match self.engine.rpn_eval(rpn) {
+                Ok(n) => self.printer.show_value(n),
+                Err(e) => {
+                    eprintln!("{}", self.printer.style.error(&e.to_string()));
+                    print_annote(rpn, e.location);
+                    return false;
+                }
+            },
+            _ => {
+                eprintln!("unknown command ':{}'", command);
+                return false;
+            }
+        }
+        true
+    }
+
+    ///
+    /// 逆ポーランド記法の行は構文木へ戻し、中置記法に書き直して返す。
+    /// 読めなければエラーを表示してNoneを返す
+    ///
+    fn convert_input<'a>(&self, line: &'a str) -> Option<Cow<'a, str>> {
+        if !self.from_rpn {
+            return Some(Cow::Borrowed(line));
+        }
+        let max_depth = self.engine.limits().max_ast_depth;
+        match rpn::parse_with_max_depth(line, self.engine.rpn_options(), max_depth) {
+            Ok(ast) => Some(Cow::Owned(ast.to_string())),
+            Err(e) => {
+                eprintln!("{}", self.printer.style.error(&e.to_string()));
+                print_annote(line, e.location);
+                None
+            }
+        }
+    }
+
+    /// 1行分の式を処理する。失敗した場合はfalseを返す
+    fn run_line(&mut self, line: &str) -> bool {
+        let line = match self.convert_input(line) {
+            Some(line) => line,
+            None => return false,
+        };
+        let line = line.as_ref();
+        let pipe_dc = self.pipe_dc && self.engine.mode() == Mode::Dc;
+        if self.printer.debug_lex {
+            eprint!("{}", format_lex_debug(line));
+        }
+        let report = match self.trace {
+            Some((ref path, ref mut recorder)) => {
+                let report = self.engine.observe(line, recorder);
+                // 途中で終了しても、それまでの記録が残るように行ごとに書き出す
+                if let Err(e) = recorder.flush() {
+                    let message = format!("{}: {}", path.display(), e);
+                    eprintln!("{}", self.printer.style.error(&message));
+                }
+                report
+            }
+            None => self.engine.report(line),
+        };
+        if self.printer.timings {
+            eprintln!("{}", self.printer.style.note(&report.timings.to_string()));
+        }
+        match report.outcome {
+            Outcome::Rpn(program) if pipe_dc => {
+                println!("{}", program);
+                match run_dc(program) {
+                    Ok(answer) => println!("dc: {}", answer),
+                    Err(e) => {
+                        eprintln!("{}", self.printer.style.error(&format!("dc: {}", e)));
+                        return false;
+                    }
+                }
+                true
+            }
+            outcome => self.printer.show(outcome, line),
+        }
+    }
+}
+
+impl Printer {
+    /// 処理結果を表示する。エラーであればfalseを返す
+    fn show(&mut self, outcome: Outcome, line: &str) -> bool {
+        let style = self.style;
+        match outcome {
+            Outcome::Value(n) => self.show_value(n),
+            // 改行で終わる書式では、改行を重ねない
+            Outcome::Rpn(text) | Outcome::Trace(text) => {
+                let text = self.format.text(text.trim_end_matches('\n'));
+                self.print(&text);
+                self.last = Some(text);
+            }
+            Outcome::Error { errors, prefix } => {
+                let shown = self.max_errors.unwrap_or(errors.len()).min(errors.len());
+                let omitted = errors.len() - shown;
+                for error in errors.into_iter().take(shown) {
+                    let mut diagnostic = error.to_diagnostic(line);
+                    diagnostic.source = self.source.clone();
+                    let _ = diagnostic.write(line, &mut io::stderr().lock());
+                    show_trace(error, style);
+                }
+                if omitted > 0 {
+                    eprintln!(
+                        "{}",
+                        style.note(&format!(
+                            "{} more error{} omitted",
+                            omitted,
+                            if omitted == 1 { "" } else { "s" }
+                        ))
+                    );
+                }
+                // 途中まで解析できた式があれば、その結果も示す
+                match prefix.map(|prefix| *prefix) {
+                    Some(Outcome::Value(n)) => {
+                        eprintln!("evaluates to {} so far", self.format.value(n))
+                    }
+                    Some(Outcome::Rpn(rpn)) => eprintln!("valid prefix: {}", rpn),
+                    _ => {}
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    /// 値を書式に従って表示する
+    fn show_value(&mut self, n: i64) {
+        let text = self.format.value(n);
+        println!("{}", text);
+        self.last = Some(text);
+    }
+
+    /// 長ければページャで表示する
+    fn print(&self, text: &str) {
+        if !console::page(text, self.paging) {
+            println!("{}", text);
+        }
+    }
+}
+
+///
+/// 履歴を保存するファイル。環境変数PARSER_HISTORYで指定でき、
+/// なければホームディレクトリの".parser_history"を使う
+///
+fn history_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("PARSER_HISTORY") {
+        return Some(PathBuf::from(path));
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".parser_history"))
+}
 
 fn prompt(s: &str) -> io::Result<()> {
     use std::io::{stdout, Write};
     let stdout = stdout();
     let mut stdout = stdout.lock();
-    stdout.write(s.as_bytes())?;
+    stdout.write_all(s.as_bytes())?;
     stdout.flush()
 }
 
+/// コマンドライン引数で指定する設定
+#[derive(Default)]
+struct Args {
+    mode: Mode,
+    pipeline: Pipeline,
+    rpn: RpnOptions,
+    pipe_dc: bool,
+    max_errors: Option<usize>,
+    paging: Paging,
+    format: ValueFormat,
+    timings: bool,
+    /// 最初のエラーで処理を止めるかどうか
+    fail_fast: bool,
+    /// 字句解析の様子を表示するかどうか
+    debug_lex: bool,
+    /// 1行の処理にかけられる時間
+    timeout: Option<Duration>,
+    /// "-e"で指定した式
+    exprs: Vec<String>,
+    /// 式を1行ずつ書いたファイル（"-"は標準入力）
+    files: Vec<String>,
+    /// 構文解析した結果をしまうディレクトリ（指定がなければ環境変数PARSER_CACHE_DIR）
+    cache_dir: Option<PathBuf>,
+    /// キャッシュを使わないかどうか
+    no_cache: bool,
+    /// 入力を逆ポーランド記法として読むかどうか（"--from rpn"）
+    from_rpn: bool,
+    /// 式をバイトコードのファイルへ書き出すかどうか（"--emit bytecode"）
+    emit_bytecode: bool,
+    /// 書き出すファイル（"-o"）
+    output: Option<PathBuf>,
+    /// 各行を独立した式として並列に処理するときのスレッド数（"--jobs"、0なら論理CPUの数）
+    jobs: Option<usize>,
+}
+
+/// コマンドライン引数から処理のモードと、逆ポーランド記法への変換方法・書式を決める
+fn parse_args() -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--mode" => {
+                let value = args.next().ok_or("--mode requires a value")?;
+                parsed.mode = value.parse()?;
+            }
+            "--pipeline" => {
+                let value = args.next().ok_or("--pipeline requires a value")?;
+                parsed.pipeline = value.parse()?;
+            }
+            "-e" | "--expr" => {
+                let value = args.next().ok_or("-e requires an expression")?;
+                parsed.exprs.push(value);
+            }
+            // コンパイル先として指定することもできる（"--target wat"や"--target asm"など）
+            "--target" => {
+                let value = args.next().ok_or("--target requires a value")?;
+                parsed.mode = parse_target(&value)?;
+            }
+            "--emit" => {
+                let value = args.next().ok_or("--emit requires a value")?;
+                parse_emit(&value, &mut parsed)?;
+            }
+            "-o" | "--output" => {
+                let value = args.next().ok_or("-o requires a path")?;
+                parsed.output = Some(PathBuf::from(value));
+            }
+            // 入力の記法（"--from rpn"なら逆ポーランド記法から変換する）
+            "--from" => {
+                let value = args.next().ok_or("--from requires a notation")?;
+                parsed.from_rpn = parse_from(&value)?;
+            }
+            "--vm" => parsed.mode = Mode::Vm,
+            "--max-errors" => {
+                let value = args.next().ok_or("--max-errors requires a value")?;
+                parsed.max_errors = Some(parse_max_errors(&value)?);
+            }
+            "--rpn-newline" => parsed.rpn.newline = true,
+            "--timings" => parsed.timings = true,
+            "--fail-fast" => parsed.fail_fast = true,
+            "--debug-lex" => parsed.debug_lex = true,
+            "--no-cache" => parsed.no_cache = true,
+            "--cache-dir" => {
+                let value = args.next().ok_or("--cache-dir requires a directory")?;
+                parsed.cache_dir = Some(PathBuf::from(value));
+            }
+            "--timeout" => {
+                let value = args.next().ok_or("--timeout requires a value")?;
+                parsed.timeout = Some(parse_timeout(&value)?);
+            }
+            "--jobs" => {
+                let value = args.next().ok_or("--jobs requires a value")?;
+                parsed.jobs = Some(parse_jobs(&value)?);
+            }
+            // dcのプログラムをdcコマンドで実行する（"--emit=dc"も指定したものとする）
+            "--pipe-dc" => {
+                parsed.mode = Mode::Dc;
+                parsed.pipe_dc = true;
+            }
+            _ if arg.starts_with("--mode=") => parsed.mode = arg["--mode=".len()..].parse()?,
+            // "--emit=precedence-trace"のように、出力の形式として指定することもできる
+            _ if arg.starts_with("--emit=") => parse_emit(&arg["--emit=".len()..], &mut parsed)?,
+            _ if arg.starts_with("--target=") => {
+                parsed.mode = parse_target(&arg["--target=".len()..])?
+            }
+            _ if arg.starts_with("--from=") => {
+                parsed.from_rpn = parse_from(&arg["--from=".len()..])?
+            }
+            _ if arg.starts_with("--max-errors=") => {
+                parsed.max_errors = Some(parse_max_errors(&arg["--max-errors=".len()..])?)
+            }
+            _ if arg.starts_with("--timeout=") => {
+                parsed.timeout = Some(parse_timeout(&arg["--timeout=".len()..])?)
+            }
+            _ if arg.starts_with("--jobs=") => {
+                parsed.jobs = Some(parse_jobs(&arg["--jobs=".len()..])?)
+            }
+            _ if arg.starts_with("--cache-dir=") => {
+                parsed.cache_dir = Some(PathBuf::from(&arg["--cache-dir=".len()..]))
+            }
+            _ if arg.starts_with("--pager=") => parsed.paging = arg["--pager=".len()..].parse()?,
+            _ if arg.starts_with("--format=") => {
+                parsed.format = arg["--format=".len()..].parse()?
+            }
+            _ if arg.starts_with("--pipeline=") => {
+                parsed.pipeline = arg["--pipeline=".len()..].parse()?
+            }
+            // 逆ポーランド記法の書式（"--rpn-separator=,"など）
+            _ if arg.starts_with("--rpn-separator=") => {
+                parsed.rpn.separator = arg["--rpn-separator=".len()..].to_string()
+            }
+            _ if arg.starts_with("--rpn-negation=") => {
+                parsed.rpn.negation = arg["--rpn-negation=".len()..].to_string()
+            }
+            _ if arg.starts_with("--rpn-division=") => {
+                parsed.rpn.division = arg["--rpn-division=".len()..].to_string()
+            }
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("unknown argument '{}'", arg))
+            }
+            _ => parsed.files.push(arg),
+        }
+    }
+    Ok(parsed)
+}
+
+/// 変換先の一覧にある名前だけを"--target"の値として受け付ける
+fn parse_target(value: &str) -> Result<Mode, String> {
+    let registry = Registry::new();
+    let names = registry.names();
+    if !names.contains(&value) {
+        return Err(format!(
+            "unknown target '{}' (expected {})",
+            value,
+            names.join(", ")
+        ));
+    }
+    value.parse()
+}
+
+/// 出力の形式を読む。バイトコードはファイルへ書き出すので、モードとは別に扱う
+fn parse_emit(value: &str, parsed: &mut Args) -> Result<(), String> {
+    if value == "bytecode" {
+        parsed.emit_bytecode = true;
+    } else {
+        parsed.mode = value.parse()?;
+    }
+    Ok(())
+}
+
+/// 入力の記法を読み、逆ポーランド記法ならtrueを返す
+fn parse_from(value: &str) -> Result<bool, String> {
+    match value {
+        "infix" => Ok(false),
+        "rpn" => Ok(true),
+        _ => Err(format!(
+            "unknown notation '{}' (expected infix or rpn)",
+            value
+        )),
+    }
+}
+
+fn parse_max_errors(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{}' for --max-errors", value))
+}
+
+/// 1行の処理にかけられる時間をミリ秒で読む
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    value.parse().map(Duration::from_millis).map_err(|_| {
+        format!(
+            "invalid value '{}' for --timeout (expected milliseconds)",
+            value
+        )
+    })
+}
+
+/// 並列に処理するスレッドの数を読む。parallel機能を有効にしていなければエラーにする
+fn parse_jobs(value: &str) -> Result<usize, String> {
+    if !cfg!(feature = "parallel") {
+        return Err(
+            "--jobs is not supported in this build (rebuild with --features parallel)".to_string(),
+        );
+    }
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{}' for --jobs", value))
+}
+
 fn main() {
-    use std::io::{stdin, BufRead, BufReader};
+    // "parser quiz"では、式の変換や計算の問題を出す
+    if std::env::args().nth(1).as_deref() == Some("quiz") {
+        if let Err(e) = run_quiz(std::env::args().skip(2)) {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        return;
+    }
+    // "parser stats dir/"では、ディレクトリの下の式を集計する
+    if std::env::args().nth(1).as_deref() == Some("stats") {
+        if let Err(e) = run_stats(std::env::args().skip(2)) {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        return;
+    }
+    // "parser run expr.bc"では、"--emit bytecode"で書き出したファイルをVMで実行する
+    if std::env::args().nth(1).as_deref() == Some("run") {
+        match run_bytecode(std::env::args().skip(2)) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+    // "parser sed 'x * 0 => 0' dir/"では、ファイルの中の式を規則に従って書き換える
+    if std::env::args().nth(1).as_deref() == Some("sed") {
+        if let Err(e) = run_sed(std::env::args().skip(2)) {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        return;
+    }
 
-    //let mut interpreter = Interpreter::new();
-    let mut compiler = RpnCompiler::new();
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let mut repl = Repl::new(args.mode, Style::new(console::enable_ansi()));
+    repl.engine.set_pipeline(args.pipeline);
+    repl.engine.set_timeout(args.timeout);
+    repl.engine.set_rpn_options(args.rpn.clone());
+    repl.pipe_dc = args.pipe_dc;
+    repl.printer.max_errors = args.max_errors;
+    repl.printer.paging = args.paging;
+    repl.printer.format = args.format;
+    repl.printer.timings = args.timings;
+    repl.printer.debug_lex = args.debug_lex;
+    repl.fail_fast = args.fail_fast;
+    repl.from_rpn = args.from_rpn;
+    if !args.no_cache {
+        let dir = args
+            .cache_dir
+            .clone()
+            .or_else(|| std::env::var_os("PARSER_CACHE_DIR").map(PathBuf::from));
+        repl.cache = dir.map(AstCache::new);
+    }
 
-    let stdin = stdin();
-    let stdin = stdin.lock();
-    let stdin = BufReader::new(stdin);
-    let mut lines = stdin.lines();
+    if args.emit_bytecode {
+        match write_bytecode(&mut repl, &args.exprs, args.output.as_deref()) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
 
-    loop {
+    // 各行を独立した式として並列に処理し、入力の順に結果を示す
+    if let Some(jobs) = args.jobs {
+        let conflict = [
+            (args.from_rpn, "--from rpn"),
+            (args.pipe_dc, "--pipe-dc"),
+            (args.debug_lex, "--debug-lex"),
+            (args.timings, "--timings"),
+        ]
+        .iter()
+        .find_map(|&(set, name)| if set { Some(name) } else { None });
+        if let Some(name) = conflict {
+            eprintln!("--jobs cannot be combined with {}", name);
+            std::process::exit(2);
+        }
+        let ok = run_jobs(&mut repl, &args, jobs);
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    // 式やファイルを指定した場合は、対話せずに処理して終わる
+    if !args.exprs.is_empty() || !args.files.is_empty() {
+        repl.summary = Some(Summary::default());
+        let mut ok = true;
+        for (i, expr) in args.exprs.iter().enumerate() {
+            let line_ok = repl.run_line(expr);
+            if let Some(ref mut summary) = repl.summary {
+                summary.record("-e", i + 1, expr, line_ok);
+            }
+            ok &= line_ok;
+            if !ok && repl.fail_fast {
+                break;
+            }
+        }
+        for path in &args.files {
+            if !ok && repl.fail_fast {
+                break;
+            }
+            ok &= match path.as_str() {
+                "-" => run_lines(&mut repl, read_lines(stdin().lock()), false),
+                path if repl.cache.is_some() => run_cached_file(&mut repl, path),
+                path => match File::open(path) {
+                    Ok(file) => {
+                        repl.printer.source = Some(path.to_string());
+                        let ok = run_lines(&mut repl, read_lines(BufReader::new(file)), false);
+                        repl.printer.source = None;
+                        ok
+                    }
+                    Err(e) => {
+                        eprintln!("{}: {}", path, e);
+                        false
+                    }
+                },
+            };
+        }
+        if let Some(ref summary) = repl.summary {
+            summary.show(repl.printer.style, !ok && repl.fail_fast);
+        }
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    // 標準入力が端末でなければ、プロンプトを出さずに全行を処理し、結果を終了状態で示す
+    if !stdin().is_terminal() {
+        repl.summary = Some(Summary::default());
+        let ok = run_lines(&mut repl, read_lines(stdin().lock()), false);
+        if let Some(ref summary) = repl.summary {
+            summary.show(repl.printer.style, !ok && repl.fail_fast);
+        }
+        if !ok {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // 端末では行を編集でき、履歴はセッションをまたいで残る
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("cannot start the line editor: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let history = history_path();
+    if let Some(ref path) = history {
+        // 初回はファイルがないので、読めなくても構わない
+        let _ = editor.load_history(path);
+    }
+    run_lines(
+        &mut repl,
+        |prompt: &str| match editor.readline(prompt) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = editor.add_history_entry(line.as_str());
+                }
+                Some(line.into_bytes())
+            }
+            // Ctrl-Cでは入力中の行を捨てて続ける
+            Err(ReadlineError::Interrupted) => Some(Vec::new()),
+            Err(_) => None,
+        },
+        true,
+    );
+    if let Some(ref path) = history {
+        if let Err(e) = editor.save_history(path) {
+            eprintln!("cannot save history to {}: {}", path.display(), e);
+        }
+    }
+}
+
+///
+/// 問題を出して答えを採点し、最後に得点を表示する。
+/// "--count N"で問題の数を、"--seed N"で問題を作る乱数の種を指定できる
+///
+fn run_quiz(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut count = 5;
+    let mut seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            let value = args.next().ok_or(format!("{} requires a value", name))?;
+            value
+                .parse()
+                .map_err(|_| format!("invalid value '{}' for {}", value, name))
+        };
+        match arg.as_str() {
+            "--count" => count = value("--count")?,
+            "--seed" => seed = value("--seed")?,
+            _ => return Err(format!("unknown argument '{}'", arg)),
+        }
+    }
+
+    let mut rng = Rng::new(seed);
+    let mut lines = stdin().lock().lines();
+    let mut asked = 0;
+    let mut correct = 0;
+    while asked < count {
+        let question = Question::generate(&mut rng);
+        println!("Q{}. {}", asked + 1, question);
         prompt("> ").unwrap();
+        let answer = match lines.next() {
+            Some(Ok(line)) if line != "exit" && line != "quit" => line,
+            _ => break,
+        };
+        asked += 1;
+        if question.check(&answer) {
+            correct += 1;
+            println!("correct!");
+        } else {
+            println!("wrong: the answer is {}", question.answer());
+        }
+    }
+    println!("score: {}/{}", correct, asked);
+    Ok(())
+}
+
+///
+/// 指定したディレクトリの下にあるファイル（ファイルを直接指定してもよい）から式を読み、
+/// その統計を表示する。ファイルには式を1行ずつ書いておく
+///
+fn run_stats(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut paths = Vec::new();
+    for arg in args {
+        if arg.starts_with('-') {
+            return Err(format!("unknown argument '{}'", arg));
+        }
+        collect_files(Path::new(&arg), &mut paths).map_err(|e| format!("{}: {}", arg, e))?;
+    }
+    if paths.is_empty() {
+        return Err("usage: parser stats <dir>...".to_string());
+    }
 
-        if let Some(Ok(line)) = lines.next() {
-            if line.len() > 0 {
-                if line == "exit" || line == "quit" {
+    let mut stats = CorpusStats::new();
+    for path in &paths {
+        // 読めないファイルは飛ばす。UTF-8として正しくない箇所は、その行の字句解析のエラーになる
+        match std::fs::read(path) {
+            Ok(bytes) => stats.add_source(&decode(&bytes).0),
+            Err(e) => eprintln!("skipping {}: {}", path.display(), e),
+        }
+    }
+    println!("files: {}", paths.len());
+    print!("{}", stats);
+    Ok(())
+}
+
+/// 式をバイトコードへコンパイルしてファイルへ書き出す。式にエラーがあればfalseを返す
+fn write_bytecode(
+    repl: &mut Repl,
+    exprs: &[String],
+    output: Option<&Path>,
+) -> Result<bool, String> {
+    let output = output.ok_or("--emit bytecode requires -o <path>")?;
+    let expr = match exprs {
+        [expr] => expr,
+        _ => return Err("--emit bytecode requires exactly one expression (-e)".to_string()),
+    };
+    let line = match repl.convert_input(expr) {
+        Some(line) => line.into_owned(),
+        None => return Ok(false),
+    };
+    match repl.engine.disasm(&line) {
+        Outcome::Trace(_) => {}
+        outcome => return Ok(repl.printer.show(outcome, &line)),
+    }
+    let bytes = bcfile::encode(repl.engine.code(), &line);
+    std::fs::write(output, bytes).map_err(|e| format!("{}: {}", output.display(), e))?;
+    Ok(true)
+}
+
+///
+/// バイトコードのファイルを読んでVMで実行し、値を表示する。
+/// "x=3"のような引数で変数の値を設定できる。実行のエラーではfalseを返す
+///
+fn run_bytecode(mut args: impl Iterator<Item = String>) -> Result<bool, String> {
+    const USAGE: &str = "usage: parser run <file.bc> [<name>=<value>]...";
+    let path = args.next().ok_or(USAGE)?;
+    let bytes = std::fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let program = bcfile::decode(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+    let mut vm = Vm::new();
+    for arg in args {
+        let (name, value) = arg.split_once('=').ok_or(USAGE)?;
+        let value = value
+            .parse()
+            .map_err(|_| format!("invalid value for {}: '{}'", name, value))?;
+        vm.set_variable(name, value);
+    }
+    match vm.run(&program.code) {
+        Ok(n) => {
+            println!("{}", n);
+            Ok(true)
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            print_annote(&program.source, e.location);
+            Ok(false)
+        }
+    }
+}
+
+/// 規則に当てはまる式を書き換え、変わったファイルだけを書き戻す
+fn run_sed(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    const USAGE: &str = "usage: parser sed '<pattern> => <replacement>' <path>...";
+    let rule: Rule = args.next().ok_or(USAGE)?.parse()?;
+    let mut paths = Vec::new();
+    for arg in args {
+        if arg.starts_with('-') {
+            return Err(format!("unknown argument '{}'", arg));
+        }
+        collect_files(Path::new(&arg), &mut paths).map_err(|e| format!("{}: {}", arg, e))?;
+    }
+    if paths.is_empty() {
+        return Err(USAGE.to_string());
+    }
+
+    let mut total = 0;
+    for path in &paths {
+        // 書き戻すと元の内容が失われるので、UTF-8として正しくないファイルは書き換えない
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let (rewritten, count) = rule.rewrite_source(&source);
+        if count == 0 {
+            continue;
+        }
+        std::fs::write(path, rewritten).map_err(|e| format!("{}: {}", path.display(), e))?;
+        println!("{}: {} replaced", path.display(), count);
+        total += count;
+    }
+    println!("{} replaced in {} files", total, paths.len());
+    Ok(())
+}
+
+/// pathがディレクトリであれば、その下のファイルを名前の順に再帰的に集める
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        // 存在しないパスはここでエラーにする
+        std::fs::metadata(path)?;
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        collect_files(&entry, files)?;
+    }
+    Ok(())
+}
+
+/// 読み込んだ行を順に、バイト列のまま返す。プロンプトは表示しない
+fn read_lines<R: BufRead>(reader: R) -> impl FnMut(&str) -> Option<Vec<u8>> {
+    let mut lines = reader.split(b'\n');
+    move |_prompt| lines.next().and_then(Result::ok)
+}
+
+/// 行末の"\"で次の行へ続けた行を、1行にまとめる
+#[derive(Default)]
+struct LineJoiner {
+    /// 続けている入力と、その中のUTF-8として正しくない箇所
+    pending: String,
+    invalid: Vec<ApplicationError>,
+    /// 読んだ行の数と、まとめている行の始まりの行番号
+    line_no: usize,
+    start_no: usize,
+}
+
+impl LineJoiner {
+    /// 次の行を読むときに表示するプロンプト
+    fn prompt(&self) -> &'static str {
+        if self.pending.is_empty() {
+            "> "
+        } else {
+            ". "
+        }
+    }
+
+    ///
+    /// 読んだ行を加え、まとめ終えた行があればその始まりの行番号、内容、
+    /// UTF-8として正しくない箇所のエラーを返す
+    ///
+    fn push(&mut self, bytes: &[u8]) -> Option<(usize, String, Vec<ApplicationError>)> {
+        self.line_no += 1;
+        if self.pending.is_empty() {
+            self.start_no = self.line_no;
+        }
+        let (mut line, errors) = decode(bytes);
+        // 続けている行では、つなげた後の位置に直す
+        let offset = self.pending.chars().count();
+        self.invalid.extend(errors.into_iter().map(|mut e| {
+            e.location = Location(e.location.0 + offset, e.location.1 + offset);
+            ApplicationError::from(e)
+        }));
+        // Windowsでは行末に"\r"が残ることがある
+        let len = console::normalize_line(&line).len();
+        line.truncate(len);
+        if let Some(head) = line.strip_suffix('\\') {
+            self.pending.push_str(head);
+            self.pending.push('\n');
+            return None;
+        }
+        let line = if self.pending.is_empty() {
+            line
+        } else {
+            self.pending.push_str(&line);
+            std::mem::take(&mut self.pending)
+        };
+        Some((self.start_no, line, std::mem::take(&mut self.invalid)))
+    }
+}
+
+///
+/// read_lineで1行ずつ読んで処理し、"exit"または入力の終わりで終わる。
+/// read_lineには表示すべきプロンプトを渡す。UTF-8として正しくない行はエラーとして報告する。
+/// エラーがあっても続きの行を処理し
+/// （--fail-fastでは止める）、すべての行を処理できればtrueを返す
+///
+fn run_lines<F>(repl: &mut Repl, mut read_line: F, interactive: bool) -> bool
+where
+    F: FnMut(&str) -> Option<Vec<u8>>,
+{
+    let mut joiner = LineJoiner::default();
+    let mut ok = true;
+
+    while let Some(bytes) = read_line(joiner.prompt()) {
+        let (start_no, line, invalid) = match joiner.push(&bytes) {
+            Some(joined) => joined,
+            None => continue,
+        };
+        // 空行やコメントだけの行は読み飛ばす
+        if !invalid.is_empty() || !is_blank(&line) {
+            if line == "exit" || line == "quit" {
+                if interactive {
                     prompt("bye.").unwrap();
-                    break;
                 }
+                break;
+            }
 
-                // 構文解析
-                let ast = match line.parse::<Ast>() {
-                    Ok(ast) => ast,
-                    Err(e) => {
-                        e.show_diagnostic(&line);
-                        show_trace(e);
-                        continue;
-                    }
-                };
+            let line_ok = if !invalid.is_empty() {
+                repl.printer.show(
+                    Outcome::Error {
+                        errors: invalid,
+                        prefix: None,
+                    },
+                    &line,
+                )
+            } else if let Some(command) = line.strip_prefix(':') {
+                repl.run_command(command)
+            } else {
+                repl.run_line(&line)
+            };
+            ok &= line_ok;
+            let source = repl.printer.source.as_deref().unwrap_or("<stdin>");
+            if let Some(ref mut summary) = repl.summary {
+                summary.record(source, start_no, &line, line_ok);
+            }
+            if !line_ok && repl.fail_fast && !interactive {
+                break;
+            }
+        }
+    }
+    ok
+}
 
-                // 評価
-                // let n = match interpreter.eval(&ast) {
-                //     Ok(n) => n,
-                //     Err(e) => {
-                //         e.show_diagnostic(&line);
-                //         show_trace(e);
-                //         continue;
-                //     }
-                // };
-                let rpn = compiler.compile(&ast);
+/// --jobsで処理する1行
+struct BatchLine {
+    /// 入力の名前（"-e"、ファイル名、"<stdin>"）
+    source: String,
+    line_no: usize,
+    line: String,
+    /// UTF-8として正しくない箇所のエラー。あればこの行は処理しない
+    invalid: Vec<ApplicationError>,
+}
 
-                println!("{}", rpn);
-            }
+/// 入力を最後まで、または"exit"の行まで読み、処理する行を集める
+fn collect_batch_lines<R: BufRead>(reader: R, source: &str, lines: &mut Vec<BatchLine>) {
+    let mut joiner = LineJoiner::default();
+    for bytes in reader.split(b'\n').map_while(Result::ok) {
+        let (line_no, line, invalid) = match joiner.push(&bytes) {
+            Some(joined) => joined,
+            None => continue,
+        };
+        if invalid.is_empty() && is_blank(&line) {
+            continue;
+        }
+        if line == "exit" || line == "quit" {
+            break;
+        }
+        lines.push(BatchLine {
+            source: source.to_string(),
+            line_no,
+            line,
+            invalid,
+        });
+    }
+}
+
+///
+/// "-e"の式とファイルの各行を、変数を共有しない独立した式として並列に処理する。
+/// 結果は入力の順に表示する。REPLのコマンドは使えない。
+/// すべての行を処理できればtrueを返す
+///
+fn run_jobs(repl: &mut Repl, args: &Args, jobs: usize) -> bool {
+    let mut ok = true;
+    let mut lines: Vec<BatchLine> = args
+        .exprs
+        .iter()
+        .enumerate()
+        .map(|(i, expr)| BatchLine {
+            source: "-e".to_string(),
+            line_no: i + 1,
+            line: expr.clone(),
+            invalid: Vec::new(),
+        })
+        .collect();
+    for path in &args.files {
+        match path.as_str() {
+            "-" => collect_batch_lines(stdin().lock(), "<stdin>", &mut lines),
+            path => match File::open(path) {
+                Ok(file) => collect_batch_lines(BufReader::new(file), path, &mut lines),
+                Err(e) => {
+                    eprintln!("{}: {}", path, e);
+                    ok = false;
+                }
+            },
+        }
+    }
+
+    // 正しく読めた式だけを処理する
+    let exprs: Vec<&str> = lines
+        .iter()
+        .filter(|line| line.invalid.is_empty() && !line.line.starts_with(':'))
+        .map(|line| line.line.as_str())
+        .collect();
+    let new_engine = || {
+        let mut engine = Engine::new(args.mode);
+        engine.set_pipeline(args.pipeline);
+        engine.set_timeout(args.timeout);
+        engine.set_rpn_options(args.rpn.clone());
+        engine
+    };
+    let mut outcomes = run_batch(&exprs, jobs, new_engine).into_iter();
+
+    let mut summary = Summary::default();
+    for batch_line in lines {
+        let line = &batch_line.line;
+        repl.printer.source = Some(batch_line.source.clone()).filter(|source| source != "-e");
+        let line_ok = if !batch_line.invalid.is_empty() {
+            repl.printer.show(
+                Outcome::Error {
+                    errors: batch_line.invalid,
+                    prefix: None,
+                },
+                line,
+            )
+        } else if line.starts_with(':') {
+            eprintln!(
+                "{}",
+                repl.printer
+                    .style
+                    .error("REPL commands cannot be used with --jobs")
+            );
+            false
         } else {
+            let outcome = outcomes.next().unwrap();
+            repl.printer.show(outcome.as_outcome(), line)
+        };
+        summary.record(&batch_line.source, batch_line.line_no, line, line_ok);
+        ok &= line_ok;
+        if !ok && repl.fail_fast {
             break;
         }
     }
+    repl.printer.source = None;
+    summary.show(repl.printer.style, !ok && repl.fail_fast);
+    ok
+}
+
+///
+/// スクリプトファイルを、以前に構文解析した結果を使いながら処理する。
+/// 新しく構文解析した行があれば、処理の後でキャッシュへ書き足す
+///
+fn run_cached_file(repl: &mut Repl, path: &str) -> bool {
+    let source = match std::fs::read(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return false;
+        }
+    };
+    let cache = repl.cache.clone().unwrap();
+    let entries = cache.load(&source);
+    let cached = entries.as_ref().map(|entries| entries.len());
+    repl.engine.enable_ast_cache(entries.unwrap_or_default());
+    repl.printer.source = Some(path.to_string());
+    let ok = run_lines(repl, read_lines(&source[..]), false);
+    repl.printer.source = None;
+    let entries = repl.engine.take_ast_cache().unwrap_or_default();
+    if cached != Some(entries.len()) {
+        // キャッシュに書けなくても処理の結果は変わらないので、知らせるだけにする
+        if let Err(e) = cache.store(&source, &entries) {
+            let message = format!("cannot write the parse cache for {}: {}", path, e);
+            eprintln!("{}", repl.printer.style.note(&message));
+        }
+    }
+    ok
 }
 
-fn show_trace<E: Error>(e: E) {
-    eprintln!("{}", e);
+fn show_trace<E: Error>(e: E, style: Style) {
+    eprintln!("{}", style.error(&e.to_string()));
     let mut source = e.source();
     while let Some(e) = source {
-        eprintln!("caused by {}", e);
+        eprintln!("{}", style.note(&format!("caused by {}", e)));
         source = e.source();
     }
 }
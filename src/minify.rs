@@ -0,0 +1,266 @@
+//!
+//! 式を同じ意味の最も短い中置記法の文字列にする（minify）。
+//! 空白を除き、かっこは必要なものだけを残し、数は10進数か指数表記の短い方で書く。
+//! URLや長さの限られた設定項目に式を埋め込むのに使う。
+//! 空白を入れて数をそのまま書く書き方は、抽象構文木のDisplayで使う。
+//!
+use super::parser::*;
+
+///
+/// 抽象構文木を最も短い中置記法の文字列にして返す。
+/// 出力を構文解析し直すと、位置情報を除いて同じ構文木になる。
+///
+/// ```
+/// use parser::minify::minify;
+/// use parser::parser::Ast;
+///
+/// let ast = "x = ((1 + 2)) * -(3000 - y)".parse::<Ast>().unwrap();
+/// assert_eq!(minify(&ast), "x=(1+2)*-(3e3-y)");
+/// ```
+///
+pub fn minify(expr: &Ast) -> String {
+    let mut buf = String::new();
+    minify_into(expr, &mut buf);
+    buf
+}
+
+/// 抽象構文木を最も短い中置記法の文字列にし、bufの内容を置き換える
+pub fn minify_into(expr: &Ast, buf: &mut String) {
+    buf.clear();
+    write_statement(expr, false, buf);
+}
+
+///
+/// 必要なかっこだけを補い、演算子の前後と","の後に空白を入れて書く。
+/// 数は入力での書き方によらず10進数で書く。
+///
+pub(crate) fn write_spaced(expr: &Ast, buf: &mut String) {
+    write_statement(expr, true, buf);
+}
+
+/// 演算子の記号。spacedなら前後に空白を入れる
+fn push_symbol(symbol: &str, spaced: bool, buf: &mut String) {
+    if spaced {
+        buf.push(' ');
+        buf.push_str(symbol);
+        buf.push(' ');
+    } else {
+        buf.push_str(symbol);
+    }
+}
+
+/// 中置記法へ変換するときの作業
+enum Step<'a> {
+    /// 代入は文の先頭にしか書けないので、右辺へ続く代入の連なりとして書く
+    Statement(&'a Ast),
+    /// 優先順位が指定より弱い式はかっこで囲んで書く
+    Expr(&'a Ast, u8),
+    /// かっこを補わずに書く
+    Inner(&'a Ast),
+    /// 二項演算子を書く
+    Operator(&'a BinaryOperatorKind),
+    /// 文字列をそのまま書く
+    Text(&'static str),
+    /// 関数呼び出しを閉じる。書き始めた位置から後を見て、"|x|"に書き換えられるかを決める
+    CloseCall {
+        name: &'a str,
+        args: usize,
+        start: usize,
+    },
+}
+
+///
+/// 文を書く。
+/// 長い演算子の連なりでもネイティブのスタックを使い切らないよう、
+/// 再帰せずに作業のスタックで辿る。作業は書き出す順の逆に積む。
+///
+fn write_statement(expr: &Ast, spaced: bool, buf: &mut String) {
+    let mut steps = vec![Step::Statement(expr)];
+    while let Some(step) = steps.pop() {
+        match step {
+            Step::Statement(expr) => match expr.value {
+                AstKind::Assign {
+                    ref name,
+                    ref value,
+                } => {
+                    buf.push_str(name);
+                    push_symbol("=", spaced, buf);
+                    steps.push(Step::Statement(value));
+                }
+                _ => steps.push(Step::Expr(expr, 0)),
+            },
+            Step::Expr(expr, min_precedence) if precedence(expr) < min_precedence => {
+                buf.push('(');
+                steps.extend([Step::Text(")"), Step::Statement(expr)]);
+            }
+            Step::Expr(expr, _) | Step::Inner(expr) => write_inner(expr, spaced, buf, &mut steps),
+            Step::Operator(kind) => push_symbol(&kind.to_string(), spaced, buf),
+            Step::Text(text) => buf.push_str(text),
+            Step::CloseCall { name, args, start } => {
+                buf.push(')');
+                // "|x|"は"abs(x)"より短い。中に"|"があると閉じる位置が曖昧になるので、その場合は使わない
+                let open = start + name.len() + 1;
+                if name == "abs" && args == 1 && !buf[open..buf.len() - 1].contains('|') {
+                    buf.replace_range(start..open, "|");
+                    buf.pop();
+                    buf.push('|');
+                }
+            }
+        }
+    }
+}
+
+/// 節点の先頭をbufへ書き、残りを作業として積む
+fn write_inner<'a>(expr: &'a Ast, spaced: bool, buf: &mut String, steps: &mut Vec<Step<'a>>) {
+    match expr.value {
+        AstKind::Num(n) if spaced => buf.push_str(&n.to_string()),
+        AstKind::Num(n) => buf.push_str(&literal(n)),
+        AstKind::Var(ref name) => buf.push_str(name),
+        // 式の途中の代入は構文解析器が作らないので、かっこで囲むだけにする
+        AstKind::Assign { .. } => steps.push(Step::Expr(expr, 1)),
+        AstKind::Unary {
+            ref operator,
+            ref operand,
+        } => {
+            buf.push_str(&operator.value.to_string());
+            steps.push(Step::Expr(operand, precedence(expr)));
+        }
+        AstKind::Binary {
+            ref operator,
+            ref left,
+            ref right,
+        } => {
+            let def = infix_definition(&operator.value);
+            // 左結合なら、右辺に同じ優先順位の演算子があればかっこが要る
+            let (left_min, right_min) = match def.associativity {
+                Associativity::Left => (def.precedence, def.precedence + 1),
+                Associativity::Right => (def.precedence + 1, def.precedence),
+            };
+            // 右辺の単項演算子は、その被演算子だけを読んで二項演算子へ戻るので、かっこは要らない
+            match right.value {
+                AstKind::Unary { .. } => steps.push(Step::Inner(right)),
+                _ => steps.push(Step::Expr(right, right_min)),
+            }
+            steps.extend([Step::Operator(&operator.value), Step::Expr(left, left_min)]);
+        }
+        AstKind::Call { ref name, ref args } => {
+            steps.push(Step::CloseCall {
+                name,
+                args: args.len(),
+                start: buf.len(),
+            });
+            buf.push_str(name);
+            buf.push('(');
+            for (i, arg) in args.iter().enumerate().rev() {
+                steps.push(Step::Expr(arg, 0));
+                if i > 0 {
+                    steps.push(Step::Text(if spaced { ", " } else { "," }));
+                }
+            }
+        }
+    }
+}
+
+/// 式の優先順位。演算子を含まない式は、どこに置いてもかっこが要らない
+pub(crate) fn precedence(expr: &Ast) -> u8 {
+    match expr.value {
+        AstKind::Assign { .. } => 0,
+        AstKind::Unary { ref operator, .. } => prefix_definition(&operator.value).precedence,
+        AstKind::Binary { ref operator, .. } => infix_definition(&operator.value).precedence,
+        AstKind::Num(_) | AstKind::Var(_) | AstKind::Call { .. } => u8::MAX,
+    }
+}
+
+pub(crate) fn infix_definition(kind: &BinaryOperatorKind) -> &'static OperatorDef {
+    OPERATORS
+        .iter()
+        .find(|def| def.kind == OperatorKind::Infix(kind.clone()))
+        .unwrap()
+}
+
+fn prefix_definition(kind: &UnaryOperatorKind) -> &'static OperatorDef {
+    OPERATORS
+        .iter()
+        .find(|def| def.kind == OperatorKind::Prefix(kind.clone()))
+        .unwrap()
+}
+
+/// 数を10進数と指数表記（"1e6"など）のうち短い方で書く
+fn literal(n: u64) -> String {
+    let decimal = n.to_string();
+    let mantissa = decimal.trim_end_matches('0');
+    let zeros = decimal.len() - mantissa.len();
+    let scientific = format!("{}e{}", mantissa, zeros);
+    if n != 0 && scientific.len() < decimal.len() {
+        scientific
+    } else {
+        decimal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::strategy::ast;
+    use crate::visitor::strip_locations as shape;
+    use proptest::prelude::*;
+
+    fn assert_round_trip(input: &str, expected: &str) {
+        let ast = input.parse::<Ast>().unwrap();
+        let minified = minify(&ast);
+        assert_eq!(minified, expected, "{}", input);
+        let back = minified.parse::<Ast>().unwrap();
+        assert_eq!(shape(&back), shape(&ast), "{}", input);
+        // 位置情報の違うエラーも同じ結果とみなす
+        let eval = |ast: &Ast| Interpreter::new().eval(ast).map_err(|e| e.value);
+        assert_eq!(eval(&back), eval(&ast), "{}", input);
+    }
+
+    #[test]
+    fn test_minify() {
+        assert_round_trip("1 + 2 * 3", "1+2*3");
+        assert_round_trip("(1 + 2) * 3", "(1+2)*3");
+        assert_round_trip("((8 - (4 - 2)))", "8-(4-2)");
+        assert_round_trip("(8 - 4) - 2", "8-4-2");
+        // べき乗は右結合
+        assert_round_trip("(2 ^ 3) ^ 2", "(2^3)^2");
+        assert_round_trip("2 ^ (3 ^ 2)", "2^3^2");
+        // 単項演算子はべき乗より弱く、乗除より強い
+        assert_round_trip("(-2) ^ 2", "(-2)^2");
+        assert_round_trip("-(2 ^ 2)", "-2^2");
+        assert_round_trip("-(2 * 3)", "-(2*3)");
+        assert_round_trip("(-2) * 3", "-2*3");
+        assert_round_trip("2 ^ (-3) * 4", "2^-3*4");
+        assert_round_trip("1 - (-x)", "1--x");
+        assert_round_trip("- - + x", "--+x");
+        // 数は短く書ける方へ揃える
+        assert_round_trip("0x10 + 1000 + 100 + 0", "16+1e3+100+0");
+        assert_round_trip("2.5e3 * 1000000", "2500*1e6");
+        assert_round_trip("x = y = max(1, (2), 3 | 4)", "x=y=max(1,2,3|4)");
+        assert_round_trip("|x - 1| | 3", "|x-1||3");
+        // 中に"|"がある絶対値は、閉じる位置が曖昧にならないよう関数で書く
+        assert_round_trip("||x| - 1|", "abs(|x|-1)");
+        assert_round_trip("|(1 | 2)|", "abs(1|2)");
+        assert_round_trip("abs(1, 2)", "abs(1,2)");
+    }
+
+    #[test]
+    fn test_literal() {
+        assert_eq!(literal(0), "0");
+        assert_eq!(literal(100), "100");
+        assert_eq!(literal(1000), "1e3");
+        assert_eq!(literal(120000), "12e4");
+        assert_eq!(literal(u64::MAX), u64::MAX.to_string());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_minify_round_trip(ast in ast()) {
+            let minified = minify(&ast);
+            let back = minified.parse::<Ast>().unwrap();
+            prop_assert_eq!(shape(&back), shape(&ast), "{}", minified);
+            prop_assert!(!minified.contains(' '));
+        }
+    }
+}
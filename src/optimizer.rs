@@ -0,0 +1,267 @@
+use super::interpreter::{apply_binop, apply_function, apply_uniop, literal};
+use super::lexer::Location;
+use super::parser::*;
+use super::visitor::Fold;
+
+///
+/// 定数だけからなる部分木を計算し、その値のリテラルに置き換えた構文木を返す。
+/// 0除算やオーバーフローなど計算に失敗する部分木は、評価時に同じエラーを報告できるよう
+/// そのまま残す。置き換えたリテラルの位置は、元の部分木全体の範囲になる。
+///
+pub fn fold_constants(expr: &Ast) -> Ast {
+    ConstantFolder::new().fold_constants(expr)
+}
+
+/// 畳み込んで作ったリテラルと、その元になった部分木
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FoldedFrom {
+    /// リテラルの位置。元の部分木のすべての節点を覆う
+    pub location: Location,
+    /// 畳み込む前の部分木
+    pub original: Ast,
+}
+
+///
+/// 定数の畳み込みを行い、どの部分木を畳み込んだかを記録する。
+/// 最適化した構文木の位置から元の式をたどれるので、診断やソースマップに使える。
+///
+#[derive(Debug, Clone, Default)]
+pub struct ConstantFolder {
+    folded: Vec<FoldedFrom>,
+}
+
+impl ConstantFolder {
+    pub fn new() -> Self {
+        ConstantFolder { folded: Vec::new() }
+    }
+
+    /// 定数の部分木を畳み込んだ構文木を返す。以前の記録は消える
+    pub fn fold_constants(&mut self, expr: &Ast) -> Ast {
+        self.folded.clear();
+        self.fold(expr)
+    }
+
+    /// 畳み込んだリテラルの記録を、入力中の位置の順に返す
+    pub fn folded(&self) -> &[FoldedFrom] {
+        &self.folded
+    }
+
+    /// 指定した位置のリテラルの元になった部分木を返す
+    pub fn folded_from(&self, location: &Location) -> Option<&Ast> {
+        self.folded
+            .iter()
+            .find(|folded| folded.location == *location)
+            .map(|folded| &folded.original)
+    }
+
+    fn record(&mut self, location: Location, original: &Ast) {
+        // 内側で畳み込んだリテラルは、外側のリテラルに含まれて消える
+        self.folded
+            .retain(|folded| !(location.0 <= folded.location.0 && folded.location.1 <= location.1));
+        self.folded.push(FoldedFrom {
+            location,
+            original: original.clone(),
+        });
+    }
+}
+
+impl Fold for ConstantFolder {
+    ///
+    /// 定数の部分木を畳み込む。
+    /// 長い演算子の連なりでもスタックを使い切らないよう、再帰せずに子から順に畳み込む。
+    /// 外側で畳み込む部分木を何度も複製しないよう、元の部分木は最後にまとめて記録する。
+    ///
+    fn fold(&mut self, expr: &Ast) -> Ast {
+        // 子を畳み込み終えた節点はtrueとともに積み直す。
+        // 畳み込んだ部分木は、元の部分木のすべての節点を覆う範囲とともに積む
+        let mut tasks = vec![(expr, false)];
+        let mut folded: Vec<(Ast, Location)> = Vec::new();
+        let mut records: Vec<(Location, &Ast)> = Vec::new();
+        while let Some((expr, ready)) = tasks.pop() {
+            let children = expr.value.children();
+            if !ready {
+                tasks.push((expr, true));
+                tasks.extend(children.iter().rev().map(|&child| (child, false)));
+                continue;
+            }
+            let mut location = expr.location.clone();
+            let start = folded.len() - children.len();
+            let children = folded.split_off(start).into_iter().map(|(child, span)| {
+                location = location.merge(&span);
+                child
+            });
+            let node = Ast::new(expr.value.with_children(children), expr.location.clone());
+            // 負の数のリテラルは既に最も簡単な形になっている
+            if constant(&node).is_some() {
+                folded.push((node, location));
+                continue;
+            }
+            match evaluate(&node, &location) {
+                Some(literal) => {
+                    records.retain(|(inner, _)| !(location.0 <= inner.0 && inner.1 <= location.1));
+                    records.push((location.clone(), expr));
+                    folded.push((literal, location));
+                }
+                None => folded.push((node, location)),
+            }
+        }
+        for (location, original) in records {
+            self.record(location, original);
+        }
+        folded.pop().unwrap().0
+    }
+}
+
+/// 子がすべて定数である節点を計算し、結果のリテラルをlocationの位置に作る
+fn evaluate(expr: &Ast, location: &Location) -> Option<Ast> {
+    use super::parser::AstKind::*;
+    let value = match expr.value {
+        Unary {
+            ref operator,
+            ref operand,
+        } => apply_uniop(&operator.value, constant(operand)?).ok()?,
+        Binary {
+            ref operator,
+            ref left,
+            ref right,
+        } => apply_binop(&operator.value, constant(left)?, constant(right)?).ok()?,
+        Call { ref name, ref args } => {
+            let args = args.iter().map(constant).collect::<Option<Vec<_>>>()?;
+            apply_function(name, &args).ok()?
+        }
+        _ => return None,
+    };
+    to_literal(value, location)
+}
+
+/// 数値のリテラルか、それに単項の"-"を付けたものであれば、その値を返す
+fn constant(expr: &Ast) -> Option<i64> {
+    match expr.value {
+        AstKind::Num(n) => literal(n).ok(),
+        AstKind::Unary {
+            operator:
+                UnaryOperator {
+                    value: UnaryOperatorKind::Minus,
+                    ..
+                },
+            ref operand,
+        } => match operand.value {
+            AstKind::Num(n) => literal(n).ok()?.checked_neg(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+///
+/// 値を表すリテラルを作る。負の数は単項の"-"を付けて表す。
+/// i64::MINは符号を外すとリテラルとして評価できないので、置き換えない。
+///
+fn to_literal(value: i64, location: &Location) -> Option<Ast> {
+    if value >= 0 {
+        return Some(Ast::num(value as u64, location.clone()));
+    }
+    let magnitude = value.checked_neg()? as u64;
+    Some(Ast::unary(
+        UnaryOperator::minus(location.clone()),
+        Ast::num(magnitude, location.clone()),
+        location.clone(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::strategy::ast;
+    use proptest::prelude::*;
+
+    fn fold(input: &str) -> Ast {
+        fold_constants(&input.parse().unwrap())
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        assert_eq!(fold("1 + 2 * 3"), Ast::num(7, Location(0, 9)));
+        assert_eq!(
+            fold("x = y + 2 * (3 - 1)"),
+            Ast::assign(
+                "x",
+                Ast::binary(
+                    BinaryOperator::add(Location(6, 7)),
+                    Ast::var("y", Location(4, 5)),
+                    Ast::num(4, Location(8, 18)),
+                    Location(4, 18)
+                ),
+                Location(0, 18)
+            )
+        );
+        assert_eq!(
+            fold("2 - max(1, 5)"),
+            Ast::unary(
+                UnaryOperator::minus(Location(0, 13)),
+                Ast::num(3, Location(0, 13)),
+                Location(0, 13)
+            )
+        );
+        assert_eq!(fold("(-3) ^ 2"), Ast::num(9, Location(1, 8)));
+
+        // 計算に失敗する部分木は残し、その外側も畳み込まない
+        let ast = "1 / (2 - 2) + 3 * 4".parse::<Ast>().unwrap();
+        match fold_constants(&ast).value {
+            AstKind::Binary {
+                ref left,
+                ref right,
+                ..
+            } => {
+                assert_eq!(
+                    left.value,
+                    AstKind::Binary {
+                        operator: BinaryOperator::div(Location(2, 3)),
+                        left: Box::new(Ast::num(1, Location(0, 1))),
+                        right: Box::new(Ast::num(0, Location(5, 10))),
+                    }
+                );
+                assert_eq!(**right, Ast::num(12, Location(14, 19)));
+            }
+            ref ast => panic!("unexpected ast: {:?}", ast),
+        }
+        let ast = "2 ^ 63 - 1".parse::<Ast>().unwrap();
+        assert_eq!(fold_constants(&ast), ast);
+    }
+
+    #[test]
+    fn test_folded_from() {
+        let ast = "x + (1 + 2) * -3".parse::<Ast>().unwrap();
+        let mut folder = ConstantFolder::new();
+        let folded = folder.fold_constants(&ast);
+        match folded.value {
+            AstKind::Binary { ref right, .. } => {
+                let original = folder.folded_from(&right.location).unwrap();
+                assert_eq!(right.location, Location(5, 16));
+                assert_eq!(original.location, Location(5, 16));
+                assert!(matches!(original.value, AstKind::Binary { .. }));
+            }
+            ref ast => panic!("unexpected ast: {:?}", ast),
+        }
+        // 外側に含まれた"1 + 2"の記録は残らない
+        assert_eq!(folder.folded().len(), 1);
+
+        folder.fold_constants(&"x".parse().unwrap());
+        assert!(folder.folded().is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_fold_preserves_value(ast in ast()) {
+            // 位置情報の違うエラーも同じ結果とみなす
+            let eval = |ast: &Ast| {
+                let mut interpreter = Interpreter::new();
+                interpreter.eval(&"x = 7".parse().unwrap()).unwrap();
+                interpreter.eval(ast).map_err(|e| e.value)
+            };
+            let folded = fold_constants(&ast);
+            prop_assert_eq!(eval(&folded), eval(&ast), "{} => {}", ast, folded);
+        }
+    }
+}
@@ -1,18 +1,30 @@
+use super::interpreter::InterpreterError;
 use super::lexer::*;
 
 use std::error::Error;
 use std::fmt;
-use std::iter::Iterator;
-use std::iter::Peekable;
+use std::io::{self, Write};
 use std::str::FromStr;
 
+use smallvec::{smallvec, SmallVec};
+
 /// 単項演算子の種類
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum UnaryOperatorKind {
     Plus,
     Minus,
 }
 
+impl fmt::Display for UnaryOperatorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnaryOperatorKind::Plus => write!(f, "+"),
+            UnaryOperatorKind::Minus => write!(f, "-"),
+        }
+    }
+}
+
 pub type UnaryOperator = Annotation<UnaryOperatorKind>;
 
 impl UnaryOperator {
@@ -27,11 +39,29 @@ impl UnaryOperator {
 
 /// 二項演算子の種類
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BinaryOperatorKind {
     Add,
     Sub,
     Multi,
     Div,
+    Pow,
+    /// ビット論理和
+    BitOr,
+}
+
+impl fmt::Display for BinaryOperatorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::BinaryOperatorKind::*;
+        match self {
+            Add => write!(f, "+"),
+            Sub => write!(f, "-"),
+            Multi => write!(f, "*"),
+            Div => write!(f, "/"),
+            Pow => write!(f, "^"),
+            BitOr => write!(f, "|"),
+        }
+    }
 }
 
 pub type BinaryOperator = Annotation<BinaryOperatorKind>;
@@ -49,12 +79,26 @@ impl BinaryOperator {
     pub fn div(location: Location) -> Self {
         Self::new(BinaryOperatorKind::Div, location)
     }
+    pub fn pow(location: Location) -> Self {
+        Self::new(BinaryOperatorKind::Pow, location)
+    }
+    pub fn bit_or(location: Location) -> Self {
+        Self::new(BinaryOperatorKind::BitOr, location)
+    }
 }
 
 /// 抽象構文木の種類
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AstKind {
     Num(u64),
+    /// 変数の参照
+    Var(String),
+    /// 変数への代入
+    Assign {
+        name: String,
+        value: Box<Ast>,
+    },
     Unary {
         operator: UnaryOperator,
         operand: Box<Ast>,
@@ -64,14 +108,223 @@ pub enum AstKind {
         left: Box<Ast>,
         right: Box<Ast>,
     },
+    /// 関数の呼び出し
+    Call {
+        name: String,
+        args: Vec<Ast>,
+    },
 }
 
 pub type Ast = Annotation<AstKind>;
 
+///
+/// 式の形のとおりに節点を組み立て、位置情報をすべてLocation(0, 0)にした抽象構文木を作る。
+/// 期待する構文木をテストで書くときに使う。構文解析器を通さないので、構文解析器のテストにも使える。
+///
+/// 書ける式は数（整数のリテラル）、変数、代入、単項演算子、二項演算子、関数の呼び出しとかっこで、
+/// 優先順位と結合性は構文解析器と同じに解釈する。絶対値は"|x|"ではなく"abs(x)"と書く。
+///
+/// ```
+/// use parser::ast;
+/// use parser::parser::Ast;
+/// use parser::visitor::strip_locations;
+///
+/// let parsed = "1 + 2 * -3".parse::<Ast>().unwrap();
+/// assert_eq!(strip_locations(&parsed), ast!(1 + 2 * -3));
+/// assert_eq!(ast!(1 + 2 * -3), ast!(1 + (2 * (-3))));
+/// ```
+///
+#[macro_export]
+macro_rules! ast {
+    // 代入は右結合なので、右辺を式として読む
+    (@expr $name:ident = $($value:tt)+) => {
+        $crate::parser::Ast::assign(
+            stringify!($name),
+            $crate::ast!(@expr $($value)+),
+            $crate::lexer::Location(0, 0),
+        )
+    };
+    (@expr $($expr:tt)+) => {
+        $crate::ast!(@level bit_or $($expr)+)
+    };
+
+    // 左結合の二項演算子の段（bit_or、add、multi）。その段の最も後ろにある二項演算子で左右に分ける。
+    // 状態は[分け目より前] [分け目の演算子] [分け目より後ろ] 直前のトークンの種類 残りのトークン。
+    // 項の直後にある演算子だけが二項演算子で、それ以外の"+"や"-"は単項演算子
+    (@level $level:ident $($expr:tt)+) => {
+        $crate::ast!(@scan $level [] [] [] operator $($expr)+)
+    };
+    (@level $level:ident) => {
+        compile_error!("ast!: missing operand")
+    };
+    (@scan bit_or [$($left:tt)*] [$($split:tt)?] [$($right:tt)*] operand | $($rest:tt)*) => {
+        $crate::ast!(@scan bit_or [$($left)* $($split)? $($right)*] [|] [] operator $($rest)*)
+    };
+    (@scan add [$($left:tt)*] [$($split:tt)?] [$($right:tt)*] operand + $($rest:tt)*) => {
+        $crate::ast!(@scan add [$($left)* $($split)? $($right)*] [+] [] operator $($rest)*)
+    };
+    (@scan add [$($left:tt)*] [$($split:tt)?] [$($right:tt)*] operand - $($rest:tt)*) => {
+        $crate::ast!(@scan add [$($left)* $($split)? $($right)*] [-] [] operator $($rest)*)
+    };
+    (@scan multi [$($left:tt)*] [$($split:tt)?] [$($right:tt)*] operand * $($rest:tt)*) => {
+        $crate::ast!(@scan multi [$($left)* $($split)? $($right)*] [*] [] operator $($rest)*)
+    };
+    (@scan multi [$($left:tt)*] [$($split:tt)?] [$($right:tt)*] operand / $($rest:tt)*) => {
+        $crate::ast!(@scan multi [$($left)* $($split)? $($right)*] [/] [] operator $($rest)*)
+    };
+    (@scan $level:ident [$($left:tt)*] [$($split:tt)?] [$($right:tt)*] $last:ident | $($rest:tt)*) => {
+        $crate::ast!(@scan $level [$($left)*] [$($split)?] [$($right)* |] operator $($rest)*)
+    };
+    (@scan $level:ident [$($left:tt)*] [$($split:tt)?] [$($right:tt)*] $last:ident + $($rest:tt)*) => {
+        $crate::ast!(@scan $level [$($left)*] [$($split)?] [$($right)* +] operator $($rest)*)
+    };
+    (@scan $level:ident [$($left:tt)*] [$($split:tt)?] [$($right:tt)*] $last:ident - $($rest:tt)*) => {
+        $crate::ast!(@scan $level [$($left)*] [$($split)?] [$($right)* -] operator $($rest)*)
+    };
+    (@scan $level:ident [$($left:tt)*] [$($split:tt)?] [$($right:tt)*] $last:ident * $($rest:tt)*) => {
+        $crate::ast!(@scan $level [$($left)*] [$($split)?] [$($right)* *] operator $($rest)*)
+    };
+    (@scan $level:ident [$($left:tt)*] [$($split:tt)?] [$($right:tt)*] $last:ident / $($rest:tt)*) => {
+        $crate::ast!(@scan $level [$($left)*] [$($split)?] [$($right)* /] operator $($rest)*)
+    };
+    (@scan $level:ident [$($left:tt)*] [$($split:tt)?] [$($right:tt)*] $last:ident ^ $($rest:tt)*) => {
+        $crate::ast!(@scan $level [$($left)*] [$($split)?] [$($right)* ^] operator $($rest)*)
+    };
+    (@scan $level:ident [$($left:tt)*] [$($split:tt)?] [$($right:tt)*] $last:ident $next:tt $($rest:tt)*) => {
+        $crate::ast!(@scan $level [$($left)*] [$($split)?] [$($right)* $next] operand $($rest)*)
+    };
+    (@scan $level:ident [] [] [$($expr:tt)*] $last:ident) => {
+        $crate::ast!(@next $level $($expr)*)
+    };
+    (@scan $level:ident [$($left:tt)*] [$split:tt] [$($right:tt)*] $last:ident) => {
+        $crate::parser::Ast::binary(
+            $crate::ast!(@operator $split),
+            $crate::ast!(@level $level $($left)*),
+            $crate::ast!(@next $level $($right)*),
+            $crate::lexer::Location(0, 0),
+        )
+    };
+
+    // 一つ上の優先順位の段
+    (@next bit_or $($expr:tt)*) => {
+        $crate::ast!(@level add $($expr)*)
+    };
+    (@next add $($expr:tt)*) => {
+        $crate::ast!(@level multi $($expr)*)
+    };
+    (@next multi $($expr:tt)*) => {
+        $crate::ast!(@unary $($expr)*)
+    };
+
+    (@operator |) => { $crate::parser::BinaryOperator::bit_or($crate::lexer::Location(0, 0)) };
+    (@operator +) => { $crate::parser::BinaryOperator::add($crate::lexer::Location(0, 0)) };
+    (@operator -) => { $crate::parser::BinaryOperator::sub($crate::lexer::Location(0, 0)) };
+    (@operator *) => { $crate::parser::BinaryOperator::multi($crate::lexer::Location(0, 0)) };
+    (@operator /) => { $crate::parser::BinaryOperator::div($crate::lexer::Location(0, 0)) };
+
+    // 単項演算子は二項演算子の"^"より弱く結び付く
+    (@unary - $($operand:tt)*) => {
+        $crate::parser::Ast::unary(
+            $crate::parser::UnaryOperator::minus($crate::lexer::Location(0, 0)),
+            $crate::ast!(@unary $($operand)*),
+            $crate::lexer::Location(0, 0),
+        )
+    };
+    (@unary + $($operand:tt)*) => {
+        $crate::parser::Ast::unary(
+            $crate::parser::UnaryOperator::plus($crate::lexer::Location(0, 0)),
+            $crate::ast!(@unary $($operand)*),
+            $crate::lexer::Location(0, 0),
+        )
+    };
+    (@unary $($expr:tt)*) => {
+        $crate::ast!(@pow [] $($expr)*)
+    };
+
+    // "^"は右結合なので、最初に現れたもので分ける。右辺には単項演算子を置ける
+    (@pow [$($base:tt)+] ^ $($exponent:tt)*) => {
+        $crate::parser::Ast::binary(
+            $crate::parser::BinaryOperator::pow($crate::lexer::Location(0, 0)),
+            $crate::ast!(@term $($base)+),
+            $crate::ast!(@unary $($exponent)*),
+            $crate::lexer::Location(0, 0),
+        )
+    };
+    (@pow [$($base:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::ast!(@pow [$($base)* $next] $($rest)*)
+    };
+    (@pow [$($term:tt)*]) => {
+        $crate::ast!(@term $($term)*)
+    };
+
+    // 項
+    (@term ($($expr:tt)+)) => {
+        $crate::ast!(@expr $($expr)+)
+    };
+    (@term $name:ident ($($args:tt)*)) => {
+        $crate::parser::Ast::call(
+            stringify!($name),
+            $crate::ast!(@args [] [] $($args)*),
+            $crate::lexer::Location(0, 0),
+        )
+    };
+    (@term $name:ident) => {
+        $crate::parser::Ast::var(stringify!($name), $crate::lexer::Location(0, 0))
+    };
+    (@term $n:literal) => {
+        $crate::parser::Ast::num($n, $crate::lexer::Location(0, 0))
+    };
+    (@term) => {
+        compile_error!("ast!: missing operand")
+    };
+    (@term $($term:tt)*) => {
+        compile_error!(concat!("ast!: not a term: ", stringify!($($term)*)))
+    };
+
+    // 関数の引数を","で区切り、それぞれを式として読む
+    (@args [$($args:expr,)*] [$($arg:tt)+] , $($rest:tt)*) => {
+        $crate::ast!(@args [$($args,)* $crate::ast!(@expr $($arg)+),] [] $($rest)*)
+    };
+    (@args [$($args:expr,)*] [$($arg:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::ast!(@args [$($args,)*] [$($arg)* $next] $($rest)*)
+    };
+    (@args [$($args:expr,)*] [$($arg:tt)+]) => {
+        vec![$($args,)* $crate::ast!(@expr $($arg)+)]
+    };
+    (@args [$($args:expr,)*] []) => {
+        vec![$($args,)*]
+    };
+
+    ($($expr:tt)+) => {
+        $crate::ast!(@expr $($expr)+)
+    };
+}
+
+/// 必要なかっこだけを補った中置記法で書く（"(1 + 2) * -x"など）
+impl fmt::Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = String::new();
+        super::minify::write_spaced(self, &mut buf);
+        f.write_str(&buf)
+    }
+}
+
 impl Ast {
     pub fn num(number: u64, location: Location) -> Self {
         Self::new(AstKind::Num(number), location)
     }
+    pub fn var(name: &str, location: Location) -> Self {
+        Self::new(AstKind::Var(name.to_string()), location)
+    }
+    pub fn assign(name: &str, value: Ast, location: Location) -> Self {
+        Self::new(
+            AstKind::Assign {
+                name: name.to_string(),
+                value: Box::new(value),
+            },
+            location,
+        )
+    }
     pub fn unary(operator: UnaryOperator, operand: Ast, location: Location) -> Self {
         Self::new(
             AstKind::Unary {
@@ -91,20 +344,163 @@ impl Ast {
             location,
         )
     }
+    pub fn call(name: &str, args: Vec<Ast>, location: Location) -> Self {
+        Self::new(
+            AstKind::Call {
+                name: name.to_string(),
+                args,
+            },
+            location,
+        )
+    }
+}
+
+impl Ast {
+    ///
+    /// 構文木の節点の数を返す。変換結果の領域を前もって確保するのに使う。
+    /// 深く入れ子になった木でもスタックを使い切らないよう、再帰せずに数える。
+    /// 辿りかけの節点が少ないうちはヒープ確保を行わない。
+    ///
+    pub fn node_count(&self) -> usize {
+        let mut count = 0;
+        let mut stack: SmallVec<[&Ast; 32]> = SmallVec::new();
+        stack.push(self);
+        while let Some(node) = stack.pop() {
+            count += 1;
+            match node.value {
+                AstKind::Num(_) | AstKind::Var(_) => {}
+                AstKind::Assign { ref value, .. } => stack.push(value),
+                AstKind::Unary { ref operand, .. } => stack.push(operand),
+                AstKind::Binary {
+                    ref left,
+                    ref right,
+                    ..
+                } => {
+                    stack.push(left);
+                    stack.push(right);
+                }
+                AstKind::Call { ref args, .. } => stack.extend(args),
+            }
+        }
+        count
+    }
+}
+
+///
+/// 子の節点を取り出してから落とす。
+/// 既定の落とし方は子を再帰的に落とすので、深く入れ子になった木ではスタックを使い切る。
+///
+impl Drop for AstKind {
+    fn drop(&mut self) {
+        let mut orphans = Vec::new();
+        take_children(self, &mut orphans);
+        while let Some(mut orphan) = orphans.pop() {
+            take_children(&mut orphan.value, &mut orphans);
+        }
+    }
+}
+
+///
+/// 子から順に複製する。
+/// 既定の複製は子を再帰的に複製するので、"1 + 1 + …"のような長い連なりではスタックを使い切る。
+///
+impl Clone for AstKind {
+    fn clone(&self) -> Self {
+        // 子を複製し終えた節点はtrueとともに積み直し、複製した子から組み立てる
+        let mut tasks = vec![(self, false)];
+        let mut copies: Vec<AstKind> = Vec::new();
+        while let Some((kind, ready)) = tasks.pop() {
+            let children = kind.children();
+            if !ready {
+                tasks.push((kind, true));
+                tasks.extend(children.iter().rev().map(|child| (&child.value, false)));
+                continue;
+            }
+            let start = copies.len() - children.len();
+            let copied = copies
+                .split_off(start)
+                .into_iter()
+                .zip(children)
+                .map(|(value, child)| Ast::new(value, child.location.clone()));
+            copies.push(kind.with_children(copied));
+        }
+        copies.pop().unwrap()
+    }
+}
+
+impl AstKind {
+    /// 子の節点を左から順に返す
+    pub(crate) fn children(&self) -> SmallVec<[&Ast; 2]> {
+        match self {
+            AstKind::Num(_) | AstKind::Var(_) => SmallVec::new(),
+            AstKind::Assign { value, .. } => smallvec![&**value],
+            AstKind::Unary { operand, .. } => smallvec![&**operand],
+            AstKind::Binary { left, right, .. } => smallvec![&**left, &**right],
+            AstKind::Call { args, .. } => args.iter().collect(),
+        }
+    }
+
+    ///
+    /// 子だけを、左から順に並べたchildrenに置き換えた節点を作る。
+    /// 子を持たない節点はそのまま複製する。
+    ///
+    pub(crate) fn with_children(&self, children: impl IntoIterator<Item = Ast>) -> AstKind {
+        let mut children = children.into_iter();
+        let mut next = || Box::new(children.next().unwrap());
+        match self {
+            AstKind::Num(n) => AstKind::Num(*n),
+            AstKind::Var(name) => AstKind::Var(name.clone()),
+            AstKind::Assign { name, .. } => AstKind::Assign {
+                name: name.clone(),
+                value: next(),
+            },
+            AstKind::Unary { operator, .. } => AstKind::Unary {
+                operator: operator.clone(),
+                operand: next(),
+            },
+            AstKind::Binary { operator, .. } => AstKind::Binary {
+                operator: operator.clone(),
+                left: next(),
+                right: next(),
+            },
+            AstKind::Call { name, .. } => AstKind::Call {
+                name: name.clone(),
+                args: children.collect(),
+            },
+        }
+    }
+}
+
+/// 子の節点をorphansへ移す。ボックスの中身は子を持たない節点に置き換える
+fn take_children(kind: &mut AstKind, orphans: &mut Vec<Ast>) {
+    let mut take = |child: &mut Box<Ast>| {
+        if !matches!(child.value, AstKind::Num(_) | AstKind::Var(_)) {
+            orphans.push(std::mem::replace(child, Ast::num(0, Location(0, 0))));
+        }
+    };
+    match kind {
+        AstKind::Num(_) | AstKind::Var(_) => {}
+        AstKind::Assign { value, .. } => take(value),
+        AstKind::Unary { operand, .. } => take(operand),
+        AstKind::Binary { left, right, .. } => {
+            take(left);
+            take(right);
+        }
+        AstKind::Call { args, .. } => orphans.append(args),
+    }
 }
 
 /// str::parse::<Ast>()を使えるようにする
 impl FromStr for Ast {
     type Err = ApplicationError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let tokens = lex(s)?;
-        let ast = parse(tokens)?;
-        Ok(ast)
+        parse_stream(StreamingLexer::new(s))
     }
 }
 
 /// 構文解析のエラー
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ParseError {
     /// 予期せぬトークンが現れた
     UnexpectedToken(Token),
@@ -116,6 +512,16 @@ pub enum ParseError {
     UnclosedOpenParen(Token),
     /// 式の解析が終わったが、余計なトークンが現れた
     RedundantExpression(Token),
+    /// 対応する開きかっこのない閉じかっこ（直前に閉じたかっこの開きかっこがあれば、それも持つ）
+    UnmatchedRParen(Token, Option<Token>),
+    /// 演算子の後に式がないまま入力が終わった
+    MissingOperand(Token),
+    /// かっこの中身が空である（開きかっこと閉じかっこ）
+    EmptyParens(Token, Token),
+    /// 変数以外のものに代入しようとした（"="のトークン）
+    InvalidAssignment(Token),
+    /// 式の入れ子が深すぎる（深さの上限を超えた位置のトークン）
+    TooDeep(Token),
     /// 解析の途中で入力が終わった
     Eof,
 }
@@ -124,31 +530,141 @@ impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::ParseError::*;
         match self {
-            UnexpectedToken(tok) => write!(f, "{}: '{}' is not expected", tok.location, tok.value),
-            NotExpression(tok) => write!(
-                f,
-                "{}: '{}' is not start of expression",
-                tok.location, tok.value
-            ),
-            NotOperator(tok) => write!(f, "{}: '{}' is not an operator", tok.location, tok.value),
-            UnclosedOpenParen(tok) => write!(f, "{}: '{}' is not closed", tok.location, tok.value),
-            RedundantExpression(tok) => write!(
+            EmptyParens(open, close) => write!(
                 f,
-                "{}: expression after '{}' is redundant",
-                tok.location, tok.value
+                "{}: {}",
+                open.location.merge(&close.location),
+                self.message()
             ),
-            Eof => write!(f, "End of file"),
+            Eof => write!(f, "{}", self.message()),
+            _ => match self.token() {
+                Some(tok) => write!(f, "{}: {}", tok.location, self.message()),
+                None => write!(f, "{}", self.message()),
+            },
+        }
+    }
+}
+
+impl ParseError {
+    /// 位置を除いたエラーの説明を返す
+    pub fn message(&self) -> String {
+        use self::ParseError::*;
+        match self {
+            UnexpectedToken(tok) => format!("'{}' is not expected", tok.value),
+            NotExpression(tok) => format!("'{}' is not start of expression", tok.value),
+            NotOperator(tok) => format!("'{}' is not an operator", tok.value),
+            UnclosedOpenParen(tok) => format!("'{}' is not closed", tok.value),
+            RedundantExpression(tok) => format!("expression after '{}' is redundant", tok.value),
+            UnmatchedRParen(..) => "')' has no matching '('".to_string(),
+            MissingOperand(tok) => format!("expression expected after operator '{}'", tok.value),
+            EmptyParens(..) => "empty parentheses".to_string(),
+            InvalidAssignment(tok) => {
+                format!("left hand side of '{}' is not a variable", tok.value)
+            }
+            TooDeep(_) => "expression is nested too deeply".to_string(),
+            Eof => "End of file".to_string(),
         }
     }
 }
 
 impl Error for ParseError {}
 
+impl ParseError {
+    /// エラーの修正方法の提案を返す
+    pub fn suggestion(&self) -> Option<String> {
+        use self::ParseError::*;
+        match self {
+            MissingOperand(tok) => Some(format!(
+                "add an expression after '{}', e.g. '{} 1'",
+                tok.value, tok.value
+            )),
+            EmptyParens(..) => {
+                Some("put an expression between '(' and ')', or remove them".to_string())
+            }
+            UnmatchedRParen(..) => {
+                Some("remove the ')', or add a '(' where the group should start".to_string())
+            }
+            TooDeep(..) => Some("split the expression into assignments to variables".to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// 構文エラーの前後のトークン
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext<'t> {
+    /// エラーの直前のトークン
+    pub before: Option<&'t Token>,
+    /// エラーとなったトークン（入力の終わりに達した場合はNone）
+    pub token: Option<&'t Token>,
+    /// エラーの直後のトークン
+    pub after: Option<&'t Token>,
+    /// 本来何かが現れるべきだった位置
+    pub expected_at: Location,
+}
+
+impl ParseError {
+    /// エラーの原因となったトークンを返す
+    pub fn token(&self) -> Option<&Token> {
+        use self::ParseError::*;
+        match self {
+            UnexpectedToken(tok)
+            | NotExpression(tok)
+            | NotOperator(tok)
+            | UnclosedOpenParen(tok)
+            | RedundantExpression(tok)
+            | UnmatchedRParen(tok, _)
+            | MissingOperand(tok)
+            | InvalidAssignment(tok)
+            | TooDeep(tok) => Some(tok),
+            // 中身が空のかっこは、閉じかっこで式が足りないことが分かる
+            EmptyParens(_, close) => Some(close),
+            Eof => None,
+        }
+    }
+
+    ///
+    /// 解析したトークン列から、エラーの前後のトークンを取り出す。
+    /// tokensには、このエラーを返したときに解析していたトークン列を渡す。
+    ///
+    pub fn context<'t>(&self, tokens: &'t [Token]) -> ErrorContext<'t> {
+        // 入力の終わりに達した場合は、最後のトークンの直後を指す
+        let index = match self.token() {
+            Some(tok) => tokens.iter().position(|t| t == tok),
+            None => None,
+        };
+        let (before, token, after) = match index {
+            Some(i) => (
+                i.checked_sub(1).map(|j| &tokens[j]),
+                Some(&tokens[i]),
+                tokens.get(i + 1),
+            ),
+            None => (tokens.last(), None, None),
+        };
+        let after_token = |tok: &Token| Location(tok.location.1, tok.location.1 + 1);
+        let expected_at = match (self, token, before) {
+            (ParseError::MissingOperand(_), Some(tok), _) => after_token(tok),
+            (ParseError::EmptyParens(open, _), _, _) => after_token(open),
+            (_, Some(tok), _) => tok.location.clone(),
+            (_, None, Some(tok)) => after_token(tok),
+            (_, None, None) => Location(0, 1),
+        };
+        ErrorContext {
+            before,
+            token,
+            after,
+            expected_at,
+        }
+    }
+}
+
 /// エラーを統一的に扱うエラー型
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ApplicationError {
     Lexer(LexError),
     Parser(ParseError),
+    Interpreter(InterpreterError),
 }
 
 impl From<LexError> for ApplicationError {
@@ -163,9 +679,18 @@ impl From<ParseError> for ApplicationError {
     }
 }
 
+impl From<InterpreterError> for ApplicationError {
+    fn from(e: InterpreterError) -> Self {
+        ApplicationError::Interpreter(e)
+    }
+}
+
 impl fmt::Display for ApplicationError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "parse error")
+        match self {
+            ApplicationError::Lexer(_) | ApplicationError::Parser(_) => write!(f, "parse error"),
+            ApplicationError::Interpreter(_) => write!(f, "evaluation error"),
+        }
     }
 }
 
@@ -175,197 +700,846 @@ impl Error for ApplicationError {
         match self {
             Lexer(lex_error) => Some(lex_error),
             Parser(parse_error) => Some(parse_error),
+            Interpreter(interpreter_error) => Some(interpreter_error),
         }
     }
 }
 
 impl ApplicationError {
-    /// エラーの詳細を表示する
+    /// エラーの詳細を標準エラー出力へ表示する
     pub fn show_diagnostic(&self, input: &str) {
-        let (e, loc): (&dyn Error, Location) = match self {
-            ApplicationError::Lexer(e) => (e, e.location.clone()),
-            ApplicationError::Parser(e) => {
-                let loc = match e {
-                    ParseError::UnexpectedToken(Token { location, .. })
-                    | ParseError::NotExpression(Token { location, .. })
-                    | ParseError::NotOperator(Token { location, .. })
-                    | ParseError::UnclosedOpenParen(Token { location, .. }) => location.clone(),
-                    // 冗長なトークンがある場合、それ以降のすべてが冗長である
-                    ParseError::RedundantExpression(Token { location, .. }) => {
-                        Location(location.0, input.len())
-                    }
-                    ParseError::Eof => Location(input.len(), input.len() + 1),
-                };
-                (e, loc)
+        // 標準エラー出力へ書けなければ、知らせる先がない
+        let _ = self.write_diagnostic(input, &mut io::stderr().lock());
+    }
+
+    /// エラーの詳細（メッセージ、位置の注釈、直し方の提案）をwへ書き出す
+    pub fn write_diagnostic(&self, input: &str, w: &mut impl Write) -> io::Result<()> {
+        self.to_diagnostic(input).write(input, w)
+    }
+}
+
+///
+/// トークンのリストの構文を解析する。
+/// 絶対値の"|"の中に現れた"|"は、閉じる"|"とビット論理和のどちらとも読める。
+/// 閉じる"|"として読んで失敗した場合は、最後の選択をビット論理和に変えて解析し直す。
+/// 解析し直す回数はBACKTRACK_LIMITまでとし、それを超えたら最初のエラーを返す。
+///
+pub fn parse(tokens: &[Token]) -> Result<Ast, ParseError> {
+    parse_with_max_depth(tokens, DEFAULT_MAX_DEPTH)
+}
+
+///
+/// 入れ子の深さの上限を指定してトークンのリストの構文を解析する。
+/// かっこ、単項演算子、右結合の演算子の連なり、関数の呼び出しは入れ子を1段ずつ深くする。
+/// "1 + 1 + …"のような左結合の演算子の連なりは再帰せずに読むので、長くても入れ子にはならない。
+/// 上限を超えたらスタックを使い切る前にParseError::TooDeepを返す。
+///
+pub fn parse_with_max_depth(tokens: &[Token], max_depth: usize) -> Result<Ast, ParseError> {
+    parse_attempts(tokens, max_depth).map_err(|(e, _)| e)
+}
+
+///
+/// parse_with_max_depthと同じく解析する。
+/// 失敗したら最初のエラーとともに、どの解釈でもかっこの外で式として読み終えた最も後ろの位置を返す。
+/// 入力のその位置までの接頭辞は、式として解析できる。
+///
+fn parse_attempts(tokens: &[Token], max_depth: usize) -> Result<Ast, (ParseError, usize)> {
+    parse_retrying(&mut TokenCursor::new(tokens).with_max_depth(max_depth))
+}
+
+///
+/// カーソルの位置から入力全体を解析する。
+/// 失敗したら"|"の解釈を選び直し、最初のエラーとかっこの外で式として読み終えた最も後ろの位置を返す。
+///
+fn parse_retrying(cursor: &mut TokenCursor) -> Result<Ast, (ParseError, usize)> {
+    let mut first_error = None;
+    for _ in 0..=BACKTRACK_LIMIT {
+        match parse_all(cursor) {
+            Ok(ast) => return Ok(splice(ast, cursor.restart.left.take())),
+            Err(e) => {
+                first_error.get_or_insert(e);
             }
-        };
-        println!("{}", e);
-        print_annote(input, loc);
+        }
+        // まだ試していない選択のうち、最も後ろのものを変える
+        let mut choices = std::mem::take(&mut cursor.decisions);
+        while choices.last() == Some(&true) {
+            choices.pop();
+        }
+        match choices.last_mut() {
+            Some(choice) => *choice = true,
+            None => break,
+        }
+        cursor.retry(choices);
     }
+    Err((first_error.unwrap(), cursor.complete))
 }
 
-/// トークンのリストの構文を解析する
-pub fn parse(tokens: Vec<Token>) -> Result<Ast, ParseError> {
-    // LL(1)パーサであるため、Peekableなイテレータを作成する
-    let mut tokens_iter = tokens.into_iter().peekable();
-    // 式の評価
-    let ret = parse_expr(&mut tokens_iter)?;
+/// 入力全体を1つの文として解析する
+fn parse_all(cursor: &mut TokenCursor) -> Result<Ast, ParseError> {
+    // 文の評価
+    let ret = parse_statement(cursor)?;
     // 式の評価の後は何もないはず
-    match tokens_iter.next() {
+    match cursor.peek().cloned() {
+        Some(tok) if tok.value == TokenKind::RParen => Err(cursor.unmatched(&tok)),
         Some(tok) => Err(ParseError::RedundantExpression(tok)),
         None => Ok(ret),
     }
 }
 
-/// EXPR = EXPR3 ;
-fn parse_expr<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Ast, ParseError>
-where
-    Tokens: Iterator<Item = Token>,
-{
-    parse_expr3(tokens)
+/// 最上位の演算子の連なりの左端にある仮の節点を、読み終えた式committedに置き換える
+fn splice(mut ast: Ast, committed: Option<Ast>) -> Ast {
+    let committed = match committed {
+        Some(committed) => committed,
+        None => return ast,
+    };
+    let mut node = &mut ast;
+    while let AstKind::Binary { ref mut left, .. } = node.value {
+        node = left;
+    }
+    *node = committed;
+    ast
 }
 
-/// EXPR3 = EXPR3, ("+" | "-"), EXPR2 | EXPR2 ;
-fn parse_expr3<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Ast, ParseError>
-where
-    Tokens: Iterator<Item = Token>,
-{
-    fn parse_expr3_op<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<BinaryOperator, ParseError>
-    where
-        Tokens: Iterator<Item = Token>,
-    {
-        let op = tokens
-            .peek()
-            .ok_or(ParseError::Eof)
-            .and_then(|tok| match tok.value {
-                TokenKind::Plus => Ok(BinaryOperator::add(tok.location.clone())),
-                TokenKind::Minus => Ok(BinaryOperator::sub(tok.location.clone())),
-                _ => Err(ParseError::NotOperator(tok.clone())),
-            })?;
-        tokens.next();
-        Ok(op)
+/// 解析し直す回数の上限。選択肢の組み合わせは"|"の数に対して指数的に増えるので制限する
+const BACKTRACK_LIMIT: usize = 64;
+
+/// 入れ子の深さの既定の上限。デバッグビルドでも、メインスレッドの8MBのスタックに収まる深さにする
+pub const DEFAULT_MAX_DEPTH: usize = 512;
+
+///
+/// トークンの列と現在の位置。
+/// スライスから読む場合はトークン列を消費しないので、解析後もエラーの前後のトークンを参照できる。
+/// イテレータから読む場合は必要になったトークンだけを読み込み、戻ることのなくなったトークンは捨てる。
+/// position()で保存した位置へrewind()で戻れるので、複数の解釈を試すこともできる。
+///
+#[derive(Debug)]
+pub struct TokenCursor<'t> {
+    source: Source<'t>,
+    pos: usize,
+    /// 囲んでいる絶対値の"|"の数
+    bars: usize,
+    /// 曖昧な箇所で選ぶ解釈（falseが既定の解釈）
+    choices: Vec<bool>,
+    /// これまでに選んだ解釈
+    decisions: Vec<bool>,
+    /// 最後に閉じたかっこの開きかっこ
+    closed: Option<Token>,
+    /// 解析中の式の入れ子の深さ
+    depth: usize,
+    /// 入れ子の深さの上限
+    max_depth: usize,
+    /// 囲んでいるかっこ、関数の呼び出し、絶対値の数
+    groups: usize,
+    /// かっこの外で式として読み終えた最も後ろの位置
+    complete: usize,
+    /// 解釈を選び直して解析するときに始める位置
+    restart: Restart,
+    /// 選び直した解析が、最上位の演算子の連なりをrestartの式から続けるかどうか
+    resuming: bool,
+}
+
+/// トークンを読む元
+#[derive(Debug)]
+enum Source<'t> {
+    /// すべてのトークンを持つスライス
+    Slice(&'t [Token]),
+    /// トークンを1つずつ返すイテレータ
+    Stream(TokenStream<'t>),
+}
+
+/// イテレータと、そこから読み込んだトークンのうちまだ捨てていないもの
+struct TokenStream<'t> {
+    tokens: std::iter::Fuse<&'t mut dyn Iterator<Item = Result<Token, LexError>>>,
+    /// 先頭はstart番目のトークン
+    buffer: Vec<Token>,
+    start: usize,
+    /// 字句解析のエラー。見つかったら入力はそこで終わったものとし、それ以上は読まない
+    error: Option<LexError>,
+}
+
+impl fmt::Debug for TokenStream<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TokenStream")
+            .field("buffer", &self.buffer)
+            .field("start", &self.start)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl Source<'_> {
+    /// index番目のトークンを返す。イテレータからは、その位置までを読み込む
+    fn get(&mut self, index: usize) -> Option<&Token> {
+        match self {
+            Source::Slice(tokens) => tokens.get(index),
+            Source::Stream(stream) => {
+                while stream.start + stream.buffer.len() <= index && stream.error.is_none() {
+                    match stream.tokens.next() {
+                        Some(Ok(tok)) => stream.buffer.push(tok),
+                        Some(Err(e)) => stream.error = Some(e),
+                        None => break,
+                    }
+                }
+                let index = index
+                    .checked_sub(stream.start)
+                    .expect("token already released");
+                stream.buffer.get(index)
+            }
+        }
+    }
+
+    /// 戻ることのできる位置の範囲
+    fn bounds(&self) -> (usize, usize) {
+        match self {
+            Source::Slice(tokens) => (0, tokens.len()),
+            Source::Stream(stream) => (stream.start, stream.start + stream.buffer.len()),
+        }
+    }
+}
+
+/// 解釈を選び直して解析するときに始める位置
+#[derive(Debug, Default)]
+struct Restart {
+    pos: usize,
+    /// その位置で最後に閉じていたかっこの開きかっこ
+    closed: Option<Token>,
+    /// その位置までに読み終えた、最上位の演算子の連なりの左辺
+    left: Option<Ast>,
+}
+
+impl<'t> TokenCursor<'t> {
+    pub fn new(tokens: &'t [Token]) -> Self {
+        Self::with_choices(tokens, Vec::new())
+    }
+
+    /// 曖昧な箇所で選ぶ解釈を先頭から順に指定してカーソルを作成する
+    pub fn with_choices(tokens: &'t [Token], choices: Vec<bool>) -> Self {
+        Self::with_source(Source::Slice(tokens), choices)
+    }
+
+    /// 字句解析器の返すトークンを、必要になったときに読むカーソルを作成する
+    fn from_stream(tokens: &'t mut dyn Iterator<Item = Result<Token, LexError>>) -> Self {
+        let stream = TokenStream {
+            tokens: tokens.fuse(),
+            buffer: Vec::new(),
+            start: 0,
+            error: None,
+        };
+        Self::with_source(Source::Stream(stream), Vec::new())
+    }
+
+    fn with_source(source: Source<'t>, choices: Vec<bool>) -> Self {
+        TokenCursor {
+            source,
+            pos: 0,
+            bars: 0,
+            choices,
+            decisions: Vec::new(),
+            closed: None,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            groups: 0,
+            complete: 0,
+            restart: Restart::default(),
+            resuming: false,
+        }
+    }
+
+    /// 入れ子の深さの上限を変える
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// 曖昧な箇所でどちらの解釈を選ぶかを決める。指定がなければ既定の解釈（false）を選ぶ
+    pub fn decide(&mut self) -> bool {
+        let choice = self
+            .choices
+            .get(self.decisions.len())
+            .cloned()
+            .unwrap_or(false);
+        self.decisions.push(choice);
+        choice
+    }
+
+    /// 現在の位置（次に読むトークンの添字）を返す
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// position()で保存した位置へ戻る。イテレータから読む場合、捨てたトークンへは戻れない
+    pub fn rewind(&mut self, pos: usize) {
+        let (start, end) = self.source.bounds();
+        assert!(start <= pos && pos <= end, "position out of range");
+        self.pos = pos;
+    }
+
+    /// まだ読んでいないトークンを返す。イテレータから読む場合は、先読みしたものだけを返す
+    pub fn remaining(&self) -> &[Token] {
+        match self.source {
+            Source::Slice(tokens) => &tokens[self.pos..],
+            Source::Stream(ref stream) => &stream.buffer[self.pos - stream.start..],
+        }
+    }
+
+    /// 次のトークンを読まずに返す
+    pub fn peek(&mut self) -> Option<&Token> {
+        self.source.get(self.pos)
+    }
+
+    /// n個先（0なら次）のトークンを読まずに返す
+    pub fn peek_nth(&mut self, n: usize) -> Option<&Token> {
+        self.source.get(self.pos + n)
+    }
+
+    /// かっこの外にいれば、ここまでを式として読み終えたことを記録する
+    fn mark_complete(&mut self) {
+        if self.groups == 0 {
+            self.complete = self.complete.max(self.pos);
+        }
+    }
+
+    /// 閉じかっこのエラーで示せるよう、閉じたかっこの開きかっこを覚えておく
+    fn close_group(&mut self, open: &Token) {
+        self.closed = Some(open.clone());
+    }
+
+    /// 閉じかっこに対応する開きかっこがない場合のエラーを作る
+    fn unmatched(&self, rparen: &Token) -> ParseError {
+        ParseError::UnmatchedRParen(rparen.clone(), self.closed.clone())
     }
 
-    parse_left_binop(tokens, parse_expr2, parse_expr3_op)
+    ///
+    /// 最上位の演算子の連なりをleftまで読み終えたことを記録する。
+    /// イテレータから読んでいて、ここより前の解釈を選び直すことがなければ、ここを解析し直すときの始まりとし、
+    /// それまでのトークンを捨てる。読み終えた式はカーソルが預かり、代わりに位置だけを持つ仮の節点を返す。
+    ///
+    fn commit(&mut self, left: Ast) -> Ast {
+        let stream = match self.source {
+            Source::Stream(ref mut stream) => stream,
+            Source::Slice(_) => return left,
+        };
+        // 既定でない解釈だけを選んできたなら、それより前を選び直すことはない
+        if self.depth != 1 || self.groups > 0 || !self.decisions.iter().all(|&choice| choice) {
+            return left;
+        }
+        let decided = self.decisions.len().min(self.choices.len());
+        self.choices.drain(..decided);
+        self.decisions.clear();
+        let left = splice(left, self.restart.left.take());
+        let placeholder = Ast::num(0, left.location.clone());
+        self.restart = Restart {
+            pos: self.pos,
+            closed: self.closed.clone(),
+            left: Some(left),
+        };
+        stream.buffer.drain(..self.pos - stream.start);
+        stream.start = self.pos;
+        placeholder
+    }
+
+    /// 選び直した解析では、最上位の演算子の連なりを預かった式の仮の節点から続ける
+    fn resume(&mut self) -> Option<Ast> {
+        if !std::mem::replace(&mut self.resuming, false) {
+            return None;
+        }
+        let left = self.restart.left.as_ref()?;
+        Some(Ast::num(0, left.location.clone()))
+    }
+
+    /// 解釈をchoicesに選び直し、解析し直す位置へ戻る
+    fn retry(&mut self, choices: Vec<bool>) {
+        self.pos = self.restart.pos;
+        self.closed = self.restart.closed.clone();
+        self.bars = 0;
+        self.groups = 0;
+        self.depth = 0;
+        self.choices = choices;
+        self.decisions.clear();
+        self.resuming = true;
+    }
+
+    /// 次のトークンを読み進める
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<&Token> {
+        let tok = self.source.get(self.pos)?;
+        self.pos += 1;
+        Some(tok)
+    }
 }
 
-/// EXPR2 = EXPR2, ("*" | "/"), EXPR1 | EXPR1 ;
-fn parse_expr2<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Ast, ParseError>
+/// STATEMENT = EXPR, "=", STATEMENT | EXPR ;
+/// ただし、"="の左辺は変数でなければならない
+fn parse_statement(tokens: &mut TokenCursor) -> Result<Ast, ParseError> {
+    let left = parse_expr(tokens)?;
+    match tokens.peek().map(|tok| &tok.value) {
+        Some(TokenKind::Equal) => {
+            let eq = tokens.next().unwrap().clone();
+            let name = match left.value {
+                AstKind::Var(ref name) => name.clone(),
+                _ => return Err(ParseError::InvalidAssignment(eq)),
+            };
+            // 代入は右結合とする
+            let value = nested(tokens, parse_statement).map_err(|e| missing_operand(e, &eq))?;
+            let loc = left.location.merge(&value.location);
+            Ok(Ast::assign(&name, value, loc))
+        }
+        _ => Ok(left),
+    }
+}
+
+///
+/// 字句解析器の返すトークンを、構文解析に必要になったときに1つずつ読みながら解析する。
+/// 字句解析のエラーに行き当たれば、残りの入力は読まずにそのエラーを返す。
+/// 読んだトークンは、"|"の解釈を選び直すときに戻る位置から後ろだけを残す。
+/// 最上位の演算子の連なり（"1 + 1 + …"）を読み進めるごとに戻る位置も進むので、長い和でもトークンをすべては保持しない。
+///
+pub fn parse_stream<I>(tokens: I) -> Result<Ast, ApplicationError>
 where
-    Tokens: Iterator<Item = Token>,
+    I: IntoIterator<Item = Result<Token, LexError>>,
 {
-    fn parse_expr2_op<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<BinaryOperator, ParseError>
-    where
-        Tokens: Iterator<Item = Token>,
+    let mut tokens = tokens.into_iter();
+    let mut cursor = TokenCursor::from_stream(&mut tokens);
+    let result = parse_retrying(&mut cursor);
+    // 字句解析のエラーで入力が途切れていれば、そのエラーを先に報告する
+    if let Source::Stream(TokenStream {
+        error: Some(ref e), ..
+    }) = cursor.source
     {
-        let op = tokens
-            .peek()
-            .ok_or(ParseError::Eof)
-            .and_then(|tok| match tok.value {
-                TokenKind::Asterisk => Ok(BinaryOperator::multi(tok.location.clone())),
-                TokenKind::Slash => Ok(BinaryOperator::div(tok.location.clone())),
-                _ => Err(ParseError::NotOperator(tok.clone())),
-            })?;
-        tokens.next();
-        Ok(op)
-    }
-
-    parse_left_binop(tokens, parse_expr1, parse_expr2_op)
-}
-
-/// 左結合の二項演算子を解析する
-fn parse_left_binop<Tokens>(
-    tokens: &mut Peekable<Tokens>,
-    subexpr_parser: fn(&mut Peekable<Tokens>) -> Result<Ast, ParseError>,
-    op_parser: fn(&mut Peekable<Tokens>) -> Result<BinaryOperator, ParseError>,
-) -> Result<Ast, ParseError>
-where
-    Tokens: Iterator<Item = Token>,
-{
-    let mut left = subexpr_parser(tokens)?;
-    loop {
-        match tokens.peek() {
-            Some(_) => {
-                let op = match op_parser(tokens) {
-                    Ok(op) => op,
-                    Err(_) => break,
-                };
-                let right = subexpr_parser(tokens)?;
-                let loc = left.location.merge(&right.location);
-                left = Ast::binary(op, left, right, loc);
+        return Err(e.clone().into());
+    }
+    Ok(result.map_err(|(e, _)| e)?)
+}
+
+/// 構文解析に失敗した場合の途中経過
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PartialParse {
+    /// 入力の先頭から解析できた最長の式（1つもなければNone）
+    pub ast: Option<Ast>,
+    /// 見つかったエラー（入力の先頭に近い順）。先頭は入力全体を解析したときのエラー
+    pub errors: Vec<ParseError>,
+}
+
+///
+/// トークンのリストの構文を解析する。
+/// 失敗した場合は、エラーの後の演算子で同期して解析を続け、見つかったエラーをすべて返す。
+/// あわせて、先頭から解析できた最長の式も返す。
+///
+pub fn parse_with_recovery(tokens: &[Token]) -> Result<Ast, Box<PartialParse>> {
+    parse_with_recovery_and_max_depth(tokens, DEFAULT_MAX_DEPTH)
+}
+
+/// 入れ子の深さの上限を指定して、parse_with_recoveryと同じく解析する
+pub fn parse_with_recovery_and_max_depth(
+    tokens: &[Token],
+    max_depth: usize,
+) -> Result<Ast, Box<PartialParse>> {
+    let parse = |tokens| parse_with_max_depth(tokens, max_depth);
+    let (error, complete) = match parse_attempts(tokens, max_depth) {
+        Ok(ast) => return Ok(ast),
+        Err(e) => e,
+    };
+    // 失敗した解析の途中で式として読み終えた最も後ろの位置までを、もう一度だけ解析する
+    let ast = match complete {
+        0 => None,
+        len => parse(&tokens[..len]).ok(),
+    };
+
+    let mut errors: Vec<ParseError> = vec![];
+    let mut next = Some(error);
+    let mut start = 0;
+    while let Some(error) = next.take() {
+        let pos = error
+            .token()
+            .and_then(|tok| tokens.iter().position(|t| t.location == tok.location));
+        let resume = pos.and_then(|pos| synchronize(tokens, pos));
+        // 同期で読み飛ばした開きかっこに対応する閉じかっこは、回復によって生じたエラーなので報告しない
+        let follow_on = match (&error, pos) {
+            (ParseError::UnmatchedRParen(..), Some(pos)) if start > 0 => {
+                unclosed_parens(&tokens[..pos]) > 0
             }
+            _ => false,
+        };
+        // 深すぎる式は、続きから解析し直しても同じエラーを繰り返すだけなので打ち切る
+        let resume = match error {
+            ParseError::TooDeep(_) => None,
+            _ => resume,
+        };
+        // 同じエラーは一度だけ報告する
+        if !follow_on && !errors.contains(&error) {
+            errors.push(error);
+        }
+        // 同期できる演算子がなければ、残りのエラーは報告しない
+        if let Some(resume) = resume.filter(|&resume| resume > start && resume < tokens.len()) {
+            start = resume;
+            next = parse(&tokens[start..]).err();
+        }
+    }
+    Err(Box::new(PartialParse { ast, errors }))
+}
+
+/// 閉じられていない開きかっこの数を返す
+fn unclosed_parens(tokens: &[Token]) -> usize {
+    tokens.iter().fold(0, |depth: usize, tok| match tok.value {
+        TokenKind::LParen => depth + 1,
+        TokenKind::RParen => depth.saturating_sub(1),
+        _ => depth,
+    })
+}
+
+///
+/// エラーの位置pos以降で最初の演算子を探し、その直後の位置を返す。
+/// 演算子の後には被演算子が続くので、そこから式として解析を再開できる。
+///
+fn synchronize(tokens: &[Token], pos: usize) -> Option<usize> {
+    use self::TokenKind::*;
+    tokens[pos..]
+        .iter()
+        .position(|tok| {
+            matches!(
+                tok.value,
+                Plus | Minus | Asterisk | Slash | Caret | Equal | Comma | Pipe
+            )
+        })
+        .map(|offset| pos + offset + 1)
+}
+
+/// 二項演算子の結合性
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Associativity {
+    Left,
+    Right,
+}
+
+/// 演算子が前置の単項演算子か二項演算子か
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum OperatorKind {
+    Prefix(UnaryOperatorKind),
+    Infix(BinaryOperatorKind),
+}
+
+/// 演算子の表の1行
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OperatorDef {
+    /// 演算子を表すトークン
+    pub token: TokenKind,
+    /// 優先順位。大きいほど強く結び付く
+    pub precedence: u8,
+    pub associativity: Associativity,
+    pub kind: OperatorKind,
+}
+
+/// 演算子の表。演算子を追加する場合は、ここに1行加えればよい
+pub const OPERATORS: &[OperatorDef] = &[
+    OperatorDef {
+        token: TokenKind::Pipe,
+        precedence: 1,
+        associativity: Associativity::Left,
+        kind: OperatorKind::Infix(BinaryOperatorKind::BitOr),
+    },
+    OperatorDef {
+        token: TokenKind::Plus,
+        precedence: 2,
+        associativity: Associativity::Left,
+        kind: OperatorKind::Infix(BinaryOperatorKind::Add),
+    },
+    OperatorDef {
+        token: TokenKind::Minus,
+        precedence: 2,
+        associativity: Associativity::Left,
+        kind: OperatorKind::Infix(BinaryOperatorKind::Sub),
+    },
+    OperatorDef {
+        token: TokenKind::Asterisk,
+        precedence: 3,
+        associativity: Associativity::Left,
+        kind: OperatorKind::Infix(BinaryOperatorKind::Multi),
+    },
+    OperatorDef {
+        token: TokenKind::Slash,
+        precedence: 3,
+        associativity: Associativity::Left,
+        kind: OperatorKind::Infix(BinaryOperatorKind::Div),
+    },
+    // "-2 ^ 2"は"-(2 ^ 2)"となるよう、単項演算子はべき乗より弱く結び付く
+    OperatorDef {
+        token: TokenKind::Plus,
+        precedence: 4,
+        associativity: Associativity::Right,
+        kind: OperatorKind::Prefix(UnaryOperatorKind::Plus),
+    },
+    OperatorDef {
+        token: TokenKind::Minus,
+        precedence: 4,
+        associativity: Associativity::Right,
+        kind: OperatorKind::Prefix(UnaryOperatorKind::Minus),
+    },
+    OperatorDef {
+        token: TokenKind::Caret,
+        precedence: 5,
+        associativity: Associativity::Right,
+        kind: OperatorKind::Infix(BinaryOperatorKind::Pow),
+    },
+];
+
+/// トークンが前置の単項演算子であれば、その種類と定義を返す
+pub(crate) fn prefix_operator(
+    tok: &Token,
+) -> Option<(&'static UnaryOperatorKind, &'static OperatorDef)> {
+    OPERATORS.iter().find_map(|def| match def.kind {
+        OperatorKind::Prefix(ref kind) if def.token == tok.value => Some((kind, def)),
+        _ => None,
+    })
+}
+
+/// トークンが二項演算子であれば、その種類と定義を返す
+pub(crate) fn binary_operator(
+    tok: &Token,
+) -> Option<(&'static BinaryOperatorKind, &'static OperatorDef)> {
+    OPERATORS.iter().find_map(|def| match def.kind {
+        OperatorKind::Infix(ref kind) if def.token == tok.value => Some((kind, def)),
+        _ => None,
+    })
+}
+
+/// EXPR = PREFIX, { BINOP, PREFIX } ;
+/// 演算子の優先順位と結合性は、演算子の表に従う
+fn parse_expr(tokens: &mut TokenCursor) -> Result<Ast, ParseError> {
+    parse_binary(tokens, 0)
+}
+
+///
+/// 優先順位がmin_precedence以上の二項演算子だけを結び付けて式を解析する（Pratt parser）。
+/// かっこや単項演算子の中の式もここを通るので、入れ子の深さはここで数える。
+///
+fn parse_binary(tokens: &mut TokenCursor, min_precedence: u8) -> Result<Ast, ParseError> {
+    nested(tokens, |tokens| parse_operators(tokens, min_precedence))
+}
+
+fn parse_operators(tokens: &mut TokenCursor, min_precedence: u8) -> Result<Ast, ParseError> {
+    let mut left = match tokens.resume() {
+        Some(left) => left,
+        None => parse_prefix(tokens)?,
+    };
+    tokens.mark_complete();
+    loop {
+        let (kind, def) = match tokens.peek().and_then(binary_operator) {
+            Some((kind, def)) if def.precedence >= min_precedence => (kind, def),
             _ => break,
+        };
+        // 絶対値の中では、閉じる"|"とビット論理和のどちらかを選ぶ
+        if *kind == BinaryOperatorKind::BitOr && tokens.bars > 0 && !tokens.decide() {
+            break;
         }
+        let op_token = tokens.next().unwrap().clone();
+        // 左結合なら、右辺には同じ優先順位の演算子を含めない
+        let next_precedence = match def.associativity {
+            Associativity::Left => def.precedence + 1,
+            Associativity::Right => def.precedence,
+        };
+        let right =
+            parse_binary(tokens, next_precedence).map_err(|e| missing_operand(e, &op_token))?;
+        let op = BinaryOperator::new(kind.clone(), op_token.location);
+        let loc = left.location.merge(&right.location);
+        left = tokens.commit(Ast::binary(op, left, right, loc));
+        tokens.mark_complete();
     }
     Ok(left)
 }
 
-/// EXPR1 = ("+" | "-"), ATOM | ATOM ;
-fn parse_expr1<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Ast, ParseError>
+/// PREFIX = UNOP, PREFIX | ATOM ;
+fn parse_prefix(tokens: &mut TokenCursor) -> Result<Ast, ParseError> {
+    let (kind, def) = match tokens.peek().and_then(prefix_operator) {
+        Some(operator) => operator,
+        None => return parse_atom(tokens),
+    };
+    let op_token = tokens.next().unwrap().clone();
+    let operand =
+        parse_binary(tokens, def.precedence).map_err(|e| missing_operand(e, &op_token))?;
+    let op = UnaryOperator::new(kind.clone(), op_token.location);
+    let loc = op.location.merge(&operand.location);
+    Ok(Ast::unary(op, operand, loc))
+}
+
+/// 入れ子を1段深くして解析する。深さが上限に達していれば、解析せずにTooDeepを返す
+fn nested<F>(tokens: &mut TokenCursor, parser: F) -> Result<Ast, ParseError>
 where
-    Tokens: Iterator<Item = Token>,
+    F: FnOnce(&mut TokenCursor) -> Result<Ast, ParseError>,
 {
-    match tokens.peek().map(|tok| tok.value) {
-        Some(TokenKind::Plus) | Some(TokenKind::Minus) => {
-            let op = match tokens.next() {
-                Some(Token {
-                    value: TokenKind::Plus,
-                    location, // locationは何でもよい
-                }) => UnaryOperator::plus(location),
-                Some(Token {
-                    value: TokenKind::Minus,
-                    location,
-                }) => UnaryOperator::minus(location),
-                _ => unreachable!(),
-            };
-            // ATOM
-            let atom = parse_atom(tokens)?;
-            let loc = op.location.merge(&atom.location);
-            Ok(Ast::unary(op, atom, loc))
+    if tokens.depth >= tokens.max_depth {
+        return Err(match tokens.peek() {
+            Some(tok) => ParseError::TooDeep(tok.clone()),
+            None => ParseError::Eof,
+        });
+    }
+    tokens.depth += 1;
+    let result = parser(tokens);
+    tokens.depth -= 1;
+    result
+}
+
+/// 演算子の直後で入力が終わった場合、Eofを演算子を指すエラーへ置き換える
+fn missing_operand(e: ParseError, op_token: &Token) -> ParseError {
+    match e {
+        ParseError::Eof => ParseError::MissingOperand(op_token.clone()),
+        e => e,
+    }
+}
+
+/// ATOM = UNUMBER | LITERAL | CALL | IDENT | "(", EXPR, ")" | "|", EXPR, "|" ;
+fn parse_atom(tokens: &mut TokenCursor) -> Result<Ast, ParseError> {
+    // 次が無ければエラー
+    let tok = tokens.next().ok_or(ParseError::Eof)?.clone();
+    match tok.value {
+        // UNUMBER
+        TokenKind::Number(n) => Ok(Ast::num(n, tok.location)),
+        // LITERAL（値のあるものだけを数として読む）
+        TokenKind::Literal(Literal { value: Some(n), .. }) => Ok(Ast::num(n, tok.location)),
+        // CALL
+        TokenKind::Ident(ref name)
+            if tokens.peek().map(|t| &t.value) == Some(&TokenKind::LParen) =>
+        {
+            // 関数の名前だけでも変数として読める
+            tokens.mark_complete();
+            parse_call(tokens, name, &tok)
+        }
+        // IDENT
+        TokenKind::Ident(ref name) => Ok(Ast::var(name, tok.location.clone())),
+        // "(" EXPR3 ")"
+        TokenKind::LParen => {
+            // "()"のように中身が空の場合
+            if let Some(TokenKind::RParen) = tokens.peek().map(|t| &t.value) {
+                let rparen = tokens.next().unwrap().clone();
+                return Err(ParseError::EmptyParens(tok, rparen));
+            }
+            let exp = in_group(tokens, parse_expr)?;
+            close_paren(tokens, tok, exp)
+        }
+        // "|" EXPR "|" は絶対値を求める関数の呼び出しとする
+        TokenKind::Pipe => {
+            tokens.bars += 1;
+            tokens.groups += 1;
+            let exp = parse_expr(tokens);
+            tokens.groups -= 1;
+            tokens.bars -= 1;
+            close_bar(tokens, tok, exp)
         }
-        // | ATOM
-        _ => parse_atom(tokens),
+        _ => Err(ParseError::NotExpression(tok)),
     }
 }
 
-/// ATOM = UNUMBER | "(", EXPR3, ")" ;
-fn parse_atom<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Ast, ParseError>
-where
-    Tokens: Iterator<Item = Token>,
-{
-    tokens
-        .next()
-        .ok_or(ParseError::Eof) // 次が無ければエラー
-        .and_then(|tok| match tok.value {
-            // UNUMBER
-            TokenKind::Number(n) => Ok(Ast::num(n, tok.location)),
-            // "(" EXPR3 ")"
-            TokenKind::LParen => {
-                let exp = parse_expr(tokens)?;
-                match tokens.next() {
-                    // ")"の場合
-                    Some(Token {
-                        value: TokenKind::RParen,
-                        .. // 他のフィールドは何でもよい
-                    }) => Ok(exp),
-                    // ")"以外の何かの場合
-                    Some(t) => Err(ParseError::RedundantExpression(t)),
-                    // 次のトークンがない場合
-                    _ => Err(ParseError::UnclosedOpenParen(tok)),
-                }
+/// "(" EXPR の後の閉じかっこを読む
+fn close_paren(tokens: &mut TokenCursor, open: Token, exp: Ast) -> Result<Ast, ParseError> {
+    match tokens.next() {
+        // ")"の場合
+        Some(Token {
+            value: TokenKind::RParen,
+            .. // 他のフィールドは何でもよい
+        }) => {
+            tokens.close_group(&open);
+            Ok(exp)
+        }
+        // ")"以外の何かの場合
+        Some(t) => Err(ParseError::RedundantExpression(t.clone())),
+        // 次のトークンがない場合
+        _ => Err(ParseError::UnclosedOpenParen(open)),
+    }
+}
+
+/// "|" EXPR の後の閉じる"|"を読み、絶対値を求める関数の呼び出しにする
+fn close_bar(
+    tokens: &mut TokenCursor,
+    open: Token,
+    exp: Result<Ast, ParseError>,
+) -> Result<Ast, ParseError> {
+    let exp = exp.map_err(|e| match e {
+        ParseError::Eof => ParseError::UnclosedOpenParen(open.clone()),
+        e => e,
+    })?;
+    match tokens.next().cloned() {
+        Some(
+            close @ Token {
+                value: TokenKind::Pipe,
+                ..
+            },
+        ) => {
+            let loc = open.location.merge(&close.location);
+            Ok(Ast::call("abs", vec![exp], loc))
+        }
+        Some(t) if t.value == TokenKind::RParen => Err(tokens.unmatched(&t)),
+        Some(t) => Err(ParseError::RedundantExpression(t)),
+        None => Err(ParseError::UnclosedOpenParen(open)),
+    }
+}
+
+/// かっこの中では、外側の絶対値の"|"を閉じられない
+fn in_group(
+    tokens: &mut TokenCursor,
+    parser: fn(&mut TokenCursor) -> Result<Ast, ParseError>,
+) -> Result<Ast, ParseError> {
+    let bars = std::mem::replace(&mut tokens.bars, 0);
+    tokens.groups += 1;
+    let result = parser(tokens);
+    tokens.groups -= 1;
+    tokens.bars = bars;
+    result
+}
+
+/// CALL = IDENT, "(", [ EXPR, { ",", EXPR } ], ")" ;
+fn parse_call(tokens: &mut TokenCursor, name: &str, name_token: &Token) -> Result<Ast, ParseError> {
+    let lparen = tokens.next().unwrap().clone();
+    let mut args = Vec::new();
+    // 引数がない場合
+    if let Some(TokenKind::RParen) = tokens.peek().map(|t| &t.value) {
+        let rparen = tokens.next().unwrap().clone();
+        tokens.close_group(&lparen);
+        let loc = name_token.location.merge(&rparen.location);
+        return Ok(Ast::call(name, args, loc));
+    }
+    let unclosed = |e| match e {
+        ParseError::Eof => ParseError::UnclosedOpenParen(lparen.clone()),
+        e => e,
+    };
+    args.push(in_group(tokens, parse_expr).map_err(unclosed)?);
+    loop {
+        match tokens.next().cloned() {
+            // ","の後には次の引数が続く
+            Some(
+                comma @ Token {
+                    value: TokenKind::Comma,
+                    ..
+                },
+            ) => {
+                let arg = in_group(tokens, parse_expr).map_err(|e| missing_operand(e, &comma))?;
+                args.push(arg);
             }
-            _ => Err(ParseError::NotExpression(tok)),
-        })
+            Some(
+                rparen @ Token {
+                    value: TokenKind::RParen,
+                    ..
+                },
+            ) => {
+                tokens.close_group(&lparen);
+                let loc = name_token.location.merge(&rparen.location);
+                return Ok(Ast::call(name, args, loc));
+            }
+            Some(t) => return Err(ParseError::RedundantExpression(t)),
+            None => return Err(ParseError::UnclosedOpenParen(lparen)),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::strategy::ast;
+    use crate::visitor::strip_locations;
+    use proptest::prelude::*;
 
     #[test]
     fn test_parser() {
         // 1 + 2 * 3 - -10
-        let ast = parse(vec![
+        let ast = parse(&[
             Token::number(1, Location(0, 1)),
             Token::plus(Location(2, 3)),
             Token::number(2, Location(4, 5)),
@@ -374,36 +1548,592 @@ mod tests {
             Token::minus(Location(10, 11)),
             Token::minus(Location(12, 13)),
             Token::number(10, Location(13, 15)),
-        ]);
+        ])
+        .unwrap();
+        assert_eq!(strip_locations(&ast), ast!(1 + 2 * 3 - -10));
+        // 二項演算子の節点は左辺の始まりから右辺の終わりまでを指す
+        assert_eq!(ast.location, Location(0, 15));
+        match ast.value {
+            AstKind::Binary {
+                ref operator,
+                ref left,
+                ref right,
+            } => {
+                assert_eq!(operator.location, Location(10, 11));
+                assert_eq!(left.location, Location(0, 9));
+                assert_eq!(right.location, Location(12, 15));
+            }
+            other => panic!("not a binary expression: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_missing_operand() {
+        assert_eq!(
+            "1 +".parse::<Ast>(),
+            Err(ApplicationError::Parser(ParseError::MissingOperand(
+                Token::plus(Location(2, 3))
+            )))
+        );
         assert_eq!(
-            ast,
-            Ok(Ast::binary(
-                BinaryOperator::sub(Location(10, 11)),
-                Ast::binary(
+            "2 * -".parse::<Ast>(),
+            Err(ApplicationError::Parser(ParseError::MissingOperand(
+                Token::minus(Location(4, 5))
+            )))
+        );
+    }
+
+    #[test]
+    fn test_parse_empty_parens() {
+        assert_eq!(
+            "1 + ()".parse::<Ast>(),
+            Err(ApplicationError::Parser(ParseError::EmptyParens(
+                Token::lparen(Location(4, 5)),
+                Token::rparen(Location(5, 6))
+            )))
+        );
+    }
+
+    #[test]
+    fn test_parse_pow_right_assoc() {
+        let shape = |input: &str| strip_locations(&input.parse::<Ast>().unwrap());
+        // 2 ^ 3 ^ 2 は 2 ^ (3 ^ 2) と解釈される
+        assert_eq!(shape("2 ^ 3 ^ 2"), ast!(2 ^ (3 ^ 2)));
+        // -2 ^ 2 は -(2 ^ 2) と解釈される
+        assert_eq!(shape("-2 ^ 2"), ast!(-(2 ^ 2)));
+        assert_eq!("-2 ^ 2".parse::<Ast>().unwrap().location, Location(0, 6));
+    }
+
+    #[test]
+    fn test_parse_assign() {
+        // x = y = 1
+        let ast = parse(&[
+            Token::ident("x", Location(0, 1)),
+            Token::equal(Location(2, 3)),
+            Token::ident("y", Location(4, 5)),
+            Token::equal(Location(6, 7)),
+            Token::number(1, Location(8, 9)),
+        ])
+        .unwrap();
+        assert_eq!(strip_locations(&ast), ast!(x = y = 1));
+        assert_eq!(ast.location, Location(0, 9));
+        assert_eq!(
+            "1 = 2".parse::<Ast>(),
+            Err(ApplicationError::Parser(ParseError::InvalidAssignment(
+                Token::equal(Location(2, 3))
+            )))
+        );
+    }
+
+    #[test]
+    fn test_parse_with_recovery() {
+        // 1 + 2 * (3
+        let tokens = lex("1 + 2 * (3").unwrap();
+        assert_eq!(
+            parse_with_recovery(&tokens),
+            Err(Box::new(PartialParse {
+                ast: Some(Ast::binary(
                     BinaryOperator::add(Location(2, 3)),
                     Ast::num(1, Location(0, 1)),
-                    Ast::binary(
-                        BinaryOperator::new(BinaryOperatorKind::Multi, Location(6, 7)),
-                        Ast::num(2, Location(4, 5)),
-                        Ast::num(3, Location(8, 9)),
-                        Location(4, 9)
-                    ),
-                    Location(0, 9),
-                ),
+                    Ast::num(2, Location(4, 5)),
+                    Location(0, 5)
+                )),
+                errors: vec![ParseError::UnclosedOpenParen(Token::lparen(Location(8, 9)))],
+            }))
+        );
+
+        let tokens = lex(") 1").unwrap();
+        assert_eq!(
+            parse_with_recovery(&tokens),
+            Err(Box::new(PartialParse {
+                ast: None,
+                errors: vec![ParseError::NotExpression(Token::rparen(Location(0, 1)))],
+            }))
+        );
+
+        // エラーの後の演算子で同期し、後続のエラーも報告する
+        let tokens = lex("1 + ) * 2 3 - (4").unwrap();
+        let partial = parse_with_recovery(&tokens).unwrap_err();
+        assert_eq!(
+            partial.errors,
+            vec![
+                ParseError::NotExpression(Token::rparen(Location(4, 5))),
+                ParseError::RedundantExpression(Token::number(3, Location(10, 11))),
+                ParseError::UnclosedOpenParen(Token::lparen(Location(14, 15))),
+            ]
+        );
+
+        // 読み飛ばした開きかっこに対応する閉じかっこや、入力の終わりは報告しない
+        let tokens = lex("((1 2 + 3) * 4) + 5 6").unwrap();
+        let partial = parse_with_recovery(&tokens).unwrap_err();
+        assert_eq!(
+            partial.errors,
+            vec![
+                ParseError::RedundantExpression(Token::number(2, Location(4, 5))),
+                ParseError::RedundantExpression(Token::number(6, Location(20, 21))),
+            ]
+        );
+        let tokens = lex("1 + + + +").unwrap();
+        let partial = parse_with_recovery(&tokens).unwrap_err();
+        assert_eq!(
+            partial.errors,
+            vec![ParseError::MissingOperand(Token::plus(Location(8, 9)))]
+        );
+        let tokens = lex("(1 + ) * 2) + 3").unwrap();
+        let partial = parse_with_recovery(&tokens).unwrap_err();
+        assert_eq!(
+            partial.errors,
+            vec![
+                ParseError::NotExpression(Token::rparen(Location(5, 6))),
+                ParseError::UnmatchedRParen(Token::rparen(Location(10, 11)), None),
+            ]
+        );
+        // 深すぎる式の続きは解析し直さない
+        let tokens = lex("(((1))) + (((2)))").unwrap();
+        let partial = parse_with_recovery_and_max_depth(&tokens, 3).unwrap_err();
+        assert_eq!(
+            partial.errors,
+            vec![ParseError::TooDeep(Token::number(1, Location(3, 4)))]
+        );
+    }
+
+    #[test]
+    fn test_parse_stream() {
+        assert_eq!(
+            parse_stream(StreamingLexer::new("(1 +\n 2) * x")),
+            Ok(parse(&lex("(1 +\n 2) * x").unwrap()).unwrap())
+        );
+        // 字句解析のエラーの後ろは読まない
+        let mut lexer = StreamingLexer::new("1 $ 2 @ 3");
+        assert_eq!(
+            parse_stream(lexer.by_ref()),
+            Err(ApplicationError::Lexer(LexError::new(
+                LexErrorKind::InvalidChar('$'),
+                Location(2, 3)
+            )))
+        );
+        assert_eq!(lexer.next(), Some(Ok(Token::number(2, Location(4, 5)))));
+    }
+
+    /// 読まれたトークンの数を数えるイテレータ。複製できないので、読んだトークンは構文解析器が持つしかない
+    struct Counting<'a> {
+        lexer: StreamingLexer<'a>,
+        count: &'a std::cell::Cell<usize>,
+    }
+
+    impl Iterator for Counting<'_> {
+        type Item = Result<Token, LexError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let item = self.lexer.next()?;
+            self.count.set(self.count.get() + 1);
+            Some(item)
+        }
+    }
+
+    #[test]
+    fn test_parse_stream_on_demand() {
+        let count = std::cell::Cell::new(0);
+        let counting = |input| Counting {
+            lexer: StreamingLexer::new(input),
+            count: &count,
+        };
+        // エラーの後ろのトークンは読まない
+        assert_eq!(
+            parse_stream(counting("1 + ) 2 3 4 5")),
+            Err(ApplicationError::Parser(ParseError::NotExpression(
+                Token::rparen(Location(4, 5))
+            )))
+        );
+        assert_eq!(count.get(), 3);
+
+        // 長い和では、読み終えたトークンを捨てながら読み進める
+        count.set(0);
+        let input = format!("1{}", " + 1".repeat(1000));
+        let mut tokens = counting(&input);
+        let mut cursor = TokenCursor::from_stream(&mut tokens);
+        let ast = parse_retrying(&mut cursor).unwrap();
+        assert_eq!(ast.to_string(), input);
+        match cursor.source {
+            Source::Stream(ref stream) => assert!(stream.buffer.len() <= 2, "{:?}", stream),
+            Source::Slice(_) => unreachable!(),
+        }
+        assert_eq!(count.get(), 2001);
+
+        // 読み進めた後でも、"|"の解釈を選び直せる
+        for input in &[
+            "1 + 2 + |a | b|",
+            "1 * 2 + |1| + 2| - 3",
+            "x + |a| | |b| * 2",
+            "1 + 2 + |a | b",
+        ] {
+            assert_eq!(
+                parse_stream(counting(input)),
+                parse(&lex(input).unwrap()).map_err(ApplicationError::from),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_unmatched_rparen() {
+        let parse_str = |s: &str| parse(&lex(s).unwrap());
+        assert_eq!(
+            parse_str("1 + 2)"),
+            Err(ParseError::UnmatchedRParen(
+                Token::rparen(Location(5, 6)),
+                None
+            ))
+        );
+        assert_eq!(
+            parse_str("max(1, 2) * (3))"),
+            Err(ParseError::UnmatchedRParen(
+                Token::rparen(Location(15, 16)),
+                Some(Token::lparen(Location(12, 13)))
+            ))
+        );
+        assert_eq!(
+            parse_str("max(1, 2))"),
+            Err(ParseError::UnmatchedRParen(
+                Token::rparen(Location(9, 10)),
+                Some(Token::lparen(Location(3, 4)))
+            ))
+        );
+        assert_eq!(
+            parse_str("|1)"),
+            Err(ParseError::UnmatchedRParen(
+                Token::rparen(Location(2, 3)),
+                None
+            ))
+        );
+    }
+
+    #[test]
+    fn test_error_context() {
+        let tokens = lex("1 + )").unwrap();
+        let e = parse(&tokens).unwrap_err();
+        assert_eq!(
+            e.context(&tokens),
+            ErrorContext {
+                before: Some(&tokens[1]),
+                token: Some(&tokens[2]),
+                after: None,
+                expected_at: Location(4, 5),
+            }
+        );
+
+        let tokens = lex("2 * (1 +").unwrap();
+        let e = parse(&tokens).unwrap_err();
+        assert_eq!(e, ParseError::MissingOperand(Token::plus(Location(7, 8))));
+        assert_eq!(
+            e.context(&tokens),
+            ErrorContext {
+                before: Some(&tokens[3]),
+                token: Some(&tokens[4]),
+                after: None,
+                expected_at: Location(8, 9),
+            }
+        );
+    }
+
+    #[test]
+    fn test_write_diagnostic() {
+        let mut buf = Vec::new();
+        let e = ApplicationError::from(parse(&lex("1 +").unwrap()).unwrap_err());
+        e.write_diagnostic("1 +", &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "error[P0006]: expression expected after operator '+'\n\
+             1:3 | 1 +\n    \
+             |   ^\n    \
+             = help: add an expression after '+', e.g. '+ 1'\n    \
+             = id: P0006-11bb0953\n"
+        );
+    }
+
+    #[test]
+    fn test_ast_macro() {
+        let shape = |input: &str| strip_locations(&input.parse::<Ast>().unwrap());
+        // 優先順位と結合性は構文解析器と同じ
+        assert_eq!(shape("1 + 2 * -3"), ast!(1 + 2 * -3));
+        assert_eq!(shape("1 + 2 * -3"), ast!(1 + (2 * (-3))));
+        assert_eq!(shape("2 ^ 3 ^ 2"), ast!(2 ^ 3 ^ 2));
+        assert_eq!(shape("2 ^ 3 ^ 2"), ast!(2 ^ (3 ^ 2)));
+        assert_eq!(shape("-2 ^ 2"), ast!(-2 ^ 2));
+        assert_eq!(shape("-2 ^ 2"), ast!(-(2 ^ 2)));
+        assert_eq!(shape("2 ^ -3 ^ 2"), ast!(2 ^ -3 ^ 2));
+        assert_eq!(shape("-2 * 3 - -x"), ast!(-2 * 3 - -x));
+        assert_eq!(shape("-2 * 3 - -x"), ast!(((-2) * 3) - (-x)));
+        assert_eq!(shape("8 - 4 - 2"), ast!(8 - 4 - 2));
+        assert_eq!(shape("8 - 4 - 2"), ast!((8 - 4) - 2));
+        assert_eq!(shape("8 / 4 * 2"), ast!(8 / 4 * 2));
+        assert_eq!(shape("1 | 2 + 3 * 4 | 5"), ast!(1 | 2 + 3 * 4 | 5));
+        assert_eq!(
+            shape("+x ^ y - f(1, -2 ^ 2) / (3 - 4)"),
+            ast!(+x ^ y - f(1, -2 ^ 2) / (3 - 4))
+        );
+        assert_eq!(shape("|a| | |b|"), ast!(abs(a) | abs(b)));
+        assert_eq!(shape("x = y = 0x10"), ast!(x = y = 16));
+        assert_eq!(shape("max(1, 2.5e3)"), ast!(max(1, 2500)));
+        assert_ne!(ast!(1 - 2), ast!(2 - 1));
+        assert_ne!(ast!(8 - 4 - 2), ast!(8 - (4 - 2)));
+        // 構文解析器を通さずに組み立てる
+        let at = || Location(0, 0);
+        assert_eq!(
+            ast!(x = -max(y, (1 + 2) * 3)),
+            Ast::assign(
+                "x",
                 Ast::unary(
-                    UnaryOperator::minus(Location(12, 13)),
-                    Ast::num(10, Location(13, 15)),
-                    Location(12, 15)
+                    UnaryOperator::minus(at()),
+                    Ast::call(
+                        "max",
+                        vec![
+                            Ast::var("y", at()),
+                            Ast::binary(
+                                BinaryOperator::multi(at()),
+                                Ast::binary(
+                                    BinaryOperator::add(at()),
+                                    Ast::num(1, at()),
+                                    Ast::num(2, at()),
+                                    at()
+                                ),
+                                Ast::num(3, at()),
+                                at()
+                            ),
+                        ],
+                        at()
+                    ),
+                    at()
                 ),
-                Location(0, 15)
-            ))
-        )
+                at()
+            )
+        );
+        assert_eq!(ast!(f()), Ast::call("f", vec![], at()));
+    }
+
+    #[test]
+    fn test_ast_display() {
+        let show = |input: &str| input.parse::<Ast>().unwrap().to_string();
+        assert_eq!(show("1+2*3"), "1 + 2 * 3");
+        assert_eq!(show("((1 + 2)) * -(x)"), "(1 + 2) * -x");
+        assert_eq!(show("x=y=(2^3)^2"), "x = y = (2 ^ 3) ^ 2");
+        assert_eq!(show("8 - (4 - 2) - 1e3"), "8 - (4 - 2) - 1000");
+        assert_eq!(show("max(1,|a-b|,c|d)"), "max(1, |a - b|, c | d)");
+        assert_eq!(show("1 - - 2"), "1 - -2");
+    }
+
+    #[test]
+    fn test_node_count() {
+        let count = |input: &str| input.parse::<Ast>().unwrap().node_count();
+        assert_eq!(count("1"), 1);
+        assert_eq!(count("x = -(1 + 2)"), 5);
+        assert_eq!(count("max(1, y, min())"), 4);
     }
 
     #[test]
     fn test_parse_atom_num() {
         let tokens = vec![Token::number(1, Location(0, 1))];
-        let mut iter = tokens.into_iter().peekable();
-        assert_eq!(parse_atom(&mut iter), Ok(Ast::num(1, Location(0, 1))));
+        let mut cursor = TokenCursor::new(&tokens);
+        assert_eq!(parse_atom(&mut cursor), Ok(Ast::num(1, Location(0, 1))));
+        assert_eq!(cursor.position(), 1);
+    }
+
+    #[test]
+    fn test_token_cursor_rewind() {
+        let tokens = lex("f(1)").unwrap();
+        let mut cursor = TokenCursor::new(&tokens);
+        let start = cursor.position();
+        assert_eq!(cursor.peek_nth(1), Some(&Token::lparen(Location(1, 2))));
+        assert_eq!(cursor.next(), Some(&Token::ident("f", Location(0, 1))));
+        assert_eq!(cursor.remaining().len(), 3);
+        // 保存した位置へ戻ると、同じトークンをもう一度読める
+        cursor.rewind(start);
+        assert_eq!(cursor.next(), Some(&Token::ident("f", Location(0, 1))));
+    }
+
+    #[test]
+    fn test_call() {
+        let ast = "min(2, x) + f()".parse::<Ast>().unwrap();
+        assert_eq!(strip_locations(&ast), ast!(min(2, x) + f()));
+        // 呼び出しの節点は関数の名前から")"までを指す
+        match ast.value {
+            AstKind::Binary {
+                ref left,
+                ref right,
+                ..
+            } => {
+                assert_eq!(left.location, Location(0, 9));
+                assert_eq!(right.location, Location(12, 15));
+            }
+            other => panic!("not a binary expression: {:?}", other),
+        }
+        assert_eq!(
+            "min(2,".parse::<Ast>(),
+            Err(ApplicationError::Parser(ParseError::MissingOperand(
+                Token::comma(Location(5, 6))
+            )))
+        );
+        assert_eq!(
+            "min(2 3)".parse::<Ast>(),
+            Err(ApplicationError::Parser(ParseError::RedundantExpression(
+                Token::number(3, Location(6, 7))
+            )))
+        );
+        assert_eq!(
+            "sqrt(16".parse::<Ast>(),
+            Err(ApplicationError::Parser(ParseError::UnclosedOpenParen(
+                Token::lparen(Location(4, 5))
+            )))
+        );
+    }
+
+    #[test]
+    fn test_abs_bars() {
+        let shape = |input: &str| strip_locations(&input.parse::<Ast>().unwrap());
+        // 閉じる"|"として読めないので、ビット論理和として読み直す
+        assert_eq!(shape("|a | b|"), ast!(abs(a | b)));
+        assert_eq!(shape("|a| | |b|"), ast!(abs(a) | abs(b)));
+        assert_eq!("|a| | |b|".parse::<Ast>().unwrap().location, Location(0, 9));
+        assert_eq!(
+            "|1 + 2".parse::<Ast>(),
+            Err(ApplicationError::Parser(ParseError::UnclosedOpenParen(
+                Token::pipe(Location(0, 1))
+            )))
+        );
+    }
+
+    #[test]
+    fn test_backtrack_limit() {
+        // 解釈の組み合わせが指数的に増える入力でも、上限までで解析を諦め、
+        // 既定の解釈で解析したときのエラーを返す
+        let input = format!("|1{}", " | 1".repeat(40));
+        let tokens = lex(&input).unwrap();
+        assert_eq!(
+            parse(&tokens),
+            Err(ParseError::RedundantExpression(Token::number(
+                1,
+                Location(5, 6)
+            )))
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let tokens = lex("((1))").unwrap();
+        assert!(parse_with_max_depth(&tokens, 3).is_ok());
+        let tokens = lex("(((1)))").unwrap();
+        assert_eq!(
+            parse_with_max_depth(&tokens, 3),
+            Err(ParseError::TooDeep(Token::number(1, Location(3, 4))))
+        );
+        // 左結合の連なりは長くても入れ子にならない
+        let tokens = lex("1 + 1 + 1 + 1 * 2 - 1").unwrap();
+        assert!(parse_with_max_depth(&tokens, 3).is_ok());
+        // 右辺の入れ子や右結合の連なりは深くなる
+        let tokens = lex("1 + (1 + (1 + 1))").unwrap();
+        assert!(parse_with_max_depth(&tokens, 3).is_err());
+        let tokens = lex("2 ^ 2 ^ 2 ^ 2").unwrap();
+        assert!(parse_with_max_depth(&tokens, 3).is_err());
+        let tokens = lex("max(1, -(2)) + 1").unwrap();
+        assert!(parse_with_max_depth(&tokens, 3).is_err());
+        assert!(parse_with_max_depth(&tokens, 4).is_ok());
+
+        // デバッグビルドでは関数呼び出しがスタックを多く使うので、メインスレッドと同じ大きさのスタックで確かめる
+        let nested = std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(|| {
+                // 上限のすぐ内側までは解析できる
+                let depth = DEFAULT_MAX_DEPTH - 1;
+                let input = format!("{}1{}", "(".repeat(depth - 1), ")".repeat(depth - 1));
+                assert!(input.parse::<Ast>().is_ok());
+
+                // かっこのない長い和や積は、どれだけ長くても解析できる
+                let n = 100_000;
+                for input in &[
+                    format!("1{}", " + 1".repeat(n)),
+                    format!("1{}", " * 2 - 1".repeat(n)),
+                ] {
+                    assert!(input.parse::<Ast>().is_ok(), "{}...", &input[..10]);
+                }
+
+                // スタックを使い切らずにエラーを返す
+                for input in &[
+                    format!("{}1{}", "(".repeat(n), ")".repeat(n)),
+                    format!("{}1", "-".repeat(n)),
+                    format!("{}1{}", "|".repeat(n), "|".repeat(n)),
+                    format!("{}1{}", "f(".repeat(n), ")".repeat(n)),
+                    format!("{}2", "2 ^ ".repeat(n)),
+                    format!("{}1", "x = ".repeat(n)),
+                ] {
+                    let tokens = lex(input).unwrap();
+                    assert!(
+                        matches!(parse(&tokens), Err(ParseError::TooDeep(_))),
+                        "{}...",
+                        &input[..10]
+                    );
+                }
+            })
+            .unwrap();
+        nested.join().unwrap();
+    }
+
+    #[test]
+    fn test_operator_table() {
+        let shape = |input: &str| strip_locations(&input.parse::<Ast>().unwrap());
+        // 左結合
+        assert_eq!(shape("8 / 2 / 2"), ast!((8 / 2) / 2));
+        // 単項演算子は重ねられる
+        assert_eq!(shape("- -2"), ast!(-(-2)));
+        // 同じトークンでも、前置と二項で別の優先順位を持てる
+        let minus = Token::minus(Location(0, 1));
+        assert_eq!(prefix_operator(&minus).unwrap().1.precedence, 4);
+        assert_eq!(binary_operator(&minus).unwrap().1.precedence, 2);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_display_round_trip(ast in ast()) {
+            // Displayは必要なかっこだけを補うので、読み直すと同じ形になる
+            let shown = ast.to_string();
+            let back = shown.parse::<Ast>().unwrap();
+            prop_assert_eq!(strip_locations(&back), strip_locations(&ast), "{}", shown);
+        }
+
+        #[test]
+        fn prop_recovery_prefix(input in prop::collection::vec(
+            prop::sample::select(vec!["1", "x", "f", "+", "-", "*", "^", "=", "|", "(", ")", ","]),
+            1..12,
+        )) {
+            // 途中までの式は、解析できる最も長い接頭辞と同じになる
+            let tokens = lex(&input.join(" ")).unwrap();
+            if let Err(partial) = parse_with_recovery(&tokens) {
+                let longest = (1..tokens.len())
+                    .rev()
+                    .find_map(|len| parse(&tokens[..len]).ok());
+                prop_assert_eq!(partial.ast, longest, "{}", input.join(" "));
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let ast = "x = max(1, -y) | 2".parse::<Ast>().unwrap();
+        let json = serde_json::to_string(&ast).unwrap();
+        assert_eq!(serde_json::from_str::<Ast>(&json).unwrap(), ast);
+        assert!(
+            json.starts_with(r#"{"value":{"Assign":{"name":"x","#),
+            "{}",
+            json
+        );
+
+        let tokens = lex("1 + )").unwrap();
+        let error = ApplicationError::from(parse(&tokens).unwrap_err());
+        let json = serde_json::to_string(&error).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ApplicationError>(&json).unwrap(),
+            error
+        );
     }
 }
@@ -0,0 +1,258 @@
+//!
+//! バイトコードののぞき穴最適化。
+//! 命令列の末尾の数命令だけを見て、同じ結果になるより短い命令列に置き換える。
+//!
+//! - 単位元の演算を除く（"push 0; add"、"push 1; mul"など）
+//! - 定数だけを使う演算を、その結果を積む1命令にする（"push 1; push 2; add"は"push 3"）
+//! - 定数の符号の反転を重ねたものは打ち消す（"push 2; neg; neg"は"push 2"）
+//!
+//! 評価器と同じエラーを同じ位置で報告できるよう、計算に失敗する演算は畳み込まない。
+//! 値のわからない"neg; neg"は、値がi64::MINなら最初の反転で桁あふれになるので打ち消さない。
+//!
+use std::fmt::Write;
+
+use super::bytecode::{disassemble, Instruction, InstructionKind};
+use super::interpreter::{apply_binop, apply_function, apply_uniop};
+use super::lexer::Location;
+use super::parser::{BinaryOperatorKind, UnaryOperatorKind};
+
+///
+/// 命令列を最適化する。
+/// 置き換えた命令の位置は、置き換える前の命令すべての範囲になる。
+///
+/// ```
+/// use parser::bytecode::{BytecodeCompiler, InstructionKind};
+/// use parser::peephole::optimize;
+///
+/// let ast = "x * (3 - 2) + 2 * 3".parse().unwrap();
+/// let mut code = BytecodeCompiler::new().compile(&ast).unwrap();
+/// optimize(&mut code);
+/// let kinds: Vec<_> = code.iter().map(|inst| inst.value.to_string()).collect();
+/// assert_eq!(kinds, vec!["load x", "push 6", "add"]);
+/// ```
+///
+pub fn optimize(code: &mut Vec<Instruction>) {
+    let mut optimized = Vec::with_capacity(code.len());
+    for inst in code.drain(..) {
+        optimized.push(inst);
+        // 置き換えた結果の末尾が、さらに置き換えられることがある
+        while rewrite(&mut optimized) {}
+    }
+    *code = optimized;
+}
+
+///
+/// 最適化する前と後の命令列を、disassembleと同じ形で続けて書き、bufの内容を置き換える。
+///
+/// ```text
+/// before:
+///    0  push 1          0-1     1
+///    1  push 2          4-5     2
+///    2  add             2-3     +
+/// after:
+///    0  push 3          0-5     1 + 2
+/// ```
+///
+pub fn format_optimized(code: &[Instruction], source: &str, buf: &mut String) {
+    let mut optimized = code.to_vec();
+    optimize(&mut optimized);
+    let mut listing = String::new();
+    buf.clear();
+    disassemble(code, source, &mut listing);
+    writeln!(buf, "before:\n{}", listing).unwrap();
+    disassemble(&optimized, source, &mut listing);
+    write!(buf, "after:\n{}", listing).unwrap();
+}
+
+/// 命令列の末尾を1度だけ置き換える。置き換えたらtrueを返す
+fn rewrite(code: &mut Vec<Instruction>) -> bool {
+    use self::InstructionKind::*;
+    let n = code.len();
+    let last = &code[n - 1];
+    match last.value {
+        // x + 0, x - 0, x * 1, x / 1, x | 0, x ^ 1 はxのまま
+        Add | Sub | Mul | Div | Or | Pow if n >= 2 => {
+            if let Push(k) = code[n - 2].value {
+                if Some(k) == identity(&last.value) {
+                    code.truncate(n - 2);
+                    return true;
+                }
+            }
+            if n < 3 {
+                return false;
+            }
+            match (&code[n - 3].value, &code[n - 2].value) {
+                (&Push(left), &Push(right)) => {
+                    let operator = binary_operator(&last.value);
+                    fold(code, 3, apply_binop(&operator, left, right).ok())
+                }
+                _ => false,
+            }
+        }
+        Neg if n >= 2 => match code[n - 2].value {
+            Push(operand) => fold(
+                code,
+                2,
+                apply_uniop(&UnaryOperatorKind::Minus, operand).ok(),
+            ),
+            _ => false,
+        },
+        Call(ref name, argc) if n > argc => {
+            let args: Option<Vec<i64>> = code[n - 1 - argc..n - 1]
+                .iter()
+                .map(|inst| match inst.value {
+                    Push(value) => Some(value),
+                    _ => None,
+                })
+                .collect();
+            let value = args.and_then(|args| apply_function(name, &args).ok());
+            fold(code, argc + 1, value)
+        }
+        _ => false,
+    }
+}
+
+/// 末尾のcount個の命令を、valueを積む1命令に置き換える。valueがなければ何もしない
+fn fold(code: &mut Vec<Instruction>, count: usize, value: Option<i64>) -> bool {
+    let value = match value {
+        Some(value) => value,
+        None => return false,
+    };
+    let start = code.len() - count;
+    let location = code[start..]
+        .iter()
+        .fold(code[start].location.clone(), |location: Location, inst| {
+            location.merge(&inst.location)
+        });
+    code.truncate(start);
+    code.push(Instruction::new(InstructionKind::Push(value), location));
+    true
+}
+
+/// 右の被演算子がこの値なら、左の被演算子をそのまま返す値
+fn identity(kind: &InstructionKind) -> Option<i64> {
+    use self::InstructionKind::*;
+    match kind {
+        Add | Sub | Or => Some(0),
+        Mul | Div | Pow => Some(1),
+        _ => None,
+    }
+}
+
+fn binary_operator(kind: &InstructionKind) -> BinaryOperatorKind {
+    use self::InstructionKind::*;
+    match kind {
+        Add => BinaryOperatorKind::Add,
+        Sub => BinaryOperatorKind::Sub,
+        Mul => BinaryOperatorKind::Multi,
+        Div => BinaryOperatorKind::Div,
+        Pow => BinaryOperatorKind::Pow,
+        _ => BinaryOperatorKind::BitOr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{BytecodeCompiler, Vm};
+    use crate::parser::Ast;
+    use crate::strategy::ast;
+    use proptest::prelude::*;
+
+    fn optimized(input: &str) -> Vec<String> {
+        let mut code = BytecodeCompiler::new()
+            .compile(&input.parse().unwrap())
+            .unwrap();
+        optimize(&mut code);
+        code.iter().map(|inst| inst.value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_optimize() {
+        assert_eq!(optimized("x + 0"), vec!["load x"]);
+        assert_eq!(optimized("x * 1 / 1 - 0 | 0 ^ 1"), vec!["load x"]);
+        assert_eq!(optimized("x + (1 - 1)"), vec!["load x"]);
+        assert_eq!(optimized("- -2"), vec!["push 2"]);
+        assert_eq!(
+            optimized("y = max(1, 2 * 3, -4)"),
+            vec!["push 6", "store y"]
+        );
+        // 値のわからない符号の反転は打ち消さない
+        assert_eq!(optimized("- -x"), vec!["load x", "neg", "neg"]);
+        // 単位元を左に置いた式は、命令列の末尾では見分けられない
+        assert_eq!(optimized("0 + x"), vec!["push 0", "load x", "add"]);
+        // 計算に失敗する演算は、実行時に同じエラーになるよう残す
+        assert_eq!(
+            optimized("1 / 0 + 2"),
+            vec!["push 1", "push 0", "div", "push 2", "add"]
+        );
+        assert_eq!(optimized("sqrt(-1)"), vec!["push -1", "call sqrt 1"]);
+
+        let mut code = BytecodeCompiler::new()
+            .compile(&"x = 1 + 2 * 3".parse().unwrap())
+            .unwrap();
+        optimize(&mut code);
+        assert_eq!(
+            code,
+            vec![
+                Instruction::new(InstructionKind::Push(7), Location(4, 13)),
+                Instruction::new(InstructionKind::Store("x".to_string()), Location(0, 13)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_same_result() {
+        let mut vm = Vm::new();
+        let mut optimized_vm = Vm::new();
+        let mut compiler = BytecodeCompiler::new();
+        for line in &[
+            "x = 2 ^ 3 ^ 2 - 0",
+            "(x - 512) / 1 + max(3, 1) * 1",
+            "x / (5 - 5)",
+            "9223372036854775807 + 1 - 1",
+            "y = -x * 1 | 0",
+            "pow(2, 0 - 1)",
+            "min() + 0",
+        ] {
+            let code = compiler.compile(&line.parse::<Ast>().unwrap()).unwrap();
+            let mut optimized = code.clone();
+            optimize(&mut optimized);
+            assert_eq!(optimized_vm.run(&optimized), vm.run(&code), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_format_optimized() {
+        let line = "1 + 2";
+        let code = BytecodeCompiler::new()
+            .compile(&line.parse().unwrap())
+            .unwrap();
+        let mut buf = String::new();
+        format_optimized(&code, line, &mut buf);
+        assert_eq!(
+            buf,
+            "before:
+   0  push 1          0-1     1
+   1  push 2          4-5     2
+   2  add             2-3     +
+after:
+   0  push 3          0-5     1 + 2"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_optimize_preserves_result(ast in ast()) {
+            // 符号付き整数に収まらない数値リテラルはコンパイルできない
+            let code = match BytecodeCompiler::new().compile(&ast) {
+                Ok(code) => code,
+                Err(_) => return Ok(()),
+            };
+            let mut optimized = code.clone();
+            optimize(&mut optimized);
+            prop_assert!(optimized.len() <= code.len());
+            prop_assert_eq!(Vm::new().run(&optimized), Vm::new().run(&code), "{}", ast);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//!
+//! 処理結果を表示する前の加工。
+//! 値の書式や単位の付け方を差し替えられるので、REPLを作り直さずに表示だけを変えられる。
+//!
+use std::fmt;
+use std::str::FromStr;
+
+///
+/// 表示する前に結果を受け取り、表示する文字列を返す。
+/// 既定のメソッドは何も加工しない。
+///
+pub trait PostProcess {
+    /// 評価した値を表示する文字列にする
+    fn value(&self, n: i64) -> String {
+        n.to_string()
+    }
+
+    /// 逆ポーランド記法や図など、文字列の結果を加工する
+    fn text(&self, text: &str) -> String {
+        text.to_string()
+    }
+}
+
+/// 関数を値の書式として使う
+impl<F: Fn(i64) -> String> PostProcess for F {
+    fn value(&self, n: i64) -> String {
+        self(n)
+    }
+}
+
+/// 値を表示する書式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ValueFormat {
+    /// 10進数
+    #[default]
+    Decimal,
+    /// 3桁ごとに","で区切った10進数
+    Grouped,
+    /// "0x"を付けた16進数
+    Hex,
+    /// "0o"を付けた8進数
+    Octal,
+    /// "0b"を付けた2進数
+    Binary,
+}
+
+impl FromStr for ValueFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dec" => Ok(ValueFormat::Decimal),
+            "grouped" => Ok(ValueFormat::Grouped),
+            "hex" => Ok(ValueFormat::Hex),
+            "oct" => Ok(ValueFormat::Octal),
+            "bin" => Ok(ValueFormat::Binary),
+            _ => Err(format!(
+                "unknown format '{}' (expected dec, grouped, hex, oct or bin)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ValueFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValueFormat::Decimal => write!(f, "dec"),
+            ValueFormat::Grouped => write!(f, "grouped"),
+            ValueFormat::Hex => write!(f, "hex"),
+            ValueFormat::Octal => write!(f, "oct"),
+            ValueFormat::Binary => write!(f, "bin"),
+        }
+    }
+}
+
+impl PostProcess for ValueFormat {
+    fn value(&self, n: i64) -> String {
+        // 負の数は符号と絶対値に分けて書く（i64::MINも表せるよう符号なしにする）
+        let sign = if n < 0 { "-" } else { "" };
+        let magnitude = n.unsigned_abs();
+        match self {
+            ValueFormat::Decimal => n.to_string(),
+            ValueFormat::Grouped => format!("{}{}", sign, group_digits(&magnitude.to_string())),
+            ValueFormat::Hex => format!("{}{:#x}", sign, magnitude),
+            ValueFormat::Octal => format!("{}{:#o}", sign, magnitude),
+            ValueFormat::Binary => format!("{}{:#b}", sign, magnitude),
+        }
+    }
+}
+
+/// 10進数の数字の列を、3桁ごとに","で区切る
+fn group_digits(digits: &str) -> String {
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+///
+/// 値の後ろに単位を付ける。値の書式はinnerに任せる。
+///
+/// ```
+/// use parser::postprocess::{PostProcess, ValueFormat, WithUnit};
+///
+/// let post = WithUnit::new(ValueFormat::Grouped, "bytes");
+/// assert_eq!(post.value(1048576), "1,048,576 bytes");
+/// ```
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WithUnit<P> {
+    inner: P,
+    unit: String,
+}
+
+impl<P: PostProcess> WithUnit<P> {
+    pub fn new(inner: P, unit: &str) -> Self {
+        WithUnit {
+            inner,
+            unit: unit.to_string(),
+        }
+    }
+}
+
+impl<P: PostProcess> PostProcess for WithUnit<P> {
+    fn value(&self, n: i64) -> String {
+        format!("{} {}", self.inner.value(n), self.unit)
+    }
+
+    fn text(&self, text: &str) -> String {
+        self.inner.text(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_format() {
+        let format = |format: &str, n| format.parse::<ValueFormat>().unwrap().value(n);
+        assert_eq!(format("dec", -42), "-42");
+        assert_eq!(format("grouped", 1234567), "1,234,567");
+        assert_eq!(format("grouped", -123), "-123");
+        assert_eq!(format("grouped", i64::MIN), "-9,223,372,036,854,775,808");
+        assert_eq!(format("hex", 255), "0xff");
+        assert_eq!(format("hex", -255), "-0xff");
+        assert_eq!(format("oct", 8), "0o10");
+        assert_eq!(format("bin", 5), "0b101");
+        assert!("roman".parse::<ValueFormat>().is_err());
+        assert_eq!(ValueFormat::Hex.text("1 2 +"), "1 2 +");
+    }
+
+    #[test]
+    fn test_custom_post_process() {
+        let halves = |n: i64| format!("{}.{}", n / 2, if n % 2 == 0 { 0 } else { 5 });
+        assert_eq!(halves.value(7), "3.5");
+        assert_eq!(WithUnit::new(halves, "m").value(4), "2.0 m");
+    }
+}
@@ -0,0 +1,152 @@
+//!
+//! 前置記法（ポーランド記法）への変換。
+//! 逆ポーランド記法と語の並びが逆になる形で、演算子を被演算子の前に置く。
+//!
+use super::compiler::{Backend, CompileError, DEFAULT_NEGATION};
+use super::interpreter::{function_arity, Arity};
+use super::parser::*;
+
+///
+/// 前置記法へのコンパイラ。
+/// 単項の"-"は"neg"、代入は"=変数名"の語で表し、語は空白で区切る。
+///
+/// ```
+/// use parser::compiler::Backend;
+/// use parser::parser::Ast;
+/// use parser::prefix::PrefixCompiler;
+///
+/// let ast = "x = (1 + 2) * -max(3, 4, 5)".parse::<Ast>().unwrap();
+/// let prefix = PrefixCompiler::new().compile(&ast).unwrap();
+/// assert_eq!(prefix, "=x * + 1 2 neg max max 3 4 5");
+/// ```
+///
+#[derive(Default)]
+pub struct PrefixCompiler;
+
+impl PrefixCompiler {
+    pub fn new() -> Self {
+        PrefixCompiler
+    }
+}
+
+impl Backend for PrefixCompiler {
+    fn name(&self) -> &str {
+        "prefix"
+    }
+
+    fn compile_into(&mut self, expr: &Ast, buf: &mut String) -> Result<(), CompileError> {
+        buf.clear();
+        PrefixEmitter { buf }.emit(expr);
+        Ok(())
+    }
+}
+
+/// 前置記法へ変換するときの作業
+enum Step<'a> {
+    /// 節点を変換する
+    Node(&'a Ast),
+    /// 語の区切りを書き出す
+    Separator,
+}
+
+///
+/// 構文木を辿りながら、前置記法の語をbufへ書き出す。
+/// 長い演算子の連なりでもネイティブのスタックを使い切らないよう、再帰せずに作業のスタックで辿る。
+///
+struct PrefixEmitter<'a> {
+    buf: &'a mut String,
+}
+
+impl PrefixEmitter<'_> {
+    /// 語を書き、後に続く語との区切りを入れる
+    fn word(&mut self, word: &str) {
+        self.buf.push_str(word);
+        self.buf.push(' ');
+    }
+
+    fn emit(&mut self, expr: &Ast) {
+        use super::parser::AstKind::*;
+        let mut steps = vec![Step::Node(expr)];
+        while let Some(step) = steps.pop() {
+            let expr = match step {
+                Step::Node(expr) => expr,
+                Step::Separator => {
+                    self.buf.push(' ');
+                    continue;
+                }
+            };
+            match expr.value {
+                Num(n) => self.buf.push_str(&n.to_string()),
+                Var(ref name) => self.buf.push_str(name),
+                Assign {
+                    ref name,
+                    ref value,
+                } => {
+                    self.buf.push('=');
+                    self.word(name);
+                    steps.push(Step::Node(value));
+                }
+                // 単項の"+"は値を変えないので何も書かない
+                Unary {
+                    ref operator,
+                    ref operand,
+                } => {
+                    if operator.value == UnaryOperatorKind::Minus {
+                        self.word(DEFAULT_NEGATION);
+                    }
+                    steps.push(Step::Node(operand));
+                }
+                Binary {
+                    ref operator,
+                    ref left,
+                    ref right,
+                } => {
+                    self.word(&operator.value.to_string());
+                    steps.extend([Step::Node(right), Step::Separator, Step::Node(left)]);
+                }
+                // 可変個の引数を取る関数は、2引数の呼び出しを重ねる形（"min min a b c"）にする
+                Call { ref name, ref args } => {
+                    let calls = match function_arity(name) {
+                        Some(Arity::AtLeast(_)) => args.len().saturating_sub(1),
+                        _ => 1,
+                    };
+                    for _ in 0..calls {
+                        self.word(name);
+                    }
+                    // 引数のない呼び出しでは、関数名の後の区切りが余る
+                    if args.is_empty() {
+                        self.buf.pop();
+                    }
+                    for (i, arg) in args.iter().enumerate().rev() {
+                        steps.push(Step::Node(arg));
+                        if i > 0 {
+                            steps.push(Step::Separator);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(input: &str) -> String {
+        PrefixCompiler::new()
+            .compile(&input.parse().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_prefix() {
+        assert_eq!(compile("1 + 2 * 3"), "+ 1 * 2 3");
+        assert_eq!(compile("(1 - 2) - 3"), "- - 1 2 3");
+        assert_eq!(compile("2 ^ 3 ^ 2"), "^ 2 ^ 3 2");
+        assert_eq!(compile("- + x"), "neg x");
+        assert_eq!(compile("x = y = |z|"), "=x =y abs z");
+        assert_eq!(compile("pow(2, 10) | min(1)"), "| pow 2 10 1");
+        assert_eq!(compile("f()"), "f");
+    }
+}
@@ -0,0 +1,308 @@
+//!
+//! 評価した値の出どころの追跡。
+//! 部分式の値ごとに、その値を決めた入力中の数や変数の位置を記録し、
+//! 最終的な結果に入力のどの部分がどれだけ影響したかを示す。
+//!
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::interpreter::*;
+use super::lexer::Location;
+use super::parser::*;
+use super::tree::node;
+
+/// 部分式の値と、その値を決めた数や変数の位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracedValue<'a> {
+    pub node: &'a Ast,
+    pub value: i64,
+    /// 値を決めた数や変数の位置（入力の順）
+    pub sources: Vec<Location>,
+}
+
+///
+/// 式を評価し、部分式の値ごとに出どころを記録する。
+/// 結果は値を求めた順（子は親より先）に並び、最後が式全体の値になる。
+/// 基本的には子の出どころをすべて合わせるが、minとmaxは選ばれた引数の出どころだけを引き継ぐ。
+///
+pub fn trace_sources<'a>(
+    interpreter: &mut Interpreter,
+    expr: &'a Ast,
+) -> Result<Vec<TracedValue<'a>>, InterpreterError> {
+    let mut traced: Vec<TracedValue<'_>> = Vec::new();
+    // 求めた値のうち、まだ親に渡していないものの添字
+    let mut pending: Vec<usize> = Vec::new();
+    // eval_withは渡した構文木の節点をそのまま通知するので、同じ寿命の参照として扱える
+    let mut nodes: Vec<(&'a Ast, i64)> = Vec::new();
+    let all = nodes_by_address(expr);
+    interpreter.eval_with(expr, &mut |node, value| {
+        let node = all.get(&(node as *const Ast)).copied().unwrap_or(expr);
+        nodes.push((node, value));
+    })?;
+    for (expr, value) in nodes {
+        let (_, children) = node(expr);
+        let inputs = pending.split_off(pending.len() - children.len());
+        let sources = if children.is_empty() {
+            vec![expr.location.clone()]
+        } else {
+            match expr.value {
+                // 選ばれた引数だけが結果を決める
+                AstKind::Call { ref name, .. } if name == "min" || name == "max" => inputs
+                    .iter()
+                    .find(|&&i| traced[i].value == value)
+                    .map(|&i| traced[i].sources.clone())
+                    .unwrap_or_default(),
+                _ => {
+                    let mut sources: Vec<_> = inputs
+                        .iter()
+                        .flat_map(|&i| traced[i].sources.iter().cloned())
+                        .collect();
+                    sources.sort_by_key(|location| (location.0, location.1));
+                    sources.dedup();
+                    sources
+                }
+            }
+        };
+        pending.push(traced.len());
+        traced.push(TracedValue {
+            node: expr,
+            value,
+            sources,
+        });
+    }
+    Ok(traced)
+}
+
+///
+/// 構文木のすべての節点を、そのアドレスから引けるようにする。
+/// 通知された節点を、構文木の中の同じ節点への参照として探すのに使う。
+///
+fn nodes_by_address(root: &Ast) -> HashMap<*const Ast, &Ast> {
+    let mut all = HashMap::new();
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        all.insert(node as *const Ast, node);
+        stack.extend(node.value.children());
+    }
+    all
+}
+
+/// 入力中の数や変数が結果に与えた影響
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contribution {
+    pub location: Location,
+    /// 数や変数の値
+    pub value: i64,
+    ///
+    /// その値を1増やしたときの結果の変化。結果の出どころでなければ0。
+    /// 1増やすと評価に失敗する場合はNone
+    ///
+    pub influence: Option<i64>,
+    /// 結果の出どころかどうか
+    pub used: bool,
+}
+
+/// 式の結果と、入力の各部分の影響
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub value: i64,
+    /// 式の中のすべての数と変数（入力の順）
+    pub contributions: Vec<Contribution>,
+}
+
+///
+/// 式を評価し、入力中の数や変数のそれぞれが結果にどれだけ影響したかを調べる。
+/// 影響は、その数や変数だけを1増やして評価し直したときの結果の変化で表す。
+/// 評価し直すときは評価前の状態の複製を使うので、代入が二重に行われることはない
+///
+pub fn explain(interpreter: &mut Interpreter, expr: &Ast) -> Result<Explanation, InterpreterError> {
+    let before = interpreter.clone();
+    let traced = trace_sources(interpreter, expr)?;
+    let result = traced.last().unwrap();
+    let contributions = traced
+        .iter()
+        .filter(|traced| node(traced.node).1.is_empty())
+        .map(|leaf| {
+            let used = result.sources.contains(&leaf.node.location);
+            let influence = if used {
+                let perturbed = perturb(expr, &leaf.node.location);
+                before
+                    .clone()
+                    .eval(&perturbed)
+                    .ok()
+                    .and_then(|value| value.checked_sub(result.value))
+            } else {
+                Some(0)
+            };
+            Contribution {
+                location: leaf.node.location.clone(),
+                value: leaf.value,
+                influence,
+                used,
+            }
+        })
+        .collect();
+    Ok(Explanation {
+        value: result.value,
+        contributions,
+    })
+}
+
+///
+/// 位置がtargetの数や変数を、その値に1を足す式に置き換えた構文木を作る。
+/// 長い演算子の連なりでもスタックを使い切らないよう、複製してから再帰せずに置き換える。
+///
+fn perturb(expr: &Ast, target: &Location) -> Ast {
+    use super::parser::AstKind::*;
+    let mut perturbed = expr.clone();
+    let mut stack = vec![&mut perturbed];
+    while let Some(node) = stack.pop() {
+        match node.value {
+            Num(_) | Var(_) if node.location == *target => {
+                let loc = node.location.clone();
+                let leaf = std::mem::replace(node, Ast::num(1, loc.clone()));
+                *node = Ast::binary(
+                    BinaryOperator::add(loc.clone()),
+                    leaf,
+                    Ast::num(1, loc.clone()),
+                    loc,
+                );
+            }
+            Num(_) | Var(_) => {}
+            Assign { ref mut value, .. } => stack.push(value),
+            Unary {
+                ref mut operand, ..
+            } => stack.push(operand),
+            Binary {
+                ref mut left,
+                ref mut right,
+                ..
+            } => stack.extend([&mut **left, &mut **right]),
+            Call { ref mut args, .. } => stack.extend(args.iter_mut()),
+        }
+    }
+    perturbed
+}
+
+///
+/// 式の結果と入力の各部分の影響を表にする。
+///
+/// ```text
+/// 2 * x + max(1, 5) = 31
+/// span   text  value  effect of +1
+/// 0-1    2     2      +13
+/// 4-5    x     13     +2
+/// 12-13  1     1      not used
+/// 15-16  5     5      +1
+/// ```
+///
+pub fn format_explanation(input: &str, explanation: &Explanation, buf: &mut String) {
+    buf.clear();
+    write!(buf, "{} = {}", input.trim(), explanation.value).unwrap();
+    let rows: Vec<[String; 4]> = explanation
+        .contributions
+        .iter()
+        .map(|c| {
+            let text: String = input
+                .chars()
+                .skip(c.location.0)
+                .take(c.location.1 - c.location.0)
+                .collect();
+            let effect = match (c.used, c.influence) {
+                (false, _) => "not used".to_string(),
+                (true, Some(influence)) => format!("{:+}", influence),
+                (true, None) => "error".to_string(),
+            };
+            [c.location.to_string(), text, c.value.to_string(), effect]
+        })
+        .collect();
+    let header = ["span", "text", "value", "effect of +1"].map(String::from);
+    let mut widths = [0; 3];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        buf.push('\n');
+        for (width, cell) in widths.iter().zip(row) {
+            write!(buf, "{:<w$}  ", cell, w = width).unwrap();
+        }
+        buf.push_str(&row[3]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn traced_sources(input: &str) -> Vec<Location> {
+        let ast = input.parse::<Ast>().unwrap();
+        let traced = trace_sources(&mut Interpreter::new(), &ast).unwrap();
+        traced.last().unwrap().sources.clone()
+    }
+
+    #[test]
+    fn test_trace_sources() {
+        assert_eq!(
+            traced_sources("(1 + 2) * 3"),
+            vec![Location(1, 2), Location(5, 6), Location(10, 11)]
+        );
+        // maxとminは選ばれた引数の出どころだけを引き継ぐ
+        assert_eq!(
+            traced_sources("max(1, 2 * 3, 4)"),
+            vec![Location(7, 8), Location(11, 12)]
+        );
+        assert_eq!(traced_sources("min(4, 4)"), vec![Location(4, 5)]);
+        // 部分式ごとに出どころを記録する
+        let ast = "-(1 + 2)".parse::<Ast>().unwrap();
+        let traced = trace_sources(&mut Interpreter::new(), &ast).unwrap();
+        let values: Vec<_> = traced.iter().map(|t| (t.value, t.sources.len())).collect();
+        assert_eq!(values, vec![(1, 1), (2, 1), (3, 2), (-3, 2)]);
+        assert!(std::ptr::eq(traced[3].node, &ast));
+    }
+
+    #[test]
+    fn test_explain() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval(&"x = 13".parse::<Ast>().unwrap()).unwrap();
+        let input = "y = 2 * x + max(1, 5)";
+        let ast = input.parse::<Ast>().unwrap();
+        let explanation = explain(&mut interpreter, &ast).unwrap();
+        assert_eq!(explanation.value, 31);
+        let effects: Vec<_> = explanation
+            .contributions
+            .iter()
+            .map(|c| (c.location.0, c.used, c.influence))
+            .collect();
+        assert_eq!(
+            effects,
+            vec![
+                (4, true, Some(13)),
+                (8, true, Some(2)),
+                (16, false, Some(0)),
+                (19, true, Some(1)),
+            ]
+        );
+        // 代入は1回だけ行われる
+        assert_eq!(interpreter.variable("y"), Some(31));
+
+        let mut buf = String::new();
+        format_explanation(input, &explanation, &mut buf);
+        assert_eq!(
+            buf,
+            "y = 2 * x + max(1, 5) = 31\n\
+             span   text  value  effect of +1\n\
+             4-5    2     2      +13\n\
+             8-9    x     13     +2\n\
+             16-17  1     1      not used\n\
+             19-20  5     5      +1"
+        );
+
+        // 1増やすと評価に失敗する場合
+        let ast = "10 / (1 - 0)".parse::<Ast>().unwrap();
+        let explanation = explain(&mut Interpreter::new(), &ast).unwrap();
+        assert_eq!(explanation.contributions[1].influence, Some(-5));
+        assert_eq!(explanation.contributions[2].influence, None);
+    }
+}
@@ -0,0 +1,237 @@
+//!
+//! 逆ポーランド記法を学ぶための問題の生成と採点。
+//! 無作為に作った式を一方の記法で示し、もう一方の記法か値を答えてもらう。
+//!
+use std::fmt;
+
+use super::compiler::RpnCompiler;
+use super::interpreter::Interpreter;
+use super::lexer::Location;
+use super::parser::*;
+
+///
+/// 問題を作るための擬似乱数（xorshift64*）。
+/// 同じ種から同じ問題の列を作れるので、試験や再現に使える。
+///
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // 0の状態からは0しか出てこないので、種を混ぜてから使う
+        Rng {
+            state: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// 0以上n未満の数を返す
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// 問われる内容
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Task {
+    /// 中置記法の式を逆ポーランド記法に直す
+    ToRpn,
+    /// 逆ポーランド記法の式を中置記法に直す
+    ToInfix,
+    /// 中置記法の式の値を求める
+    Evaluate,
+}
+
+/// 1つの問題
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub task: Task,
+    pub expr: Ast,
+    value: i64,
+    rpn: String,
+}
+
+impl Question {
+    ///
+    /// 問題を無作為に作る。式は1桁の数と四則演算からなり、演算子を少なくとも1つ含む。
+    /// 0除算を含む式は作らない
+    ///
+    pub fn generate(rng: &mut Rng) -> Self {
+        let task = match rng.below(3) {
+            0 => Task::ToRpn,
+            1 => Task::ToInfix,
+            _ => Task::Evaluate,
+        };
+        loop {
+            let depth = 1 + rng.below(3) as usize;
+            let expr = random_expr(rng, depth);
+            if let AstKind::Num(_) = expr.value {
+                continue;
+            }
+            if let Ok(value) = Interpreter::new().eval(&expr) {
+                return Question::new(task, expr, value);
+            }
+        }
+    }
+
+    fn new(task: Task, expr: Ast, value: i64) -> Self {
+        let rpn = RpnCompiler::new().compile(&expr);
+        Question {
+            task,
+            expr,
+            value,
+            rpn,
+        }
+    }
+
+    /// 模範解答を返す
+    pub fn answer(&self) -> String {
+        match self.task {
+            Task::ToRpn => self.rpn.clone(),
+            Task::ToInfix => infix(&self.expr),
+            Task::Evaluate => self.value.to_string(),
+        }
+    }
+
+    ///
+    /// 答えを採点する。空白の入れ方は問わず、中置記法では同じ構造の式であれば
+    /// 余分なかっこがあっても正解とする
+    ///
+    pub fn check(&self, answer: &str) -> bool {
+        match self.task {
+            Task::ToRpn => answer.split_whitespace().eq(self.rpn.split_whitespace()),
+            Task::ToInfix => match answer.parse::<Ast>() {
+                Ok(ast) => RpnCompiler::new().compile(&ast) == self.rpn,
+                Err(_) => false,
+            },
+            Task::Evaluate => answer.trim().parse() == Ok(self.value),
+        }
+    }
+}
+
+impl fmt::Display for Question {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.task {
+            Task::ToRpn => write!(f, "convert to RPN: {}", infix(&self.expr)),
+            Task::ToInfix => write!(f, "convert to infix: {}", self.rpn),
+            Task::Evaluate => write!(f, "evaluate: {}", infix(&self.expr)),
+        }
+    }
+}
+
+/// 深さがdepth以下の式を無作為に作る
+fn random_expr(rng: &mut Rng, depth: usize) -> Ast {
+    let loc = || Location(0, 0);
+    if depth == 0 || rng.below(4) == 0 {
+        return Ast::num(1 + rng.below(9), loc());
+    }
+    let operator = match rng.below(4) {
+        0 => BinaryOperator::add(loc()),
+        1 => BinaryOperator::sub(loc()),
+        2 => BinaryOperator::multi(loc()),
+        _ => BinaryOperator::div(loc()),
+    };
+    let left = random_expr(rng, depth - 1);
+    let right = random_expr(rng, depth - 1);
+    Ast::binary(operator, left, right, loc())
+}
+
+/// 数と二項演算子からなる式を、必要なかっこだけを付けた中置記法で書く
+fn infix(expr: &Ast) -> String {
+    match expr.value {
+        AstKind::Binary {
+            ref operator,
+            ref left,
+            ref right,
+        } => {
+            let def = definition(&operator.value);
+            // 左結合なら、右辺に同じ優先順位の演算子があればかっこが要る
+            let (left_min, right_min) = match def.associativity {
+                Associativity::Left => (def.precedence, def.precedence + 1),
+                Associativity::Right => (def.precedence + 1, def.precedence),
+            };
+            format!(
+                "{} {} {}",
+                operand(left, left_min),
+                operator.value,
+                operand(right, right_min)
+            )
+        }
+        AstKind::Num(n) => n.to_string(),
+        // 問題の式には現れない
+        _ => unreachable!("unexpected node in a quiz expression"),
+    }
+}
+
+/// 優先順位がmin_precedenceより弱い演算子の式はかっこで囲む
+fn operand(expr: &Ast, min_precedence: u8) -> String {
+    match expr.value {
+        AstKind::Binary { ref operator, .. }
+            if definition(&operator.value).precedence < min_precedence =>
+        {
+            format!("({})", infix(expr))
+        }
+        _ => infix(expr),
+    }
+}
+
+fn definition(kind: &BinaryOperatorKind) -> &'static OperatorDef {
+    OPERATORS
+        .iter()
+        .find(|def| def.kind == OperatorKind::Infix(kind.clone()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn question(task: Task, input: &str) -> Question {
+        let expr = input.parse::<Ast>().unwrap();
+        let value = Interpreter::new().eval(&expr).unwrap();
+        Question::new(task, expr, value)
+    }
+
+    #[test]
+    fn test_check() {
+        let q = question(Task::ToRpn, "(1 + 2) * 3");
+        assert_eq!(q.to_string(), "convert to RPN: (1 + 2) * 3");
+        assert!(q.check("1  2 + 3 *"));
+        assert!(!q.check("1 2 3 * +"));
+
+        let q = question(Task::ToInfix, "8 - (4 - 2)");
+        assert_eq!(q.to_string(), "convert to infix: 8 4 2 - -");
+        assert!(q.check("8-(4-2)"));
+        assert!(q.check("(8 - ((4 - 2)))"));
+        assert!(!q.check("8 - 4 - 2"));
+        assert!(!q.check("8 -"));
+        assert_eq!(q.answer(), "8 - (4 - 2)");
+
+        let q = question(Task::Evaluate, "7 / 2 * 2");
+        assert!(q.check(" 6 "));
+        assert!(!q.check("7"));
+    }
+
+    #[test]
+    fn test_generate() {
+        let mut rng = Rng::new(42);
+        for _ in 0..100 {
+            let q = Question::generate(&mut rng);
+            // 模範解答は必ず正解になり、中置記法は読み直すと同じ式になる
+            assert!(q.check(&q.answer()), "{}", q);
+            assert!(matches!(q.expr.value, AstKind::Binary { .. }));
+            let reparsed = infix(&q.expr).parse::<Ast>().unwrap();
+            assert_eq!(RpnCompiler::new().compile(&reparsed), q.rpn);
+        }
+        let first = Question::generate(&mut Rng::new(7));
+        assert_eq!(first, Question::generate(&mut Rng::new(7)));
+    }
+}
@@ -0,0 +1,523 @@
+//!
+//! 式の構造に基づく検索と置換。
+//! "x * 0 => 0"のような規則で、パターンに当てはまる部分式を置き換える。
+//! 置き換えるのは当てはまった部分の文字列だけなので、残りの空白やコメントはそのまま残る。
+//!
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use super::lexer::*;
+use super::minify::{infix_definition, precedence};
+use super::parser::*;
+use super::tree::node;
+
+///
+/// 置換の規則。
+/// パターンの中の変数はプレースホルダで、どのような部分式にも当てはまる。
+/// 同じプレースホルダが2回以上現れる場合は、同じ形の部分式にだけ当てはまる。
+/// 数、演算子、関数の名前、代入する変数の名前は、そのものにだけ当てはまる。
+///
+/// ```
+/// use parser::rewrite::Rule;
+///
+/// let rule: Rule = "x * 1 => x".parse().unwrap();
+/// assert_eq!(rule.rewrite("(a + b) * 1 * 2"), Some(("(a + b) * 2".to_string(), 1)));
+/// ```
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pattern: Ast,
+    replacement: Ast,
+    /// 置換後の式の文字列。プレースホルダの部分を当てはまった文字列で置き換えて使う
+    replacement_text: String,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    /// "パターン => 置換後の式"の形の規則を解析する
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, replacement) = s
+            .split_once("=>")
+            .ok_or_else(|| format!("rule '{}' has no '=>'", s))?;
+        let pattern = pattern
+            .parse::<Ast>()
+            .map_err(|e| format!("invalid pattern: {}", e))?;
+        let replacement_text = replacement.trim().to_string();
+        let replacement = replacement_text
+            .parse::<Ast>()
+            .map_err(|e| format!("invalid replacement: {}", e))?;
+        let mut bound = HashSet::new();
+        placeholders(&pattern, &mut bound);
+        let mut used = HashSet::new();
+        placeholders(&replacement, &mut used);
+        if let Some(name) = used.difference(&bound).next() {
+            return Err(format!(
+                "placeholder '{}' in the replacement does not appear in the pattern",
+                name
+            ));
+        }
+        Ok(Rule {
+            pattern,
+            replacement,
+            replacement_text,
+        })
+    }
+}
+
+impl Rule {
+    ///
+    /// 1つの式の中で、パターンに当てはまる部分式をすべて置き換える。
+    /// 当てはまった部分式の中はそれ以上探さない。
+    /// 書き換えた式と置き換えた数を返す。式として解析できなければNoneを返す。
+    ///
+    pub fn rewrite(&self, input: &str) -> Option<(String, usize)> {
+        let ast = input.parse::<Ast>().ok()?;
+        let tokens = lex_borrowed(input).ok()?;
+        let mut rewriter = Rewriter {
+            rule: self,
+            input,
+            tokens: &tokens,
+            edits: Vec::new(),
+        };
+        rewriter.visit(&ast, Context::STATEMENT);
+        let count = rewriter.edits.len();
+        Some((splice(input, rewriter.edits), count))
+    }
+
+    ///
+    /// 1行に1つの式を書いた文字列を書き換え、置き換えた数とあわせて返す。
+    /// 行末の"\"で次の行へ続く式も1つの式として扱う。
+    /// 空行やコメントだけの行、REPLのコマンド（":"で始まる行）、解析できない式は書き換えない。
+    ///
+    pub fn rewrite_source(&self, source: &str) -> (String, usize) {
+        let mut output = String::with_capacity(source.len());
+        let mut count = 0;
+        let mut pending = String::new();
+        for line in source.split_inclusive('\n') {
+            let body = line.trim_end_matches('\n').trim_end_matches('\r');
+            let ending = &line[body.len()..];
+            if let Some(head) = body.strip_suffix('\\') {
+                // 行の継続を表す"\"は、位置がずれないよう空白に置き換えて解析する
+                pending.push_str(head);
+                pending.push(' ');
+                pending.push_str(ending);
+                continue;
+            }
+            pending.push_str(body);
+            let expr = std::mem::take(&mut pending);
+            let trimmed = expr.trim();
+            let rewritten = if is_blank(trimmed) || trimmed.starts_with(':') {
+                None
+            } else {
+                self.rewrite(&expr)
+            };
+            match rewritten {
+                Some((text, n)) if n > 0 => {
+                    output.push_str(&restore_continuations(&text));
+                    count += n;
+                }
+                _ => output.push_str(&restore_continuations(&expr)),
+            }
+            output.push_str(ending);
+        }
+        // 最後の行が"\"で終わっている場合
+        output.push_str(&restore_continuations(&pending));
+        (output, count)
+    }
+}
+
+/// 部分式を置く場所が求める優先順位
+#[derive(Debug, Clone, Copy)]
+struct Context {
+    /// これより弱い演算子の式はかっこで囲む
+    min_precedence: u8,
+    /// 二項演算子の右辺かどうか。右辺の単項演算子はかっこで囲まなくてよい
+    right_operand: bool,
+}
+
+impl Context {
+    const STATEMENT: Context = Context {
+        min_precedence: 0,
+        right_operand: false,
+    };
+
+    fn new(min_precedence: u8) -> Self {
+        Context {
+            min_precedence,
+            right_operand: false,
+        }
+    }
+
+    fn needs_parens(&self, expr: &Ast) -> bool {
+        let unary = matches!(expr.value, AstKind::Unary { .. });
+        !(self.right_operand && unary) && precedence(expr) < self.min_precedence
+    }
+}
+
+/// 式の子を、それぞれが置かれた場所の求める優先順位とともに返す
+fn children(expr: &Ast) -> Vec<(&Ast, Context)> {
+    match expr.value {
+        AstKind::Num(_) | AstKind::Var(_) => vec![],
+        AstKind::Assign { ref value, .. } => vec![(value, Context::STATEMENT)],
+        AstKind::Unary { ref operand, .. } => vec![(operand, Context::new(precedence(expr)))],
+        AstKind::Binary {
+            ref operator,
+            ref left,
+            ref right,
+        } => {
+            let def = infix_definition(&operator.value);
+            let (left_min, right_min) = match def.associativity {
+                Associativity::Left => (def.precedence, def.precedence + 1),
+                Associativity::Right => (def.precedence + 1, def.precedence),
+            };
+            vec![
+                (left, Context::new(left_min)),
+                (
+                    right,
+                    Context {
+                        min_precedence: right_min,
+                        right_operand: true,
+                    },
+                ),
+            ]
+        }
+        AstKind::Call { ref args, .. } => args.iter().map(|arg| (arg, Context::new(0))).collect(),
+    }
+}
+
+/// 構文木を辿って、パターンに当てはまる部分式の置き換えを集める
+struct Rewriter<'r> {
+    rule: &'r Rule,
+    input: &'r str,
+    tokens: &'r [BorrowedToken<'r>],
+    /// 置き換える範囲と文字列
+    edits: Vec<(Location, String)>,
+}
+
+impl Rewriter<'_> {
+    ///
+    /// 部分式を根から順に調べ、当てはまったものの置き換えを集める。
+    /// 長い演算子の連なりでもスタックを使い切らないよう、再帰せずに作業のスタックで辿る。
+    ///
+    fn visit(&mut self, expr: &Ast, context: Context) {
+        let mut stack = vec![(expr, context)];
+        while let Some((expr, context)) = stack.pop() {
+            let mut bindings = HashMap::new();
+            if !match_pattern(&self.rule.pattern, expr, &mut bindings) {
+                stack.extend(children(expr).into_iter().rev());
+                continue;
+            }
+            let span = self.span(&expr.location);
+            let replacement = self.instantiate(&bindings);
+            // 置き換えた式が置かれた場所より弱ければ、かっこで囲む
+            let root = match self.rule.replacement.value {
+                AstKind::Var(ref name) => bindings[name.as_str()],
+                _ => &self.rule.replacement,
+            };
+            let replacement = if context.needs_parens(root) && !self.parenthesized(&span) {
+                format!("({})", replacement)
+            } else {
+                replacement
+            };
+            self.edits.push((span, replacement));
+        }
+    }
+
+    /// プレースホルダを、当てはまった部分式の元の文字列で置き換えた置換後の式を作る
+    fn instantiate(&self, bindings: &HashMap<&str, &Ast>) -> String {
+        let mut edits = Vec::new();
+        let mut stack = vec![(&self.rule.replacement, Context::STATEMENT)];
+        while let Some((expr, context)) = stack.pop() {
+            if let AstKind::Var(ref name) = expr.value {
+                let bound = bindings[name.as_str()];
+                let text = self.text(&self.span(&bound.location));
+                // 置換後の式の根は、置き換える場所に合わせて後でかっこを付ける
+                let text =
+                    if context.needs_parens(bound) && !std::ptr::eq(expr, &self.rule.replacement) {
+                        format!("({})", text)
+                    } else {
+                        text
+                    };
+                edits.push((expr.location.clone(), text));
+            }
+            stack.extend(children(expr));
+        }
+        splice(&self.rule.replacement_text, edits)
+    }
+
+    ///
+    /// 部分式の文字列の範囲を返す。
+    /// 構文木の位置はかっこを含まないので、"(1 + 2) * 3"の"*"の位置は"1"から始まる。
+    /// 範囲の中で対応の取れないかっこがあれば、対応するかっこまで広げる
+    ///
+    fn span(&self, location: &Location) -> Location {
+        let tokens = self.tokens;
+        let mut first = tokens
+            .iter()
+            .position(|token| token.location.0 >= location.0)
+            .unwrap_or(tokens.len());
+        let mut last = tokens
+            .iter()
+            .rposition(|token| token.location.1 <= location.1)
+            .map_or(0, |i| i + 1);
+        // 範囲の中の閉じかっこのうち、開きかっこが範囲の外にあるものと、その逆を数える
+        let mut depth = 0i32;
+        let mut lowest = 0;
+        for token in &tokens[first..last] {
+            match token.value {
+                TokenKind::LParen => depth += 1,
+                TokenKind::RParen => depth -= 1,
+                _ => {}
+            }
+            lowest = lowest.min(depth);
+        }
+        let mut unopened = -lowest;
+        let mut unclosed = depth - lowest;
+        while unopened > 0 && first > 0 {
+            first -= 1;
+            match tokens[first].value {
+                TokenKind::LParen => unopened -= 1,
+                TokenKind::RParen => unopened += 1,
+                _ => {}
+            }
+        }
+        while unclosed > 0 && last < tokens.len() {
+            match tokens[last].value {
+                TokenKind::LParen => unclosed += 1,
+                TokenKind::RParen => unclosed -= 1,
+                _ => {}
+            }
+            last += 1;
+        }
+        match (
+            tokens.get(first),
+            last.checked_sub(1).and_then(|i| tokens.get(i)),
+        ) {
+            (Some(start), Some(end)) if first < last => Location(start.location.0, end.location.1),
+            _ => location.clone(),
+        }
+    }
+
+    /// 範囲の直前と直後がかっこであれば、すでにかっこで囲まれている
+    fn parenthesized(&self, span: &Location) -> bool {
+        let before = self
+            .tokens
+            .iter()
+            .rev()
+            .find(|token| token.location.1 <= span.0);
+        let after = self.tokens.iter().find(|token| token.location.0 >= span.1);
+        matches!(
+            (before.map(|t| &t.value), after.map(|t| &t.value)),
+            (Some(TokenKind::LParen), Some(TokenKind::RParen))
+        )
+    }
+
+    /// 範囲の文字列を返す
+    fn text(&self, span: &Location) -> String {
+        self.input
+            .chars()
+            .skip(span.0)
+            .take(span.1 - span.0)
+            .collect()
+    }
+}
+
+///
+/// パターンが式に当てはまるかどうか。当てはまればプレースホルダに部分式を割り当てる。
+/// プレースホルダは左にあるものから順に割り当てる。
+///
+fn match_pattern<'a>(
+    pattern: &'a Ast,
+    expr: &'a Ast,
+    bindings: &mut HashMap<&'a str, &'a Ast>,
+) -> bool {
+    use super::parser::AstKind::*;
+    let mut stack = vec![(pattern, expr)];
+    while let Some((pattern, expr)) = stack.pop() {
+        let matched = match (&pattern.value, &expr.value) {
+            (Var(name), _) => {
+                match bindings.get(name.as_str()) {
+                    Some(bound) if !same(bound, expr) => return false,
+                    Some(_) => {}
+                    None => {
+                        bindings.insert(name, expr);
+                    }
+                }
+                continue;
+            }
+            (Num(a), Num(b)) => a == b,
+            (Assign { name: a, .. }, Assign { name: b, .. }) => a == b,
+            (Unary { operator: a, .. }, Unary { operator: b, .. }) => a.value == b.value,
+            (Binary { operator: a, .. }, Binary { operator: b, .. }) => a.value == b.value,
+            (Call { name: a, args: x }, Call { name: b, args: y }) => a == b && x.len() == y.len(),
+            _ => false,
+        };
+        if !matched {
+            return false;
+        }
+        let pairs = pattern
+            .value
+            .children()
+            .into_iter()
+            .zip(expr.value.children());
+        stack.extend(pairs.rev());
+    }
+    true
+}
+
+/// 位置情報を除いて同じ形の式かどうか
+fn same(a: &Ast, b: &Ast) -> bool {
+    let mut stack = vec![(a, b)];
+    while let Some((a, b)) = stack.pop() {
+        let (a_label, a_children) = node(a);
+        let (b_label, b_children) = node(b);
+        if a_label != b_label || a_children.len() != b_children.len() {
+            return false;
+        }
+        stack.extend(a_children.into_iter().zip(b_children));
+    }
+    true
+}
+
+/// 式の中のプレースホルダを集める
+fn placeholders<'a>(expr: &'a Ast, names: &mut HashSet<&'a str>) {
+    let mut stack = vec![expr];
+    while let Some(expr) = stack.pop() {
+        if let AstKind::Var(ref name) = expr.value {
+            names.insert(name);
+        }
+        stack.extend(expr.value.children());
+    }
+}
+
+/// 範囲（文字数）を文字列で置き換える。範囲は重ならないものとする
+fn splice(input: &str, mut edits: Vec<(Location, String)>) -> String {
+    edits.sort_by_key(|(location, _)| location.0);
+    let mut output = String::with_capacity(input.len());
+    let mut edits = edits.into_iter().peekable();
+    let mut skip_until = 0;
+    for (i, c) in input.chars().enumerate() {
+        if let Some((location, _)) = edits.peek() {
+            if location.0 == i {
+                let (location, text) = edits.next().unwrap();
+                output.push_str(&text);
+                skip_until = location.1;
+            }
+        }
+        if i >= skip_until {
+            output.push(c);
+        }
+    }
+    // 入力の末尾での置き換え
+    for (_, text) in edits {
+        output.push_str(&text);
+    }
+    output
+}
+
+/// 空白に置き換えた行の継続の"\"を元に戻す
+fn restore_continuations(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\n' {
+            let cr = output.ends_with('\r');
+            if cr {
+                output.pop();
+            }
+            if output.ends_with(' ') {
+                output.pop();
+                output.push('\\');
+            }
+            if cr {
+                output.push('\r');
+            }
+        }
+        output.push(c);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(rule: &str, input: &str) -> String {
+        let rule: Rule = rule.parse().unwrap();
+        rule.rewrite(input).unwrap().0
+    }
+
+    #[test]
+    fn test_rewrite() {
+        assert_eq!(rewrite("x * 0 => 0", "a + b * 0"), "a + 0");
+        assert_eq!(rewrite("x * 0 => 0", "a + b * 00"), "a + 0");
+        assert_eq!(rewrite("x * 1 => x", "(a + b) * 1"), "a + b");
+        assert_eq!(rewrite("x * 1 => x", "2 * (a + b) * 1"), "2 * (a + b)");
+        // 置き換えた式は、置かれた場所に合わせてかっこで囲む
+        assert_eq!(
+            rewrite("pow(x, y) => x ^ y", "pow(a + 1, 2) * 3"),
+            "(a + 1) ^ 2 * 3"
+        );
+        assert_eq!(rewrite("x - y => x + -y", "2 * (a - b)"), "2 * (a + -b)");
+        assert_eq!(rewrite("x - y => x + -y", "2 * a - b"), "2 * a + -b");
+        assert_eq!(rewrite("x ^ 1 => x", "2 * b ^ 1"), "2 * b");
+        assert_eq!(rewrite("x + 0 => x", "2 * (a + 0)"), "2 * (a)");
+        assert_eq!(rewrite("x + 0 => x", "2 ^ (a * b + 0)"), "2 ^ (a * b)");
+        // 同じプレースホルダは同じ形の部分式にだけ当てはまる
+        assert_eq!(
+            rewrite("x - x => 0", "(a+1) - (a + 1) + (a - b)"),
+            "0 + (a - b)"
+        );
+        // 空白とコメントはそのまま残る
+        assert_eq!(
+            rewrite("max(x, y) => max(y, x)", "y =  max( 1,\tz*2 ) + 1  # swap"),
+            "y =  max(z*2, 1) + 1  # swap"
+        );
+        // 当てはまった部分式の中は探さない
+        assert_eq!(rewrite("x * 1 => x", "(a * 1) * 1"), "a * 1");
+        // マルチバイト文字を含む行
+        assert_eq!(
+            rewrite("x * 1 => x", "a * 1 // あ\n+ b * 1"),
+            "a // あ\n+ b"
+        );
+        let rule: Rule = "x * 0 => 0".parse().unwrap();
+        assert_eq!(rule.rewrite("1 +"), None);
+        assert_eq!(rule.rewrite("1 + 2"), Some(("1 + 2".to_string(), 0)));
+    }
+
+    #[test]
+    fn test_rewrite_source() {
+        let rule: Rule = "x * 1 => x".parse().unwrap();
+        let source = "a * 1\r\n\
+                      # b * 1\n\
+                      :mode eval\n\
+                      (c + 1) * \\\n  1 + d * 1\n\
+                      e * \\\n  2\n\
+                      1 +\n\
+                      f * 1";
+        assert_eq!(
+            rule.rewrite_source(source),
+            (
+                "a\r\n\
+                 # b * 1\n\
+                 :mode eval\n\
+                 c + 1 + d\n\
+                 e * \\\n  2\n\
+                 1 +\n\
+                 f"
+                .to_string(),
+                4
+            )
+        );
+    }
+
+    #[test]
+    fn test_invalid_rule() {
+        assert!("x * 0".parse::<Rule>().is_err());
+        assert!("x * => 0".parse::<Rule>().is_err());
+        assert_eq!(
+            "x * 0 => y".parse::<Rule>(),
+            Err("placeholder 'y' in the replacement does not appear in the pattern".to_string())
+        );
+    }
+}
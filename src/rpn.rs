@@ -0,0 +1,657 @@
+//!
+//! 逆ポーランド記法の文字列を実行するスタックマシンと、抽象構文木へ戻す読み取り器。
+//! RpnCompilerの出力を評価器と同じ規則で計算し、変換が正しいことを確かめるのに使う。
+//!
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use super::compiler::RpnOptions;
+use super::interpreter::*;
+use super::lexer::*;
+use super::parser::*;
+
+/// 逆ポーランド記法の実行エラーの種類
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RpnErrorKind {
+    /// 語に必要な数の値がスタックにない
+    StackUnderflow,
+    /// 解釈できない語
+    UnknownWord(String),
+    /// 最後にスタックに残った値が1つではない（残った値の数）
+    UnbalancedStack(usize),
+    /// 計算のエラー
+    Eval(InterpreterErrorKind),
+}
+
+pub type RpnError = Annotation<RpnErrorKind>;
+
+impl fmt::Display for RpnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RpnErrorKind::*;
+        match &self.value {
+            StackUnderflow => write!(f, "{}: not enough values on the stack", self.location),
+            UnknownWord(word) => write!(f, "{}: unknown word '{}'", self.location, word),
+            UnbalancedStack(n) => write!(f, "{}: {} values left on the stack", self.location, n),
+            Eval(kind) => write!(
+                f,
+                "{}: {}",
+                self.location,
+                InterpreterError::new(kind.clone(), self.location.clone())
+            ),
+        }
+    }
+}
+
+impl Error for RpnError {}
+
+/// 逆ポーランド記法を抽象構文木へ戻すときのエラーの種類
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RpnParseErrorKind {
+    /// 演算子や関数に必要な数の被演算子がない
+    StackUnderflow,
+    /// 式が1つにまとまらず、被演算子が余った（余った数）
+    TrailingOperands(usize),
+    /// 語が1つもない
+    EmptyInput,
+    /// 64ビットに収まらない数
+    InvalidNumber(String),
+    /// 解釈できない語
+    UnknownWord(String),
+    /// 構文木が深すぎる
+    TooDeep,
+}
+
+pub type RpnParseError = Annotation<RpnParseErrorKind>;
+
+impl fmt::Display for RpnParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RpnParseErrorKind::*;
+        match &self.value {
+            StackUnderflow => write!(f, "{}: not enough operands", self.location),
+            TrailingOperands(n) => write!(
+                f,
+                "{}: {} operand{} not used by any operator",
+                self.location,
+                n,
+                if *n == 1 { "" } else { "s" }
+            ),
+            EmptyInput => write!(f, "{}: no expression", self.location),
+            InvalidNumber(word) => write!(f, "{}: number too large '{}'", self.location, word),
+            UnknownWord(word) => write!(f, "{}: unknown word '{}'", self.location, word),
+            TooDeep => write!(f, "{}: expression is nested too deeply", self.location),
+        }
+    }
+}
+
+impl Error for RpnParseError {}
+
+///
+/// 既定の書式の逆ポーランド記法を抽象構文木へ戻す（RpnCompilerの逆変換）。
+/// 各節点の位置は、その部分式を表す語の並びの位置にする。
+///
+/// ```
+/// use parser::rpn;
+///
+/// let ast = rpn::parse("1 2 3 * + 10 neg -").unwrap();
+/// assert_eq!(ast.to_string(), "1 + 2 * 3 - -10");
+/// ```
+///
+pub fn parse(input: &str) -> Result<Ast, RpnParseError> {
+    parse_with_options(input, &RpnOptions::default())
+}
+
+///
+/// 書式を指定して、逆ポーランド記法を抽象構文木へ戻す。
+/// 単項の"+"は出力に現れないので戻らない。
+/// 可変個の引数を取る関数は2引数の呼び出しの繰り返しから1つの呼び出しにまとめる（"1 2 max 3 max"は"max(1, 2, 3)"）。
+///
+pub fn parse_with_options(input: &str, options: &RpnOptions) -> Result<Ast, RpnParseError> {
+    parse_with_max_depth(input, options, DEFAULT_MAX_DEPTH)
+}
+
+///
+/// 入れ子の深さの上限を指定して、parse_with_optionsと同じく抽象構文木へ戻す。
+/// 中置記法の構文解析器と同じく、単項演算子、右結合の演算子の連なり、関数の呼び出し、代入の入れ子が上限を超えたらTooDeepを返す。
+///
+pub fn parse_with_max_depth(
+    input: &str,
+    options: &RpnOptions,
+    max_depth: usize,
+) -> Result<Ast, RpnParseError> {
+    // 節点と、その部分木の入れ子の深さ
+    let mut stack = Vec::new();
+    for (start, word) in words(input, &options.separator) {
+        let start = input[..start].chars().count();
+        let loc = Location(start, start + word.chars().count());
+        let (node, height) = parse_word(word, &loc, options, &mut stack)
+            .map_err(|kind| RpnParseError::new(kind, loc.clone()))?;
+        if height > max_depth {
+            return Err(RpnParseError::new(RpnParseErrorKind::TooDeep, loc));
+        }
+        stack.push((node, height));
+    }
+    match stack.len() {
+        1 => Ok(stack.pop().unwrap().0),
+        0 => Err(RpnParseError::new(
+            RpnParseErrorKind::EmptyInput,
+            Location(0, input.chars().count()),
+        )),
+        // 余った被演算子の位置を示す
+        n => Err(RpnParseError::new(
+            RpnParseErrorKind::TrailingOperands(n - 1),
+            stack[1].0.location.merge(&stack[n - 1].0.location),
+        )),
+    }
+}
+
+/// 語を1つ読み、スタックから被演算子を取り出して節点を作る。節点とその部分木の入れ子の深さを返す
+fn parse_word(
+    word: &str,
+    loc: &Location,
+    options: &RpnOptions,
+    stack: &mut Vec<(Ast, usize)>,
+) -> Result<(Ast, usize), RpnParseErrorKind> {
+    let node = if word == options.negation {
+        let (operand, height) = pop_nodes(stack, 1)?.remove(0);
+        let location = operand.location.merge(loc);
+        (
+            Ast::unary(UnaryOperator::minus(loc.clone()), operand, location),
+            height + 1,
+        )
+    } else if let Some(operator) = binary_word(word, options) {
+        let mut args = pop_nodes(stack, 2)?;
+        let (right, right_height) = args.pop().unwrap();
+        let (left, left_height) = args.pop().unwrap();
+        let location = left.location.merge(loc);
+        // 左結合の演算子の連なりは、中置記法の構文解析器と同じく入れ子として数えない
+        let height = if operator == BinaryOperatorKind::Pow {
+            left_height.max(right_height) + 1
+        } else {
+            left_height.max(right_height + 1)
+        };
+        let node = Ast::binary(
+            BinaryOperator::new(operator, loc.clone()),
+            left,
+            right,
+            location,
+        );
+        (node, height)
+    } else if let Some(name) = word.strip_prefix('=').filter(|name| is_ident(name)) {
+        let (value, height) = pop_nodes(stack, 1)?.remove(0);
+        let location = value.location.merge(loc);
+        (Ast::assign(name, value, location), height + 1)
+    } else if let Some(arity) = function_arity(word) {
+        let mut args = match arity {
+            Arity::Exactly(n) => pop_nodes(stack, n)?,
+            Arity::AtLeast(_) => pop_nodes(stack, 2)?,
+        };
+        let location = args[0].0.location.merge(loc);
+        // 可変個の引数を取る関数で、同じ関数の呼び出しが第1引数なら、その引数の後に続ける
+        let nested = matches!(
+            args[0].0.value,
+            AstKind::Call { ref name, .. } if name == word
+        );
+        if nested && matches!(arity, Arity::AtLeast(_)) {
+            let (last, last_height) = args.pop().unwrap();
+            let (mut call, height) = args.pop().unwrap();
+            if let AstKind::Call { ref mut args, .. } = call.value {
+                args.push(last);
+            }
+            call.location = location;
+            return Ok((call, height.max(last_height + 1)));
+        }
+        let height = args.iter().map(|&(_, height)| height).max().unwrap_or(0);
+        let args = args.into_iter().map(|(arg, _)| arg).collect();
+        (Ast::call(word, args, location), height + 1)
+    } else if word.bytes().all(|b| b.is_ascii_digit()) {
+        let n = word
+            .parse()
+            .map_err(|_| RpnParseErrorKind::InvalidNumber(word.to_string()))?;
+        (Ast::num(n, loc.clone()), 1)
+    } else if is_ident(word) {
+        (Ast::var(word, loc.clone()), 1)
+    } else {
+        return Err(RpnParseErrorKind::UnknownWord(word.to_string()));
+    };
+    Ok(node)
+}
+
+/// スタックからn個の節点を取り出し、積んだ順に返す
+fn pop_nodes<T>(stack: &mut Vec<T>, n: usize) -> Result<Vec<T>, RpnParseErrorKind> {
+    let start = stack
+        .len()
+        .checked_sub(n)
+        .ok_or(RpnParseErrorKind::StackUnderflow)?;
+    Ok(stack.split_off(start))
+}
+
+///
+/// 逆ポーランド記法の評価器。
+/// 語は空白（または書式で指定した区切り）で区切り、単項の"-"は独立した語（既定では"neg"）で表す。
+/// 変数の値を保持するので、行をまたいで状態が引き継がれる。
+///
+#[derive(Debug, Clone)]
+pub struct RpnEvaluator {
+    options: RpnOptions,
+    stack: Vec<i64>,
+    env: HashMap<String, i64>,
+}
+
+impl Default for RpnEvaluator {
+    fn default() -> Self {
+        RpnEvaluator::new()
+    }
+}
+
+impl RpnEvaluator {
+    pub fn new() -> Self {
+        Self::with_options(RpnOptions::default())
+    }
+
+    /// RpnCompilerと同じ出力の書式を指定して評価器を作成する
+    pub fn with_options(options: RpnOptions) -> Self {
+        RpnEvaluator {
+            options,
+            stack: Vec::new(),
+            env: HashMap::new(),
+        }
+    }
+
+    /// 出力の書式を変更する。変数の値はそのまま引き継ぐ
+    pub fn set_options(&mut self, options: RpnOptions) {
+        self.options = options;
+    }
+
+    /// 変数の現在の値を返す
+    pub fn variable(&self, name: &str) -> Option<i64> {
+        self.env.get(name).cloned()
+    }
+
+    /// すべての変数とその値を返す
+    pub fn variables(&self) -> &HashMap<String, i64> {
+        &self.env
+    }
+
+    /// 変数をすべて置き換える
+    pub fn set_variables(&mut self, variables: HashMap<String, i64>) {
+        self.env = variables;
+    }
+
+    /// 逆ポーランド記法の文字列を実行し、最後にスタックに残った値を返す
+    pub fn eval(&mut self, input: &str) -> Result<i64, RpnError> {
+        self.stack.clear();
+        let separator = self.options.separator.clone();
+        for (start, word) in words(input, &separator) {
+            let start = input[..start].chars().count();
+            let loc = Location(start, start + word.chars().count());
+            self.eval_word(word)
+                .map_err(|kind| RpnError::new(kind, loc))?;
+        }
+        match self.stack.len() {
+            1 => Ok(self.stack[0]),
+            n => Err(RpnError::new(
+                RpnErrorKind::UnbalancedStack(n),
+                Location(0, input.chars().count()),
+            )),
+        }
+    }
+
+    fn eval_word(&mut self, word: &str) -> Result<(), RpnErrorKind> {
+        let eval_error = RpnErrorKind::Eval;
+        let value = if word == self.options.negation {
+            let operand = self.pop(1)?[0];
+            apply_uniop(&super::parser::UnaryOperatorKind::Minus, operand).map_err(eval_error)?
+        } else if let Some(operator) = binary_word(word, &self.options) {
+            let args = self.pop(2)?;
+            apply_binop(&operator, args[0], args[1]).map_err(eval_error)?
+        } else if let Some(name) = word.strip_prefix('=').filter(|name| is_ident(name)) {
+            let value = self.pop(1)?[0];
+            self.env.insert(name.to_string(), value);
+            value
+        } else if let Some(arity) = function_arity(word) {
+            // 可変個の引数を取る関数は、2引数の呼び出しを繰り返す形で出力される
+            let argc = match arity {
+                Arity::Exactly(n) => n,
+                Arity::AtLeast(_) => 2,
+            };
+            let args = self.pop(argc)?;
+            apply_function(word, &args).map_err(eval_error)?
+        } else if word.bytes().all(|b| b.is_ascii_digit()) {
+            let n = word
+                .parse()
+                .map_err(|_| eval_error(InterpreterErrorKind::Overflow))?;
+            literal(n).map_err(eval_error)?
+        } else if is_ident(word) {
+            self.variable(word).ok_or_else(|| {
+                eval_error(InterpreterErrorKind::UndefinedVariable(word.to_string()))
+            })?
+        } else {
+            return Err(RpnErrorKind::UnknownWord(word.to_string()));
+        };
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// スタックからn個の値を取り出し、積んだ順に返す
+    fn pop(&mut self, n: usize) -> Result<Vec<i64>, RpnErrorKind> {
+        let start = self
+            .stack
+            .len()
+            .checked_sub(n)
+            .ok_or(RpnErrorKind::StackUnderflow)?;
+        Ok(self.stack.split_off(start))
+    }
+}
+
+/// 二項演算子の語を読む。除算は書式で指定した語だけを受け付ける
+fn binary_word(word: &str, options: &RpnOptions) -> Option<BinaryOperatorKind> {
+    if word == options.division {
+        return Some(BinaryOperatorKind::Div);
+    }
+    binary_operator(word).filter(|operator| *operator != BinaryOperatorKind::Div)
+}
+
+fn binary_operator(word: &str) -> Option<BinaryOperatorKind> {
+    use super::parser::BinaryOperatorKind::*;
+    match word {
+        "+" => Some(Add),
+        "-" => Some(Sub),
+        "*" => Some(Multi),
+        "/" => Some(Div),
+        "^" => Some(Pow),
+        "|" => Some(BitOr),
+        _ => None,
+    }
+}
+
+fn is_ident(word: &str) -> bool {
+    let mut bytes = word.bytes();
+    match bytes.next() {
+        Some(b) if b.is_ascii_alphabetic() || b == b'_' => {
+            bytes.all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        }
+        _ => false,
+    }
+}
+
+/// 区切りと空白で区切った語と、その開始位置を返す
+fn words<'a>(input: &'a str, separator: &'a str) -> impl Iterator<Item = (usize, &'a str)> {
+    // 空白だけの区切りは、改行やタブを含めたすべての空白として扱う
+    let separator = if separator.trim().is_empty() {
+        " "
+    } else {
+        separator
+    };
+    input
+        .split(separator)
+        .flat_map(str::split_whitespace)
+        .map(move |word| (word.as_ptr() as usize - input.as_ptr() as usize, word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::RpnCompiler;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_rpn_eval() {
+        let mut rpn = RpnEvaluator::new();
+        assert_eq!(rpn.eval("1 2 3 * +"), Ok(7));
+        assert_eq!(rpn.eval("1 10 neg -"), Ok(11));
+        assert_eq!(rpn.eval("2 10 pow =x"), Ok(1024));
+        assert_eq!(rpn.eval("x 3 max 5 min"), Ok(5));
+        assert_eq!(
+            rpn.eval("1 +"),
+            Err(RpnError::new(RpnErrorKind::StackUnderflow, Location(2, 3)))
+        );
+        assert_eq!(
+            rpn.eval("1 2"),
+            Err(RpnError::new(
+                RpnErrorKind::UnbalancedStack(2),
+                Location(0, 3)
+            ))
+        );
+        assert_eq!(
+            rpn.eval("1 0 /"),
+            Err(RpnError::new(
+                RpnErrorKind::Eval(InterpreterErrorKind::DivisionByZero),
+                Location(4, 5)
+            ))
+        );
+        assert_eq!(
+            rpn.eval("+1"),
+            Err(RpnError::new(
+                RpnErrorKind::UnknownWord("+1".to_string()),
+                Location(0, 2)
+            ))
+        );
+        assert_eq!(
+            rpn.eval("1 ?"),
+            Err(RpnError::new(
+                RpnErrorKind::UnknownWord("?".to_string()),
+                Location(2, 3)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        let options = RpnOptions {
+            separator: ",".to_string(),
+            negation: "chs".to_string(),
+            division: "div".to_string(),
+            newline: true,
+        };
+        let mut rpn = RpnEvaluator::with_options(options);
+        assert_eq!(rpn.eval("1,2,max,3,max,chs,4,div,=x\n"), Ok(0));
+        assert_eq!(rpn.eval("7, 2 div"), Ok(3));
+        assert_eq!(
+            rpn.eval("7,2,/"),
+            Err(RpnError::new(
+                RpnErrorKind::UnknownWord("/".to_string()),
+                Location(4, 5)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let ast = parse("1 2 3 * + 10 neg -").unwrap();
+        assert_eq!(ast.to_string(), "1 + 2 * 3 - -10");
+        assert_eq!(ast.location, Location(0, 18));
+        match ast.value {
+            AstKind::Binary {
+                ref operator,
+                ref left,
+                ..
+            } => {
+                assert_eq!(operator.location, Location(17, 18));
+                assert_eq!(left.location, Location(0, 9));
+            }
+            ref other => panic!("{:?}", other),
+        }
+        assert_eq!(parse("2 10 pow =x").unwrap().to_string(), "x = pow(2, 10)");
+        // 2引数の呼び出しの繰り返しは1つの呼び出しにまとめる
+        assert_eq!(
+            parse("x 1 max 2 max y abs min").unwrap().to_string(),
+            "min(max(x, 1, 2), |y|)"
+        );
+        let options = RpnOptions {
+            separator: ",".to_string(),
+            negation: "chs".to_string(),
+            division: "div".to_string(),
+            newline: true,
+        };
+        assert_eq!(
+            parse_with_options("7,2,div,chs\n", &options)
+                .unwrap()
+                .to_string(),
+            "-(7 / 2)"
+        );
+
+        assert_eq!(
+            parse("1 +"),
+            Err(RpnParseError::new(
+                RpnParseErrorKind::StackUnderflow,
+                Location(2, 3)
+            ))
+        );
+        assert_eq!(
+            parse("1 2 3 +"),
+            Err(RpnParseError::new(
+                RpnParseErrorKind::TrailingOperands(1),
+                Location(2, 7)
+            ))
+        );
+        assert_eq!(
+            parse("  "),
+            Err(RpnParseError::new(
+                RpnParseErrorKind::EmptyInput,
+                Location(0, 2)
+            ))
+        );
+        assert_eq!(
+            parse("18446744073709551616"),
+            Err(RpnParseError::new(
+                RpnParseErrorKind::InvalidNumber("18446744073709551616".to_string()),
+                Location(0, 20)
+            ))
+        );
+        assert_eq!(
+            parse_with_options("1 2 /", &options),
+            Err(RpnParseError::new(
+                RpnParseErrorKind::UnknownWord("/".to_string()),
+                Location(4, 5)
+            ))
+        );
+
+        // 入れ子の深さは中置記法の構文解析器と同じく数える
+        let options = RpnOptions::default();
+        assert!(parse_with_max_depth("1 1 + 1 + 1 +", &options, 2).is_ok());
+        assert_eq!(
+            parse_with_max_depth("1 1 1 + +", &options, 2),
+            Err(RpnParseError::new(
+                RpnParseErrorKind::TooDeep,
+                Location(8, 9)
+            ))
+        );
+        assert!(parse_with_max_depth("2 2 2 ^ ^", &options, 2).is_err());
+        assert!(parse_with_max_depth("1 neg neg =x", &options, 4).is_ok());
+        assert!(parse_with_max_depth("1 neg neg =x", &options, 3).is_err());
+        // まとめた呼び出しは引数が増えても深くならない
+        assert!(parse_with_max_depth("1 2 max 3 max 4 max", &options, 2).is_ok());
+        assert!(parse_with_max_depth("1 2 + 3 max", &options, 2).is_err());
+        // 長い和は上限にかからず、右に入れ子になる式はスタックを使い切らずにエラーになる
+        let n = 100_000;
+        assert!(parse(&format!("1{}", " 1 +".repeat(n))).is_ok());
+        assert!(matches!(
+            parse(&format!("{}1{}", "1 ".repeat(n), " +".repeat(n))),
+            Err(RpnParseError {
+                value: RpnParseErrorKind::TooDeep,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut interpreter = Interpreter::new();
+        let mut rpn = RpnEvaluator::new();
+        let mut compiler = RpnCompiler::new();
+        for line in &[
+            "1 - -10",
+            "x = -2 ^ 2",
+            "- -x * +3",
+            "+(1 + 2) * -(3 - 4)",
+            "max(1, -x, 3) + |x - 10| | 1",
+            "2 ^ -1",
+            "1 - +2 - -3",
+            "+-+x",
+            "x / (5 - 5)",
+        ] {
+            let ast = line.parse::<Ast>().unwrap();
+            let expected = interpreter.eval(&ast).map_err(|e| e.value);
+            let actual = rpn
+                .eval(&compiler.compile(&ast))
+                .map_err(|e| match e.value {
+                    RpnErrorKind::Eval(kind) => kind,
+                    e => panic!("{}: {:?}", line, e),
+                });
+            assert_eq!(actual, expected, "{}", line);
+        }
+    }
+
+    /// 抽象構文木。構文解析器が作らない形（入れ子の代入など）も含める
+    fn ast() -> impl Strategy<Value = Ast> {
+        let loc = || Location(0, 0);
+        let leaf = prop_oneof![
+            4 => (0u64..1000).prop_map(move |n| Ast::num(n, loc())),
+            1 => any::<u64>().prop_map(move |n| Ast::num(n, loc())),
+            2 => prop::sample::select(vec!["x", "y"]).prop_map(move |name| Ast::var(name, loc())),
+        ];
+        leaf.prop_recursive(5, 48, 4, move |inner| {
+            let unary = prop::sample::select(vec![
+                UnaryOperator::plus(loc()),
+                UnaryOperator::minus(loc()),
+            ]);
+            let binary = prop::sample::select(vec![
+                BinaryOperator::add(loc()),
+                BinaryOperator::sub(loc()),
+                BinaryOperator::multi(loc()),
+                BinaryOperator::div(loc()),
+                BinaryOperator::pow(loc()),
+                BinaryOperator::bit_or(loc()),
+            ]);
+            prop_oneof![
+                (binary, inner.clone(), inner.clone()).prop_map(move |(op, l, r)| Ast::binary(
+                    op,
+                    l,
+                    r,
+                    loc()
+                )),
+                (unary, inner.clone()).prop_map(move |(op, e)| Ast::unary(op, e, loc())),
+                inner.clone().prop_map(move |e| Ast::assign("x", e, loc())),
+                (prop::sample::select(vec!["sqrt", "abs"]), inner.clone())
+                    .prop_map(move |(name, e)| Ast::call(name, vec![e], loc())),
+                (inner.clone(), inner.clone()).prop_map(move |(a, b)| Ast::call(
+                    "pow",
+                    vec![a, b],
+                    loc()
+                )),
+                (
+                    prop::sample::select(vec!["min", "max"]),
+                    prop::collection::vec(inner, 1..4)
+                )
+                    .prop_map(move |(name, args)| Ast::call(name, args, loc())),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_rpn_matches_interpreter(ast in ast()) {
+            let mut interpreter = Interpreter::new();
+            let mut rpn = RpnEvaluator::new();
+            interpreter.eval(&"x = 7".parse::<Ast>().unwrap()).unwrap();
+            rpn.eval("7 =x").unwrap();
+
+            let code = RpnCompiler::new().compile(&ast);
+            let expected = interpreter.eval(&ast).map_err(|e| e.value);
+            let actual = rpn.eval(&code).map_err(|e| match e.value {
+                RpnErrorKind::Eval(kind) => kind,
+                e => panic!("{}: {:?}", code, e),
+            });
+            prop_assert_eq!(actual, expected, "{}", code);
+        }
+
+        #[test]
+        fn prop_parse_inverts_compile(ast in ast()) {
+            let mut compiler = RpnCompiler::new();
+            let code = compiler.compile(&ast);
+            let back = parse(&code).unwrap();
+            prop_assert_eq!(compiler.compile(&back), code);
+        }
+    }
+}
@@ -0,0 +1,541 @@
+//!
+//! S式による抽象構文木の読み書き。
+//! "(sub (add 1 (mul 2 3)) (neg 10))"のように、節点の種類を先頭に置いたかっこで木をそのまま表す。
+//! かっこの省略や優先順位がないので、試験やほかのツールとのやり取りに使う。
+//!
+use std::error::Error;
+use std::fmt;
+
+use super::compiler::{Backend, CompileError};
+use super::lexer::*;
+use super::parser::*;
+
+/// S式の読み込みエラーの種類
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SexprErrorKind {
+    /// 数、名前、かっこのいずれでもない文字
+    UnexpectedChar(char),
+    /// 64ビットに収まらない数
+    InvalidNumber(String),
+    /// 式の途中で入力が終わった
+    UnexpectedEnd,
+    /// 式を期待した位置の閉じかっこ
+    UnexpectedRParen,
+    /// 閉じられていないかっこ
+    UnclosedParen,
+    /// 知らない節点の種類
+    UnknownForm(String),
+    /// 節点の種類に合わない個数の要素（種類、期待した個数、実際の個数）
+    WrongArity(String, usize, usize),
+    /// 変数名や関数名を期待したが、それ以外のものが現れた
+    NotName,
+    /// 式の後に余計な入力がある
+    TrailingInput,
+}
+
+pub type SexprError = Annotation<SexprErrorKind>;
+
+impl fmt::Display for SexprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SexprErrorKind::*;
+        match &self.value {
+            UnexpectedChar(c) => write!(f, "{}: unexpected character '{}'", self.location, c),
+            InvalidNumber(n) => write!(f, "{}: '{}' does not fit in 64 bits", self.location, n),
+            UnexpectedEnd => write!(f, "{}: unexpected end of input", self.location),
+            UnexpectedRParen => write!(f, "{}: ')' is not expected", self.location),
+            UnclosedParen => write!(f, "{}: '(' is not closed", self.location),
+            UnknownForm(form) => write!(f, "{}: unknown form '{}'", self.location, form),
+            WrongArity(form, expected, found) => write!(
+                f,
+                "{}: '{}' takes {} operands but {} were given",
+                self.location, form, expected, found
+            ),
+            NotName => write!(f, "{}: a name is expected", self.location),
+            TrailingInput => write!(f, "{}: input after the expression", self.location),
+        }
+    }
+}
+
+impl Error for SexprError {}
+
+/// 二項演算子の節点の名前
+fn binary_name(kind: &BinaryOperatorKind) -> &'static str {
+    use super::parser::BinaryOperatorKind::*;
+    match kind {
+        Add => "add",
+        Sub => "sub",
+        Multi => "mul",
+        Div => "div",
+        Pow => "pow",
+        BitOr => "bitor",
+    }
+}
+
+/// 単項演算子の節点の名前
+fn unary_name(kind: &UnaryOperatorKind) -> &'static str {
+    match kind {
+        UnaryOperatorKind::Plus => "pos",
+        UnaryOperatorKind::Minus => "neg",
+    }
+}
+
+///
+/// S式へのコンパイラ。
+/// 代入は"(assign x 1)"、関数呼び出しは演算子と区別するため"(call max 1 2)"と書く。
+///
+/// ```
+/// use parser::compiler::Backend;
+/// use parser::parser::Ast;
+/// use parser::sexpr::SexprCompiler;
+///
+/// let ast = "1 + 2 * 3 - -10".parse::<Ast>().unwrap();
+/// let sexpr = SexprCompiler::new().compile(&ast).unwrap();
+/// assert_eq!(sexpr, "(sub (add 1 (mul 2 3)) (neg 10))");
+/// ```
+///
+#[derive(Default)]
+pub struct SexprCompiler;
+
+impl SexprCompiler {
+    pub fn new() -> Self {
+        SexprCompiler
+    }
+}
+
+impl Backend for SexprCompiler {
+    fn name(&self) -> &str {
+        "sexpr"
+    }
+
+    fn compile_into(&mut self, expr: &Ast, buf: &mut String) -> Result<(), CompileError> {
+        buf.clear();
+        write_sexpr(expr, buf);
+        Ok(())
+    }
+}
+
+fn write_sexpr(expr: &Ast, buf: &mut String) {
+    use super::parser::AstKind::*;
+    // 長い演算子の連なりでもスタックを使い切らないよう、書き出す順の逆に作業を積む
+    let mut steps = vec![Step::Node(expr)];
+    while let Some(step) = steps.pop() {
+        let expr = match step {
+            Step::Node(expr) => expr,
+            Step::Text(text) => {
+                buf.push_str(text);
+                continue;
+            }
+        };
+        match expr.value {
+            Num(n) => buf.push_str(&n.to_string()),
+            Var(ref name) => buf.push_str(name),
+            Assign {
+                ref name,
+                ref value,
+            } => {
+                buf.push_str("(assign ");
+                buf.push_str(name);
+                buf.push(' ');
+                steps.extend([Step::Text(")"), Step::Node(value)]);
+            }
+            Unary {
+                ref operator,
+                ref operand,
+            } => {
+                buf.push('(');
+                buf.push_str(unary_name(&operator.value));
+                buf.push(' ');
+                steps.extend([Step::Text(")"), Step::Node(operand)]);
+            }
+            Binary {
+                ref operator,
+                ref left,
+                ref right,
+            } => {
+                buf.push('(');
+                buf.push_str(binary_name(&operator.value));
+                buf.push(' ');
+                steps.extend([
+                    Step::Text(")"),
+                    Step::Node(right),
+                    Step::Text(" "),
+                    Step::Node(left),
+                ]);
+            }
+            Call { ref name, ref args } => {
+                buf.push_str("(call ");
+                buf.push_str(name);
+                steps.push(Step::Text(")"));
+                for arg in args.iter().rev() {
+                    steps.extend([Step::Node(arg), Step::Text(" ")]);
+                }
+            }
+        }
+    }
+}
+
+/// S式へ変換するときの作業
+enum Step<'a> {
+    /// 節点を変換する
+    Node(&'a Ast),
+    /// 文字列をそのまま書き出す
+    Text(&'static str),
+}
+
+/// S式の字句
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Item {
+    LParen,
+    RParen,
+    /// 数や名前
+    Atom(String),
+}
+
+/// S式を字句に分ける。位置は文字単位で数える
+fn items(input: &str) -> Result<Vec<Annotation<Item>>, SexprError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut items = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        match c {
+            '(' => items.push(Annotation::new(Item::LParen, Location(pos, pos + 1))),
+            ')' => items.push(Annotation::new(Item::RParen, Location(pos, pos + 1))),
+            c if c.is_whitespace() => {}
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let start = pos;
+                while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '_')
+                {
+                    pos += 1;
+                }
+                let atom = chars[start..pos].iter().collect();
+                items.push(Annotation::new(Item::Atom(atom), Location(start, pos)));
+                continue;
+            }
+            c => {
+                return Err(SexprError::new(
+                    SexprErrorKind::UnexpectedChar(c),
+                    Location(pos, pos + 1),
+                ))
+            }
+        }
+        pos += 1;
+    }
+    Ok(items)
+}
+
+///
+/// S式を読み、抽象構文木を作る。
+/// 各節点の位置は、S式の中でその節点を表す部分の位置になる。
+///
+/// ```
+/// use parser::sexpr::from_sexpr;
+///
+/// let ast = from_sexpr("(assign x (call max 1 (neg y)))").unwrap();
+/// assert_eq!(ast.to_string(), "x = max(1, -y)");
+/// ```
+///
+pub fn from_sexpr(input: &str) -> Result<Ast, SexprError> {
+    let items = items(input)?;
+    let end = Location(input.chars().count(), input.chars().count());
+    let mut reader = Reader {
+        items: &items,
+        pos: 0,
+        end,
+    };
+    let ast = reader.expr()?;
+    match reader.items.get(reader.pos) {
+        Some(item) => Err(SexprError::new(
+            SexprErrorKind::TrailingInput,
+            item.location.clone(),
+        )),
+        None => Ok(ast),
+    }
+}
+
+/// 字句の列から抽象構文木を読む
+struct Reader<'a> {
+    items: &'a [Annotation<Item>],
+    pos: usize,
+    /// 入力の終わりの位置
+    end: Location,
+}
+
+impl Reader<'_> {
+    fn next(&mut self) -> Result<&Annotation<Item>, SexprError> {
+        let item = self
+            .items
+            .get(self.pos)
+            .ok_or_else(|| SexprError::new(SexprErrorKind::UnexpectedEnd, self.end.clone()))?;
+        self.pos += 1;
+        Ok(item)
+    }
+
+    ///
+    /// 式を1つ読む。
+    /// 深く入れ子になったS式でもスタックを使い切らないよう、再帰せずに開いているかっこをformsに積んで読む。
+    ///
+    fn expr(&mut self) -> Result<Ast, SexprError> {
+        let mut forms: Vec<Form> = Vec::new();
+        loop {
+            let ast = match (forms.last(), self.items.get(self.pos)) {
+                (Some(_), Some(item)) if item.value == Item::RParen => {
+                    self.pos += 1;
+                    forms.pop().unwrap().finish(&item.location)?
+                }
+                (Some(form), None) => {
+                    return Err(SexprError::new(
+                        SexprErrorKind::UnclosedParen,
+                        form.open.clone(),
+                    ))
+                }
+                _ => {
+                    let item = self.next()?;
+                    let location = item.location.clone();
+                    match item.value {
+                        Item::LParen => {
+                            let form = self.form(location)?;
+                            forms.push(form);
+                            continue;
+                        }
+                        Item::RParen => {
+                            return Err(SexprError::new(SexprErrorKind::UnexpectedRParen, location))
+                        }
+                        Item::Atom(ref atom) if atom.starts_with(|c: char| c.is_ascii_digit()) => {
+                            match atom.parse() {
+                                Ok(n) => Ast::num(n, location),
+                                Err(_) => {
+                                    return Err(SexprError::new(
+                                        SexprErrorKind::InvalidNumber(atom.clone()),
+                                        location,
+                                    ))
+                                }
+                            }
+                        }
+                        Item::Atom(ref name) => Ast::var(name, location),
+                    }
+                }
+            };
+            match forms.last_mut() {
+                Some(form) => form.operands.push(ast),
+                None => return Ok(ast),
+            }
+        }
+    }
+
+    /// 名前を読む
+    fn name(&mut self) -> Result<(String, Location), SexprError> {
+        let item = self.next()?;
+        match item.value {
+            Item::Atom(ref name) if !name.starts_with(|c: char| c.is_ascii_digit()) => {
+                Ok((name.clone(), item.location.clone()))
+            }
+            Item::RParen => Err(SexprError::new(
+                SexprErrorKind::UnexpectedRParen,
+                item.location.clone(),
+            )),
+            _ => Err(SexprError::new(
+                SexprErrorKind::NotName,
+                item.location.clone(),
+            )),
+        }
+    }
+
+    /// 開きかっこの後の、節点の種類と、代入や関数呼び出しの名前を読む
+    fn form(&mut self, open: Location) -> Result<Form, SexprError> {
+        let (head, head_location) = self.name()?;
+        let kind = match head.as_str() {
+            "assign" => FormKind::Assign(self.name()?.0),
+            "call" => FormKind::Call(self.name()?.0),
+            "pos" => FormKind::Unary(UnaryOperatorKind::Plus),
+            "neg" => FormKind::Unary(UnaryOperatorKind::Minus),
+            _ => OPERATORS
+                .iter()
+                .find_map(|def| match def.kind {
+                    OperatorKind::Infix(ref kind) if binary_name(kind) == head => {
+                        Some(FormKind::Binary(kind.clone()))
+                    }
+                    _ => None,
+                })
+                .ok_or_else(|| {
+                    SexprError::new(
+                        SexprErrorKind::UnknownForm(head.clone()),
+                        head_location.clone(),
+                    )
+                })?,
+        };
+        Ok(Form {
+            open,
+            head,
+            head_location,
+            kind,
+            operands: Vec::new(),
+        })
+    }
+}
+
+/// 読んでいる途中のかっこ
+struct Form {
+    /// 開きかっこの位置
+    open: Location,
+    /// 節点の種類の名前とその位置
+    head: String,
+    head_location: Location,
+    kind: FormKind,
+    /// これまでに読んだ要素
+    operands: Vec<Ast>,
+}
+
+/// かっこが表す節点の種類
+enum FormKind {
+    Assign(String),
+    Call(String),
+    Unary(UnaryOperatorKind),
+    Binary(BinaryOperatorKind),
+}
+
+impl Form {
+    /// 閉じかっこまで読んだ要素から節点を作る
+    fn finish(self, close: &Location) -> Result<Ast, SexprError> {
+        let location = self.open.merge(close);
+        let expected = match self.kind {
+            FormKind::Call(_) => self.operands.len(),
+            FormKind::Assign(_) | FormKind::Unary(_) => 1,
+            FormKind::Binary(_) => 2,
+        };
+        if self.operands.len() != expected {
+            return Err(SexprError::new(
+                SexprErrorKind::WrongArity(self.head, expected, self.operands.len()),
+                location,
+            ));
+        }
+        let mut operands = self.operands;
+        Ok(match self.kind {
+            FormKind::Assign(name) => Ast::assign(&name, operands.remove(0), location),
+            FormKind::Call(name) => Ast::call(&name, operands, location),
+            FormKind::Unary(kind) => {
+                let operator = UnaryOperator::new(kind, self.head_location);
+                Ast::unary(operator, operands.remove(0), location)
+            }
+            FormKind::Binary(kind) => {
+                let right = operands.pop().unwrap();
+                let left = operands.pop().unwrap();
+                let operator = BinaryOperator::new(kind, self.head_location);
+                Ast::binary(operator, left, right, location)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::visitor::strip_locations;
+
+    fn to_sexpr(input: &str) -> String {
+        SexprCompiler::new()
+            .compile(&input.parse().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_to_sexpr() {
+        assert_eq!(
+            to_sexpr("1 + 2 * 3 - -10"),
+            "(sub (add 1 (mul 2 3)) (neg 10))"
+        );
+        assert_eq!(
+            to_sexpr("x = y = +2 ^ 0x10 / z | 1"),
+            "(assign x (assign y (bitor (div (pos (pow 2 16)) z) 1)))"
+        );
+        assert_eq!(
+            to_sexpr("pow(2, |x|) + f()"),
+            "(add (call pow 2 (call abs x)) (call f))"
+        );
+    }
+
+    #[test]
+    fn test_from_sexpr() {
+        let ast = from_sexpr("(sub (add 1 x) (neg 10))").unwrap();
+        assert_eq!(ast.location, Location(0, 24));
+        match ast.value {
+            AstKind::Binary {
+                ref operator,
+                ref left,
+                ..
+            } => {
+                assert_eq!(operator.location, Location(1, 4));
+                assert_eq!(left.location, Location(5, 14));
+            }
+            _ => panic!("{:?}", ast),
+        }
+        // 読み直すと、位置を除いて同じ構文木になる
+        for input in [
+            "1 + 2 * 3 - -10",
+            "x = y = +2 ^ 16 / z | 1",
+            "max(1, pow(2, 3), abs(-x)) - f()",
+        ] {
+            let ast = input.parse::<Ast>().unwrap();
+            let back = from_sexpr(&to_sexpr(input)).unwrap();
+            assert_eq!(strip_locations(&back), strip_locations(&ast), "{}", input);
+        }
+        // 長い和のS式は深く入れ子になるが、スタックを使い切らずに読める
+        let long = format!("1{}", " + 1".repeat(100_000));
+        assert_eq!(from_sexpr(&to_sexpr(&long)).unwrap().to_string(), long);
+        assert_eq!(
+            from_sexpr("  (call   max 1\n 2 )  ").map(|ast| ast.to_string()),
+            Ok("max(1, 2)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sexpr_errors() {
+        let error = |input: &str| from_sexpr(input).unwrap_err();
+        assert_eq!(
+            error("(add 1 2"),
+            SexprError::new(SexprErrorKind::UnclosedParen, Location(0, 1))
+        );
+        assert_eq!(
+            error("(add 1"),
+            SexprError::new(SexprErrorKind::UnclosedParen, Location(0, 1))
+        );
+        assert_eq!(
+            error("(mod 1 2)"),
+            SexprError::new(
+                SexprErrorKind::UnknownForm("mod".to_string()),
+                Location(1, 4)
+            )
+        );
+        assert_eq!(
+            error("(neg 1 2)"),
+            SexprError::new(
+                SexprErrorKind::WrongArity("neg".to_string(), 1, 2),
+                Location(0, 9)
+            )
+        );
+        assert_eq!(
+            error("(assign 1 2)"),
+            SexprError::new(SexprErrorKind::NotName, Location(8, 9))
+        );
+        assert_eq!(
+            error("1 2"),
+            SexprError::new(SexprErrorKind::TrailingInput, Location(2, 3))
+        );
+        assert_eq!(
+            error(")"),
+            SexprError::new(SexprErrorKind::UnexpectedRParen, Location(0, 1))
+        );
+        assert_eq!(
+            error(""),
+            SexprError::new(SexprErrorKind::UnexpectedEnd, Location(0, 0))
+        );
+        assert_eq!(
+            error("(add 1 +)"),
+            SexprError::new(SexprErrorKind::UnexpectedChar('+'), Location(7, 8))
+        );
+        assert_eq!(
+            error("99999999999999999999").to_string(),
+            "0-20: '99999999999999999999' does not fit in 64 bits"
+        );
+    }
+}
@@ -0,0 +1,567 @@
+//!
+//! 操車場アルゴリズム（shunting-yard）による中置記法から逆ポーランド記法への変換。
+//! 抽象構文木を経由せずトークンを1つずつ処理し、そのたびに出力と演算子スタックの状態を記録する。
+//! 変換の途中経過を示す教材として使う。
+//!
+use std::fmt::Write;
+
+use super::compiler::RpnOptions;
+use super::interpreter::{function_arity, Arity};
+use super::lexer::*;
+use super::parser::*;
+
+/// 1つのトークンを処理した後の状態
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Step {
+    /// 処理したトークン（入力の終わりでは"(end)"）
+    pub token: String,
+    /// 出力済みの逆ポーランド記法の列
+    pub output: Vec<String>,
+    /// 演算子スタック（末尾が先頭）
+    pub stack: Vec<String>,
+}
+
+/// 演算子スタックに積むもの
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Entry {
+    /// 演算子（表示する記号、優先順位、右結合かどうか）
+    Operator(String, u8, bool),
+    /// 開きかっこ、または絶対値の開始の"|"
+    Open(Token),
+    /// 関数と、これまでに区切った引数の数
+    Function(String, usize),
+    /// 変数への代入
+    Assign(String),
+}
+
+impl Entry {
+    fn label(&self) -> String {
+        match self {
+            Entry::Operator(symbol, ..) | Entry::Function(symbol, _) => symbol.clone(),
+            Entry::Open(tok) => tok.value.to_string(),
+            Entry::Assign(name) => format!("={}", name),
+        }
+    }
+}
+
+/// 途中経過を記録しながら逆ポーランド記法へ変換する変換器
+#[derive(Debug, Default)]
+pub struct ShuntingYard {
+    options: RpnOptions,
+    output: Vec<String>,
+    stack: Vec<Entry>,
+    steps: Vec<Step>,
+    /// 最後に閉じたかっこの開きかっこ
+    closed: Option<Token>,
+}
+
+impl ShuntingYard {
+    pub fn new() -> Self {
+        Self::with_options(RpnOptions::default())
+    }
+
+    /// 逆ポーランド記法の出力の書式を指定して変換器を作成する
+    pub fn with_options(options: RpnOptions) -> Self {
+        ShuntingYard {
+            options,
+            output: Vec::new(),
+            stack: Vec::new(),
+            steps: Vec::new(),
+            closed: None,
+        }
+    }
+
+    ///
+    /// トークンの列を変換し、トークンごとの途中経過を返す。
+    /// 最後の状態の出力は、RpnCompilerの出力と同じ並びになる。
+    /// 構文として正しくない入力は、構文解析のエラーを返す。
+    ///
+    pub fn convert(&mut self, tokens: &[Token]) -> Result<Vec<Step>, ParseError> {
+        // 構文の検査と、"|"がビット論理和かどうかの判定は構文解析器に任せる
+        let ast = parse(tokens)?;
+        self.run(tokens, Some(&bit_or_locations(&ast)), true)?;
+        Ok(std::mem::take(&mut self.steps))
+    }
+
+    ///
+    /// 抽象構文木を作らずに、トークンの列を逆ポーランド記法へ変換し、bufの内容を置き換える。
+    /// 絶対値の中の"|"は常に閉じる"|"として読むので、構文解析器が読み直して
+    /// ビット論理和と判定する入力（"|a | b|"など）はエラーになる。
+    ///
+    pub fn compile_into(&mut self, tokens: &[Token], buf: &mut String) -> Result<(), ParseError> {
+        buf.clear();
+        self.run(tokens, None, false)?;
+        for (i, word) in self.output.iter().enumerate() {
+            if i > 0 {
+                buf.push_str(&self.options.separator);
+            }
+            buf.push_str(word);
+        }
+        if self.options.newline {
+            buf.push('\n');
+        }
+        Ok(())
+    }
+
+    ///
+    /// トークンを1つずつ処理する。
+    /// bit_orにはビット論理和の"|"の位置を渡す。Noneなら絶対値の中の"|"は閉じる"|"とする。
+    ///
+    fn run(
+        &mut self,
+        tokens: &[Token],
+        bit_or: Option<&[Location]>,
+        record: bool,
+    ) -> Result<(), ParseError> {
+        self.output.clear();
+        self.stack.clear();
+        self.steps.clear();
+        self.closed = None;
+        // 次に被演算子が来るべきかどうか（単項演算子の判定に使う）
+        let mut expect_operand = true;
+        for (i, tok) in tokens.iter().enumerate() {
+            let next = tokens.get(i + 1).map(|t| &t.value);
+            match tok.value {
+                // 被演算子の後に被演算子は続かない
+                TokenKind::Number(_)
+                | TokenKind::Literal(_)
+                | TokenKind::Ident(_)
+                | TokenKind::LParen
+                    if !expect_operand =>
+                {
+                    return Err(ParseError::RedundantExpression(tok.clone()));
+                }
+                TokenKind::Number(n) | TokenKind::Literal(Literal { value: Some(n), .. }) => {
+                    self.output.push(n.to_string());
+                    expect_operand = false;
+                }
+                // 代入先の変数は"="と一緒に出力する
+                TokenKind::Ident(_) if next == Some(&TokenKind::Equal) => {}
+                TokenKind::Ident(ref name) if next == Some(&TokenKind::LParen) => {
+                    self.stack.push(Entry::Function(name.clone(), 0));
+                }
+                TokenKind::Ident(ref name) => {
+                    self.output.push(name.clone());
+                    expect_operand = false;
+                }
+                // 代入できるのは文の先頭（または"="の直後）の変数だけ
+                TokenKind::Equal => {
+                    let at_statement_start = i == 1 || tokens[i - 2].value == TokenKind::Equal;
+                    let name = match tokens.get(i.wrapping_sub(1)).map(|t| &t.value) {
+                        Some(TokenKind::Ident(name)) if at_statement_start => name.clone(),
+                        _ => return Err(ParseError::InvalidAssignment(tok.clone())),
+                    };
+                    self.stack.push(Entry::Assign(name));
+                    expect_operand = true;
+                }
+                TokenKind::LParen => {
+                    self.stack.push(Entry::Open(tok.clone()));
+                    expect_operand = true;
+                }
+                // 絶対値の開始の"|"はabs関数の呼び出しとして扱う
+                TokenKind::Pipe if expect_operand => {
+                    self.stack.push(Entry::Function("abs".to_string(), 0));
+                    self.stack.push(Entry::Open(tok.clone()));
+                }
+                TokenKind::Pipe if self.is_close_bar(tok, bit_or) => {
+                    self.close(tok, false)?;
+                    expect_operand = false;
+                }
+                TokenKind::RParen => {
+                    if expect_operand {
+                        let len = self.stack.len();
+                        match (
+                            i.checked_sub(1).map(|j| &tokens[j].value),
+                            self.stack.last(),
+                        ) {
+                            // 関数呼び出しの"()"は引数がないことを表す
+                            (Some(TokenKind::LParen), Some(Entry::Open(_)))
+                                if len >= 2
+                                    && matches!(self.stack[len - 2], Entry::Function(..)) => {}
+                            (Some(TokenKind::LParen), Some(Entry::Open(open))) => {
+                                return Err(ParseError::EmptyParens(open.clone(), tok.clone()));
+                            }
+                            _ => return Err(ParseError::NotExpression(tok.clone())),
+                        }
+                    }
+                    self.close(tok, expect_operand)?;
+                    expect_operand = false;
+                }
+                _ if expect_operand && prefix_operator(tok).is_none() => {
+                    return Err(ParseError::NotExpression(tok.clone()));
+                }
+                TokenKind::Comma => {
+                    self.pop_until_open();
+                    let len = self.stack.len();
+                    let in_call = len >= 2
+                        && matches!(
+                            self.stack[len - 1],
+                            Entry::Open(Token {
+                                value: TokenKind::LParen,
+                                ..
+                            })
+                        );
+                    match self.stack.get_mut(len.wrapping_sub(2)) {
+                        Some(Entry::Function(name, args)) if in_call => {
+                            *args += 1;
+                            if *args >= 2 && is_variadic(name) {
+                                self.output.push(name.clone());
+                            }
+                        }
+                        // 関数呼び出しのかっこの外の","
+                        _ => return Err(ParseError::RedundantExpression(tok.clone())),
+                    }
+                    expect_operand = true;
+                }
+                // 単項の"+"は何もしない
+                TokenKind::Plus if expect_operand => {}
+                _ if expect_operand => {
+                    let (kind, def) = prefix_operator(tok).unwrap();
+                    let symbol = match kind {
+                        UnaryOperatorKind::Minus => self.options.negation.clone(),
+                        kind => kind.to_string(),
+                    };
+                    self.stack
+                        .push(Entry::Operator(symbol, def.precedence, true));
+                }
+                _ => {
+                    let (kind, def) = binary_operator(tok).unwrap();
+                    let right = def.associativity == Associativity::Right;
+                    // 優先順位の高い演算子、または同じ優先順位の左結合の演算子を先に出力する
+                    while let Some(Entry::Operator(_, precedence, _)) = self.stack.last() {
+                        if *precedence > def.precedence || (*precedence == def.precedence && !right)
+                        {
+                            let entry = self.stack.pop().unwrap();
+                            self.output.push(entry.label());
+                        } else {
+                            break;
+                        }
+                    }
+                    self.stack.push(Entry::Operator(
+                        self.options.binary_operator(kind),
+                        def.precedence,
+                        right,
+                    ));
+                    expect_operand = true;
+                }
+            }
+            if record {
+                self.record(tok.value.to_string());
+            }
+        }
+        // 演算子の後で入力が終わった
+        if expect_operand {
+            return Err(match tokens.last() {
+                Some(tok) if prefix_operator(tok).is_some() || binary_operator(tok).is_some() => {
+                    ParseError::MissingOperand(tok.clone())
+                }
+                _ => ParseError::Eof,
+            });
+        }
+        // 残った演算子をすべて出力する
+        while let Some(entry) = self.stack.pop() {
+            if let Entry::Open(tok) = entry {
+                return Err(ParseError::UnclosedOpenParen(tok));
+            }
+            self.output.push(entry.label());
+        }
+        if record {
+            self.record("(end)".to_string());
+        }
+        Ok(())
+    }
+
+    /// 被演算子の後の"|"が、絶対値を閉じる"|"かどうかを返す
+    fn is_close_bar(&self, tok: &Token, bit_or: Option<&[Location]>) -> bool {
+        match bit_or {
+            Some(bit_or) => !bit_or.contains(&tok.location),
+            // 最も内側のかっこが絶対値の"|"であれば閉じる
+            None => matches!(
+                self.stack
+                    .iter()
+                    .rev()
+                    .find(|entry| matches!(entry, Entry::Open(_))),
+                Some(Entry::Open(Token {
+                    value: TokenKind::Pipe,
+                    ..
+                }))
+            ),
+        }
+    }
+
+    /// 閉じかっこ（または絶対値の終わり）を処理する。emptyは中身が空かどうか
+    fn close(&mut self, tok: &Token, empty: bool) -> Result<(), ParseError> {
+        self.pop_until_open();
+        // 開きかっこと種類が合わなければ、余計なトークンである
+        match self.stack.pop() {
+            Some(Entry::Open(open)) if open.value == close_of(&tok.value) => {
+                if open.value == TokenKind::LParen {
+                    self.closed = Some(open);
+                }
+            }
+            _ if tok.value == TokenKind::RParen => {
+                return Err(ParseError::UnmatchedRParen(
+                    tok.clone(),
+                    self.closed.clone(),
+                ))
+            }
+            _ => return Err(ParseError::RedundantExpression(tok.clone())),
+        }
+        if let Some(Entry::Function(..)) = self.stack.last() {
+            if let Some(Entry::Function(name, args)) = self.stack.pop() {
+                let args = if empty { args } else { args + 1 };
+                // 可変個の引数を取る関数は、2つ目以降の引数ごとに出力する
+                if !is_variadic(&name) || args == 0 || args >= 2 {
+                    self.output.push(name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 開きかっこまでの演算子を出力する
+    fn pop_until_open(&mut self) {
+        while let Some(entry) = self.stack.last() {
+            if let Entry::Open(_) = entry {
+                break;
+            }
+            let entry = self.stack.pop().unwrap();
+            self.output.push(entry.label());
+        }
+    }
+
+    fn record(&mut self, token: String) {
+        self.steps.push(Step {
+            token,
+            output: self.output.clone(),
+            stack: self.stack.iter().map(Entry::label).collect(),
+        });
+    }
+}
+
+/// 閉じるトークンに対応する開くトークンを返す
+fn close_of(kind: &TokenKind) -> TokenKind {
+    match kind {
+        TokenKind::RParen => TokenKind::LParen,
+        _ => TokenKind::Pipe,
+    }
+}
+
+fn is_variadic(name: &str) -> bool {
+    matches!(function_arity(name), Some(Arity::AtLeast(_)))
+}
+
+/// ビット論理和の演算子の位置を集める
+fn bit_or_locations(expr: &Ast) -> Vec<Location> {
+    let mut locations = Vec::new();
+    let mut stack = vec![expr];
+    while let Some(expr) = stack.pop() {
+        if let AstKind::Binary { ref operator, .. } = expr.value {
+            if operator.value == BinaryOperatorKind::BitOr {
+                locations.push(operator.location.clone());
+            }
+        }
+        stack.extend(expr.value.children());
+    }
+    locations
+}
+
+///
+/// 途中経過を表として文字列にする。
+///
+/// ```text
+/// token  output  stack
+/// 1      1
+/// +      1       +
+/// ```
+///
+pub fn format_steps(steps: &[Step], buf: &mut String) {
+    buf.clear();
+    let rows: Vec<(String, String, String)> = steps
+        .iter()
+        .map(|step| {
+            (
+                step.token.clone(),
+                step.output.join(" "),
+                step.stack.join(" "),
+            )
+        })
+        .collect();
+    let width = |f: fn(&(String, String, String)) -> &String, title: &str| {
+        rows.iter()
+            .map(|row| f(row).chars().count())
+            .fold(title.len(), std::cmp::max)
+    };
+    let token_width = width(|row| &row.0, "token");
+    let output_width = width(|row| &row.1, "output");
+    let header = (
+        "token".to_string(),
+        "output".to_string(),
+        "stack".to_string(),
+    );
+    for (i, (token, output, stack)) in std::iter::once(&header).chain(&rows).enumerate() {
+        if i > 0 {
+            buf.push('\n');
+        }
+        let line = format!(
+            "{:tw$}  {:ow$}  {}",
+            token,
+            output,
+            stack,
+            tw = token_width,
+            ow = output_width
+        );
+        write!(buf, "{}", line.trim_end()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::RpnCompiler;
+    use proptest::prelude::*;
+
+    fn convert(input: &str) -> Vec<Step> {
+        ShuntingYard::new().convert(&lex(input).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_steps() {
+        let steps = convert("1 + 2 * 3");
+        let stacks: Vec<_> = steps.iter().map(|step| step.stack.join(" ")).collect();
+        assert_eq!(stacks, vec!["", "+", "+", "+ *", "+ *", ""]);
+        assert_eq!(steps.last().unwrap().output.join(" "), "1 2 3 * +");
+    }
+
+    #[test]
+    fn test_matches_rpn_compiler() {
+        for input in &[
+            "1 + 2 * 3 - 4",
+            "(1 + 2) * 3",
+            "2 ^ 3 ^ 2",
+            "x = y = 8 / 2 / 2",
+            "max(1, 2 * 3, min(4, 5), 6) + sqrt(16)",
+            "|a - 3| | 4",
+            "f() + pow(2, 10)",
+            "min(5)",
+            "+(1 + 2) * - +x",
+        ] {
+            let ast = input.parse::<Ast>().unwrap();
+            let steps = convert(input);
+            assert_eq!(
+                steps.last().unwrap().output.join(" "),
+                RpnCompiler::new().compile(&ast),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_unary_and_errors() {
+        assert_eq!(
+            convert("-2 ^ 2").last().unwrap().output.join(" "),
+            "2 2 ^ neg"
+        );
+        assert_eq!(
+            ShuntingYard::new().convert(&lex("(1 + 2").unwrap()),
+            Err(ParseError::UnclosedOpenParen(Token::lparen(Location(0, 1))))
+        );
+    }
+
+    #[test]
+    fn test_format_steps() {
+        let mut buf = String::new();
+        format_steps(&convert("1 + 2"), &mut buf);
+        assert_eq!(
+            buf,
+            "token  output  stack\n1      1\n+      1       +\n2      1 2     +\n(end)  1 2 +"
+        );
+    }
+
+    #[test]
+    fn test_compile_without_ast() {
+        let mut shunting_yard = ShuntingYard::new();
+        let mut compile = |input: &str| {
+            let mut buf = String::new();
+            shunting_yard
+                .compile_into(&lex(input).unwrap(), &mut buf)
+                .map(|_| buf)
+        };
+        assert_eq!(
+            compile("x = -2 ^ |y| * f()"),
+            Ok("2 y abs ^ neg f * =x".to_string())
+        );
+        assert_eq!(
+            compile("1 + 2 ) "),
+            Err(ParseError::UnmatchedRParen(
+                Token::rparen(Location(6, 7)),
+                None
+            ))
+        );
+        assert_eq!(
+            compile("()"),
+            Err(ParseError::EmptyParens(
+                Token::lparen(Location(0, 1)),
+                Token::rparen(Location(1, 2))
+            ))
+        );
+        assert_eq!(
+            compile("1 *"),
+            Err(ParseError::MissingOperand(Token::asterisk(Location(2, 3))))
+        );
+        assert_eq!(
+            compile("(1, 2)"),
+            Err(ParseError::RedundantExpression(Token::comma(Location(
+                2, 3
+            ))))
+        );
+        assert_eq!(
+            compile("1 + x = 2"),
+            Err(ParseError::InvalidAssignment(Token::equal(Location(6, 7))))
+        );
+        assert_eq!(
+            compile("(1 + 2"),
+            Err(ParseError::UnclosedOpenParen(Token::lparen(Location(0, 1))))
+        );
+        assert_eq!(
+            compile(")"),
+            Err(ParseError::NotExpression(Token::rparen(Location(0, 1))))
+        );
+    }
+
+    /// "|"が閉じ記号とビット和のどちらにも読める箇所を含まない式の文字列
+    fn expression() -> impl Strategy<Value = String> {
+        let leaf = prop_oneof![
+            (0u64..1000).prop_map(|n| n.to_string()),
+            prop::sample::select(vec!["x", "y"]).prop_map(String::from)
+        ];
+        leaf.prop_recursive(4, 32, 3, |inner| {
+            prop_oneof![
+                (
+                    inner.clone(),
+                    prop::sample::select(vec!["+", "-", "*", "/", "^"]),
+                    inner.clone()
+                )
+                    .prop_map(|(l, op, r)| format!("{} {} {}", l, op, r)),
+                (inner.clone(), inner.clone()).prop_map(|(l, r)| format!("({} | {})", l, r)),
+                (prop::sample::select(vec!["-", "+"]), inner.clone())
+                    .prop_map(|(op, e)| format!("{}{}", op, e)),
+                inner.clone().prop_map(|e| format!("({})", e)),
+                inner.clone().prop_map(|e| format!("|{}|", e)),
+                prop::collection::vec(inner, 1..4)
+                    .prop_map(|args| format!("max({})", args.join(", "))),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_compile_matches_rpn_compiler(input in expression()) {
+            let ast = input.parse::<Ast>().unwrap();
+            let mut buf = String::new();
+            ShuntingYard::new().compile_into(&lex(&input).unwrap(), &mut buf).unwrap();
+            prop_assert_eq!(buf, RpnCompiler::new().compile(&ast));
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//!
+//! 式の集まり（コーパス）の統計。
+//! 演算子の使われ方や式の深さ、数の範囲、構文解析に失敗した式の割合を集計し、
+//! 手持ちの式を別の処理系へ移す前に、どのような式があるかを把握できるようにする。
+//!
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::lexer::is_blank;
+use super::parser::*;
+
+/// エラーの種類ごとの集計
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorCount {
+    pub count: usize,
+    /// 最初に見つけたエラーの説明
+    pub example: String,
+}
+
+///
+/// 式の集まりについての統計。
+/// 式を1つずつaddで加え、Displayで一覧にして示す。
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CorpusStats {
+    /// 加えた式の数
+    pub expressions: usize,
+    /// 字句解析か構文解析に失敗した式の数
+    pub failures: usize,
+    /// 演算子・関数の呼び出し・代入の出現回数
+    pub operators: BTreeMap<String, usize>,
+    /// 構文木の深さごとの式の数。数や変数だけの式の深さは1とする
+    pub depths: BTreeMap<usize, usize>,
+    /// 数の最小値と最大値
+    pub literals: Option<(u64, u64)>,
+    /// 数の出現回数
+    pub literal_count: usize,
+    /// 診断の符号（"P0004"など）ごとのエラーの数
+    pub errors: BTreeMap<String, ErrorCount>,
+}
+
+impl CorpusStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 1つの式を解析して集計に加える
+    pub fn add(&mut self, input: &str) {
+        self.expressions += 1;
+        match input.parse::<Ast>() {
+            Ok(ast) => {
+                let depth = self.count_nodes(&ast);
+                *self.depths.entry(depth).or_insert(0) += 1;
+            }
+            Err(e) => {
+                self.failures += 1;
+                let diagnostic = e.to_diagnostic(input);
+                self.errors
+                    .entry(diagnostic.code)
+                    .or_insert(ErrorCount {
+                        count: 0,
+                        example: diagnostic.message,
+                    })
+                    .count += 1;
+            }
+        }
+    }
+
+    ///
+    /// 1つの構文木の中の演算子と数を数え、深さを返す。
+    /// 長い演算子の連なりでもスタックを使い切らないよう、再帰せずに作業のスタックで辿る。
+    ///
+    fn count_nodes(&mut self, expr: &Ast) -> usize {
+        use super::parser::AstKind::*;
+        let mut max_depth = 0;
+        let mut stack = vec![(expr, 1)];
+        while let Some((expr, depth)) = stack.pop() {
+            max_depth = max_depth.max(depth);
+            let name = match expr.value {
+                Num(n) => {
+                    self.literal_count += 1;
+                    self.literals = Some(match self.literals {
+                        Some((min, max)) => (min.min(n), max.max(n)),
+                        None => (n, n),
+                    });
+                    None
+                }
+                Var(_) => None,
+                Assign { .. } => Some("=".to_string()),
+                Unary { ref operator, .. } => Some(format!("{} (unary)", operator.value)),
+                Binary { ref operator, .. } => Some(operator.value.to_string()),
+                Call { ref name, .. } => Some(format!("{}()", name)),
+            };
+            if let Some(name) = name {
+                *self.operators.entry(name).or_insert(0) += 1;
+            }
+            stack.extend(
+                expr.value
+                    .children()
+                    .into_iter()
+                    .map(|child| (child, depth + 1)),
+            );
+        }
+        max_depth
+    }
+
+    ///
+    /// 式を1行ずつ書いた文字列を集計に加える。
+    /// 空行やコメントだけの行とREPLのコマンド（":"で始まる行）は除き、
+    /// 行末の"\"は次の行へ続ける
+    ///
+    pub fn add_source(&mut self, source: &str) {
+        let mut pending = String::new();
+        for line in source.lines() {
+            let line = line.trim_end_matches('\r');
+            if let Some(head) = line.strip_suffix('\\') {
+                pending.push_str(head);
+                pending.push('\n');
+                continue;
+            }
+            pending.push_str(line);
+            let expr = std::mem::take(&mut pending);
+            let trimmed = expr.trim();
+            if !is_blank(trimmed) && !trimmed.starts_with(':') {
+                self.add(&expr);
+            }
+        }
+    }
+
+    /// 構文解析に失敗した式の割合（百分率）
+    pub fn failure_rate(&self) -> f64 {
+        if self.expressions == 0 {
+            0.0
+        } else {
+            self.failures as f64 * 100.0 / self.expressions as f64
+        }
+    }
+}
+
+impl fmt::Display for CorpusStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "expressions: {} ({} failed to parse, {:.1}%)",
+            self.expressions,
+            self.failures,
+            self.failure_rate()
+        )?;
+
+        // 多く使われているものから並べる
+        writeln!(f, "operators:")?;
+        let mut operators: Vec<_> = self.operators.iter().collect();
+        operators.sort_by_key(|&(name, count)| (Reverse(count), name));
+        for (name, count) in operators {
+            writeln!(f, "  {:<12} {}", name, count)?;
+        }
+
+        writeln!(f, "depth:")?;
+        for (depth, count) in &self.depths {
+            writeln!(f, "  {:<12} {}", depth, count)?;
+        }
+
+        match self.literals {
+            Some((min, max)) => {
+                writeln!(f, "literals: {} in {}..={}", self.literal_count, min, max)?
+            }
+            None => writeln!(f, "literals: none")?,
+        }
+
+        writeln!(f, "errors:")?;
+        let mut errors: Vec<_> = self.errors.iter().collect();
+        errors.sort_by_key(|&(code, error)| (Reverse(error.count), code));
+        for (code, error) in errors {
+            writeln!(f, "  {:<12} {} (e.g. {})", code, error.count, error.example)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corpus_stats() {
+        let mut stats = CorpusStats::new();
+        stats.add_source(
+            "1 + 2 * 3\n\
+             :mode eval\n\
+             # comment\n\
+             \n\
+             x = -max(4, 100)\n\
+             (1 +\n\
+             7\n\
+             2 + \\\n\
+             3 +\n",
+        );
+        assert_eq!(stats.expressions, 5);
+        assert_eq!(stats.failures, 2);
+        assert_eq!(stats.failure_rate(), 40.0);
+        assert_eq!(stats.operators["+"], 1);
+        assert_eq!(stats.operators["- (unary)"], 1);
+        assert_eq!(stats.operators["max()"], 1);
+        assert_eq!(stats.operators["="], 1);
+        assert_eq!(
+            stats.depths,
+            vec![(1, 1), (3, 1), (4, 1)].into_iter().collect()
+        );
+        assert_eq!(stats.literals, Some((1, 100)));
+        assert_eq!(stats.literal_count, 6);
+        assert_eq!(stats.errors["P0006"].count, 2);
+
+        let report = stats.to_string();
+        assert!(
+            report.starts_with("expressions: 5 (2 failed to parse, 40.0%)\n"),
+            "{}",
+            report
+        );
+        assert!(report.contains("literals: 6 in 1..=100\n"), "{}", report);
+        assert!(report.contains("  P0006        2 (e.g. "), "{}", report);
+    }
+}
@@ -0,0 +1,59 @@
+//!
+//! プロパティテストで使う、ランダムな抽象構文木の生成器。
+//!
+use proptest::prelude::*;
+
+use super::lexer::Location;
+use super::parser::*;
+
+///
+/// 構文解析器が作りうる抽象構文木。代入は文の先頭だけに置く。
+/// 位置情報はすべてLocation(0, 0)なので、strip_locationsした木と比べられる。
+/// 組み込み関数は引数の数を誤ったものも作り、評価のエラーも確かめられるようにする。
+///
+pub fn ast() -> impl Strategy<Value = Ast> {
+    let loc = || Location(0, 0);
+    let leaf = prop_oneof![
+        4 => (0u64..1000).prop_map(move |n| Ast::num(n, loc())),
+        1 => any::<u64>().prop_map(move |n| Ast::num(n, loc())),
+        2 => prop::sample::select(vec!["x", "y"]).prop_map(move |name| Ast::var(name, loc())),
+    ];
+    let expr = leaf.prop_recursive(5, 48, 4, move |inner| {
+        let unary = prop::sample::select(vec![
+            UnaryOperator::plus(loc()),
+            UnaryOperator::minus(loc()),
+        ]);
+        let binary = prop::sample::select(vec![
+            BinaryOperator::add(loc()),
+            BinaryOperator::sub(loc()),
+            BinaryOperator::multi(loc()),
+            BinaryOperator::div(loc()),
+            BinaryOperator::pow(loc()),
+            BinaryOperator::bit_or(loc()),
+        ]);
+        prop_oneof![
+            (binary, inner.clone(), inner.clone()).prop_map(move |(op, l, r)| Ast::binary(
+                op,
+                l,
+                r,
+                loc()
+            )),
+            (unary, inner.clone()).prop_map(move |(op, e)| Ast::unary(op, e, loc())),
+            (
+                prop::sample::select(vec!["abs", "max", "min", "sqrt", "pow"]),
+                prop::collection::vec(inner, 0..4)
+            )
+                .prop_map(move |(name, args)| Ast::call(name, args, loc())),
+        ]
+    });
+    (
+        expr,
+        prop::collection::vec(prop::sample::select(vec!["x", "y"]), 0..3),
+    )
+        .prop_map(move |(e, names)| {
+            names
+                .into_iter()
+                .rev()
+                .fold(e, |value, name| Ast::assign(name, value, loc()))
+        })
+}
@@ -0,0 +1,120 @@
+use std::fmt::Write;
+
+use super::lexer::*;
+use super::parser::*;
+
+///
+/// 演算子の結合の様子を図示する。
+/// 入力の下に、各演算子が結び付けた範囲を"[---]"で示し、入れ子の深い順に並べる。
+///
+/// ```text
+/// 1 + 2 * 3
+///     [---] * (depth 2)
+/// [-------] + (depth 1)
+/// ```
+///
+#[derive(Default)]
+pub struct PrecedenceTracer {
+    spans: Vec<(usize, Location, String)>,
+}
+
+impl PrecedenceTracer {
+    pub fn new() -> Self {
+        PrecedenceTracer { spans: Vec::new() }
+    }
+
+    /// 入力とその抽象構文木から、演算子の結合の図を作って返す
+    pub fn trace(&mut self, input: &str, expr: &Ast) -> String {
+        let mut buf = String::new();
+        self.trace_into(input, expr, &mut buf);
+        buf
+    }
+
+    /// 演算子の結合の図を作り、bufの内容を置き換える
+    pub fn trace_into(&mut self, input: &str, expr: &Ast, buf: &mut String) {
+        buf.clear();
+        self.spans.clear();
+        self.collect(expr);
+        // 入れ子の深いものから、同じ深さなら左にあるものから並べる
+        self.spans
+            .sort_by(|a, b| b.0.cmp(&a.0).then((a.1).0.cmp(&(b.1).0)));
+
+        // 改行やタブは幅が定まらないので、空白に置き換えて1行で表示する
+        let line: String = input
+            .chars()
+            .map(|c| if c.is_whitespace() { ' ' } else { c })
+            .collect();
+        buf.push_str(&line);
+        for (depth, loc, label) in &self.spans {
+            buf.push('\n');
+            buf.push_str(&bracket_line(&line, loc));
+            write!(buf, " {} (depth {})", label, depth).unwrap();
+        }
+    }
+
+    ///
+    /// 演算子の節点の範囲を、子から順に集める。
+    /// 長い演算子の連なりでもスタックを使い切らないよう、再帰せずに作業のスタックで辿る。
+    ///
+    fn collect(&mut self, expr: &Ast) {
+        use super::parser::AstKind::*;
+        // 子を集め終えた節点はtrueとともに積み直す
+        let mut tasks = vec![(expr, 1, false)];
+        while let Some((expr, depth, ready)) = tasks.pop() {
+            if !ready {
+                tasks.push((expr, depth, true));
+                let children = expr.value.children();
+                tasks.extend(
+                    children
+                        .into_iter()
+                        .rev()
+                        .map(|child| (child, depth + 1, false)),
+                );
+                continue;
+            }
+            let label = match expr.value {
+                Num(_) | Var(_) => continue,
+                Assign { .. } => "=".to_string(),
+                Unary { ref operator, .. } => format!("{} (unary)", operator.value),
+                Binary { ref operator, .. } => operator.value.to_string(),
+                Call { ref name, .. } => format!("{}()", name),
+            };
+            self.spans.push((depth, expr.location.clone(), label));
+        }
+    }
+}
+
+/// 位置情報が指す範囲を"[---]"で囲む行を作る
+fn bracket_line(input: &str, loc: &Location) -> String {
+    let carets = caret_line(input, loc);
+    let width = carets.len() - carets.trim_start().len();
+    let len = carets.len() - width;
+    let bracket = if len < 2 {
+        "|".to_string()
+    } else {
+        format!("[{}]", "-".repeat(len - 2))
+    };
+    format!("{}{}", " ".repeat(width), bracket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precedence_trace() {
+        let input = "1 + 2 * 3";
+        let ast = input.parse::<Ast>().unwrap();
+        assert_eq!(
+            PrecedenceTracer::new().trace(input, &ast),
+            "1 + 2 * 3\n    [---] * (depth 2)\n[-------] + (depth 1)"
+        );
+
+        let input = "x = -2 ^ 2";
+        let ast = input.parse::<Ast>().unwrap();
+        assert_eq!(
+            PrecedenceTracer::new().trace(input, &ast),
+            "x = -2 ^ 2\n     [---] ^ (depth 3)\n    [----] - (unary) (depth 2)\n[--------] = (depth 1)"
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use std::fmt::Write;
+
+use super::lexer::Location;
+use super::parser::*;
+
+///
+/// 抽象構文木を字下げした木の形で表示する。
+/// 各節点には演算子や値と、入力中の位置を表示する。
+///
+/// ```text
+/// + 0-9
+/// ├── 1 0-1
+/// └── * 4-9
+///     ├── 2 4-5
+///     └── 3 8-9
+/// ```
+///
+pub fn format_tree(expr: &Ast, buf: &mut String) {
+    buf.clear();
+    let (label, children) = node(expr);
+    write!(buf, "{} {}", label, expr.location).unwrap();
+    // 長い演算子の連なりでもスタックを使い切らないよう、再帰せずに作業のスタックで辿る。
+    // 字下げは1つの文字列に書き、各節点はそのうち自分より上の段の分の長さを持つ
+    let mut prefix = String::new();
+    let mut steps: Vec<(&Ast, usize, bool)> = Vec::new();
+    push_children(&children, 0, &mut steps);
+    while let Some((child, len, last)) = steps.pop() {
+        prefix.truncate(len);
+        let (label, grandchildren) = node(child);
+        let branch = if last { "└── " } else { "├── " };
+        write!(buf, "\n{}{}{} {}", prefix, branch, label, child.location).unwrap();
+        prefix.push_str(if last { "    " } else { "│   " });
+        push_children(&grandchildren, prefix.len(), &mut steps);
+    }
+}
+
+/// 子を、上から順に取り出せるよう逆順に積む
+fn push_children<'a>(children: &[&'a Ast], len: usize, steps: &mut Vec<(&'a Ast, usize, bool)>) {
+    for (i, &child) in children.iter().enumerate().rev() {
+        steps.push((child, len, i + 1 == children.len()));
+    }
+}
+
+/// 節点の表示と、左から順に並べた子を返す
+pub(crate) fn node(expr: &Ast) -> (String, Vec<&Ast>) {
+    use super::parser::AstKind::*;
+    match expr.value {
+        Num(n) => (n.to_string(), vec![]),
+        Var(ref name) => (name.clone(), vec![]),
+        Assign {
+            ref name,
+            ref value,
+        } => (format!("{} =", name), vec![value]),
+        Unary {
+            ref operator,
+            ref operand,
+        } => (format!("{} (unary)", operator.value), vec![operand]),
+        Binary {
+            ref operator,
+            ref left,
+            ref right,
+        } => (operator.value.to_string(), vec![left, right]),
+        Call { ref name, ref args } => (format!("{}()", name), args.iter().collect()),
+    }
+}
+
+///
+/// 抽象構文木をJSONで表す。
+/// 各節点は種類（"kind"）と入力中の位置（"span"）を持ち、演算子にも位置を付ける。
+/// 1行に収め、エディタの拡張機能などRust以外のプログラムから読めるようにする。
+///
+/// ```text
+/// {"kind":"binary","span":[0,5],"operator":{"symbol":"+","span":[2,3]},
+///  "left":{"kind":"num","span":[0,1],"value":1},"right":{"kind":"var","span":[4,5],"name":"x"}}
+/// ```
+///
+pub fn format_json(expr: &Ast, buf: &mut String) {
+    buf.clear();
+    write_json(expr, buf);
+}
+
+/// JSONへ変換するときの作業
+enum Step<'a> {
+    /// 節点を書く
+    Node(&'a Ast),
+    /// 文字列をそのまま書く
+    Text(&'static str),
+}
+
+///
+/// 節点をJSONで書く。
+/// 長い演算子の連なりでもスタックを使い切らないよう、再帰せずに作業のスタックで辿る。
+///
+fn write_json(expr: &Ast, buf: &mut String) {
+    use super::parser::AstKind::*;
+    let mut steps = vec![Step::Node(expr)];
+    while let Some(step) = steps.pop() {
+        let expr = match step {
+            Step::Node(expr) => expr,
+            Step::Text(text) => {
+                buf.push_str(text);
+                continue;
+            }
+        };
+        let kind = match expr.value {
+            Num(_) => "num",
+            Var(_) => "var",
+            Assign { .. } => "assign",
+            Unary { .. } => "unary",
+            Binary { .. } => "binary",
+            Call { .. } => "call",
+        };
+        write!(
+            buf,
+            "{{\"kind\":\"{}\",\"span\":{}",
+            kind,
+            json_span(&expr.location)
+        )
+        .unwrap();
+        steps.push(Step::Text("}"));
+        match expr.value {
+            Num(n) => write!(buf, ",\"value\":{}", n).unwrap(),
+            Var(ref name) => write!(buf, ",\"name\":{}", json_string(name)).unwrap(),
+            Assign {
+                ref name,
+                ref value,
+            } => {
+                write!(buf, ",\"name\":{},\"value\":", json_string(name)).unwrap();
+                steps.push(Step::Node(value));
+            }
+            Unary {
+                ref operator,
+                ref operand,
+            } => {
+                write_operator(&operator.value.to_string(), &operator.location, buf);
+                buf.push_str(",\"operand\":");
+                steps.push(Step::Node(operand));
+            }
+            Binary {
+                ref operator,
+                ref left,
+                ref right,
+            } => {
+                write_operator(&operator.value.to_string(), &operator.location, buf);
+                buf.push_str(",\"left\":");
+                steps.extend([
+                    Step::Node(right),
+                    Step::Text(",\"right\":"),
+                    Step::Node(left),
+                ]);
+            }
+            Call { ref name, ref args } => {
+                write!(buf, ",\"name\":{},\"args\":[", json_string(name)).unwrap();
+                steps.push(Step::Text("]"));
+                for (i, arg) in args.iter().enumerate().rev() {
+                    steps.push(Step::Node(arg));
+                    if i > 0 {
+                        steps.push(Step::Text(","));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn write_operator(symbol: &str, location: &Location, buf: &mut String) {
+    write!(
+        buf,
+        ",\"operator\":{{\"symbol\":{},\"span\":{}}}",
+        json_string(symbol),
+        json_span(location)
+    )
+    .unwrap();
+}
+
+/// 位置を[開始, 終了]の配列にする
+fn json_span(location: &Location) -> String {
+    format!("[{},{}]", location.0, location.1)
+}
+
+/// 文字列をJSONの文字列リテラルにする
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_tree() {
+        let mut buf = String::new();
+        format_tree(&"1 + 2 * 3".parse().unwrap(), &mut buf);
+        assert_eq!(
+            buf,
+            "+ 0-9\n├── 1 0-1\n└── * 4-9\n    ├── 2 4-5\n    └── 3 8-9"
+        );
+        format_tree(&"x = max(-1, 2) | y".parse().unwrap(), &mut buf);
+        assert_eq!(
+            buf,
+            "x = 0-18\n\
+             └── | 4-18\n    \
+                 ├── max() 4-14\n    \
+                 │   ├── - (unary) 8-10\n    \
+                 │   │   └── 1 9-10\n    \
+                 │   └── 2 12-13\n    \
+                 └── y 17-18"
+        );
+    }
+
+    #[test]
+    fn test_format_json() {
+        let mut buf = String::new();
+        format_json(&"1 + x".parse().unwrap(), &mut buf);
+        assert_eq!(
+            buf,
+            r#"{"kind":"binary","span":[0,5],"operator":{"symbol":"+","span":[2,3]},"left":{"kind":"num","span":[0,1],"value":1},"right":{"kind":"var","span":[4,5],"name":"x"}}"#
+        );
+        format_json(&"y = -max()".parse().unwrap(), &mut buf);
+        assert_eq!(
+            buf,
+            r#"{"kind":"assign","span":[0,10],"name":"y","value":{"kind":"unary","span":[4,10],"operator":{"symbol":"-","span":[4,5]},"operand":{"kind":"call","span":[5,10],"name":"max","args":[]}}}"#
+        );
+        // 標準的なJSONとして読める
+        format_json(&"x = max(-1, 2 ^ 3) | y".parse().unwrap(), &mut buf);
+        let json: serde_json::Value = serde_json::from_str(&buf).unwrap();
+        assert_eq!(json["value"]["left"]["args"][1]["operator"]["symbol"], "^");
+        assert_eq!(json["value"]["right"]["span"], serde_json::json!([21, 22]));
+    }
+}
@@ -0,0 +1,235 @@
+//!
+//! 抽象構文木を辿る処理の共通の枠組み。
+//! 節点の種類ごとのメソッドを必要な分だけ実装すれば、残りの節点は既定の方法で辿られる。
+//!
+use super::lexer::Location;
+use super::parser::*;
+
+///
+/// 構文木を辿る処理。
+/// 既定のメソッドは何もせず、子を左から順に辿る。
+///
+pub trait Visitor {
+    /// 節点を処理する。既定では節点の種類に応じたメソッドを呼ぶ
+    fn visit(&mut self, expr: &Ast) {
+        walk_ast(self, expr)
+    }
+
+    fn visit_num(&mut self, _n: u64, _location: &Location) {}
+
+    fn visit_var(&mut self, _name: &str, _location: &Location) {}
+
+    fn visit_assign(&mut self, _name: &str, value: &Ast, _location: &Location) {
+        self.visit(value)
+    }
+
+    fn visit_unary(&mut self, _operator: &UnaryOperator, operand: &Ast, _location: &Location) {
+        self.visit(operand)
+    }
+
+    fn visit_binary(
+        &mut self,
+        _operator: &BinaryOperator,
+        left: &Ast,
+        right: &Ast,
+        _location: &Location,
+    ) {
+        self.visit(left);
+        self.visit(right);
+    }
+
+    fn visit_call(&mut self, _name: &str, args: &[Ast], _location: &Location) {
+        for arg in args {
+            self.visit(arg);
+        }
+    }
+}
+
+/// 節点の種類に応じて、Visitorのメソッドを呼び分ける
+pub fn walk_ast<V: Visitor + ?Sized>(visitor: &mut V, expr: &Ast) {
+    use super::parser::AstKind::*;
+    let location = &expr.location;
+    match expr.value {
+        Num(n) => visitor.visit_num(n, location),
+        Var(ref name) => visitor.visit_var(name, location),
+        Assign {
+            ref name,
+            ref value,
+        } => visitor.visit_assign(name, value, location),
+        Unary {
+            ref operator,
+            ref operand,
+        } => visitor.visit_unary(operator, operand, location),
+        Binary {
+            ref operator,
+            ref left,
+            ref right,
+        } => visitor.visit_binary(operator, left, right, location),
+        Call { ref name, ref args } => visitor.visit_call(name, args, location),
+    }
+}
+
+///
+/// 構文木を作り替える処理。
+/// 既定のメソッドは、子を作り替えたうえで同じ種類・同じ位置の節点を作る。
+///
+pub trait Fold {
+    /// 節点を作り替える。既定では節点の種類に応じたメソッドを呼ぶ
+    fn fold(&mut self, expr: &Ast) -> Ast {
+        fold_ast(self, expr)
+    }
+
+    fn fold_num(&mut self, n: u64, location: &Location) -> Ast {
+        Ast::num(n, location.clone())
+    }
+
+    fn fold_var(&mut self, name: &str, location: &Location) -> Ast {
+        Ast::var(name, location.clone())
+    }
+
+    fn fold_assign(&mut self, name: &str, value: &Ast, location: &Location) -> Ast {
+        Ast::assign(name, self.fold(value), location.clone())
+    }
+
+    fn fold_unary(&mut self, operator: &UnaryOperator, operand: &Ast, location: &Location) -> Ast {
+        Ast::unary(operator.clone(), self.fold(operand), location.clone())
+    }
+
+    fn fold_binary(
+        &mut self,
+        operator: &BinaryOperator,
+        left: &Ast,
+        right: &Ast,
+        location: &Location,
+    ) -> Ast {
+        let left = self.fold(left);
+        let right = self.fold(right);
+        Ast::binary(operator.clone(), left, right, location.clone())
+    }
+
+    fn fold_call(&mut self, name: &str, args: &[Ast], location: &Location) -> Ast {
+        let args = args.iter().map(|arg| self.fold(arg)).collect();
+        Ast::call(name, args, location.clone())
+    }
+}
+
+/// 節点の種類に応じて、Foldのメソッドを呼び分ける
+pub fn fold_ast<F: Fold + ?Sized>(folder: &mut F, expr: &Ast) -> Ast {
+    use super::parser::AstKind::*;
+    let location = &expr.location;
+    match expr.value {
+        Num(n) => folder.fold_num(n, location),
+        Var(ref name) => folder.fold_var(name, location),
+        Assign {
+            ref name,
+            ref value,
+        } => folder.fold_assign(name, value, location),
+        Unary {
+            ref operator,
+            ref operand,
+        } => folder.fold_unary(operator, operand, location),
+        Binary {
+            ref operator,
+            ref left,
+            ref right,
+        } => folder.fold_binary(operator, left, right, location),
+        Call { ref name, ref args } => folder.fold_call(name, args, location),
+    }
+}
+
+/// 位置情報をすべてLocation(0, 0)にした構文木を作る。位置を問わずに形だけを比べるときに使う
+pub fn strip_locations(expr: &Ast) -> Ast {
+    StripLocations.fold(expr)
+}
+
+struct StripLocations;
+
+impl Fold for StripLocations {
+    fn fold_num(&mut self, n: u64, _location: &Location) -> Ast {
+        Ast::num(n, Location(0, 0))
+    }
+
+    fn fold_var(&mut self, name: &str, _location: &Location) -> Ast {
+        Ast::var(name, Location(0, 0))
+    }
+
+    fn fold_assign(&mut self, name: &str, value: &Ast, _location: &Location) -> Ast {
+        Ast::assign(name, self.fold(value), Location(0, 0))
+    }
+
+    fn fold_unary(&mut self, operator: &UnaryOperator, operand: &Ast, _location: &Location) -> Ast {
+        let operator = UnaryOperator::new(operator.value.clone(), Location(0, 0));
+        Ast::unary(operator, self.fold(operand), Location(0, 0))
+    }
+
+    fn fold_binary(
+        &mut self,
+        operator: &BinaryOperator,
+        left: &Ast,
+        right: &Ast,
+        _location: &Location,
+    ) -> Ast {
+        let operator = BinaryOperator::new(operator.value.clone(), Location(0, 0));
+        let left = self.fold(left);
+        Ast::binary(operator, left, self.fold(right), Location(0, 0))
+    }
+
+    fn fold_call(&mut self, name: &str, args: &[Ast], _location: &Location) -> Ast {
+        let args = args.iter().map(|arg| self.fold(arg)).collect();
+        Ast::call(name, args, Location(0, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 変数の名前を集める
+    #[derive(Default)]
+    struct Variables(Vec<String>);
+
+    impl Visitor for Variables {
+        fn visit_var(&mut self, name: &str, _location: &Location) {
+            self.0.push(name.to_string());
+        }
+    }
+
+    /// 変数を0に置き換える
+    struct Zero;
+
+    impl Fold for Zero {
+        fn fold_var(&mut self, _name: &str, location: &Location) -> Ast {
+            Ast::num(0, location.clone())
+        }
+    }
+
+    #[test]
+    fn test_visitor() {
+        let ast = "x = max(y, -z * 2) + y".parse::<Ast>().unwrap();
+        let mut variables = Variables::default();
+        variables.visit(&ast);
+        assert_eq!(variables.0, vec!["y", "z", "y"]);
+
+        let ast = "a + b * 2".parse::<Ast>().unwrap();
+        assert_eq!(Zero.fold(&ast), "0 + 0 * 2".parse::<Ast>().unwrap());
+    }
+
+    #[test]
+    fn test_strip_locations() {
+        let ast = "x = -max(y, 2)".parse::<Ast>().unwrap();
+        let loc = || Location(0, 0);
+        assert_eq!(
+            strip_locations(&ast),
+            Ast::assign(
+                "x",
+                Ast::unary(
+                    UnaryOperator::minus(loc()),
+                    Ast::call("max", vec![Ast::var("y", loc()), Ast::num(2, loc())], loc()),
+                    loc()
+                ),
+                loc()
+            )
+        );
+        assert_eq!(crate::ast!(x = -max(y, 2)), strip_locations(&ast));
+    }
+}
@@ -0,0 +1,428 @@
+//!
+//! WebAssemblyのテキスト形式（.wat）への変換。
+//! 式を計算する関数を1つだけ公開するモジュールを出力する。
+//! 抽象構文木の各節点がスタックマシンのどの命令列になるかを見せるのに使う。
+//!
+use std::fmt::Write;
+
+use super::compiler::{check_call, Backend, CompileError};
+use super::interpreter::*;
+use super::parser::*;
+
+///
+/// 組み込み関数を実装する補助関数。使われたものだけを出力する。
+/// 評価器がエラーにする引数（負の数の平方根、負の指数）ではトラップする。
+///
+const HELPERS: [(&str, &str); 5] = [
+    (
+        "abs",
+        "  (func $abs (param $n i64) (result i64)
+    i64.const 0
+    local.get $n
+    i64.sub
+    local.get $n
+    local.get $n
+    i64.const 0
+    i64.lt_s
+    select
+  )",
+    ),
+    (
+        "min",
+        "  (func $min (param $a i64) (param $b i64) (result i64)
+    local.get $a
+    local.get $b
+    local.get $a
+    local.get $b
+    i64.lt_s
+    select
+  )",
+    ),
+    (
+        "max",
+        "  (func $max (param $a i64) (param $b i64) (result i64)
+    local.get $a
+    local.get $b
+    local.get $a
+    local.get $b
+    i64.gt_s
+    select
+  )",
+    ),
+    (
+        "pow",
+        "  (func $pow (param $base i64) (param $exp i64) (result i64)
+    (local $result i64)
+    local.get $exp
+    i64.const 0
+    i64.lt_s
+    if
+      unreachable
+    end
+    local.get $exp
+    i64.eqz
+    if
+      i64.const 1
+      return
+    end
+    local.get $base
+    i64.eqz
+    if
+      i64.const 0
+      return
+    end
+    i64.const 1
+    local.set $result
+    block $done
+      loop $next
+        local.get $exp
+        i64.eqz
+        br_if $done
+        local.get $exp
+        i64.const 1
+        i64.and
+        i32.wrap_i64
+        if
+          local.get $result
+          local.get $base
+          i64.mul
+          local.set $result
+        end
+        local.get $base
+        local.get $base
+        i64.mul
+        local.set $base
+        local.get $exp
+        i64.const 1
+        i64.shr_u
+        local.set $exp
+        br $next
+      end
+    end
+    local.get $result
+  )",
+    ),
+    (
+        "sqrt",
+        "  (func $sqrt (param $n i64) (result i64)
+    (local $root i64)
+    local.get $n
+    i64.const 0
+    i64.lt_s
+    if
+      unreachable
+    end
+    local.get $n
+    f64.convert_i64_s
+    f64.sqrt
+    i64.trunc_f64_s
+    local.set $root
+    block $small
+      loop $down
+        local.get $root
+        local.get $root
+        i64.mul
+        local.get $n
+        i64.le_u
+        br_if $small
+        local.get $root
+        i64.const 1
+        i64.sub
+        local.set $root
+        br $down
+      end
+    end
+    block $large
+      loop $up
+        local.get $root
+        i64.const 1
+        i64.add
+        local.get $root
+        i64.const 1
+        i64.add
+        i64.mul
+        local.get $n
+        i64.gt_u
+        br_if $large
+        local.get $root
+        i64.const 1
+        i64.add
+        local.set $root
+        br $up
+      end
+    end
+    local.get $root
+  )",
+    ),
+];
+
+///
+/// WebAssemblyのテキスト形式へのコンパイラ。
+/// 式は"main"という名前で公開する関数になり、代入より前に読む変数はその引数になる。
+/// 四則演算の桁あふれは検出せず、64ビットで折り返す。ゼロ除算はトラップする。
+///
+#[derive(Default)]
+pub struct WasmCompiler {
+    /// 引数になる変数（最初に読む順）
+    params: Vec<String>,
+    /// 代入してから読む変数
+    locals: Vec<String>,
+    /// 使われた補助関数（HELPERSの順）
+    helpers: [bool; HELPERS.len()],
+    /// 関数本体の命令列
+    body: String,
+}
+
+impl WasmCompiler {
+    pub fn new() -> Self {
+        WasmCompiler::default()
+    }
+
+    /// 関数本体に命令を1つ加える
+    fn emit(&mut self, instruction: &str) {
+        self.body.push_str("    ");
+        self.body.push_str(instruction);
+        self.body.push('\n');
+    }
+
+    /// 変数を初めて見たとき、読むなら引数に、代入するなら局所変数にする
+    fn declare(&mut self, name: &str, assigned: bool) {
+        if self.params.iter().chain(&self.locals).any(|n| n == name) {
+            return;
+        }
+        if assigned {
+            self.locals.push(name.to_string());
+        } else {
+            self.params.push(name.to_string());
+        }
+    }
+
+    /// 補助関数を呼ぶ
+    fn call(&mut self, name: &str) {
+        let index = HELPERS.iter().position(|&(n, _)| n == name).unwrap();
+        self.helpers[index] = true;
+        self.emit(&format!("call ${}", name));
+    }
+
+    ///
+    /// 節点を命令列へ変換する。
+    /// 長い演算子の連なりでもネイティブのスタックを使い切らないよう、
+    /// 再帰せずに作業のスタックで辿る。作業は命令を出す順の逆に積む。
+    ///
+    fn compile_inner(&mut self, expr: &Ast) -> Result<(), InterpreterError> {
+        use super::parser::AstKind::*;
+        let mut steps = vec![Step::Node(expr)];
+        while let Some(step) = steps.pop() {
+            let expr = match step {
+                Step::Node(expr) => expr,
+                Step::Emit(instruction) => {
+                    self.emit(instruction);
+                    continue;
+                }
+                Step::Call(name) => {
+                    self.call(name);
+                    continue;
+                }
+                Step::Assign(name) => {
+                    self.declare(name, true);
+                    self.emit(&format!("local.tee ${}", name));
+                    continue;
+                }
+            };
+            match expr.value {
+                Num(n) => {
+                    let n =
+                        literal(n).map_err(|e| InterpreterError::new(e, expr.location.clone()))?;
+                    self.emit(&format!("i64.const {}", n));
+                }
+                Var(ref name) => {
+                    self.declare(name, false);
+                    self.emit(&format!("local.get ${}", name));
+                }
+                // 代入した値を式の値として残す
+                Assign {
+                    ref name,
+                    ref value,
+                } => steps.extend([Step::Assign(name), Step::Node(value)]),
+                Unary {
+                    ref operator,
+                    ref operand,
+                } => match operator.value {
+                    UnaryOperatorKind::Plus => steps.push(Step::Node(operand)),
+                    // 符号を反転する命令はないので、0から引く
+                    UnaryOperatorKind::Minus => {
+                        self.emit("i64.const 0");
+                        steps.extend([Step::Emit("i64.sub"), Step::Node(operand)]);
+                    }
+                },
+                Binary {
+                    ref operator,
+                    ref left,
+                    ref right,
+                } => {
+                    steps.push(match operator.value {
+                        BinaryOperatorKind::Add => Step::Emit("i64.add"),
+                        BinaryOperatorKind::Sub => Step::Emit("i64.sub"),
+                        BinaryOperatorKind::Multi => Step::Emit("i64.mul"),
+                        BinaryOperatorKind::Div => Step::Emit("i64.div_s"),
+                        BinaryOperatorKind::BitOr => Step::Emit("i64.or"),
+                        BinaryOperatorKind::Pow => Step::Call("pow"),
+                    });
+                    steps.extend([Step::Node(right), Step::Node(left)]);
+                }
+                Call { ref name, ref args } => {
+                    check_call(name, args.len(), &expr.location)?;
+                    // minとmaxは2つずつ比べる
+                    let pairwise = name == "min" || name == "max";
+                    if !pairwise {
+                        steps.push(Step::Call(name));
+                    }
+                    for (i, arg) in args.iter().enumerate().rev() {
+                        if i > 0 && pairwise {
+                            steps.push(Step::Call(name));
+                        }
+                        steps.push(Step::Node(arg));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 命令列へ変換するときの作業
+enum Step<'a> {
+    /// 節点を変換する
+    Node(&'a Ast),
+    /// 命令をそのまま出す
+    Emit(&'static str),
+    /// 補助関数を呼ぶ
+    Call(&'a str),
+    /// 計算した値を変数に代入する
+    Assign(&'a str),
+}
+
+impl Backend for WasmCompiler {
+    fn name(&self) -> &str {
+        "wat"
+    }
+
+    /// 抽象構文木をWebAssemblyのテキスト形式のモジュールへ変換し、bufの内容を置き換える
+    fn compile_into(&mut self, expr: &Ast, buf: &mut String) -> Result<(), CompileError> {
+        buf.clear();
+        self.params.clear();
+        self.locals.clear();
+        self.helpers = [false; HELPERS.len()];
+        self.body.clear();
+        self.compile_inner(expr)?;
+
+        buf.push_str("(module\n");
+        for (&(_, helper), &used) in HELPERS.iter().zip(&self.helpers) {
+            if used {
+                buf.push_str(helper);
+                buf.push('\n');
+            }
+        }
+        buf.push_str("  (func $main (export \"main\")");
+        for name in &self.params {
+            write!(buf, " (param ${} i64)", name).unwrap();
+        }
+        buf.push_str(" (result i64)\n");
+        for name in &self.locals {
+            writeln!(buf, "    (local ${} i64)", name).unwrap();
+        }
+        buf.push_str(&self.body);
+        buf.push_str("  )\n)");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Location;
+
+    fn compile(input: &str) -> Result<String, InterpreterError> {
+        WasmCompiler::new().compile(&input.parse().unwrap())
+    }
+
+    #[test]
+    fn test_wasm() {
+        assert_eq!(
+            compile("1 + 2 * 3"),
+            Ok("(module
+  (func $main (export \"main\") (result i64)
+    i64.const 1
+    i64.const 2
+    i64.const 3
+    i64.mul
+    i64.add
+  )
+)"
+            .to_string())
+        );
+        // 代入より前に読む変数は引数に、代入してから読む変数は局所変数になる
+        assert_eq!(
+            compile("y = -x / max(x, 2, y)"),
+            Ok("(module
+  (func $max (param $a i64) (param $b i64) (result i64)
+    local.get $a
+    local.get $b
+    local.get $a
+    local.get $b
+    i64.gt_s
+    select
+  )
+  (func $main (export \"main\") (param $x i64) (param $y i64) (result i64)
+    i64.const 0
+    local.get $x
+    i64.sub
+    local.get $x
+    i64.const 2
+    call $max
+    local.get $y
+    call $max
+    i64.div_s
+    local.tee $y
+  )
+)"
+            .to_string())
+        );
+        let program = compile("a = 2 ^ sqrt(abs(a - 1) | 4)").unwrap();
+        let functions: Vec<_> = program
+            .lines()
+            .filter(|line| line.starts_with("  (func"))
+            .collect();
+        assert_eq!(
+            functions,
+            vec![
+                "  (func $abs (param $n i64) (result i64)",
+                "  (func $pow (param $base i64) (param $exp i64) (result i64)",
+                "  (func $sqrt (param $n i64) (result i64)",
+                "  (func $main (export \"main\") (param $a i64) (result i64)",
+            ]
+        );
+        assert!(program.ends_with("    call $pow\n    local.tee $a\n  )\n)"));
+        // 左右のかっこの数が合う
+        assert_eq!(program.matches('(').count(), program.matches(')').count());
+
+        assert_eq!(
+            compile("9223372036854775808"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::Overflow,
+                Location(0, 19)
+            ))
+        );
+        assert_eq!(
+            compile("sqrt(1, 2)"),
+            Err(InterpreterError::new(
+                InterpreterErrorKind::WrongArgumentCount {
+                    name: "sqrt".to_string(),
+                    expected: Arity::Exactly(1),
+                    found: 2,
+                },
+                Location(0, 10)
+            ))
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//!
+//! ブラウザのJavaScriptから電卓を使うためのwasm-bindgenの束縛。
+//! wasm機能を有効にしてcdylibとしてビルドし、wasm-bindgenでJavaScriptの束縛を作る。
+//!
+//! ```text
+//! cargo rustc --lib --release --target wasm32-unknown-unknown --features wasm --crate-type cdylib
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/parser.wasm
+//! ```
+//!
+//! Webの試用ページなどから次のように呼べる。
+//!
+//! ```text
+//! import init, { parse, evaluate, compileRpn } from "./pkg/parser.js";
+//!
+//! await init();
+//! evaluate("1 + 2 * 3");   // 7n
+//! compileRpn("1 + 2 * 3"); // "1 2 3 * +"
+//! parse("1 + x");          // { kind: "binary", span: [0, 5], ... }
+//! try {
+//!     evaluate("1 / 0");
+//! } catch (e) {
+//!     const { code, message, start, end } = e.diagnostics[0]; // "E0001", ..., 2, 3
+//! }
+//! ```
+//!
+//! どの関数も呼び出しごとに新しい処理系で処理するので、前の呼び出しの代入は引き継がない。
+//!
+use wasm_bindgen::prelude::*;
+
+use super::batch::LineOutcome;
+use super::engine::{Engine, Mode};
+use super::parser::ApplicationError;
+
+///
+/// JavaScriptへ渡す診断。
+/// startとendは入力中の範囲で、JavaScriptの文字列の添字（UTF-16の単位）で数える。
+/// input.slice(start, end)がエラーの箇所になる。
+///
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebDiagnostic {
+    /// エラーの種類ごとに決まった符号（"P0004"など）
+    pub code: String,
+    pub message: String,
+    pub start: u32,
+    pub end: u32,
+    /// 入力に"^"で注釈を付けた表示
+    pub rendered: String,
+}
+
+/// 処理に失敗したとき投げる値。見つけたエラーを見つけた順にすべて持つ
+#[wasm_bindgen]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebError {
+    diagnostics: Vec<WebDiagnostic>,
+}
+
+#[wasm_bindgen]
+impl WebError {
+    #[wasm_bindgen(getter)]
+    pub fn diagnostics(&self) -> Vec<WebDiagnostic> {
+        self.diagnostics.clone()
+    }
+
+    /// 最初のエラーのメッセージ
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.diagnostics
+            .first()
+            .map(|diagnostic| diagnostic.message.clone())
+            .unwrap_or_default()
+    }
+
+    /// すべてのエラーの注釈付きの表示をつなげたもの
+    #[wasm_bindgen(js_name = toString)]
+    pub fn to_js_string(&self) -> String {
+        let rendered: Vec<_> = self
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.rendered.as_str())
+            .collect();
+        rendered.join("\n")
+    }
+}
+
+impl WebError {
+    fn new(input: &str, errors: &[ApplicationError]) -> Self {
+        let diagnostics = errors
+            .iter()
+            .map(|error| {
+                let diagnostic = error.to_diagnostic(input);
+                // 最初に加えた位置がエラーの箇所で、位置がなければ入力の終わりを指す
+                let (start, end) = match diagnostic.labels.first() {
+                    Some((location, _)) => (location.0, location.1),
+                    None => {
+                        let len = input.chars().count();
+                        (len, len)
+                    }
+                };
+                WebDiagnostic {
+                    code: diagnostic.code.clone(),
+                    message: diagnostic.message.clone(),
+                    start: utf16_offset(input, start),
+                    end: utf16_offset(input, end),
+                    rendered: diagnostic.render(input),
+                }
+            })
+            .collect();
+        WebError { diagnostics }
+    }
+}
+
+/// 文字単位の位置を、JavaScriptの文字列の添字へ変換する
+fn utf16_offset(input: &str, chars: usize) -> u32 {
+    input
+        .chars()
+        .take(chars)
+        .map(|c| c.len_utf16() as u32)
+        .sum()
+}
+
+/// 新しい処理系で1行を処理する
+fn run(mode: Mode, input: &str) -> Result<LineOutcome, WebError> {
+    match LineOutcome::from(Engine::new(mode).run(input)) {
+        LineOutcome::Error { errors, .. } => Err(WebError::new(input, &errors)),
+        outcome => Ok(outcome),
+    }
+}
+
+///
+/// 式を構文解析し、抽象構文木をJavaScriptのオブジェクトとして返す。
+/// 形はtree::format_jsonの出力と同じで、各節点の"span"は文字単位の位置。
+///
+#[wasm_bindgen]
+pub fn parse(input: &str) -> Result<JsValue, WebError> {
+    match run(Mode::AstJson, input)? {
+        LineOutcome::Trace(json) => {
+            Ok(js_sys::JSON::parse(&json).expect("format_json writes valid JSON"))
+        }
+        outcome => unreachable!("{:?}", outcome),
+    }
+}
+
+///
+/// 式を評価する。JavaScriptではBigIntとして受け取る。
+/// JavaScriptではevalという名前を使えないので、evaluateとして公開する。
+///
+#[wasm_bindgen(js_name = evaluate)]
+pub fn eval(input: &str) -> Result<i64, WebError> {
+    match run(Mode::Eval, input)? {
+        LineOutcome::Value(n) => Ok(n),
+        outcome => unreachable!("{:?}", outcome),
+    }
+}
+
+/// 式を逆ポーランド記法へ変換する
+#[wasm_bindgen(js_name = compileRpn)]
+pub fn compile_rpn(input: &str) -> Result<String, WebError> {
+    match run(Mode::Rpn, input)? {
+        LineOutcome::Rpn(rpn) => Ok(rpn),
+        outcome => unreachable!("{:?}", outcome),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(error: &WebError) -> Vec<&str> {
+        error.diagnostics.iter().map(|d| d.code.as_str()).collect()
+    }
+
+    #[test]
+    fn test_bindings() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(compile_rpn("1 + 2 * 3"), Ok("1 2 3 * +".to_string()));
+        assert!(matches!(
+            run(Mode::AstJson, "1 + x"),
+            Ok(LineOutcome::Trace(json)) if json.starts_with("{\"kind\":\"binary\"")
+        ));
+
+        let error = eval("1 / 0").unwrap_err();
+        assert_eq!(codes(&error), ["E0001"]);
+        assert_eq!(
+            (error.diagnostics[0].start, error.diagnostics[0].end),
+            (2, 3)
+        );
+        assert_eq!(error.message(), error.diagnostics[0].message);
+        assert!(error.to_js_string().starts_with("error[E0001]: "));
+        // 前の呼び出しの代入は引き継がない
+        assert_eq!(eval("x = 1"), Ok(1));
+        assert_eq!(codes(&eval("x").unwrap_err()), ["E0002"]);
+
+        // 見つけたエラーをすべて返す
+        let error = compile_rpn("1 $ 2 @ 3").unwrap_err();
+        assert_eq!(codes(&error), ["L0001", "L0001"]);
+        assert!(error.to_js_string().contains("\n\nerror[L0001]"));
+    }
+
+    #[test]
+    fn test_utf16_span() {
+        // "😀"はUTF-16では2単位なので、後ろの位置は文字単位より1つずつ大きくなる
+        let error = eval("1 + 😀 $").unwrap_err();
+        let spans: Vec<_> = error.diagnostics.iter().map(|d| (d.start, d.end)).collect();
+        assert_eq!(codes(&error)[..2], ["L0001", "L0001"]);
+        assert_eq!(spans[..2], [(4, 6), (7, 8)]);
+    }
+}
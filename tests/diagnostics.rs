@@ -0,0 +1,125 @@
+//!
+//! 誤った入力の診断の表示を、保存しておいた表示（スナップショット）と比べるテスト。
+//! 表示を意図して変えたときは、次のようにしてスナップショットを書き直す。
+//!
+//! UPDATE_SNAPSHOTS=1 cargo test --test diagnostics
+//!
+//! 書き直さずに表示が変わった場合は、新しい表示を"<名前>.snap.new"に書き出して失敗する。
+//!
+use std::fs;
+use std::path::PathBuf;
+
+use parser::engine::{Engine, Mode, Outcome};
+use parser::limits::Limits;
+
+/// スナップショットの名前と、誤った入力
+const CORPUS: &[(&str, &str)] = &[
+    // 字句解析のエラー
+    ("invalid_char", "1 + $"),
+    ("number_too_large", "99999999999999999999999 * 2"),
+    ("ident_after_number", "2x + 1"),
+    ("missing_digits", "0x + 1"),
+    ("invalid_digit", "0b102"),
+    ("not_an_integer", "1.5 * 2"),
+    ("several_lex_errors", "1 $ 2 @ 3"),
+    (
+        "input_too_long",
+        "100 + 200 + 300 + 400 + 500 + 600 + 700 + 800 + 900 + 1000 + 1100",
+    ),
+    ("too_many_tokens", "1+2+3+4+5+6+7+8+9+10+11+12+13+14+15+16"),
+    // 構文解析のエラー
+    ("not_expression", "1 + * 2"),
+    ("unclosed_paren", "2 * (1 + 3"),
+    ("redundant_expression", "(1 + 2 3)"),
+    ("unmatched_rparen", "(1 + 2)) * 3"),
+    ("missing_operand", "1 +"),
+    ("empty_parens", "2 * ()"),
+    ("invalid_assignment", "1 + x = 2"),
+    ("unclosed_abs", "|1 - 2"),
+    ("too_deep", "((((((((((1))))))))))"),
+    ("several_parse_errors", "1 + * 2 - / 3"),
+    // 評価のエラー
+    ("division_by_zero", "1 + 10 / (5 - 5)"),
+    ("undefined_variable", "x * 2"),
+    ("overflow", "9223372036854775807 + 1"),
+    ("negative_exponent", "2 ^ -1"),
+    ("unknown_function", "foo(1)"),
+    ("wrong_argument_count", "pow(2)"),
+    ("invalid_argument", "sqrt(-4)"),
+    ("too_many_steps", "1+1+1+1+1+1+1+1+1+1+1"),
+    // 位置の表示
+    ("multiline", "1 +\n2 / 0"),
+    ("wide_chars", "あい + $"),
+    ("tab", "1\t+\t$"),
+];
+
+/// コーパスを処理するときの上限。上限に関わるエラーも短い入力で確かめられるようにする
+const LIMITS: Limits = Limits {
+    max_input_len: 64,
+    max_tokens: 30,
+    max_ast_depth: 8,
+    max_eval_steps: 20,
+};
+
+/// 入力を評価し、見つかったエラーの表示を返す
+fn render(input: &str) -> String {
+    let mut engine = Engine::new(Mode::Eval);
+    engine.set_limits(LIMITS);
+    let errors = match engine.run(input) {
+        Outcome::Error { errors, .. } => errors,
+        outcome => panic!("{:?} did not fail: {:?}", input, outcome),
+    };
+    let mut buf = Vec::new();
+    for (i, error) in errors.iter().enumerate() {
+        if i > 0 {
+            buf.push(b'\n');
+        }
+        error.write_diagnostic(input, &mut buf).unwrap();
+    }
+    let rendered = String::from_utf8(buf).unwrap();
+    format!("input: {:?}\n---\n{}", input, rendered)
+}
+
+fn snapshot_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("snapshots")
+        .join(format!("{}.snap", name))
+}
+
+#[test]
+fn test_diagnostic_snapshots() {
+    let update = std::env::var_os("UPDATE_SNAPSHOTS").is_some();
+    let mut mismatches = Vec::new();
+    for (name, input) in CORPUS {
+        let actual = render(input);
+        let path = snapshot_path(name);
+        let new_path = path.with_extension("snap.new");
+        if update {
+            fs::write(&path, &actual).unwrap();
+            let _ = fs::remove_file(&new_path);
+            continue;
+        }
+        match fs::read_to_string(&path) {
+            Ok(expected) if expected == actual => {
+                let _ = fs::remove_file(&new_path);
+            }
+            expected => {
+                fs::write(&new_path, &actual).unwrap();
+                match expected {
+                    Ok(expected) => mismatches.push(format!(
+                        "{}:\n--- expected\n{}--- actual\n{}",
+                        name, expected, actual
+                    )),
+                    Err(_) => mismatches.push(format!("{}: no snapshot\n{}", name, actual)),
+                }
+            }
+        }
+    }
+    assert!(
+        mismatches.is_empty(),
+        "{} snapshot(s) differ (rerun with UPDATE_SNAPSHOTS=1 to accept):\n\n{}",
+        mismatches.len(),
+        mismatches.join("\n")
+    );
+}